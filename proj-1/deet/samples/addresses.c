@@ -0,0 +1,12 @@
+#include <stdio.h>
+#include <stdlib.h>
+
+int main() {
+    int local = 0;
+    void *heap = malloc(16);
+    printf("main at %p\n", (void *)main);
+    printf("stack at %p\n", (void *)&local);
+    printf("heap at %p\n", heap);
+    free(heap);
+    return 0;
+}
@@ -0,0 +1,16 @@
+#include <stdio.h>
+
+int ticks = 0;
+
+void tick(int i) {
+    ticks++;
+    printf("tick %d\n", i);
+}
+
+int main() {
+    for (int i = 0; i < 10; i++) {
+        tick(i);
+    }
+    printf("%d ticks\n", ticks);
+    return 0;
+}
@@ -0,0 +1,22 @@
+#include <stdio.h>
+
+// Built with -O2 by the tests: collatz_steps gets inlined into main, and its instructions are
+// interleaved with main's, so stepping jumps back and forth between the two functions' lines
+
+static int collatz_steps(unsigned long n) {
+    int steps = 0;
+    while (n != 1) {
+        n = (n % 2 == 0) ? n / 2 : 3 * n + 1;
+        steps++;
+    }
+    return steps;
+}
+
+int main(int argc, char *argv[]) {
+    int total = 0;
+    for (unsigned long n = 1; n <= 5; n++) {
+        total += collatz_steps(n + argc);
+    }
+    printf("total %d\n", total);
+    return 0;
+}
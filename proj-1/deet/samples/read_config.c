@@ -0,0 +1,16 @@
+#include <stdio.h>
+
+int main() {
+    // Opened relative to the working directory
+    FILE *config = fopen("config.txt", "r");
+    if (config == NULL) {
+        printf("Could not open config.txt\n");
+        return 1;
+    }
+    char line[256];
+    while (fgets(line, sizeof(line), config) != NULL) {
+        printf("config: %s", line);
+    }
+    fclose(config);
+    return 0;
+}
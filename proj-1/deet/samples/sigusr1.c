@@ -0,0 +1,19 @@
+#include <signal.h>
+#include <stdio.h>
+
+volatile sig_atomic_t handled = 0;
+
+void handler(int sig) {
+    handled = sig;
+}
+
+int main() {
+    signal(SIGUSR1, handler);
+    raise(SIGUSR1);
+    if (handled) {
+        printf("handler ran\n");
+    } else {
+        printf("handler did not run\n");
+    }
+    return 0;
+}
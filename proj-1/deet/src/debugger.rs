@@ -1,9 +1,10 @@
 use crate::debugger_command::DebuggerCommand;
-use crate::dwarf_data::{DwarfData, Error as DwarfError};
+use crate::dwarf_data::{DwarfData, Error as DwarfError, Location};
 use crate::inferior::{self, Inferior};
 use rustyline::error::ReadlineError;
-use rustyline::Editor;
+use rustyline::{Config, Editor};
 use std::collections::HashMap;
+use std::path::Path;
 use std::fmt::format;
 
 #[derive(Clone)]
@@ -19,11 +20,18 @@ pub struct Debugger {
     inferior: Option<Inferior>,
     debug_data: DwarfData,
     breakpoints: HashMap<usize, Option<u8>>,
+    /// Breakpoint addresses in the order they were set, so breakpoint numbers can be resolved
+    breakpoint_addrs: Vec<usize>,
+    /// Working directory to start the inferior in (set with `set cwd`). None means deet's own cwd
+    cwd: Option<String>,
+    /// Start the inferior with address space randomization turned off (--no-aslr)
+    no_aslr: bool,
 }
 
 impl Debugger {
-    /// Initializes the debugger.
-    pub fn new(target: &str) -> Debugger {
+    /// Initializes the debugger. `history_size` caps how many commands are kept in
+    /// ~/.deet_history (None keeps rustyline's default).
+    pub fn new(target: &str, no_aslr: bool, history_size: Option<usize>) -> Debugger {
         // TODO (milestone 3): initialize the DwarfData
         let debug_data = match DwarfData::from_file(target) {
             Ok(val) => val,
@@ -36,18 +44,32 @@ impl Debugger {
                 std::process::exit(1);
             }
         };
+        // Command resolves a relative program path against the inferior's working directory, so
+        // pin the target down now in case `set cwd` moves the inferior somewhere else
+        let target = std::fs::canonicalize(target)
+            .map(|path| path.to_string_lossy().to_string())
+            .unwrap_or_else(|_| target.to_string());
         let history_path = format!("{}/.deet_history", std::env::var("HOME").unwrap());
-        let mut readline = Editor::<()>::new().unwrap();
-        // Attempt to load history from ~/.deet_history if it exists
+        // Ctrl-R reverse history search comes with rustyline's default (emacs) key bindings
+        let mut config = Config::builder();
+        if let Some(history_size) = history_size {
+            config = config.max_history_size(history_size);
+        }
+        let mut readline = Editor::<()>::with_config(config.build()).unwrap();
+        // Attempt to load history from ~/.deet_history if it exists. Only the newest entries up to
+        // the history size are kept, so the file is trimmed the next time it's saved
         let _ = readline.load_history(&history_path);
         debug_data.print();
         Debugger {
-            target: target.to_string(),
+            target,
             history_path,
             readline,
             inferior: None,
             debug_data: debug_data,
             breakpoints: HashMap::new(),
+            breakpoint_addrs: Vec::new(),
+            cwd: None,
+            no_aslr,
         }
     }
 
@@ -55,7 +77,14 @@ impl Debugger {
         loop {
             match self.get_next_command() {
                 DebuggerCommand::Run(args) => {
-                    if let Some(inferior) = Inferior::new(&self.target, &args) {
+                    if let Some(cwd) = &self.cwd {
+                        if !Path::new(cwd).is_dir() {
+                            let message = format!("Working directory {} does not exist", cwd);
+                            Debugger::report_message(&message);
+                            continue;
+                        }
+                    }
+                    if let Some(inferior) = Inferior::new(&self.target, &args, self.cwd.as_deref(), self.no_aslr) {
                         // Create the inferior
                         if self.inferior.is_some() {
                             let prev_proc = self.inferior.as_mut().unwrap();
@@ -67,41 +96,18 @@ impl Debugger {
                         // You may use self.inferior.as_mut().unwrap() to get a mutable reference
                         // to the Inferior object
                         let inferior = self.inferior.as_mut().unwrap();
-                        // inject breakpoints
-                        for (addr, prev_byte) in self.breakpoints.clone() {
-                            // 如果已经插入breakpoints，直接跳过
-                            if prev_byte.is_some() {
-                                continue;
-                            }
+                        // inject breakpoints (a fresh process has none of them yet, even if
+                        // they were injected into an earlier run)
+                        for addr in self.breakpoint_addrs.clone() {
                             let prev_byte = inferior
                                 .write_byte(addr, 0xcc)
                                 .expect("Errors: When setting breakpoint at {breakpoint}");
                             self.breakpoints.insert(addr, Some(prev_byte));
                         }
                         let status = inferior
-                            .continue_exec(&self.breakpoints, &self.debug_data)
+                            .continue_exec(&self.breakpoints, &self.debug_data, None)
                             .expect("nix::error");
-
-                        match status {
-                            inferior::Status::Stopped(signal, rip) => {
-                                let message = format!("Child stopped (signal {})", signal);
-                                Debugger::report_message(&message);
-
-                                let line = self.debug_data.get_line_from_addr(rip);
-                                if let Some(line) = line {
-                                    let message = format!("Stopped at {}", line);
-                                    Debugger::report_message(&message);
-                                }
-                            }
-                            inferior::Status::Exited(code) => {
-                                let message = format!("Child exited (status {})", code);
-                                Debugger::report_message(&message);
-                            }
-                            inferior::Status::Signaled(signal) => {
-                                let message = format!("signaled by {}", signal);
-                                Debugger::report_message(&message);
-                            }
-                        }
+                        self.report_status(status);
                     } else {
                         Debugger::report_message(&"Error starting subprocess".to_string());
                     }
@@ -114,34 +120,91 @@ impl Debugger {
                     }
                     return;
                 }
-                DebuggerCommand::Continue => match &self.inferior {
-                    Some(_) => {
-                        let inferior = self.inferior.as_mut().unwrap();
+                DebuggerCommand::Continue => match self.inferior.as_mut() {
+                    Some(inferior) => {
                         let status = inferior
-                            .continue_exec(&self.breakpoints, &self.debug_data)
+                            .continue_exec(&self.breakpoints, &self.debug_data, None)
                             .expect("nix::error");
-                        match status {
-                            inferior::Status::Stopped(signal, rip) => {
-                                let message = format!("Child stopped (signal {})", signal);
-                                Debugger::report_message(&message);
-                                let line = self.debug_data.get_line_from_addr(rip);
-                                if let Some(line) = line {
-                                    let message = format!("Stopped at {}", line);
-                                    Debugger::report_message(&message);
-                                }
-                            }
-                            inferior::Status::Exited(code) => {
-                                let message = format!("Child exited (status {})", code);
-                                Debugger::report_message(&message);
-                            }
-                            inferior::Status::Signaled(signal) => {
-                                let message = format!("signaled by {}", signal);
-                                Debugger::report_message(&message);
-                            }
+                        self.report_status(status);
+                    }
+                    None => println!("The program is not running currently!"),
+                },
+                DebuggerCommand::Signal(signal) => match self.inferior.as_mut() {
+                    Some(inferior) => {
+                        println!("Continuing with signal {}", signal);
+                        let status = inferior
+                            .continue_exec(&self.breakpoints, &self.debug_data, Some(signal))
+                            .expect("nix::error");
+                        self.report_status(status);
+                    }
+                    None => println!("The program is not running currently!"),
+                },
+                DebuggerCommand::Next => match self.inferior.as_mut() {
+                    Some(inferior) => {
+                        let status = inferior
+                            .step_line(&self.breakpoints, &self.debug_data)
+                            .expect("nix::error");
+                        self.report_status(status);
+                    }
+                    None => println!("The program is not running currently!"),
+                },
+                DebuggerCommand::Count(bp_num) => {
+                    match (self.inferior.as_mut(), self.breakpoint_addrs.get(bp_num)) {
+                        (None, _) => println!("The program is not running currently!"),
+                        (_, None) => println!("No breakpoint number {}", bp_num),
+                        (Some(inferior), Some(&addr)) => {
+                            let (hits, status) = inferior
+                                .count_breakpoint_hits(addr, &self.breakpoints)
+                                .expect("nix::error");
+                            let message = format!(
+                                "Breakpoint {} ({:#x}) was hit {} more times",
+                                bp_num, addr, hits
+                            );
+                            Debugger::report_message(&message);
+                            self.report_status(status);
                         }
                     }
+                }
+                DebuggerCommand::SetCwd(path) => {
+                    println!("Working directory for the next run set to {}", path);
+                    self.cwd = Some(path);
+                }
+                DebuggerCommand::SetNearestLine(enabled) => {
+                    self.debug_data.set_nearest_line_fallback(enabled);
+                    println!(
+                        "Nearest-line fallback for addresses without line info is {}",
+                        if enabled { "on" } else { "off" }
+                    );
+                }
+                DebuggerCommand::InfoCwd => match &self.cwd {
+                    Some(cwd) => println!("Working directory for the program: {}", cwd),
+                    None => println!("No working directory set; the program runs in deet's cwd"),
+                },
+                DebuggerCommand::InfoProcMappings => match &self.inferior {
+                    Some(inferior) => match inferior.memory_mappings() {
+                        Ok(mappings) => {
+                            println!(
+                                "{:>18} {:>18} {:>10} {:>10} {:<5} {}",
+                                "Start Addr", "End Addr", "Size", "Offset", "Perms", "objfile"
+                            );
+                            for mapping in mappings {
+                                println!(
+                                    "{:>#18x} {:>#18x} {:>#10x} {:>#10x} {:<5} {}",
+                                    mapping.start,
+                                    mapping.end,
+                                    mapping.end - mapping.start,
+                                    mapping.offset,
+                                    mapping.permissions,
+                                    mapping.path
+                                );
+                            }
+                        }
+                        Err(err) => println!("Could not read memory mappings: {}", err),
+                    },
                     None => println!("The program is not running currently!"),
                 },
+                DebuggerCommand::DebugState => self.print_debug_state(),
+                DebuggerCommand::Print(expr) => self.print_symbol(&expr),
                 DebuggerCommand::BackTrace => {
                     if self.inferior.is_some() {
                         self.inferior
@@ -155,13 +218,13 @@ impl Debugger {
                     if addr.starts_with("*0x") {
                         addr.remove(0);
                         let addr = Debugger::parse_address(&addr).unwrap();
-                        Debugger::record_breakpoint(addr, &mut self.inferior, &mut self.breakpoints);
+                        Debugger::record_breakpoint(addr, &mut self.inferior, &mut self.breakpoints, &mut self.breakpoint_addrs);
                     }else {
                         match Debugger::parse_address(&addr) {
                             Some(addr) => {
                                 // get a line 
                                 if let Some(addr) = self.debug_data.get_addr_for_line(None, addr) {
-                                    Debugger::record_breakpoint(addr, &mut self.inferior, &mut self.breakpoints);
+                                    Debugger::record_breakpoint(addr, &mut self.inferior, &mut self.breakpoints, &mut self.breakpoint_addrs);
                                 }else{
                                     let message = format!("No such line {}" , addr);
                                     Debugger::report_message(&message);
@@ -187,7 +250,7 @@ impl Debugger {
                                     //         self.breakpoints.insert(addr, None);
                                     //     }
                                     // }
-                                    Debugger::record_breakpoint(addr, &mut self.inferior, &mut self.breakpoints);
+                                    Debugger::record_breakpoint(addr, &mut self.inferior, &mut self.breakpoints, &mut self.breakpoint_addrs);
                                 }else{
                                     let message = format!("No such function {}" , addr);
                                     Debugger::report_message(&message);
@@ -201,7 +264,109 @@ impl Debugger {
         }
     }
     
-    fn record_breakpoint(addr :usize , inferior : &mut Option<Inferior> , breakpoints : &mut HashMap<usize, Option<u8>> ){
+    /// Handles `print [&]symbol` for functions and global variables. Addresses come straight from
+    /// the debug info, so they can be printed before the program is started; a global's value is
+    /// only shown while the program is running.
+    fn print_symbol(&self, expr: &str) {
+        let take_address = expr.starts_with('&');
+        let name = expr.trim_start_matches('&');
+        if let Some(addr) = self.debug_data.get_addr_for_function(None, name) {
+            println!("{} = function {} at {:#x}", expr, name, addr);
+            return;
+        }
+        let var = match self.debug_data.get_global_variable(name) {
+            Some(var) => var,
+            None => {
+                println!("No function or global variable named \"{}\"", name);
+                return;
+            }
+        };
+        let addr = match var.location {
+            Location::Address(addr) => addr,
+            Location::FramePointerOffset(_) => {
+                println!("{} does not have a static address", name);
+                return;
+            }
+        };
+        if take_address {
+            println!("{} = ({} *) {:#x}", expr, var.entity_type.name, addr);
+            return;
+        }
+        match &self.inferior {
+            Some(inferior) => match inferior.read_value(addr, var.entity_type.size) {
+                Ok(value) => println!(
+                    "{} = {} ({} at {:#x})",
+                    name, value, var.entity_type.name, addr
+                ),
+                Err(err) => println!("Could not read {} at {:#x}: {}", name, addr, err),
+            },
+            None => println!(
+                "{} is a global {} at {:#x} (start the program to see its value)",
+                name, var.entity_type.name, addr
+            ),
+        }
+    }
+
+    /// Dumps the debugger's internal bookkeeping. This is for debugging deet itself (and for
+    /// scripted checks of its state), so the format is not meant to be pretty.
+    fn print_debug_state(&self) {
+        println!("target: {}", self.target);
+        println!("cwd: {}", self.cwd.as_deref().unwrap_or("<inherited>"));
+        println!("no_aslr: {}", self.no_aslr);
+        println!("nearest_line_fallback: {}", self.debug_data.nearest_line_fallback());
+        println!("breakpoints: {}", self.breakpoint_addrs.len());
+        for (num, addr) in self.breakpoint_addrs.iter().enumerate() {
+            match self.breakpoints.get(addr) {
+                Some(Some(orig_byte)) => println!(
+                    "  #{} addr={:#x} orig_byte={:#04x} injected=true",
+                    num, addr, orig_byte
+                ),
+                _ => println!("  #{} addr={:#x} orig_byte=<none> injected=false", num, addr),
+            }
+        }
+        match &self.inferior {
+            Some(inferior) => {
+                println!("inferior: running (pid {})", inferior.pid());
+                match nix::sys::ptrace::getregs(inferior.pid()) {
+                    Ok(regs) => println!("rip: {:#x}", regs.rip),
+                    Err(err) => println!("rip: <unavailable: {}>", err),
+                }
+            }
+            None => println!("inferior: none"),
+        }
+    }
+
+    /// Prints how the inferior stopped. If it is no longer alive, it is dropped so that later
+    /// commands report that nothing is running, and its breakpoints are marked as not injected.
+    fn report_status(&mut self, status: inferior::Status) {
+        match status {
+            inferior::Status::Stopped(signal, rip) => {
+                let message = format!("Child stopped (signal {})", signal);
+                Debugger::report_message(&message);
+                let message = format!("Stopped at {}", self.debug_data.describe_addr(rip));
+                Debugger::report_message(&message);
+            }
+            inferior::Status::Exited(code) => {
+                let message = format!("Child exited (status {})", code);
+                Debugger::report_message(&message);
+                self.clear_inferior();
+            }
+            inferior::Status::Signaled(signal) => {
+                let message = format!("signaled by {}", signal);
+                Debugger::report_message(&message);
+                self.clear_inferior();
+            }
+        }
+    }
+
+    fn clear_inferior(&mut self) {
+        self.inferior = None;
+        for orig_byte in self.breakpoints.values_mut() {
+            *orig_byte = None;
+        }
+    }
+
+    fn record_breakpoint(addr :usize , inferior : &mut Option<Inferior> , breakpoints : &mut HashMap<usize, Option<u8>> , breakpoint_addrs : &mut Vec<usize> ){
         if breakpoints.contains_key(&addr) {
             // 如果已经插入了这个breakPoints，直接跳过
             let message = format!("BreakPoint {:#x} has been added ", addr);
@@ -210,6 +375,7 @@ impl Debugger {
             let message =
                 format!("Set breakpoint {} at {:#x}", breakpoints.len(), addr);
             Debugger::report_message(&message);
+            breakpoint_addrs.push(addr);
             if inferior.is_some() {
                 let inferior = inferior.as_mut().unwrap();
                 let prev_byte = inferior
@@ -1,9 +1,23 @@
+use nix::sys::signal::Signal;
+use std::convert::TryFrom;
+
 pub enum DebuggerCommand {
     Quit,
     Run(Vec<String>),
     Continue,
     BackTrace ,
     Break(String) ,
+    Print(String),
+    Next,
+    Count(usize),
+    SetCwd(String),
+    /// Whether locations without line info are shown relative to the nearest preceding line
+    SetNearestLine(bool),
+    InfoCwd,
+    InfoProcMappings,
+    Signal(Signal),
+    /// Internal: dumps deet's own state (for debugging deet itself, not the inferior)
+    DebugState,
 }
 
 impl DebuggerCommand {
@@ -19,8 +33,42 @@ impl DebuggerCommand {
             "c" | "continue" | "cont" =>  Some(DebuggerCommand::Continue),
             "bt"| "back" | "backtrace" => Some(DebuggerCommand::BackTrace),
             "b" | "break" => Some(DebuggerCommand::Break(tokens[1].to_string())) , 
+            "n" | "next" => Some(DebuggerCommand::Next),
+            "p" | "print" => Some(DebuggerCommand::Print(tokens.get(1)?.to_string())),
+            "count" => Some(DebuggerCommand::Count(tokens.get(1)?.parse().ok()?)),
+            "signal" => Some(DebuggerCommand::Signal(parse_signal(tokens.get(1)?)?)),
+            "debug-state" => Some(DebuggerCommand::DebugState),
+            "set" => match *tokens.get(1)? {
+                "cwd" if tokens.len() > 2 => Some(DebuggerCommand::SetCwd(tokens[2..].join(" "))),
+                "nearest-line" => match *tokens.get(2)? {
+                    "on" => Some(DebuggerCommand::SetNearestLine(true)),
+                    "off" => Some(DebuggerCommand::SetNearestLine(false)),
+                    _ => None,
+                },
+                _ => None,
+            },
+            "info" => match *tokens.get(1)? {
+                "cwd" => Some(DebuggerCommand::InfoCwd),
+                "proc" if tokens.get(2) == Some(&"mappings") => {
+                    Some(DebuggerCommand::InfoProcMappings)
+                }
+                _ => None,
+            },
             // Default case:
             _ => None,
         }
     }
 }
+
+/// Parses a signal given as a number ("10"), a full name ("SIGUSR1"), or a short name ("usr1").
+fn parse_signal(name: &str) -> Option<Signal> {
+    if let Ok(number) = name.parse::<i32>() {
+        return Signal::try_from(number).ok();
+    }
+    let name = name.to_uppercase();
+    if name.starts_with("SIG") {
+        name.parse().ok()
+    } else {
+        format!("SIG{}", name).parse().ok()
+    }
+}
@@ -13,6 +13,9 @@ pub enum Error {
 pub struct DwarfData {
     files: Vec<File>,
     addr2line: Context<addr2line::gimli::EndianRcSlice<addr2line::gimli::RunTimeEndian>>,
+    /// Whether describe_addr falls back to the nearest preceding line when an address has no line
+    /// info of its own (common in optimized builds)
+    nearest_line_fallback: bool,
 }
 
 impl fmt::Debug for DwarfData {
@@ -41,6 +44,7 @@ impl DwarfData {
         Ok(DwarfData {
             files: gimli_wrapper::load_file(&object, endian)?,
             addr2line: Context::new(&object).or_else(|e| Err(gimli_wrapper::Error::from(e)))?,
+            nearest_line_fallback: true,
         })
     }
 
@@ -87,6 +91,14 @@ impl DwarfData {
         }
     }
 
+    #[allow(dead_code)]
+    pub fn get_global_variable(&self, var_name: &str) -> Option<&Variable> {
+        self.files
+            .iter()
+            .flat_map(|file| file.global_variables.iter())
+            .find(|var| var.name == var_name)
+    }
+
     #[allow(dead_code)]
     pub fn get_line_from_addr(&self, curr_addr: usize) -> Option<Line> {
         let location = self
@@ -100,6 +112,53 @@ impl DwarfData {
         })
     }
 
+    /// Finds the closest line entry at or before `curr_addr` within the function containing it,
+    /// returning the line along with how far past its first instruction `curr_addr` is.
+    #[allow(dead_code)]
+    pub fn get_nearest_line(&self, curr_addr: usize) -> Option<(Line, usize)> {
+        for file in &self.files {
+            let func = match file.functions.iter().find(|func| {
+                func.address <= curr_addr && curr_addr < func.address + func.text_length
+            }) {
+                Some(func) => func,
+                None => continue,
+            };
+            let line = file
+                .lines
+                .iter()
+                // Line 0 means "no source line" (see gimli_wrapper), so it's no help here
+                .filter(|line| {
+                    line.number != 0 && func.address <= line.address && line.address <= curr_addr
+                })
+                .max_by_key(|line| line.address)?;
+            return Some((line.clone(), curr_addr - line.address));
+        }
+        None
+    }
+
+    pub fn set_nearest_line_fallback(&mut self, enabled: bool) {
+        self.nearest_line_fallback = enabled;
+    }
+
+    pub fn nearest_line_fallback(&self) -> bool {
+        self.nearest_line_fallback
+    }
+
+    /// Describes where `curr_addr` is in the source, for messages shown to the user. Never fails:
+    /// if there's no exact line info this gives "file:line + 0xoffset" from the nearest preceding
+    /// line (when that fallback is enabled), and otherwise just the raw address.
+    pub fn describe_addr(&self, curr_addr: usize) -> String {
+        if let Some(line) = self.get_line_from_addr(curr_addr) {
+            return line.to_string();
+        }
+        if self.nearest_line_fallback {
+            if let Some((line, offset)) = self.get_nearest_line(curr_addr) {
+                return format!("{} + {:#x}", line, offset);
+            }
+        }
+        format!("{:#x} (no line info)", curr_addr)
+    }
+
     #[allow(dead_code)]
     pub fn get_function_from_addr(&self, curr_addr: usize) -> Option<String> {
         let frame = self
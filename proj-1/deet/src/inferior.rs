@@ -2,6 +2,7 @@ use crate::dwarf_data::DwarfData;
 use crate::dwarf_data::Line;
 use crate::inferior;
 use addr2line::gimli::DebugAddrBase;
+use nix::sys::personality::{self, Persona};
 use nix::sys::ptrace;
 use nix::sys::signal;
 use nix::sys::signal::Signal;
@@ -30,6 +31,17 @@ pub enum Status {
     Signaled(signal::Signal),
 }
 
+/// One line of /proc/<pid>/maps: a region of the inferior's address space.
+pub struct MemoryMapping {
+    pub start: usize,
+    pub end: usize,
+    /// Permission string as the kernel prints it, e.g. "r-xp"
+    pub permissions: String,
+    pub offset: usize,
+    /// Backing file or pseudo-path such as [heap]; empty for anonymous mappings
+    pub path: String,
+}
+
 /// This function calls ptrace with PTRACE_TRACEME to enable debugging on a process. You should use
 /// pre_exec with Command to call this in the child process.
 fn child_traceme() -> Result<(), std::io::Error> {
@@ -39,20 +51,41 @@ fn child_traceme() -> Result<(), std::io::Error> {
     )))
 }
 
+/// This function turns off address space layout randomization for the calling process (and
+/// anything it execs), so the inferior's stack, heap, and library addresses match across runs.
+fn child_disable_aslr() -> Result<(), std::io::Error> {
+    let persona = personality::get().or(Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "personality get failed",
+    )))?;
+    personality::set(persona | Persona::ADDR_NO_RANDOMIZE).or(Err(std::io::Error::new(
+        std::io::ErrorKind::Other,
+        "personality ADDR_NO_RANDOMIZE failed",
+    )))?;
+    Ok(())
+}
+
 pub struct Inferior {
     child: Child,
 }
 
 impl Inferior {
     /// Attempts to start a new inferior process. Returns Some(Inferior) if successful, or None if
-    /// an error is encountered.
-    pub fn new(target: &str, args: &Vec<String>) -> Option<Inferior> {
+    /// an error is encountered. If `cwd` is given, the inferior is started in that directory, and
+    /// if `no_aslr` is set, it runs without address space randomization.
+    pub fn new(target: &str, args: &Vec<String>, cwd: Option<&str>, no_aslr: bool) -> Option<Inferior> {
         // TODO: implement me!
         // 1. create a new Command
         let mut com = Command::new(target);
         com.args(args);
+        if let Some(cwd) = cwd {
+            com.current_dir(cwd);
+        }
         // 2. pre_exec call child_traceme
         unsafe {
+            if no_aslr {
+                com.pre_exec(child_disable_aslr);
+            }
             com.pre_exec(child_traceme);
         }
         let _child = com.spawn().ok()?;
@@ -86,38 +119,166 @@ impl Inferior {
         })
     }
 
-    pub fn continue_exec(&mut self , breakpoints: &HashMap<usize, Option<u8>> , debug_data: &DwarfData) -> Result<Status, nix::Error> {
+    /// Resumes the inferior until it next stops. If `signal` is given, it is delivered to the
+    /// inferior as it resumes (so e.g. its handler for that signal runs).
+    pub fn continue_exec(&mut self , breakpoints: &HashMap<usize, Option<u8>> , debug_data: &DwarfData , signal: Option<Signal>) -> Result<Status, nix::Error> {
         // wake up the proc
-        let mut regs = ptrace::getregs(self.pid())?;
-        let rip: usize = regs.rip.try_into().expect("get rip failed");
+        let rip: usize = ptrace::getregs(self.pid())?.rip.try_into().expect("get rip failed");
         let addr = rip - 1;
         if breakpoints.contains_key(&addr) {
-            // restore prev_byte
-            self.write_byte(addr, breakpoints.get(&addr).unwrap().expect("breakpoint should been injected"))?;
-            regs.rip = regs.rip - 1;
-            let function_name = debug_data.get_function_from_addr(addr).unwrap();
-            let line = debug_data.get_line_from_addr(addr).unwrap();
-            println!("Breakpoint at {} , {}" , function_name , line);
+            let function_name = debug_data
+                .get_function_from_addr(addr)
+                .unwrap_or_else(|| "??".to_string());
+            println!("Breakpoint at {} , {}" , function_name , debug_data.describe_addr(addr));
             println!("============================================");
-            ptrace::setregs(self.pid(), regs)?;
-            ptrace::step(self.pid(), None)?;
-            match self.wait(None)? {
-                inferior::Status::Stopped(signal, _) => {
-                    assert_eq!(signal , Signal::SIGTRAP);
-                }
-                inferior::Status::Exited(code) => {
+            if let Some(status) = self.step_past_breakpoint(addr, breakpoints)? {
+                if let inferior::Status::Exited(code) = status {
                     println!("Child exited (status {})", code);
-                    return Ok(inferior::Status::Exited(code));  
                 }
-                inferior::Status::Signaled(signal) => {
-                    return Ok(inferior::Status::Signaled(signal));
+                return Ok(status);
+            }
+        }
+        ptrace::cont(self.pid(), signal)?;
+        self.wait(None)
+    }
+
+    /// Continues the inferior without stopping at the breakpoint at `addr`, counting how many
+    /// times it is hit. Returns the hit count along with the status from the first stop that was
+    /// *not* that breakpoint (normally the inferior exiting).
+    pub fn count_breakpoint_hits(
+        &mut self,
+        addr: usize,
+        breakpoints: &HashMap<usize, Option<u8>>,
+    ) -> Result<(usize, Status), nix::Error> {
+        let mut hits = 0;
+        loop {
+            let rip: usize = ptrace::getregs(self.pid())?.rip.try_into().expect("get rip failed");
+            if breakpoints.contains_key(&(rip - 1)) {
+                if let Some(status) = self.step_past_breakpoint(rip - 1, breakpoints)? {
+                    return Ok((hits, status));
                 }
-            };
+            }
+            ptrace::cont(self.pid(), None)?;
+            match self.wait(None)? {
+                Status::Stopped(Signal::SIGTRAP, rip) if rip - 1 == addr => hits += 1,
+                status => return Ok((hits, status)),
+            }
+        }
+    }
+
+    /// Called when the inferior has just trapped on the breakpoint at `addr`: rewinds rip, runs
+    /// the original instruction, and re-inserts the 0xcc. Returns Some(status) if the inferior
+    /// didn't survive the single step or was stopped by some other signal during it, or None if
+    /// it is stopped and ready to be resumed.
+    fn step_past_breakpoint(
+        &mut self,
+        addr: usize,
+        breakpoints: &HashMap<usize, Option<u8>>,
+    ) -> Result<Option<Status>, nix::Error> {
+        let mut regs = ptrace::getregs(self.pid())?;
+        // restore prev_byte
+        self.write_byte(addr, breakpoints.get(&addr).unwrap().expect("breakpoint should been injected"))?;
+        regs.rip = addr as u64;
+        ptrace::setregs(self.pid(), regs)?;
+        ptrace::step(self.pid(), None)?;
+        let status = self.wait(None)?;
+        if let inferior::Status::Stopped(signal, _) = status {
             self.write_byte(addr, 0xcc)?;
-        }    
-        ptrace::cont(self.pid(), None)?;
-        self.wait(None)
+            if signal == Signal::SIGTRAP {
+                return Ok(None);
+            }
+        }
+        Ok(Some(status))
+    }
+
+    /// Single-steps the inferior until it reaches a different source line than the one it started
+    /// on, returning the status after the last step. Stepping stops early if the inferior exits,
+    /// is killed, or stops for any reason other than the single-step trap.
+    pub fn step_line(
+        &mut self,
+        breakpoints: &HashMap<usize, Option<u8>>,
+        debug_data: &DwarfData,
+    ) -> Result<Status, nix::Error> {
+        let mut regs = ptrace::getregs(self.pid())?;
+        let rip: usize = regs.rip.try_into().expect("get rip failed");
+        // If we are stopped just after a breakpoint's 0xcc, rewind so the original instruction is
+        // the next one executed (step_instruction takes care of swapping the byte back in)
+        let mut current_addr = rip;
+        if breakpoints.contains_key(&(rip - 1)) {
+            current_addr = rip - 1;
+            regs.rip = current_addr as u64;
+            ptrace::setregs(self.pid(), regs)?;
+        }
+        let start_line = debug_data.get_line_from_addr(current_addr);
+        loop {
+            match self.step_instruction(breakpoints)? {
+                Status::Stopped(Signal::SIGTRAP, rip) => {
+                    if let Some(line) = debug_data.get_line_from_addr(rip) {
+                        let same_line = start_line.as_ref().map_or(false, |start| {
+                            start.file == line.file && start.number == line.number
+                        });
+                        if !same_line {
+                            return Ok(Status::Stopped(Signal::SIGTRAP, rip));
+                        }
+                    }
+                }
+                other => return Ok(other),
+            }
+        }
+    }
+
+    /// Executes exactly one instruction. If that instruction has a breakpoint on it, the original
+    /// byte is restored for the step and the 0xcc is put back afterwards.
+    fn step_instruction(
+        &mut self,
+        breakpoints: &HashMap<usize, Option<u8>>,
+    ) -> Result<Status, nix::Error> {
+        let rip: usize = ptrace::getregs(self.pid())?.rip.try_into().expect("get rip failed");
+        match breakpoints.get(&rip) {
+            Some(Some(orig_byte)) => {
+                self.write_byte(rip, *orig_byte)?;
+                ptrace::step(self.pid(), None)?;
+                let status = self.wait(None)?;
+                if let Status::Stopped(_, _) = status {
+                    self.write_byte(rip, 0xcc)?;
+                }
+                Ok(status)
+            }
+            _ => {
+                ptrace::step(self.pid(), None)?;
+                self.wait(None)
+            }
+        }
+    }
+
+    /// Reads and parses /proc/<pid>/maps for this inferior.
+    pub fn memory_mappings(&self) -> Result<Vec<MemoryMapping>, std::io::Error> {
+        let maps = std::fs::read_to_string(format!("/proc/{}/maps", self.pid()))?;
+        let mut mappings = Vec::new();
+        for line in maps.lines() {
+            // Format: start-end perms offset dev inode [path]
+            let mut fields = line.split_whitespace();
+            let (range, permissions, offset) = match (fields.next(), fields.next(), fields.next()) {
+                (Some(range), Some(permissions), Some(offset)) => (range, permissions, offset),
+                _ => continue,
+            };
+            let (start, end) = match range.split_once('-') {
+                Some((start, end)) => (start, end),
+                None => continue,
+            };
+            // Skip dev and inode; whatever is left is the path (which may contain spaces)
+            let path = fields.skip(2).collect::<Vec<&str>>().join(" ");
+            mappings.push(MemoryMapping {
+                start: usize::from_str_radix(start, 16).unwrap_or(0),
+                end: usize::from_str_radix(end, 16).unwrap_or(0),
+                permissions: permissions.to_string(),
+                offset: usize::from_str_radix(offset, 16).unwrap_or(0),
+                path,
+            });
+        }
+        Ok(mappings)
     }
+
     pub fn try_kill(&mut self) {
         if Child::kill(&mut self.child).is_ok() {
             println!("Killing running inferior (pid {})", self.pid());
@@ -128,22 +289,47 @@ impl Inferior {
         let mut instruction_ptr: usize = ptrace::getregs(self.pid())?.rip.try_into().unwrap();
         let mut base_ptr: usize = ptrace::getregs(self.pid())?.rbp.try_into().unwrap();
         loop {
-            let function_name = debug_data.get_function_from_addr(instruction_ptr).unwrap();
-            let line = debug_data.get_line_from_addr(instruction_ptr).unwrap();
-            println!("{} ({})", function_name, line);
-            if function_name == "main" {
+            let function_name = debug_data
+                .get_function_from_addr(instruction_ptr)
+                .unwrap_or_else(|| "??".to_string());
+            println!("{} ({})", function_name, debug_data.describe_addr(instruction_ptr));
+            // Optimized code may not keep a frame pointer, in which case we can't walk any further
+            if function_name == "main" || base_ptr == 0 {
                 break;
             }
-            instruction_ptr =
-                ptrace::read(self.pid(), (base_ptr + 8) as ptrace::AddressType)? as usize;
-            base_ptr = ptrace::read(self.pid(), base_ptr as ptrace::AddressType)? as usize;
+            let caller_frame = ptrace::read(self.pid(), (base_ptr + 8) as ptrace::AddressType)
+                .and_then(|return_addr| {
+                    let saved_base_ptr =
+                        ptrace::read(self.pid(), base_ptr as ptrace::AddressType)?;
+                    Ok((return_addr as usize, saved_base_ptr as usize))
+                });
+            match caller_frame {
+                Ok((return_addr, saved_base_ptr)) => {
+                    instruction_ptr = return_addr;
+                    base_ptr = saved_base_ptr;
+                }
+                Err(_) => {
+                    println!("(backtrace stops here: no usable frame pointer)");
+                    break;
+                }
+            }
         }
         Ok(())
     }
-    pub fn get_execline(&self, debug_data: &DwarfData) -> Result<Line, nix::Error> {
+    pub fn get_execline(&self, debug_data: &DwarfData) -> Result<Option<Line>, nix::Error> {
         let instruction_ptr: usize = ptrace::getregs(self.pid())?.rip.try_into().unwrap();
-        let line = debug_data.get_line_from_addr(instruction_ptr).unwrap();
-        Ok(line)
+        Ok(debug_data
+            .get_line_from_addr(instruction_ptr)
+            .or_else(|| Some(debug_data.get_nearest_line(instruction_ptr)?.0)))
+    }
+    /// Reads `size` bytes (at most a word) starting at `addr`, as a little-endian integer.
+    pub fn read_value(&self, addr: usize, size: usize) -> Result<u64, nix::Error> {
+        let word = ptrace::read(self.pid(), addr as ptrace::AddressType)? as u64;
+        if size >= size_of::<u64>() {
+            Ok(word)
+        } else {
+            Ok(word & ((1 << (8 * size)) - 1))
+        }
     }
     pub fn write_byte(&mut self, addr: usize, val: u8) -> Result<u8, nix::Error> {
         let aligned_addr = align_addr_to_word(addr);
@@ -10,15 +10,40 @@ use std::env;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} <target program>", args[0]);
+    // Flags may appear anywhere; everything else is the target
+    let mut no_aslr = false;
+    let mut history_size = None;
+    let mut positional = Vec::new();
+    let mut remaining = args[1..].iter();
+    while let Some(arg) = remaining.next() {
+        match arg.as_str() {
+            "--no-aslr" => no_aslr = true,
+            "--history-size" => match remaining.next().map(|size| size.parse::<usize>()) {
+                Some(Ok(size)) => history_size = Some(size),
+                _ => {
+                    println!("--history-size requires a non-negative number");
+                    std::process::exit(1);
+                }
+            },
+            flag if flag.starts_with("--") => {
+                println!("Unknown option {}", flag);
+                std::process::exit(1);
+            }
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() != 1 {
+        println!(
+            "Usage: {} [--no-aslr] [--history-size N] <target program>",
+            args[0]
+        );
         std::process::exit(1);
     }
-    let target = &args[1];
+    let target = positional[0];
 
     // Disable handling of ctrl+c in this process (so that ctrl+c only gets delivered to child
     // processes)
     unsafe { signal(Signal::SIGINT, SigHandler::SigIgn) }.expect("Error disabling SIGINT handling");
 
-    Debugger::new(target).run();
+    Debugger::new(target, no_aslr, history_size).run();
 }
@@ -0,0 +1,161 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// The flags the Makefile builds samples with
+pub const SAMPLE_FLAGS: &[&str] = &["-O0", "-g", "-no-pie", "-fno-omit-frame-pointer"];
+
+static NEXT_DIR: AtomicUsize = AtomicUsize::new(0);
+
+/// Makes a fresh, empty directory for one test to put binaries, files, and a $HOME in. (Tests run
+/// in parallel, so they can't share build outputs.)
+pub fn scratch_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!(
+        "deet-test-{}-{}",
+        std::process::id(),
+        NEXT_DIR.fetch_add(1, Ordering::SeqCst)
+    ));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).expect("Could not create scratch directory");
+    dir
+}
+
+pub fn deet_bin_path() -> PathBuf {
+    let mut path = std::env::current_exe().expect("Could not get current test executable path");
+    path.pop();
+    path.pop();
+    path.push("deet");
+    path
+}
+
+/// Compiles samples/<name>.c the way the Makefile does and returns the path of the binary.
+pub fn build_sample(name: &str) -> PathBuf {
+    build_sample_with_flags(name, SAMPLE_FLAGS)
+}
+
+/// Compiles samples/<name>.c with the given compiler flags (in place of the Makefile's) and
+/// returns the path of the binary.
+pub fn build_sample_with_flags(name: &str, flags: &[&str]) -> PathBuf {
+    compile_sample(&format!("{}.c", name), flags)
+}
+
+/// Assembles samples/<name>.S, which carries its own hand-written debug info, and returns the path
+/// of the binary.
+pub fn build_asm_sample(name: &str) -> PathBuf {
+    compile_sample(&format!("{}.S", name), &["-no-pie"])
+}
+
+fn compile_sample(file_name: &str, flags: &[&str]) -> PathBuf {
+    let source = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("samples")
+        .join(file_name);
+    let name = Path::new(file_name).file_stem().unwrap();
+    let output = scratch_dir().join(name);
+    let status = Command::new(std::env::var("CC").unwrap_or_else(|_| "cc".to_string()))
+        .args(flags)
+        .arg("-o")
+        .arg(&output)
+        .arg(&source)
+        .status()
+        .expect("Could not run the C compiler");
+    assert!(status.success(), "Could not compile {}", source.display());
+    output
+}
+
+/// Runs deet on `target`, typing each of `commands` at its prompt, and returns everything it
+/// printed. deet quits when it runs out of input, so the last command needn't be "quit".
+pub fn run_deet(target: &Path, args: &[&str], commands: &[&str]) -> String {
+    run_deet_with_home(&scratch_dir(), target, args, commands)
+}
+
+/// Like `run_deet`, but with $HOME (where ~/.deet_history lives) set to `home`.
+pub fn run_deet_with_home(home: &Path, target: &Path, args: &[&str], commands: &[&str]) -> String {
+    run_deet_command(deet_command(home, target, args), commands)
+}
+
+/// Like `run_deet`, but with deet itself started in `dir` (so `target` may be relative to it).
+pub fn run_deet_in_dir(dir: &Path, target: &Path, args: &[&str], commands: &[&str]) -> String {
+    let mut command = deet_command(&scratch_dir(), target, args);
+    command.current_dir(dir);
+    run_deet_command(command, commands)
+}
+
+fn deet_command(home: &Path, target: &Path, args: &[&str]) -> Command {
+    let mut command = Command::new(deet_bin_path());
+    command
+        .args(args)
+        .arg(target)
+        .env("HOME", home)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+    command
+}
+
+fn run_deet_command(mut command: Command, commands: &[&str]) -> String {
+    let mut child = command.spawn().unwrap_or_else(|err| {
+        panic!(
+            "Could not execute deet binary {}: {}",
+            deet_bin_path().display(),
+            err
+        )
+    });
+    {
+        let mut stdin = child.stdin.take().unwrap();
+        for command in commands {
+            writeln!(stdin, "{}", command).unwrap();
+        }
+    }
+    let output = child.wait_with_output().expect("Could not wait for deet");
+    let stdout = String::from_utf8_lossy(&output.stdout).to_string();
+    // Shown if the test fails
+    println!("{}", stdout);
+    assert!(
+        output.status.success(),
+        "deet exited with {}",
+        output.status
+    );
+    stdout
+}
+
+/// Reads the byte that a (non-PIE, 64-bit little-endian) ELF binary loads at `addr`, by finding
+/// the PT_LOAD segment that covers it.
+pub fn byte_at_address(binary: &Path, addr: usize) -> u8 {
+    let elf = std::fs::read(binary).expect("Could not read binary");
+    let u16_at = |offset: usize| u16::from_le_bytes([elf[offset], elf[offset + 1]]) as usize;
+    let u64_at = |offset: usize| {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&elf[offset..offset + 8]);
+        u64::from_le_bytes(bytes) as usize
+    };
+    let (phoff, phentsize, phnum) = (u64_at(0x20), u16_at(0x36), u16_at(0x38));
+    for header in (0..phnum).map(|i| phoff + i * phentsize) {
+        const PT_LOAD: u32 = 1;
+        let (offset, vaddr, filesz) =
+            (u64_at(header + 8), u64_at(header + 16), u64_at(header + 32));
+        if elf[header..header + 4] == PT_LOAD.to_le_bytes()
+            && (vaddr..vaddr + filesz).contains(&addr)
+        {
+            return elf[offset + addr - vaddr];
+        }
+    }
+    panic!("{:#x} is not loaded from {}", addr, binary.display());
+}
+
+/// Looks up a symbol's address in a binary's symbol table, with nm.
+pub fn symbol_address(binary: &Path, name: &str) -> usize {
+    let output = Command::new("nm")
+        .arg(binary)
+        .output()
+        .expect("Could not run nm");
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(
+            |line| match line.split_whitespace().collect::<Vec<&str>>()[..] {
+                [addr, _, symbol] if symbol == name => usize::from_str_radix(addr, 16).ok(),
+                _ => None,
+            },
+        )
+        .unwrap_or_else(|| panic!("{} has no symbol {}", binary.display(), name))
+}
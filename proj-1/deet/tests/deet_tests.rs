@@ -0,0 +1,391 @@
+//! These tests drive the deet binary through its prompt, so they need a C compiler (cc, or $CC)
+//! to build the samples, and permission to ptrace child processes.
+mod common;
+
+use common::{
+    build_asm_sample, build_sample, build_sample_with_flags, byte_at_address, deet_bin_path,
+    run_deet, run_deet_in_dir, run_deet_with_home, scratch_dir, symbol_address,
+};
+
+#[test]
+fn test_count_breakpoint_hits() {
+    let target = build_sample("loop");
+    // tick() runs 10 times: the first call stops at the breakpoint, and count tallies the rest
+    let output = run_deet(&target, &[], &["break tick", "run", "count 0"]);
+    assert!(output.contains("was hit 9 more times"));
+    assert!(output.contains("10 ticks"));
+    assert!(output.contains("Child exited (status 0)"));
+}
+
+#[test]
+fn test_count_without_process_or_breakpoint() {
+    let target = build_sample("loop");
+    let output = run_deet(&target, &[], &["break tick", "count 0"]);
+    assert!(output.contains("The program is not running currently!"));
+
+    let output = run_deet(&target, &[], &["break tick", "run", "count 1"]);
+    assert!(output.contains("No breakpoint number 1"));
+}
+
+#[test]
+fn test_next_steps_one_line() {
+    let target = build_sample("count");
+    let output = run_deet(&target, &[], &["break main", "run", "next", "next"]);
+    let line3 = output.find("count.c:3").expect("run should stop at main");
+    let line4 = output
+        .find("count.c:4")
+        .expect("next should stop at line 4");
+    let line5 = output
+        .find("count.c:5")
+        .expect("next should stop at line 5");
+    assert!(line3 < line4 && line4 < line5);
+}
+
+#[test]
+fn test_next_until_exit() {
+    let target = build_sample("count");
+    let mut commands = vec!["break main", "run"];
+    commands.extend(std::iter::repeat_n("next", 10));
+    commands.push("debug-state");
+    let output = run_deet(&target, &[], &commands);
+    assert!(output.contains("Child exited (status 0)"));
+    // The exited inferior is forgotten, so the remaining nexts have nothing to step
+    assert!(output.contains("The program is not running currently!"));
+    assert!(output.contains("inferior: none"));
+}
+
+#[test]
+fn test_next_stops_on_signal() {
+    let target = build_sample("segfault");
+    let output = run_deet(
+        &target,
+        &[],
+        &["break func2", "run", "next", "next", "next"],
+    );
+    assert!(output.contains("Child stopped (signal SIGSEGV)"));
+    assert!(output.contains("segfault.c:5"));
+}
+
+#[test]
+fn test_next_without_process() {
+    let target = build_sample("count");
+    let output = run_deet(&target, &[], &["next"]);
+    assert!(output.contains("The program is not running currently!"));
+}
+
+#[test]
+fn test_set_cwd() {
+    let target = build_sample("read_config");
+    let dir = scratch_dir();
+    std::fs::write(dir.join("config.txt"), "verbose=1\n").unwrap();
+    let set_cwd = format!("set cwd {}", dir.display());
+
+    let output = run_deet(&target, &[], &[&set_cwd, "info cwd", "run"]);
+    assert!(output.contains(&format!(
+        "Working directory for the program: {}",
+        dir.display()
+    )));
+    assert!(output.contains("config: verbose=1"));
+    assert!(output.contains("Child exited (status 0)"));
+
+    // Without the override the program runs in deet's cwd, where there's no config.txt
+    let output = run_deet(&target, &[], &["info cwd", "run"]);
+    assert!(output.contains("No working directory set"));
+    assert!(output.contains("Could not open config.txt"));
+    assert!(output.contains("Child exited (status 1)"));
+}
+
+#[test]
+fn test_set_cwd_relative_target() {
+    let target = build_sample("read_config");
+    let target_dir = target.parent().unwrap();
+    let dir = scratch_dir();
+    std::fs::write(dir.join("config.txt"), "verbose=1\n").unwrap();
+    let set_cwd = format!("set cwd {}", dir.display());
+    // deet is given the target relative to its own cwd, which isn't where the program runs
+    let output = run_deet_in_dir(
+        target_dir,
+        std::path::Path::new("read_config"),
+        &[],
+        &[&set_cwd, "run"],
+    );
+    assert!(!output.contains("Error starting subprocess"));
+    assert!(output.contains("config: verbose=1"));
+    assert!(output.contains("Child exited (status 0)"));
+}
+
+#[test]
+fn test_set_cwd_missing_directory() {
+    let target = build_sample("read_config");
+    let missing = scratch_dir().join("missing");
+    let set_cwd = format!("set cwd {}", missing.display());
+    let output = run_deet(&target, &[], &[&set_cwd, "run", "debug-state"]);
+    assert!(output.contains(&format!(
+        "Working directory {} does not exist",
+        missing.display()
+    )));
+    assert!(output.contains("inferior: none"));
+}
+
+#[test]
+fn test_info_proc_mappings() {
+    let target = build_sample("count");
+    let output = run_deet(
+        &target,
+        &[],
+        &[
+            "info proc mappings",
+            "break main",
+            "run",
+            "info proc mappings",
+        ],
+    );
+    assert!(output.contains("The program is not running currently!"));
+    // The executable's own code is mapped read/execute from the target file
+    let target = target.to_str().unwrap();
+    assert!(output
+        .lines()
+        .any(|line| line.ends_with(target) && line.contains("r-xp")));
+    assert!(output.contains("[stack]"));
+}
+
+#[test]
+fn test_signal_delivers_to_handler() {
+    let target = build_sample("sigusr1");
+    let output = run_deet(&target, &[], &["run", "signal SIGUSR1"]);
+    assert!(output.contains("Child stopped (signal SIGUSR1)"));
+    assert!(output.contains("handler ran"));
+    assert!(output.contains("Child exited (status 0)"));
+
+    // Plain continue drops the signal
+    let output = run_deet(&target, &[], &["run", "continue"]);
+    assert!(output.contains("handler did not run"));
+}
+
+#[test]
+fn test_signal_stops_in_handler() {
+    let target = build_sample("sigusr1");
+    let output = run_deet(
+        &target,
+        &[],
+        &["break handler", "run", "signal usr1", "continue"],
+    );
+    let handler_bp = output
+        .find("sigusr1.c:6")
+        .expect("signal should stop at the breakpoint in the handler");
+    assert!(output[handler_bp..].contains("handler ran"));
+    assert!(output.contains("Child exited (status 0)"));
+}
+
+#[test]
+fn test_signal_without_process() {
+    let target = build_sample("sigusr1");
+    let output = run_deet(&target, &[], &["signal 10"]);
+    assert!(output.contains("The program is not running currently!"));
+}
+
+/// The lines where the addresses sample reports where main, its stack, and its heap ended up
+fn reported_addresses(output: &str) -> Vec<&str> {
+    output
+        .lines()
+        .filter(|line| line.contains(" at 0x") && !line.starts_with(' '))
+        .collect()
+}
+
+#[test]
+fn test_no_aslr_addresses_are_stable() {
+    // Built as a position-independent executable (unlike the Makefile's samples), so that main's
+    // address would be randomized too
+    let target = build_sample_with_flags(
+        "addresses",
+        &["-O0", "-g", "-fPIE", "-pie", "-fno-omit-frame-pointer"],
+    );
+    let first = run_deet(&target, &["--no-aslr"], &["run", "debug-state"]);
+    let second = run_deet(&target, &["--no-aslr"], &["run"]);
+    assert!(first.contains("no_aslr: true"));
+    let addresses = reported_addresses(&first);
+    assert_eq!(addresses.len(), 3);
+    assert!(addresses[0].starts_with("main at 0x"));
+    assert_eq!(addresses, reported_addresses(&second));
+}
+
+/// The addresses deet reported from "Set breakpoint <n> at <addr>", in order
+fn breakpoint_addresses(output: &str) -> Vec<usize> {
+    output
+        .lines()
+        .filter(|line| line.starts_with("Set breakpoint "))
+        .map(|line| {
+            let addr = line.rsplit(" at 0x").next().unwrap();
+            usize::from_str_radix(addr, 16).unwrap()
+        })
+        .collect()
+}
+
+#[test]
+fn test_debug_state() {
+    let target = build_sample("loop");
+    let output = run_deet(&target, &[], &["debug-state"]);
+    assert!(output.contains(&format!("target: {}", target.display())));
+    assert!(output.contains("cwd: <inherited>"));
+    assert!(output.contains("no_aslr: false"));
+    assert!(output.contains("breakpoints: 0"));
+    assert!(output.contains("inferior: none"));
+
+    let output = run_deet(&target, &[], &["break main", "break tick", "debug-state"]);
+    let addrs = breakpoint_addresses(&output);
+    assert_eq!(addrs.len(), 2);
+    assert!(output.contains("breakpoints: 2"));
+    for (num, addr) in addrs.iter().enumerate() {
+        assert!(output.contains(&format!(
+            "  #{} addr={:#x} orig_byte=<none> injected=false",
+            num, addr
+        )));
+    }
+
+    // Once running, the breakpoints are injected, remembering the bytes they replaced, and the
+    // inferior is stopped just past main's 0xcc
+    let output = run_deet(
+        &target,
+        &[],
+        &["break main", "break tick", "run", "debug-state"],
+    );
+    let addrs = breakpoint_addresses(&output);
+    for (num, addr) in addrs.iter().enumerate() {
+        assert!(output.contains(&format!(
+            "  #{} addr={:#x} orig_byte={:#04x} injected=true",
+            num,
+            addr,
+            byte_at_address(&target, *addr)
+        )));
+    }
+    assert!(output.contains("inferior: running (pid "));
+    assert!(output.contains(&format!("rip: {:#x}", addrs[0] + 1)));
+}
+
+/// The commands saved in the history file in `home`
+fn saved_history(home: &std::path::Path) -> Vec<String> {
+    std::fs::read_to_string(home.join(".deet_history"))
+        .expect("deet should have saved its history")
+        .lines()
+        // (rustyline starts the file with a version marker)
+        .filter(|line| !line.starts_with("#V"))
+        .map(|line| line.to_string())
+        .collect()
+}
+
+#[test]
+fn test_history_size() {
+    let target = build_sample("count");
+    let home = scratch_dir();
+    let commands = [
+        "info cwd",
+        "debug-state",
+        "print main",
+        "break main",
+        "next",
+    ];
+    run_deet_with_home(&home, &target, &["--history-size", "3"], &commands);
+    assert_eq!(saved_history(&home), &commands[2..]);
+
+    // A longer history left by an earlier session is trimmed to the newest entries
+    let home = scratch_dir();
+    run_deet_with_home(&home, &target, &[], &commands);
+    assert_eq!(saved_history(&home), &commands[..]);
+    run_deet_with_home(&home, &target, &["--history-size", "2"], &["info cwd"]);
+    assert_eq!(saved_history(&home), &["next", "info cwd"]);
+}
+
+#[test]
+fn test_history_size_requires_number() {
+    let target = build_sample("count");
+    let output = std::process::Command::new(deet_bin_path())
+        .args(["--history-size", "lots"])
+        .arg(&target)
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("--history-size requires"));
+}
+
+#[test]
+fn test_print_without_process() {
+    let target = build_sample("loop");
+    let output = run_deet(
+        &target,
+        &[],
+        &[
+            "print main",
+            "print &tick",
+            "print ticks",
+            "print &ticks",
+            "print nosuch",
+        ],
+    );
+    let main = symbol_address(&target, "main");
+    let tick = symbol_address(&target, "tick");
+    let ticks = symbol_address(&target, "ticks");
+    assert!(output.contains(&format!("main = function main at {:#x}", main)));
+    assert!(output.contains(&format!("&tick = function tick at {:#x}", tick)));
+    assert!(output.contains(&format!(
+        "ticks is a global int at {:#x} (start the program to see its value)",
+        ticks
+    )));
+    assert!(output.contains(&format!("&ticks = (int *) {:#x}", ticks)));
+    assert!(output.contains("No function or global variable named \"nosuch\""));
+}
+
+#[test]
+fn test_print_global_while_running() {
+    let target = build_sample("loop");
+    let output = run_deet(
+        &target,
+        &[],
+        &["break tick", "run", "continue", "continue", "print ticks"],
+    );
+    let ticks = symbol_address(&target, "ticks");
+    assert!(output.contains(&format!("ticks = 2 (int at {:#x})", ticks)));
+}
+
+#[test]
+fn test_next_through_optimized_code() {
+    let target = build_sample_with_flags("optimized", &["-O2", "-g", "-no-pie"]);
+    let mut commands = vec!["break main", "run"];
+    commands.extend(std::iter::repeat_n("next", 100));
+    // (run_deet checks that deet itself didn't die along the way)
+    let output = run_deet(&target, &[], &commands);
+    assert!(output.contains("total 23"));
+    assert!(output.contains("Child exited (status 0)"));
+    // next only ever stops on an address with line info, including inside the inlined
+    // collatz_steps
+    let stops: Vec<&str> = output
+        .lines()
+        .filter_map(|line| line.strip_prefix("Stopped at "))
+        .collect();
+    assert!(stops.len() > 10);
+    for stop in &stops {
+        let line = stop.rsplit("optimized.c:").next().unwrap();
+        assert!(line.parse::<usize>().is_ok(), "bad location {}", stop);
+    }
+    assert!(stops.iter().any(|stop| stop.ends_with("optimized.c:9")));
+}
+
+#[test]
+fn test_nearest_line_fallback() {
+    // The int3 in main traps with rip 5 bytes past the pushq on line 15, on an address whose line
+    // table row says line 0
+    let target = build_asm_sample("no_line_info");
+    let main_addr = symbol_address(&target, "main");
+
+    let output = run_deet(&target, &[], &["set nearest-line on", "run", "continue"]);
+    assert!(output.contains("Child stopped (signal SIGTRAP)"));
+    assert!(output
+        .lines()
+        .any(|line| line.starts_with("Stopped at ") && line.ends_with("no_line_info.S:15 + 0x5")));
+    assert!(output.contains("Child exited (status 0)"));
+
+    let output = run_deet(&target, &[], &["set nearest-line off", "run", "continue"]);
+    let expected = format!("Stopped at {:#x} (no line info)", main_addr + 5);
+    assert!(output.contains(&expected));
+    assert!(!output.contains("no_line_info.S:"));
+    assert!(output.contains("Child exited (status 0)"));
+}
@@ -0,0 +1,8 @@
+#![no_main]
+use balancebeam::parse::{self, HeaderLimits};
+use libfuzzer_sys::fuzz_target;
+
+// Whatever a client sends, parsing it should return (an error, if need be) rather than panic
+fuzz_target!(|data: &[u8]| {
+    let _ = parse::parse_request_head(data, HeaderLimits::default());
+});
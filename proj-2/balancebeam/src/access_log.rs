@@ -0,0 +1,215 @@
+use std::fmt::Write as _;
+use std::fs::{File, OpenOptions};
+use std::io::Write as _;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Values accepted by `--access-log-format`
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+pub enum AccessLogFormat {
+    /// The Apache/nginx "combined" format, with the upstream, latency and request ID appended
+    Combined,
+    /// One JSON object per line
+    Json,
+}
+
+/// Writes one line per request that balancebeam answers, either to its own file or (if no file was
+/// given) through the regular log.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    file: Option<Mutex<File>>,
+}
+
+impl AccessLog {
+    /// Returns a message describing the problem if `path` can't be opened for appending.
+    pub fn open(format: AccessLogFormat, path: Option<&str>) -> Result<AccessLog, String> {
+        let file = match path {
+            Some(path) => Some(Mutex::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|err| format!("Could not open access log {}: {}", path, err))?,
+            )),
+            None => None,
+        };
+        Ok(AccessLog { format, file })
+    }
+
+    /// Records the response to the request in `entry`. `upstream` is None if the request was
+    /// answered without being forwarded, and `bytes` is how much was sent back to the client.
+    pub fn record(
+        &self,
+        entry: &Entry,
+        upstream: Option<&str>,
+        status: http::StatusCode,
+        bytes: usize,
+    ) {
+        let latency = entry.start.elapsed();
+        let line = match self.format {
+            AccessLogFormat::Combined => format_combined(entry, upstream, status, bytes, latency),
+            AccessLogFormat::Json => format_json(entry, upstream, status, bytes, latency),
+        };
+        match &self.file {
+            Some(file) => {
+                if let Err(err) = writeln!(file.lock().unwrap(), "{}", line) {
+                    log::warn!("Failed to write to access log: {}", err);
+                }
+            }
+            None => log::info!(target: "access", "{}", line),
+        }
+    }
+}
+
+/// What the access log needs to know about a request, taken when the request is read (before
+/// balancebeam adds its own headers to it)
+pub struct Entry {
+    client_ip: String,
+    method: String,
+    target: String,
+    version: http::Version,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    request_id: Option<String>,
+    time: SystemTime,
+    start: Instant,
+}
+
+impl Entry {
+    pub fn new(client_ip: &str, request: &http::Request<Vec<u8>>) -> Entry {
+        let header = |name| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value: &http::HeaderValue| value.to_str().ok())
+                .map(|value| value.to_string())
+        };
+        Entry {
+            client_ip: client_ip.to_string(),
+            method: request.method().to_string(),
+            target: request.uri().to_string(),
+            version: request.version(),
+            referer: header("referer"),
+            user_agent: header("user-agent"),
+            request_id: header(crate::request_id::HEADER),
+            time: SystemTime::now(),
+            start: Instant::now(),
+        }
+    }
+}
+
+fn format_combined(
+    entry: &Entry,
+    upstream: Option<&str>,
+    status: http::StatusCode,
+    bytes: usize,
+    latency: Duration,
+) -> String {
+    let (year, month, day, hour, minute, second) = utc_fields(entry.time);
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    format!(
+        "{} - - [{:02}/{}/{}:{:02}:{:02}:{:02} +0000] \"{} {} {:?}\" {} {} \"{}\" \"{}\" \
+        upstream={} latency_ms={:.1} request_id={}",
+        entry.client_ip,
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        hour,
+        minute,
+        second,
+        entry.method,
+        entry.target,
+        entry.version,
+        status.as_u16(),
+        bytes,
+        entry.referer.as_deref().unwrap_or("-"),
+        entry.user_agent.as_deref().unwrap_or("-"),
+        upstream.unwrap_or("-"),
+        latency.as_secs_f64() * 1000.0,
+        entry.request_id.as_deref().unwrap_or("-")
+    )
+}
+
+fn format_json(
+    entry: &Entry,
+    upstream: Option<&str>,
+    status: http::StatusCode,
+    bytes: usize,
+    latency: Duration,
+) -> String {
+    format!(
+        "{{\"time\":\"{}\",\"client_ip\":{},\"method\":{},\"path\":{},\"upstream\":{},\
+        \"status\":{},\"bytes\":{},\"latency_ms\":{:.1},\"referer\":{},\"user_agent\":{},\
+        \"request_id\":{}}}",
+        format_time(entry.time),
+        json_string(Some(&entry.client_ip)),
+        json_string(Some(&entry.method)),
+        json_string(Some(&entry.target)),
+        json_string(upstream),
+        status.as_u16(),
+        bytes,
+        latency.as_secs_f64() * 1000.0,
+        json_string(entry.referer.as_deref()),
+        json_string(entry.user_agent.as_deref()),
+        json_string(entry.request_id.as_deref())
+    )
+}
+
+/// Formats a time as UTC in RFC 3339 form, e.g. 2020-06-01T12:00:00Z.
+pub fn format_time(time: SystemTime) -> String {
+    let (year, month, day, hour, minute, second) = utc_fields(time);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year, month, day, hour, minute, second
+    )
+}
+
+/// Quotes and escapes a string for JSON (None becomes null).
+pub fn json_string(value: Option<&str>) -> String {
+    let value = match value {
+        Some(value) => value,
+        None => return "null".to_string(),
+    };
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if (c as u32) < 0x20 => write!(quoted, "\\u{:04x}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Splits a time into its UTC (year, month, day, hour, minute, second).
+fn utc_fields(time: SystemTime) -> (i64, u32, u32, u32, u32, u32) {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs()) as i64;
+    let (days, secs_of_day) = (secs.div_euclid(86400), secs.rem_euclid(86400) as u32);
+    // Converts days since 1970-01-01 to a civil date; see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+    )
+}
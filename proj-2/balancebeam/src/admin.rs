@@ -0,0 +1,220 @@
+use crate::request::{self, HeaderLimits};
+use crate::response;
+use crate::routing::Canary;
+use crate::ProxyState;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::stream::StreamExt;
+use tokio::sync::RwLock;
+
+/// Bodies bigger than this are not read, and the connection is dropped instead
+const MAX_BODY_SIZE: usize = 4096;
+
+/// Serves the admin API on its own listener. Unlike the status and metrics endpoints, it can
+/// change how balancebeam behaves, so it works on ProxyState rather than just Stats.
+pub async fn serve(mut listener: TcpListener, state: Arc<RwLock<ProxyState>>) {
+    while let Some(stream) = listener.next().await {
+        if let Ok(stream) = stream {
+            let state = Arc::clone(&state);
+            tokio::spawn(async move {
+                handle_connection(stream, state).await;
+            });
+        }
+    }
+}
+
+/// The admin API:
+///
+/// * `GET /upstreams` lists every upstream with its health, whether it is drained, and how many
+///   connections it has open
+/// * `POST /upstreams/<address>/drain` stops sending new requests to an upstream (requests it
+///   already has are left to finish; active_connections shows when they have), until
+/// * `POST /upstreams/<address>/enable` lets it have new connections again
+/// * `GET /canary` shows the canary's upstreams and what percentage of requests it gets, and
+/// * `POST /canary?percent=<n>` changes that percentage (until the configuration is reloaded)
+/// * `GET /rate-limits` lists how much of their rate limits each client has used
+/// * `GET /config` dumps the configuration balancebeam is running with
+async fn admin_routes(
+    request: &http::Request<Vec<u8>>,
+    state: &RwLock<ProxyState>,
+) -> http::Response<Vec<u8>> {
+    let path = request.uri().path();
+    match *request.method() {
+        http::Method::GET if path == "/upstreams" => {
+            let state_read = state.read().await;
+            let body: String = state_read
+                .upstream_addresses
+                .iter()
+                .map(|upstream| render_upstream(&state_read, upstream))
+                .collect();
+            response::make_text_response(http::StatusCode::OK, body)
+        }
+        http::Method::POST if path.starts_with("/upstreams/") => {
+            // (Upstream addresses can have slashes in them, e.g. https://host:443)
+            let rest = &path["/upstreams/".len()..];
+            let (upstream, drain) = if let Some(upstream) = rest.strip_suffix("/drain") {
+                (upstream, true)
+            } else if let Some(upstream) = rest.strip_suffix("/enable") {
+                (upstream, false)
+            } else {
+                return response::make_http_error(http::StatusCode::NOT_FOUND);
+            };
+            let mut state_write = state.write().await;
+            if !state_write.upstream_addresses.iter().any(|x| x == upstream) {
+                return response::make_text_response(
+                    http::StatusCode::NOT_FOUND,
+                    format!("No upstream {}\n", upstream),
+                );
+            }
+            if drain {
+                if state_write.drained_upstreams.insert(upstream.to_string()) {
+                    log::info!(
+                        "Draining upstream {} (requested through the admin API)",
+                        upstream
+                    );
+                    state_write.stats.set_upstream_drained(upstream, true);
+                }
+            } else if state_write.drained_upstreams.remove(upstream) {
+                log::info!(
+                    "Re-enabled upstream {} (requested through the admin API)",
+                    upstream
+                );
+                state_write.stats.set_upstream_drained(upstream, false);
+            }
+            response::make_text_response(
+                http::StatusCode::OK,
+                render_upstream(&state_write, upstream),
+            )
+        }
+        http::Method::GET if path == "/canary" => {
+            let state_read = state.read().await;
+            match state_read.routes.canary() {
+                Some(canary) => {
+                    response::make_text_response(http::StatusCode::OK, render_canary(canary))
+                }
+                None => response::make_text_response(
+                    http::StatusCode::NOT_FOUND,
+                    "No canary is configured\n".to_string(),
+                ),
+            }
+        }
+        http::Method::POST if path == "/canary" => {
+            let percent = request
+                .uri()
+                .query()
+                .and_then(|query| query.strip_prefix("percent="))
+                .and_then(|percent| percent.parse::<u8>().ok())
+                .filter(|percent| *percent <= 100);
+            let percent = match percent {
+                Some(percent) => percent,
+                None => {
+                    return response::make_text_response(
+                        http::StatusCode::BAD_REQUEST,
+                        "Expected ?percent=<0 to 100>\n".to_string(),
+                    )
+                }
+            };
+            let mut state_write = state.write().await;
+            if !state_write.routes.set_canary_percent(percent) {
+                return response::make_text_response(
+                    http::StatusCode::NOT_FOUND,
+                    "No canary is configured\n".to_string(),
+                );
+            }
+            log::info!(
+                "Sending {}% of requests to the canary (requested through the admin API)",
+                percent
+            );
+            let canary = state_write.routes.canary().unwrap();
+            response::make_text_response(http::StatusCode::OK, render_canary(canary))
+        }
+        http::Method::GET if path == "/rate-limits" => {
+            let state_read = state.read().await;
+            let mut body = String::new();
+            if let Some(limits) = &state_read.request_limits {
+                if let Some(limiter) = limits.default_limiter() {
+                    for (client_ip, requests) in limiter.usage() {
+                        body += &format!(
+                            "requests {} {}/{}\n",
+                            client_ip,
+                            requests,
+                            limiter.limit().burst
+                        );
+                    }
+                }
+                for (prefix, limiter) in limits.route_limiters() {
+                    for (client_ip, requests) in limiter.usage() {
+                        body += &format!(
+                            "requests {} {} {}/{}\n",
+                            prefix,
+                            client_ip,
+                            requests,
+                            limiter.limit().burst
+                        );
+                    }
+                }
+            }
+            if let Some(limiter) = &state_read.byte_limiter {
+                for (client_ip, bytes) in limiter.usage() {
+                    body += &format!("bytes {} {}/{}\n", client_ip, bytes, limiter.max_bytes());
+                }
+            }
+            response::make_text_response(http::StatusCode::OK, body)
+        }
+        http::Method::GET if path == "/config" => {
+            let state_read = state.read().await;
+            response::make_text_response(http::StatusCode::OK, state_read.config_dump.clone())
+        }
+        _ => response::make_http_error(http::StatusCode::NOT_FOUND),
+    }
+}
+
+/// Describes an upstream in one line, e.g.
+/// `10.0.0.1:80 health=up drained=false active_connections=3`
+fn render_upstream(state: &ProxyState, upstream: &str) -> String {
+    let healthy = state.valid_upstream_addresses.iter().any(|x| x == upstream);
+    let active_connections = state
+        .upstream_info
+        .get(upstream)
+        .map_or(0, |info| info.active_connections.load(Ordering::SeqCst));
+    format!(
+        "{} health={} drained={} active_connections={}\n",
+        upstream,
+        if healthy { "up" } else { "down" },
+        state.drained_upstreams.contains(upstream),
+        active_connections
+    )
+}
+
+/// Describes the canary in one line, e.g. `percent=5 upstreams=10.0.0.6:80,10.0.0.7:80`
+fn render_canary(canary: &Canary) -> String {
+    format!(
+        "percent={} upstreams={}\n",
+        canary.percent,
+        canary.upstreams.join(",")
+    )
+}
+
+async fn handle_connection(mut conn: TcpStream, state: Arc<RwLock<ProxyState>>) {
+    loop {
+        let read = request::read_from_stream(
+            &mut conn,
+            None,
+            None,
+            MAX_BODY_SIZE,
+            None,
+            HeaderLimits::default(),
+        );
+        let request = match read.await {
+            // (A request pipelined behind a chunked one isn't worth keeping the connection for)
+            Ok((request, None, leftover)) if leftover.is_empty() => request,
+            _ => return,
+        };
+        let response = admin_routes(&request, &state).await;
+        if let Err(error) = response::write_to_stream(&response, &mut conn).await {
+            log::warn!("Failed to send admin response: {}", error);
+            return;
+        }
+    }
+}
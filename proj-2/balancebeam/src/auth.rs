@@ -0,0 +1,172 @@
+use crate::normalize;
+use std::fmt;
+
+const BASE64_ALPHABET: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Requests for paths under `prefix` have to carry one of these credentials before they're
+/// forwarded. (Debug leaves the credentials out, since the configuration gets dumped through the
+/// admin API.)
+pub struct AuthRule {
+    pub prefix: String,
+    /// Sent in the WWW-Authenticate challenge
+    pub realm: String,
+    /// Accepted HTTP Basic credentials, base64-encoded as they appear in the Authorization header
+    basic_credentials: Vec<String>,
+    bearer_tokens: Vec<String>,
+}
+
+impl AuthRule {
+    /// `basic_credentials` are `user:password` pairs.
+    pub fn new(
+        prefix: String,
+        realm: String,
+        basic_credentials: &[String],
+        bearer_tokens: Vec<String>,
+    ) -> AuthRule {
+        AuthRule {
+            prefix,
+            realm,
+            basic_credentials: basic_credentials
+                .iter()
+                .map(|credentials| base64_encode(credentials.as_bytes()))
+                .collect(),
+            bearer_tokens,
+        }
+    }
+
+    /// Whether the Authorization header `value` carries one of the rule's credentials
+    fn accepts(&self, value: &str) -> bool {
+        let (scheme, credentials) = match value.trim().split_once(' ') {
+            Some((scheme, credentials)) => (scheme, credentials.trim()),
+            None => return false,
+        };
+        let accepted = if scheme.eq_ignore_ascii_case("basic") {
+            &self.basic_credentials
+        } else if scheme.eq_ignore_ascii_case("bearer") {
+            &self.bearer_tokens
+        } else {
+            return false;
+        };
+        // (Every credential is compared, so how long this takes doesn't say which one came close)
+        accepted.iter().fold(false, |found, expected| {
+            found | constant_time_eq(credentials, expected)
+        })
+    }
+
+    /// The WWW-Authenticate headers to send with a 401, one per scheme the rule accepts
+    fn challenges(&self) -> Vec<(&'static str, String)> {
+        let mut challenges = Vec::new();
+        for (scheme, credentials) in &[
+            ("Basic", self.basic_credentials.len()),
+            ("Bearer", self.bearer_tokens.len()),
+        ] {
+            if *credentials > 0 {
+                let challenge = format!("{} realm=\"{}\"", scheme, self.realm.replace('"', "'"));
+                challenges.push(("www-authenticate", challenge));
+            }
+        }
+        challenges
+    }
+}
+
+impl fmt::Debug for AuthRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AuthRule")
+            .field("prefix", &self.prefix)
+            .field("realm", &self.realm)
+            .field("basic_credentials", &self.basic_credentials.len())
+            .field("bearer_tokens", &self.bearer_tokens.len())
+            .finish()
+    }
+}
+
+/// The `[[auth]]` rules from the config file, checked before requests for protected paths are
+/// forwarded (or answered from the cache).
+#[derive(Debug, Default)]
+pub struct AuthRules {
+    /// Longest prefix first, so the most specific rule for a path is found first
+    rules: Vec<AuthRule>,
+}
+
+impl AuthRules {
+    pub fn new(mut rules: Vec<AuthRule>) -> AuthRules {
+        rules.sort_by_key(|rule| std::cmp::Reverse(rule.prefix.len()));
+        AuthRules { rules }
+    }
+
+    /// Whether there are any rules. If there are, paths that can't be normalized have to be
+    /// refused before they get to authorize().
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Checks a request against the rule for its path, if there is one. Requests that pass have
+    /// their Authorization header taken out (it was meant for balancebeam, not the upstream).
+    ///
+    /// The path is matched as the upstream will see it (see normalize::canonical), so that e.g.
+    /// //admin, /pub/../admin and /%61dmin all need the credentials for /admin.
+    pub fn authorize(&self, request: &mut http::Request<Vec<u8>>) -> Result<(), Denied<'_>> {
+        let rule = match normalize::canonical(request.uri().path()) {
+            Ok(path) => self.rules.iter().find(|rule| {
+                path.strip_prefix(rule.prefix.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            }),
+            // (There's no telling which rule such a path falls under, so it gets the broadest
+            // one; handle_connection refuses these paths before this anyway)
+            Err(_) => self.rules.last(),
+        };
+        let rule = match rule {
+            Some(rule) => rule,
+            None => return Ok(()),
+        };
+        let authorized = request
+            .headers()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| rule.accepts(value));
+        if !authorized {
+            return Err(Denied {
+                prefix: &rule.prefix,
+                challenges: rule.challenges(),
+            });
+        }
+        request.headers_mut().remove("authorization");
+        Ok(())
+    }
+}
+
+/// Why a request was refused: the prefix of the rule it failed, and the WWW-Authenticate headers to
+/// send with the 401
+pub struct Denied<'a> {
+    pub prefix: &'a str,
+    pub challenges: Vec<(&'static str, String)>,
+}
+
+/// Compares two strings in time that depends only on their lengths.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |difference, (a, b)| difference | (a ^ b))
+            == 0
+}
+
+/// Encodes bytes as (padded, standard alphabet) base64, as HTTP Basic credentials are.
+fn base64_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let group = chunk.iter().enumerate().fold(0_u32, |group, (i, byte)| {
+            group | (*byte as u32) << (16 - 8 * i)
+        });
+        for i in 0..4 {
+            if i <= chunk.len() {
+                let index = (group >> (18 - 6 * i)) & 0x3f;
+                encoded.push(BASE64_ALPHABET[index as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
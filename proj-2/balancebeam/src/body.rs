@@ -0,0 +1,201 @@
+use crate::chunked::{self, ChunkReader};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Streamed bodies are copied in pieces of at most this many bytes
+const COPY_CHUNK_SIZE: usize = 64 * 1024;
+
+/// The part of a message body that was left in the stream because the body was bigger than the
+/// high-water mark. Once the head of the message (and whatever part of the body was read along
+/// with it) has been sent on, this is copied straight from one peer to the other.
+#[derive(Debug)]
+pub enum Unread {
+    /// This many more bytes
+    Length(usize),
+    /// The rest of a chunked body, which is passed along with its chunk framing intact. Holds the
+    /// bytes of it that were already taken off the stream, and how much more chunk data it may
+    /// carry (None = no limit).
+    Chunked(Vec<u8>, Option<usize>),
+    /// Like Chunked, but passed along with the chunk framing taken off (see unchunk)
+    Unchunked(Vec<u8>, Option<usize>),
+    /// Everything until the sender closes the connection (responses without a length)
+    UntilClose,
+}
+
+/// Sets up a chunked body that was too big to decode in memory to be passed along still chunked,
+/// given what chunked::read_body returned: the part decoded so far (in `body`) goes out as a
+/// single chunk, and the rest follows as it was sent. If the body as a whole may be at most
+/// `max_len` bytes, copying the rest fails with CopyError::TooLarge once it goes over.
+pub fn resume_chunked(
+    headers: &mut http::HeaderMap,
+    body: &mut Vec<u8>,
+    rest: Vec<u8>,
+    max_len: Option<usize>,
+) -> Unread {
+    // Transfer-Encoding overrides Content-Length, but a receiver might not know that
+    headers.remove("content-length");
+    let allowed = max_len.map(|max_len| max_len.saturating_sub(body.len()));
+    *body = chunked::frame_chunk(body);
+    Unread::Chunked(rest, allowed)
+}
+
+/// Changes a chunked body that's being streamed (as set up by resume_chunked) to be passed along
+/// without its chunk framing, for a receiver that doesn't understand chunking (an HTTP/1.0
+/// client). Nothing marks the end of the body then, so the connection has to be closed after it.
+/// Other bodies are left as they are.
+pub fn unchunk(headers: &mut http::HeaderMap, body: &mut Vec<u8>, unread: Unread) -> Unread {
+    let (rest, allowed) = match unread {
+        Unread::Chunked(rest, allowed) => (rest, allowed),
+        unread => return unread,
+    };
+    headers.remove("transfer-encoding");
+    // (resume_chunked framed the part that was already decoded as a single chunk)
+    if let Some(size_line_len) = body.windows(2).position(|window| window == b"\r\n") {
+        *body = body[size_line_len + 2..body.len() - 2].to_vec();
+    }
+    Unread::Unchunked(rest, allowed)
+}
+
+#[derive(Debug)]
+pub enum CopyError {
+    /// The sender hung up before the end of the body, or its chunk framing is broken
+    Malformed,
+    /// Encountered an I/O error when reading from the sender
+    Read(std::io::Error),
+    /// Encountered an I/O error when writing to the receiver
+    Write(std::io::Error),
+    /// The body went over the size it was allowed to be
+    TooLarge,
+}
+
+impl From<chunked::Error> for CopyError {
+    fn from(err: chunked::Error) -> CopyError {
+        match err {
+            chunked::Error::InvalidFraming => CopyError::Malformed,
+            chunked::Error::Io(err) => CopyError::Read(err),
+        }
+    }
+}
+
+/// Copies the unread part of a body from `from` to `to`, a piece at a time, so memory use doesn't
+/// depend on the size of the body. Returns the number of bytes written.
+pub async fn copy<R, W>(unread: Unread, from: &mut R, to: &mut W) -> Result<usize, CopyError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let bytes_written = match unread {
+        Unread::Length(len) => copy_length(from, to, len).await?,
+        Unread::Chunked(buffered, allowed) => {
+            copy_chunked(ChunkReader::new(from, buffered), to, allowed, true).await?
+        }
+        Unread::Unchunked(buffered, allowed) => {
+            copy_chunked(ChunkReader::new(from, buffered), to, allowed, false).await?
+        }
+        Unread::UntilClose => copy_until_close(from, to).await?,
+    };
+    to.flush().await.map_err(CopyError::Write)?;
+    Ok(bytes_written)
+}
+
+async fn copy_length<R, W>(from: &mut R, to: &mut W, len: usize) -> Result<usize, CopyError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0_u8; len.min(COPY_CHUNK_SIZE)];
+    let mut remaining = len;
+    while remaining > 0 {
+        let piece_len = remaining.min(buffer.len());
+        let bytes_read = from
+            .read(&mut buffer[..piece_len])
+            .await
+            .map_err(CopyError::Read)?;
+        if bytes_read == 0 {
+            return Err(CopyError::Malformed);
+        }
+        to.write_all(&buffer[..bytes_read])
+            .await
+            .map_err(CopyError::Write)?;
+        remaining -= bytes_read;
+    }
+    Ok(len)
+}
+
+async fn copy_until_close<R, W>(from: &mut R, to: &mut W) -> Result<usize, CopyError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0_u8; COPY_CHUNK_SIZE];
+    let mut bytes_written = 0;
+    loop {
+        let bytes_read = from.read(&mut buffer).await.map_err(CopyError::Read)?;
+        if bytes_read == 0 {
+            return Ok(bytes_written);
+        }
+        to.write_all(&buffer[..bytes_read])
+            .await
+            .map_err(CopyError::Write)?;
+        bytes_written += bytes_read;
+    }
+}
+
+/// Passes chunks along as they arrive. The framing is parsed (rather than just copied) so that we
+/// know where the body ends, and so that a chunk that would take the body past `allowed` bytes
+/// (if given) can be stopped before any of it is sent. Without `keep_framing`, only the chunks'
+/// data is passed along (and trailers are dropped).
+async fn copy_chunked<R, W>(
+    mut reader: ChunkReader<'_, R>,
+    to: &mut W,
+    mut allowed: Option<usize>,
+    keep_framing: bool,
+) -> Result<usize, CopyError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut bytes_written = 0;
+    loop {
+        let size_line = reader.read_line().await?;
+        let size = chunked::parse_size_line(&size_line)?;
+        if let Some(allowed) = &mut allowed {
+            *allowed = allowed.checked_sub(size).ok_or(CopyError::TooLarge)?;
+        }
+        if keep_framing {
+            bytes_written += write_line(to, &size_line).await?;
+        }
+        if size == 0 {
+            break;
+        }
+        let mut remaining = size;
+        while remaining > 0 {
+            let piece = reader.read_some(remaining.min(COPY_CHUNK_SIZE)).await?;
+            to.write_all(&piece).await.map_err(CopyError::Write)?;
+            remaining -= piece.len();
+        }
+        if reader.read_exact(2).await? != b"\r\n" {
+            return Err(CopyError::Malformed);
+        }
+        bytes_written += size;
+        if keep_framing {
+            bytes_written += write_line(to, b"").await?;
+        }
+    }
+    // Trailers (if any), then the blank line that ends the body
+    loop {
+        let line = reader.read_line().await?;
+        if keep_framing {
+            bytes_written += write_line(to, &line).await?;
+        }
+        if line.is_empty() {
+            return Ok(bytes_written);
+        }
+    }
+}
+
+/// Writes `line` followed by CRLF, returning the number of bytes written.
+async fn write_line<W: AsyncWrite + Unpin>(to: &mut W, line: &[u8]) -> Result<usize, CopyError> {
+    to.write_all(line).await.map_err(CopyError::Write)?;
+    to.write_all(b"\r\n").await.map_err(CopyError::Write)?;
+    Ok(line.len() + 2)
+}
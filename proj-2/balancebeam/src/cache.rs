@@ -0,0 +1,288 @@
+use crate::headers::HOP_BY_HOP_HEADERS;
+use crate::response;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Statuses that can be cached without the upstream saying so explicitly (RFC 7231 section 6.1)
+const CACHEABLE_STATUSES: [u16; 10] = [200, 203, 204, 300, 301, 404, 405, 410, 414, 501];
+
+struct CachedResponse {
+    status: http::StatusCode,
+    version: http::Version,
+    headers: http::HeaderMap,
+    body: Vec<u8>,
+    /// How old the response already was when we got it (from its Age header)
+    initial_age: Duration,
+    stored_at: Instant,
+    expires_at: Instant,
+    /// What this entry counts against max_bytes
+    size: usize,
+    /// This entry's key in Entries::by_last_use
+    last_used: u64,
+}
+
+struct Entries {
+    by_key: HashMap<String, CachedResponse>,
+    /// Keys, from least to most recently used
+    by_last_use: BTreeMap<u64, String>,
+    next_use: u64,
+    total_size: usize,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.by_key.remove(key) {
+            self.by_last_use.remove(&entry.last_used);
+            self.total_size -= entry.size;
+        }
+    }
+
+    /// Marks the entry for `key` (which must exist) as the most recently used.
+    fn touch(&mut self, key: &str) {
+        let entry = self.by_key.get_mut(key).unwrap();
+        self.by_last_use.remove(&entry.last_used);
+        entry.last_used = self.next_use;
+        self.by_last_use.insert(self.next_use, key.to_string());
+        self.next_use += 1;
+    }
+}
+
+/// Keeps upstream responses to GET requests in memory, so that repeats of a request can be
+/// answered without going to an upstream. Responses are kept for as long as their Cache-Control
+/// max-age (or Expires header) says, or for default_ttl if they don't say; responses marked
+/// no-store, no-cache or private aren't kept at all. When the cache is full, the least recently
+/// used responses are dropped to make room.
+pub struct ResponseCache {
+    max_bytes: usize,
+    default_ttl: Duration,
+    entries: Mutex<Entries>,
+}
+
+impl ResponseCache {
+    pub fn new(max_bytes: usize, default_ttl: Duration) -> ResponseCache {
+        ResponseCache {
+            max_bytes,
+            default_ttl,
+            entries: Mutex::new(Entries {
+                by_key: HashMap::new(),
+                by_last_use: BTreeMap::new(),
+                next_use: 0,
+                total_size: 0,
+            }),
+        }
+    }
+
+    /// Returns a copy of the stored response to `request`, if there's one that is still fresh and
+    /// the client is willing to take a cached response.
+    pub fn get(&self, request: &http::Request<Vec<u8>>) -> Option<http::Response<Vec<u8>>> {
+        let key = cache_key(request)?;
+        let directives = cache_control_directives(request.headers());
+        if directives
+            .iter()
+            .any(|directive| directive == "no-cache" || directive == "no-store")
+        {
+            return None;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.by_key.get(&key)?.expires_at <= Instant::now() {
+            entries.remove(&key);
+            return None;
+        }
+        entries.touch(&key);
+        let entry = &entries.by_key[&key];
+        let mut response = http::Response::builder()
+            .status(entry.status)
+            .version(entry.version)
+            .body(entry.body.clone())
+            .unwrap();
+        *response.headers_mut() = entry.headers.clone();
+        let age = entry.initial_age + entry.stored_at.elapsed();
+        response
+            .headers_mut()
+            .insert("age", http::HeaderValue::from(age.as_secs()));
+        Some(response)
+    }
+
+    /// Stores `response` as the answer to `request`, if both of them allow it.
+    pub fn put(&self, request: &http::Request<Vec<u8>>, response: &http::Response<Vec<u8>>) {
+        let key = match cache_key(request) {
+            Some(key) => key,
+            None => return,
+        };
+        let directives = cache_control_directives(request.headers());
+        if directives.iter().any(|directive| directive == "no-store") {
+            return;
+        }
+        let initial_age = Duration::from_secs(
+            header_str(response.headers(), "age")
+                .and_then(|age| age.parse().ok())
+                .unwrap_or(0),
+        );
+        let lifetime = match self.freshness_lifetime(response) {
+            Some(lifetime) if lifetime > initial_age => lifetime - initial_age,
+            _ => return,
+        };
+
+        let mut headers = response.headers().clone();
+        // (They describe the upstream connection rather than the response)
+        for name in &HOP_BY_HOP_HEADERS {
+            headers.remove(*name);
+        }
+        // The upstream marked the end of the body by closing the connection, which we won't be
+        // doing when we serve it from here
+        if response::ends_at_close(response, request.method()) {
+            let body_len = response.body().len() as u64;
+            headers.insert("content-length", http::HeaderValue::from(body_len));
+        }
+        let headers_size: usize = headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        let size = key.len() + headers_size + response.body().len();
+        if size > self.max_bytes {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(&key);
+        while entries.total_size + size > self.max_bytes {
+            let (_, oldest) = entries.by_last_use.iter().next().unwrap();
+            let oldest = oldest.clone();
+            entries.remove(&oldest);
+        }
+        let now = Instant::now();
+        let entry = CachedResponse {
+            status: response.status(),
+            version: response.version(),
+            headers,
+            body: response.body().clone(),
+            initial_age,
+            stored_at: now,
+            expires_at: now + lifetime,
+            size,
+            last_used: 0,
+        };
+        entries.by_key.insert(key.clone(), entry);
+        entries.total_size += size;
+        entries.touch(&key);
+        log::debug!(
+            "Cached the response to GET {} for {}s",
+            key,
+            lifetime.as_secs()
+        );
+    }
+
+    /// Returns how long `response` may be served from the cache (counting from when it was
+    /// generated), or None if it can't be cached at all.
+    fn freshness_lifetime(&self, response: &http::Response<Vec<u8>>) -> Option<Duration> {
+        if !CACHEABLE_STATUSES.contains(&response.status().as_u16()) {
+            return None;
+        }
+        // Responses that set cookies or vary by request header are left alone, rather than
+        // risking handing them to the wrong client
+        let headers = response.headers();
+        if headers.contains_key("set-cookie") || headers.contains_key("vary") {
+            return None;
+        }
+        let directives = cache_control_directives(headers);
+        let mut max_age = None;
+        let mut s_maxage = None;
+        for directive in &directives {
+            match directive.as_str() {
+                "no-store" | "no-cache" | "private" => return None,
+                _ => {
+                    if let Some(seconds) = directive.strip_prefix("max-age=") {
+                        max_age = parse_seconds(seconds);
+                    } else if let Some(seconds) = directive.strip_prefix("s-maxage=") {
+                        s_maxage = parse_seconds(seconds);
+                    }
+                }
+            }
+        }
+        // s-maxage is meant for shared caches like us, so it wins over max-age
+        if let Some(lifetime) = s_maxage.or(max_age) {
+            return Some(lifetime);
+        }
+        if let Some(expires) = header_str(headers, "expires") {
+            // An Expires we can't parse means the response is already stale
+            let expires = parse_http_date(expires)?;
+            let date = header_str(headers, "date")
+                .and_then(parse_http_date)
+                .unwrap_or_else(SystemTime::now);
+            return expires.duration_since(date).ok();
+        }
+        Some(self.default_ttl)
+    }
+}
+
+/// Responses are stored by method (always GET, for now), Host and request target. Requests with
+/// credentials aren't cached, since their responses are likely meant for just that client.
+fn cache_key(request: &http::Request<Vec<u8>>) -> Option<String> {
+    if request.method() != http::Method::GET || request.headers().contains_key("authorization") {
+        return None;
+    }
+    let host = header_str(request.headers(), "host").unwrap_or("");
+    Some(format!("{}{}", host, request.uri()))
+}
+
+fn header_str<'a>(headers: &'a http::HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Returns the Cache-Control directives in `headers`, lowercased.
+fn cache_control_directives(headers: &http::HeaderMap) -> Vec<String> {
+    headers
+        .get_all("cache-control")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|directive| directive.trim().to_ascii_lowercase())
+        .collect()
+}
+
+fn parse_seconds(value: &str) -> Option<Duration> {
+    value
+        .trim_matches('"')
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// Parses an HTTP date in the preferred format (e.g. "Sun, 06 Nov 1994 08:49:37 GMT"). The
+/// obsolete formats that RFC 7231 also allows aren't supported.
+fn parse_http_date(date: &str) -> Option<SystemTime> {
+    let fields: Vec<&str> = date.split_whitespace().collect();
+    if fields.len() != 6 || fields[5] != "GMT" {
+        return None;
+    }
+    let day: i64 = fields[1].parse().ok()?;
+    let month = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ]
+    .iter()
+    .position(|&month| month == fields[2])? as i64
+        + 1;
+    let year: i64 = fields[3].parse().ok()?;
+    let time: Vec<i64> = fields[4]
+        .split(':')
+        .map(|field| field.parse().ok())
+        .collect::<Option<_>>()?;
+    if time.len() != 3 {
+        return None;
+    }
+    // Converts a civil date to days since 1970-01-01; see
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    let secs = days * 86400 + time[0] * 3600 + time[1] * 60 + time[2];
+    if secs < 0 {
+        return None;
+    }
+    Some(UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
@@ -0,0 +1,196 @@
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Longest chunk-size line (or trailer line) we accept, including any chunk extensions
+const MAX_LINE_SIZE: usize = 4096;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The chunk framing is malformed, or the peer hung up before sending the last chunk
+    InvalidFraming,
+    /// Encountered an I/O error when reading from the stream
+    Io(std::io::Error),
+}
+
+/// Returns true if the message's body is sent with chunked transfer encoding. Chunked has to be the
+/// last transfer coding applied, so that's the only place we look for it.
+pub fn is_chunked(headers: &http::HeaderMap) -> bool {
+    headers
+        .get_all("transfer-encoding")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .last()
+        .is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// Returns true if the message has a Transfer-Encoding that doesn't end in chunked. There's no
+/// telling where the body of such a request ends (RFC 7230, section 3.3.3), and going by its
+/// Content-Length instead would have us disagree about it with anything that does know the coding,
+/// so it has to be refused.
+pub fn has_unknown_length(headers: &http::HeaderMap) -> bool {
+    headers.contains_key("transfer-encoding") && !is_chunked(headers)
+}
+
+/// Once a chunked body has been decoded in full, the message is forwarded with a Content-Length
+/// instead (this way the receiver doesn't need to understand chunking).
+pub fn replace_with_content_length(headers: &mut http::HeaderMap, body_len: usize) {
+    headers.remove("transfer-encoding");
+    headers.insert(
+        "content-length",
+        http::HeaderValue::from_str(&body_len.to_string()).unwrap(),
+    );
+}
+
+/// Parses a chunk-size line (without its CRLF) into the size of the chunk that follows it.
+pub fn parse_size_line(line: &[u8]) -> Result<usize, Error> {
+    // Chunk extensions (after a ';') are allowed, but don't mean anything to us
+    let size = line.split(|&b| b == b';').next().unwrap();
+    std::str::from_utf8(size)
+        .ok()
+        .and_then(|size| usize::from_str_radix(size.trim(), 16).ok())
+        .ok_or(Error::InvalidFraming)
+}
+
+/// Frames `data` as a single chunk. Empty data produces nothing, since an empty chunk would mark
+/// the end of the body.
+pub fn frame_chunk(data: &[u8]) -> Vec<u8> {
+    if data.is_empty() {
+        return Vec::new();
+    }
+    let mut framed = format!("{:x}\r\n", data.len()).into_bytes();
+    framed.extend_from_slice(data);
+    framed.extend_from_slice(b"\r\n");
+    framed
+}
+
+/// How much of a chunked body read_body got through
+#[derive(Debug)]
+pub enum Decoded {
+    /// All of it. Holds whatever was taken off the stream past the end of the body (the start of
+    /// the next message, if the sender pipelined one)
+    Complete(Vec<u8>),
+    /// Only the part up to the high-water mark. Holds the rest of the body that was taken off the
+    /// stream
+    TooBig(Vec<u8>),
+}
+
+/// Reads and decodes a chunked body, unless it turns out to be bigger than `high_water` bytes.
+/// `body` should hold whatever was read from the stream past the end of the headers.
+///
+/// Returns Decoded::Complete once the whole body has been decoded into `body`. Trailers are read
+/// and discarded; anything sent after them is handed back, to be read as the next message.
+///
+/// If the body is too big, this stops before the chunk that would take it over the limit and
+/// returns Decoded::TooBig(rest): `body` holds what was decoded so far, and `rest` holds the bytes
+/// that were taken off the stream from that chunk's size line on. Those bytes, followed by
+/// whatever is still in the stream, are the rest of the body in chunked form.
+pub async fn read_body<S>(
+    stream: &mut S,
+    body: &mut Vec<u8>,
+    high_water: usize,
+) -> Result<Decoded, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut reader = ChunkReader::new(stream, std::mem::take(body));
+    loop {
+        let size_line = reader.read_line().await?;
+        let size = parse_size_line(&size_line)?;
+        if size == 0 {
+            break;
+        }
+        // (Written this way around so that a huge chunk size can't overflow)
+        if size > high_water - body.len() {
+            let mut rest = size_line;
+            rest.extend_from_slice(b"\r\n");
+            rest.extend_from_slice(reader.unread());
+            return Ok(Decoded::TooBig(rest));
+        }
+        let chunk = reader.read_exact(size + 2).await?;
+        if !chunk.ends_with(b"\r\n") {
+            return Err(Error::InvalidFraming);
+        }
+        body.extend_from_slice(&chunk[..size]);
+    }
+    // Skip any trailers, up to the blank line that ends the body
+    while !reader.read_line().await?.is_empty() {}
+    Ok(Decoded::Complete(reader.unread().to_vec()))
+}
+
+/// Reads lines and runs of bytes out of a stream, keeping whatever it has read past them for the
+/// next call.
+pub struct ChunkReader<'a, S> {
+    stream: &'a mut S,
+    buffer: Vec<u8>,
+    /// Everything in buffer before pos has already been handed out
+    pos: usize,
+}
+
+impl<'a, S: AsyncRead + Unpin> ChunkReader<'a, S> {
+    /// `buffered` holds bytes that were already read from the stream, which come before anything
+    /// still in it.
+    pub fn new(stream: &'a mut S, buffered: Vec<u8>) -> ChunkReader<'a, S> {
+        ChunkReader {
+            stream,
+            buffer: buffered,
+            pos: 0,
+        }
+    }
+
+    /// Bytes that have been read from the stream but not handed out yet
+    fn unread(&self) -> &[u8] {
+        &self.buffer[self.pos..]
+    }
+
+    /// Reads more data into the buffer, failing if the stream has ended.
+    async fn fill(&mut self) -> Result<(), Error> {
+        // Drop what has already been handed out, so the buffer doesn't grow with the whole body
+        self.buffer.drain(..self.pos);
+        self.pos = 0;
+        let mut chunk = [0_u8; 4096];
+        let bytes_read = self.stream.read(&mut chunk).await.map_err(Error::Io)?;
+        if bytes_read == 0 {
+            return Err(Error::InvalidFraming);
+        }
+        self.buffer.extend_from_slice(&chunk[..bytes_read]);
+        Ok(())
+    }
+
+    /// Returns the next line, without its CRLF.
+    pub async fn read_line(&mut self) -> Result<Vec<u8>, Error> {
+        loop {
+            let unread = &self.buffer[self.pos..];
+            if let Some(end) = unread.windows(2).position(|window| window == b"\r\n") {
+                let line = unread[..end].to_vec();
+                self.pos += end + 2;
+                return Ok(line);
+            }
+            if unread.len() > MAX_LINE_SIZE {
+                return Err(Error::InvalidFraming);
+            }
+            self.fill().await?;
+        }
+    }
+
+    /// Returns the next `len` bytes.
+    pub async fn read_exact(&mut self, len: usize) -> Result<Vec<u8>, Error> {
+        while self.buffer.len() - self.pos < len {
+            self.fill().await?;
+        }
+        let bytes = self.buffer[self.pos..self.pos + len].to_vec();
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Returns between 1 and `max_len` of the next bytes, reading from the stream only if nothing
+    /// is buffered (so that large chunks can be passed along without holding all of them).
+    pub async fn read_some(&mut self, max_len: usize) -> Result<Vec<u8>, Error> {
+        if self.pos == self.buffer.len() {
+            self.fill().await?;
+        }
+        let len = max_len.min(self.buffer.len() - self.pos);
+        let bytes = self.buffer[self.pos..self.pos + len].to_vec();
+        self.pos += len;
+        Ok(bytes)
+    }
+}
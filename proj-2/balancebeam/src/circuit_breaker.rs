@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Where an upstream's circuit stands
+enum Circuit {
+    /// Traffic flows normally. Counts failures since the last success.
+    Closed { consecutive_failures: usize },
+    /// The upstream gets no new connections until the cooldown is up
+    Open { since: Instant },
+    /// The cooldown is up and one connection has been let through to see whether the upstream
+    /// has recovered. If that probe never reports back (e.g. its client hung up without sending a
+    /// request), another one is let through after another cooldown.
+    HalfOpen { probe_started: Instant },
+}
+
+/// Takes upstreams that keep failing out of rotation for a while. This works on top of the
+/// health checks (which mark an upstream dead when it can't be reached at all): an upstream that
+/// accepts connections but answers with errors, or too slowly, has its circuit opened after
+/// failure_threshold failed requests in a row. Once the cooldown is up, a single connection is
+/// sent its way as a probe; if the probe's request succeeds the circuit closes again, and if it
+/// fails the cooldown starts over.
+pub struct CircuitBreaker {
+    failure_threshold: usize,
+    /// Responses slower than this count as failures (None = only errors do)
+    slow_threshold: Option<Duration>,
+    cooldown: Duration,
+    /// Keyed by configured upstream address. Upstreams with no entry are closed.
+    circuits: Mutex<HashMap<String, Circuit>>,
+}
+
+impl CircuitBreaker {
+    pub fn new(
+        failure_threshold: usize,
+        slow_threshold: Option<Duration>,
+        cooldown: Duration,
+    ) -> CircuitBreaker {
+        CircuitBreaker {
+            failure_threshold,
+            slow_threshold,
+            cooldown,
+            circuits: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns true if a new connection could be sent to `upstream` right now (its circuit is
+    /// closed, or it's due for a probe).
+    pub fn is_available(&self, upstream: &str) -> bool {
+        self.is_available_locked(&self.circuits.lock().unwrap(), upstream)
+    }
+
+    fn is_available_locked(&self, circuits: &HashMap<String, Circuit>, upstream: &str) -> bool {
+        match circuits.get(upstream) {
+            None | Some(Circuit::Closed { .. }) => true,
+            Some(Circuit::Open { since }) => since.elapsed() >= self.cooldown,
+            Some(Circuit::HalfOpen { probe_started }) => probe_started.elapsed() >= self.cooldown,
+        }
+    }
+
+    /// Claims a connection to `upstream`, which becomes the probe if one is due. Returns false if
+    /// the upstream isn't available after all (another connection got to the probe first).
+    pub fn try_acquire(&self, upstream: &str) -> bool {
+        let mut circuits = self.circuits.lock().unwrap();
+        if !self.is_available_locked(&circuits, upstream) {
+            return false;
+        }
+        if !matches!(circuits.get(upstream), None | Some(Circuit::Closed { .. })) {
+            log::info!("Circuit for upstream {} is half-open; probing it", upstream);
+            let probe_started = Instant::now();
+            circuits.insert(upstream.to_string(), Circuit::HalfOpen { probe_started });
+        }
+        true
+    }
+
+    /// Records how a request forwarded to `upstream` turned out: server errors and responses
+    /// slower than the slow threshold count as failures.
+    pub fn record_response(&self, upstream: &str, status: http::StatusCode, latency: Duration) {
+        let too_slow = self
+            .slow_threshold
+            .is_some_and(|threshold| latency > threshold);
+        if status.is_server_error() || too_slow {
+            self.record_failure(upstream);
+        } else {
+            self.record_success(upstream);
+        }
+    }
+
+    pub fn record_success(&self, upstream: &str) {
+        let mut circuits = self.circuits.lock().unwrap();
+        if let Some(Circuit::HalfOpen { .. }) = circuits.get(upstream) {
+            log::info!(
+                "Probe of upstream {} succeeded; closing its circuit",
+                upstream
+            );
+        }
+        circuits.remove(upstream);
+    }
+
+    /// Records that `upstream` couldn't be reached, or failed to answer a request.
+    pub fn record_failure(&self, upstream: &str) {
+        let mut circuits = self.circuits.lock().unwrap();
+        let circuit = circuits
+            .entry(upstream.to_string())
+            .or_insert(Circuit::Closed {
+                consecutive_failures: 0,
+            });
+        match circuit {
+            Circuit::Closed {
+                consecutive_failures,
+            } => {
+                *consecutive_failures += 1;
+                if *consecutive_failures < self.failure_threshold {
+                    return;
+                }
+                log::warn!(
+                    "Opened the circuit for upstream {} after {} failures in a row; skipping it \
+                    for {}s",
+                    upstream,
+                    consecutive_failures,
+                    self.cooldown.as_secs()
+                );
+            }
+            Circuit::HalfOpen { .. } => log::warn!(
+                "Probe of upstream {} failed; skipping it for another {}s",
+                upstream,
+                self.cooldown.as_secs()
+            ),
+            // (A connection made before the circuit opened can still report failures)
+            Circuit::Open { .. } => return,
+        }
+        *circuit = Circuit::Open {
+            since: Instant::now(),
+        };
+    }
+}
@@ -0,0 +1,187 @@
+use flate2::write::{DeflateEncoder, GzEncoder};
+use std::io::Write;
+
+/// Content types compressed when no --compress-type is given
+pub const DEFAULT_TYPES: [&str; 8] = [
+    "text/html",
+    "text/css",
+    "text/plain",
+    "text/javascript",
+    "application/javascript",
+    "application/json",
+    "application/xml",
+    "image/svg+xml",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        match self {
+            Encoding::Gzip => "gzip",
+            Encoding::Deflate => "deflate",
+        }
+    }
+}
+
+/// Picks the encoding to compress a response to this request with, if the client accepts one
+/// (going by Accept-Encoding, where a q-value of 0 means "not acceptable"). Gzip wins ties, since
+/// "deflate" has a history of being implemented inconsistently.
+pub fn negotiate(request_headers: &http::HeaderMap) -> Option<Encoding> {
+    let mut best: Option<(Encoding, f32)> = None;
+    for value in request_headers.get_all("accept-encoding") {
+        let value = match value.to_str() {
+            Ok(value) => value,
+            Err(_) => continue,
+        };
+        for item in value.split(',') {
+            let mut params = item.split(';');
+            let coding = params.next().unwrap_or("").trim().to_ascii_lowercase();
+            let quality = params
+                .filter_map(|param| {
+                    let (name, value) = param.split_once('=')?;
+                    if name.trim().eq_ignore_ascii_case("q") {
+                        value.trim().parse::<f32>().ok()
+                    } else {
+                        None
+                    }
+                })
+                .next()
+                .unwrap_or(1.0);
+            let encoding = match coding.as_str() {
+                "gzip" | "x-gzip" => Encoding::Gzip,
+                "deflate" => Encoding::Deflate,
+                _ => continue,
+            };
+            if quality <= 0.0 {
+                continue;
+            }
+            let better = match best {
+                None => true,
+                Some((best_encoding, best_quality)) => {
+                    quality > best_quality
+                        || (quality == best_quality
+                            && encoding == Encoding::Gzip
+                            && best_encoding != Encoding::Gzip)
+                }
+            };
+            if better {
+                best = Some((encoding, quality));
+            }
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Compresses upstream responses on the fly for clients that accept it.
+#[derive(Debug)]
+pub struct Compressor {
+    /// Media types (without parameters, lowercase) that are worth compressing
+    types: Vec<String>,
+    /// Bodies smaller than this many bytes aren't worth the trouble
+    min_size: usize,
+}
+
+impl Compressor {
+    pub fn new(types: &[String], min_size: usize) -> Compressor {
+        Compressor {
+            types: types
+                .iter()
+                .map(|t| t.trim().to_ascii_lowercase())
+                .collect(),
+            min_size,
+        }
+    }
+
+    /// Compresses the body of `response` with `encoding`, and sets its Content-Encoding and
+    /// Content-Length to match. Responses that are already encoded, have a content type that
+    /// isn't on the list, are smaller than the minimum size, or ask not to be transformed
+    /// (Cache-Control: no-transform) are left alone, as are partial (206) responses.
+    ///
+    /// Returns true if the response was compressed.
+    pub fn compress(&self, response: &mut http::Response<Vec<u8>>, encoding: Encoding) -> bool {
+        let headers = response.headers();
+        let media_type = headers
+            .get("content-type")
+            .and_then(|value| value.to_str().ok())
+            .map(|value| {
+                let media_type = value.split(';').next().unwrap_or("");
+                media_type.trim().to_ascii_lowercase()
+            });
+        let already_encoded = headers
+            .get("content-encoding")
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| !value.trim().eq_ignore_ascii_case("identity"));
+        let no_transform = headers
+            .get_all("cache-control")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|directive| directive.trim().eq_ignore_ascii_case("no-transform"));
+        if !media_type.is_some_and(|media_type| self.types.contains(&media_type))
+            || already_encoded
+            || no_transform
+            || headers.contains_key("transfer-encoding")
+            || response.status() == http::StatusCode::PARTIAL_CONTENT
+            || response.body().len() < self.min_size
+        {
+            return false;
+        }
+
+        let compressed = match encoding {
+            Encoding::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder
+                    .write_all(response.body())
+                    .and_then(|_| encoder.finish())
+            }
+            Encoding::Deflate => {
+                let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder
+                    .write_all(response.body())
+                    .and_then(|_| encoder.finish())
+            }
+        };
+        let compressed = match compressed {
+            Ok(compressed) => compressed,
+            Err(err) => {
+                log::warn!("Could not compress response: {}", err);
+                return false;
+            }
+        };
+
+        let content_length = compressed.len();
+        *response.body_mut() = compressed;
+        let headers = response.headers_mut();
+        headers.insert(
+            "content-encoding",
+            http::HeaderValue::from_static(encoding.name()),
+        );
+        headers.insert("content-length", http::HeaderValue::from(content_length));
+        // Caches between us and the client need to know that the body depends on Accept-Encoding
+        let varies = headers
+            .get_all("vary")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|name| {
+                let name = name.trim();
+                name == "*" || name.eq_ignore_ascii_case("accept-encoding")
+            });
+        if !varies {
+            headers.append("vary", http::HeaderValue::from_static("accept-encoding"));
+        }
+        // The compressed bytes aren't the ones the upstream's ETag vouched for
+        if let Some(etag) = headers.get("etag").and_then(|etag| etag.to_str().ok()) {
+            if !etag.starts_with("W/") {
+                let weak = http::HeaderValue::from_str(&format!("W/{}", etag)).unwrap();
+                headers.insert("etag", weak);
+            }
+        }
+        true
+    }
+}
@@ -0,0 +1,1221 @@
+use crate::access_log::AccessLogFormat;
+use crate::auth::{AuthRule, AuthRules};
+use crate::cidr::{AccessList, Cidr};
+use crate::compression;
+use crate::error_pages::ErrorPages;
+use crate::headers::{HeaderRule, HeaderRules};
+use crate::health_check::{self, HealthCheck};
+use crate::rate_limit::RequestLimit;
+use crate::request::HeaderLimits;
+use crate::routing::{Canary, Route, RoutingTable, VirtualHost};
+use crate::strategy::StrategyKind;
+use clap::Parser;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
+/// provide a fancy way to automatically construct a command-line argument parser.
+//
+// Options that can also be set in the config file have no clap default, so that we can tell
+// whether they were given on the command line (and should override the file). Their defaults are
+// applied in Config::load instead.
+#[derive(Parser, Debug, Clone)]
+pub struct CmdOptions {
+    /// TOML file to read settings from. Options given on the command line override it
+    #[clap(long)]
+    config: Option<String>,
+    /// IP/port to bind to (default 0.0.0.0:1100), or unix:PATH to accept connections on a UNIX
+    /// socket instead. Can be repeated to listen on several (e.g. IPv4 and IPv6, or two ports)
+    // 表示本机上所有的ipv4地址，在1100端口上监听
+    // 之后用浏览器请求localhost:1100就会把http请求发到该进程
+    #[clap(short, long)]
+    bind: Vec<String>,
+    /// Accept connections from this many tasks at once, each with its own listening sockets
+    /// (bound with SO_REUSEPORT, so that the kernel spreads new connections across them), so that
+    /// a single accept loop doesn't hold back busy many-core machines. UNIX sockets are only
+    /// accepted from by the first
+    #[clap(long, default_value = "1")]
+    workers: usize,
+    /// Upstream host to forward requests to (or unix:PATH, for an upstream listening on a UNIX
+    /// socket). Replaces any upstreams listed in the config file
+    #[clap(short, long)]
+    upstream: Vec<String>,
+    /// Send requests whose path starts with a prefix to their own upstreams instead, given as
+    /// PREFIX=UPSTREAM[,UPSTREAM...] (e.g. /api=10.0.0.1:80,10.0.0.2:80). Can be repeated, and
+    /// replaces any routes listed in the config file
+    #[clap(long)]
+    route: Vec<String>,
+    /// How to pick an upstream for each new client connection (default random)
+    #[clap(long, value_enum)]
+    strategy: Option<StrategyKind>,
+    /// Send each client back to the upstream that served it before (named in a BALANCEBEAM_STICKY
+    /// cookie), for as long as that upstream is available
+    #[clap(long)]
+    sticky_sessions: bool,
+    /// How often to look up upstreams given by hostname again, in seconds, so that changes to the
+    /// addresses they resolve to are picked up (every address a hostname resolves to is proxied
+    /// to, as an upstream of its own). 0 = only when starting up or reloading the configuration
+    #[clap(long, default_value = "30")]
+    dns_refresh_secs: u64,
+    /// Refuse to start if more than this many distinct upstreams are given
+    #[clap(long)]
+    max_upstreams: Option<usize>,
+    /// Local IP address to make upstream connections from (defaults to whatever the OS picks)
+    #[clap(long)]
+    upstream_source_addr: Option<IpAddr>,
+    /// PEM file with the CA certificates to verify https:// upstreams against (defaults to the
+    /// Mozilla root store)
+    #[clap(long)]
+    upstream_ca_cert: Option<String>,
+    /// Clients in this IP range (e.g. 10.0.0.0/8) are proxies whose X-Forwarded-* and Forwarded
+    /// headers are kept and appended to. Can be repeated; headers from anyone else are replaced
+    #[clap(long)]
+    trusted_proxy: Vec<Cidr>,
+    /// Let clients in this IP range (e.g. 10.0.0.0/8) pick the upstream for a request with an
+    /// X-Balancebeam-Upstream header naming it, whatever its health (for debugging one upstream).
+    /// Can be repeated; the header is ignored from anyone else
+    #[clap(long)]
+    upstream_override_from: Vec<Cidr>,
+    /// Only serve clients in this IP range (e.g. 10.0.0.0/8). Can be repeated; without it, every
+    /// client is served unless --deny says otherwise
+    #[clap(long)]
+    allow: Vec<Cidr>,
+    /// Refuse clients in this IP range, even if --allow would let them in. Can be repeated
+    #[clap(long)]
+    deny: Vec<Cidr>,
+    /// Close connections from clients that --allow/--deny refuse without a word, rather than
+    /// answering their first request with a 403
+    #[clap(long)]
+    deny_silently: bool,
+    /// Expect every client connection to start with a PROXY protocol (v1 or v2) header from a
+    /// fronting load balancer, and treat the client address it gives as the real one
+    #[clap(long)]
+    accept_proxy_protocol: bool,
+    /// Start every upstream connection with a PROXY protocol v1 header naming the client
+    #[clap(long)]
+    send_proxy_protocol: bool,
+    /// PEM file with the certificate chain to present to clients. Together with --tls-key, makes
+    /// balancebeam accept HTTPS (instead of plain HTTP) connections
+    #[clap(long)]
+    tls_cert: Option<String>,
+    /// PEM file with the private key for --tls-cert
+    #[clap(long)]
+    tls_key: Option<String>,
+    /// Also speak HTTP/2 with clients: negotiated through ALPN over TLS, or when a plain HTTP
+    /// client opens with the HTTP/2 connection preface (prior knowledge). Upstreams still get
+    /// HTTP/1.1
+    #[clap(long)]
+    http2: bool,
+    /// IP/port to serve balancebeam's status endpoint (GET /status) on. Off unless given
+    #[clap(long)]
+    status_bind: Option<String>,
+    /// IP/port to serve Prometheus metrics (GET /metrics) on. Off unless given
+    #[clap(long)]
+    metrics_bind: Option<String>,
+    /// IP/port to serve the admin API (for listing, draining and re-enabling upstreams) on. It
+    /// has no authentication, so only bind it somewhere operators alone can reach. Off unless
+    /// given
+    #[clap(long)]
+    admin_bind: Option<String>,
+    /// How to write the access log, which gets a line for every request answered
+    #[clap(long, value_enum, default_value = "combined")]
+    access_log_format: AccessLogFormat,
+    /// File to append the access log to (defaults to writing it through the regular log)
+    #[clap(long)]
+    access_log_file: Option<String>,
+    /// Log a summary of the traffic handled so far (connections, requests and bytes, and requests
+    /// and errors by upstream) this often, in seconds. 0 = never
+    #[clap(long, default_value = "0")]
+    stats_log_secs: u64,
+    /// Perform active health checks on this interval (in seconds; 0 = no active health checks;
+    /// default 10)
+    #[clap(long)]
+    active_health_check_interval: Option<usize>,
+    /// Path to send request to for active health checks (default /)
+    #[clap(long)]
+    active_health_check_path: Option<String>,
+    /// POST a JSON event to this http:// or https:// URL whenever an upstream is marked dead or
+    /// alive again
+    #[clap(long)]
+    health_webhook: Option<String>,
+    /// Maximum number of requests to accept per IP per minute, on average (0 = unlimited, the
+    /// default)
+    #[clap(long)]
+    max_requests_per_minute: Option<usize>,
+    /// Keep the request limits' per-client counts in the Redis server at this HOST:PORT, so that
+    /// balancebeam replicas pointed at the same one share them (defaults to keeping them in
+    /// memory). Requests are let through if it can't be reached
+    #[clap(long)]
+    rate_limit_redis: Option<String>,
+    /// How many requests a client that has been quiet may make at once, on top of
+    /// --max-requests-per-minute (defaults to --max-requests-per-minute)
+    #[clap(long)]
+    rate_limit_burst: Option<usize>,
+    /// Maximum number of bytes (requests plus responses) to transfer per IP per minute
+    /// (0 = unlimited, the default)
+    #[clap(long)]
+    max_bytes_per_minute_per_ip: Option<usize>,
+    /// Length of the rate limiting window, in seconds. Only meant to be changed for testing
+    #[clap(long, default_value = "60", hide = true)]
+    rate_limit_window_secs: u64,
+    /// Serve at most this many client connections at once; more are left waiting to be accepted
+    /// until one closes (0 = unlimited)
+    #[clap(long, default_value = "0")]
+    max_connections: usize,
+    /// Once --max-connections are open, accept up to this many more and have them wait for a
+    /// slot, closing any beyond that as soon as they arrive. 0 (the default) leaves them waiting
+    /// to be accepted instead
+    #[clap(long, default_value = "0")]
+    max_queued_connections: usize,
+    /// Refuse new connections from a client IP that already has this many open (0 = unlimited)
+    #[clap(long, default_value = "0")]
+    max_connections_per_ip: usize,
+    /// Log a warning for any request that takes longer than this to proxy, in milliseconds
+    /// (0 = never warn)
+    #[clap(long, default_value = "0")]
+    slow_request_threshold_ms: u64,
+    /// Give up on an upstream (and respond 504) if it takes longer than this to send a complete
+    /// set of response headers, in milliseconds (0 = wait forever)
+    #[clap(long, default_value = "0")]
+    upstream_header_timeout_ms: u64,
+    /// Give up on connecting to an upstream (and try another, or respond 504) after this many
+    /// milliseconds (0 = leave it to the OS)
+    #[clap(long, default_value = "0")]
+    upstream_connect_timeout_ms: u64,
+    /// Give up on an upstream if its response body stops arriving for this many milliseconds
+    /// (0 = wait forever)
+    #[clap(long, default_value = "0")]
+    upstream_body_timeout_ms: u64,
+    /// Respond 504 if connecting to an upstream, sending it the request and reading its response
+    /// takes longer than this altogether, in milliseconds (0 = no limit)
+    #[clap(long, default_value = "0")]
+    request_timeout_ms: u64,
+    /// Close a client connection that sends nothing for this many milliseconds, whether it is
+    /// partway through a request (which gets a 408) or idle between requests (0 = wait forever)
+    #[clap(long, default_value = "60000")]
+    client_idle_timeout_ms: u64,
+    /// Respond 408 and close the connection if a client takes longer than this to send a request's
+    /// headers, counted from the first byte, in milliseconds (0 = no limit)
+    #[clap(long, default_value = "30000")]
+    client_header_timeout_ms: u64,
+    /// Keep up to this many idle connections per upstream for reuse by later clients (0 = open a
+    /// new upstream connection for every client connection)
+    #[clap(long, default_value = "0")]
+    upstream_pool_max_idle: usize,
+    /// Close pooled upstream connections that have been idle for this many seconds
+    #[clap(long, default_value = "60")]
+    upstream_pool_idle_timeout_secs: u64,
+    /// If the connection to an upstream dies while a GET (or other idempotent request) is being
+    /// forwarded, send it to up to this many other upstreams before giving up with a 502
+    #[clap(long, default_value = "1")]
+    max_retries: usize,
+    /// Stop sending new connections to an upstream after this many of its requests fail in a row
+    /// (with a 5xx, an error, or a response slower than --circuit-breaker-slow-ms), until
+    /// --circuit-breaker-cooldown-secs have passed (0 = no circuit breaker)
+    #[clap(long, default_value = "0")]
+    circuit_breaker_failures: usize,
+    /// How long an upstream's circuit stays open before a single connection is let through to
+    /// check whether it has recovered, in seconds
+    #[clap(long, default_value = "30")]
+    circuit_breaker_cooldown_secs: u64,
+    /// Count responses that take longer than this (in milliseconds) as failures for the circuit
+    /// breaker (0 = only errors count)
+    #[clap(long, default_value = "0")]
+    circuit_breaker_slow_ms: u64,
+    /// Take an upstream out of rotation for a while if its share of 5xx responses (over the last
+    /// --outlier-window-secs) is this many percentage points above the rest of the pool's
+    /// (0 = no outlier detection)
+    #[clap(long, default_value = "0")]
+    outlier_error_percent: usize,
+    /// How far back outlier detection looks, in seconds
+    #[clap(long, default_value = "30")]
+    outlier_window_secs: u64,
+    /// Only judge an upstream as an outlier once it has sent this many responses in the window
+    #[clap(long, default_value = "10")]
+    outlier_min_responses: usize,
+    /// How long an outlier is kept out of rotation the first time, in seconds (doubling each time
+    /// it's ejected again soon after being let back in)
+    #[clap(long, default_value = "30")]
+    outlier_ejection_secs: u64,
+    /// Send copies of requests to this upstream too (in the background, discarding its responses),
+    /// to try it out on real traffic
+    #[clap(long)]
+    shadow_upstream: Option<String>,
+    /// Percentage of requests to copy to --shadow-upstream
+    #[clap(long, default_value = "100", value_parser = clap::value_parser!(u8).range(0..=100))]
+    shadow_percent: u8,
+    /// On SIGTERM/SIGINT, wait up to this many seconds for open connections to finish their
+    /// requests before exiting (0 = exit right away)
+    #[clap(long, default_value = "30")]
+    shutdown_drain_timeout_secs: u64,
+    /// Keep up to this many bytes of upstream responses to GET requests in memory, and answer
+    /// repeated requests from there (0 = no caching)
+    #[clap(long, default_value = "0")]
+    cache_max_bytes: usize,
+    /// How long to cache responses that don't say (through Cache-Control or Expires) how long
+    /// they stay fresh, in seconds
+    #[clap(long, default_value = "60")]
+    cache_ttl: u64,
+    /// Insert this string just before the closing </body> tag of text/html responses
+    #[clap(long)]
+    inject_before_body_end: Option<String>,
+    /// Directory of HTML pages to answer errors with, named after their status codes (e.g.
+    /// 502.html). Statuses without one get a built-in page
+    #[clap(long)]
+    error_pages_dir: Option<String>,
+    /// Respond 413 to requests with bodies larger than this, given in bytes or with a unit (e.g.
+    /// 512KB, 10MB, 1GiB; 0 = unlimited)
+    #[clap(long, default_value = "0", value_parser = parse_byte_size)]
+    max_body_size: usize,
+    /// Respond 431 to requests whose request line and headers take up more than this, given in
+    /// bytes or with a unit (e.g. 16KiB)
+    #[clap(long, default_value = "8000", value_parser = parse_byte_size)]
+    max_request_header_bytes: usize,
+    /// Respond 431 to requests with more than this many headers
+    #[clap(long, default_value = "32")]
+    max_request_headers: usize,
+    /// Collapse repeated slashes and resolve . and .. segments in request paths before routing
+    /// and forwarding them. Paths with percent-encoded traversal sequences get a 400
+    #[clap(long)]
+    normalize_paths: bool,
+    /// Respond 400 to requests whose paths would need normalizing (have repeated slashes, . or ..
+    /// segments, or percent-encoded traversal sequences), rather than normalizing them
+    #[clap(long)]
+    reject_suspicious_paths: bool,
+    /// Request and response bodies larger than this many bytes are streamed through in pieces
+    /// instead of being read into memory in full
+    #[clap(long, default_value = "1048576")]
+    body_high_water_mark: usize,
+    /// Skip injection for HTML responses with bodies larger than this many bytes
+    #[clap(long, default_value = "1048576")]
+    inject_max_body_size: usize,
+    /// Add a Server-Timing header reporting the upstream round-trip time to each response
+    #[clap(long)]
+    expose_timing_header: bool,
+    /// Compress responses (with gzip, or deflate) for clients that accept it, unless the upstream
+    /// already did
+    #[clap(long)]
+    compress: bool,
+    /// Media type to compress with --compress (e.g. text/html). Can be repeated; defaults to
+    /// common text formats
+    #[clap(long)]
+    compress_type: Vec<String>,
+    /// Don't compress response bodies smaller than this, given in bytes or with a unit (e.g.
+    /// 512B, 1KiB)
+    #[clap(long, default_value = "1024", value_parser = parse_byte_size)]
+    compress_min_size: usize,
+}
+
+/// The contents of a config file. Everything is optional; anything missing falls back to the
+/// command line or the built-in default. For example:
+///
+/// ```toml
+/// bind = ["0.0.0.0:1100", "[::]:1100"]
+/// strategy = "round-robin"
+/// upstreams = ["10.0.0.1:80", { address = "10.0.0.2:80", weight = 3 }]
+///
+/// [[routes]]
+/// prefix = "/static"
+/// upstreams = ["10.0.0.3:80"]
+///
+/// [[virtual_hosts]]
+/// hosts = ["api.example.com"]
+/// upstreams = ["10.0.0.4:80", "10.0.0.5:80"]
+/// health_check_path = "/healthz"
+///
+/// [canary]
+/// upstreams = ["10.0.0.6:80"]
+/// percent = 5
+///
+/// [headers]
+/// request = [{ action = "add", name = "Via", value = "balancebeam" }]
+/// response = [{ action = "remove", name = "Server" }]
+///
+/// [health_check]
+/// interval = 5
+/// path = "/healthz"
+/// expected_status = "200-299"
+/// body_contains = "ok"
+///
+/// [[routes]]
+/// prefix = "/search"
+/// upstreams = ["10.0.0.7:80"]
+/// health_check = { path = "/ping", body_regex = "^pong", host = "search.internal", timeout_ms = 500 }
+///
+/// [rate_limit]
+/// max_requests_per_minute = 600
+/// burst = 100
+/// max_bytes_per_minute_per_ip = 10000000
+/// redis = "10.0.0.9:6379"
+///
+/// [[rate_limit.routes]]
+/// prefix = "/login"
+/// max_requests_per_minute = 10
+/// burst = 3
+///
+/// [[auth]]
+/// prefix = "/internal"
+/// realm = "internal"
+/// basic = ["alice:correct horse"]
+/// bearer_tokens = ["0123456789abcdef"]
+/// ```
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    bind: Option<FileBind>,
+    strategy: Option<StrategyKind>,
+    upstreams: Vec<FileUpstream>,
+    routes: Vec<FileRoute>,
+    virtual_hosts: Vec<FileVirtualHost>,
+    canary: Option<FileCanary>,
+    headers: FileHeaders,
+    health_check: FileHealthCheck,
+    rate_limit: FileRateLimit,
+    auth: Vec<FileAuth>,
+}
+
+/// `bind` in the config file: one address, or a list of them
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum FileBind {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// An upstream in the config file: either just its address, or a table with a weight too
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum FileUpstream {
+    Address(String),
+    Weighted {
+        address: String,
+        #[serde(default = "default_weight")]
+        weight: usize,
+    },
+}
+
+fn default_weight() -> usize {
+    1
+}
+
+impl FileUpstream {
+    fn into_upstream(self) -> Upstream {
+        match self {
+            FileUpstream::Address(address) => Upstream { address, weight: 1 },
+            FileUpstream::Weighted { address, weight } => Upstream { address, weight },
+        }
+    }
+}
+
+/// A `[[routes]]` table: requests for paths under `prefix` go to these upstreams
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct FileRoute {
+    prefix: String,
+    upstreams: Vec<FileUpstream>,
+    health_check: Option<FileHealthCheck>,
+}
+
+/// A `[[virtual_hosts]]` table: requests with one of these Host headers go to these upstreams,
+/// which are health-checked at `health_check_path` if it's given (short for a `health_check`
+/// table with just a path)
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct FileVirtualHost {
+    hosts: Vec<String>,
+    upstreams: Vec<FileUpstream>,
+    health_check_path: Option<String>,
+    health_check: Option<FileHealthCheck>,
+}
+
+/// The `[canary]` table: `percent` of the requests for the default upstreams go to these upstreams
+/// instead (adjustable through the admin API)
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct FileCanary {
+    upstreams: Vec<FileUpstream>,
+    percent: u8,
+    health_check: Option<FileHealthCheck>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileHeaders {
+    request: Vec<FileHeaderRule>,
+    response: Vec<FileHeaderRule>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "action", rename_all = "kebab-case", deny_unknown_fields)]
+enum FileHeaderRule {
+    Add { name: String, value: String },
+    Replace { name: String, value: String },
+    Remove { name: String },
+}
+
+/// The `[health_check]` table, or a pool's `health_check` table (which can't set the interval, and
+/// fills in whatever it leaves out from `[health_check]`)
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+struct FileHealthCheck {
+    interval: Option<usize>,
+    path: Option<String>,
+    /// Statuses that pass, e.g. "200-299,301" (default 200)
+    expected_status: Option<String>,
+    body_contains: Option<String>,
+    body_regex: Option<String>,
+    /// Host header to send (default the upstream's host:port)
+    host: Option<String>,
+    /// 0 = until the next check is due, the default
+    timeout_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileRateLimit {
+    max_requests_per_minute: Option<usize>,
+    burst: Option<usize>,
+    max_bytes_per_minute_per_ip: Option<usize>,
+    redis: Option<String>,
+    routes: Vec<FileRouteRateLimit>,
+}
+
+/// A `[[rate_limit.routes]]` table: requests for paths under `prefix` get their own request limit
+/// (per client IP) instead of the default one
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct FileRouteRateLimit {
+    prefix: String,
+    max_requests_per_minute: usize,
+    burst: Option<usize>,
+}
+
+/// An `[[auth]]` table: requests for paths under `prefix` are only forwarded with one of these
+/// credentials (`user:password` pairs for HTTP Basic auth, or bearer tokens)
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct FileAuth {
+    prefix: String,
+    realm: Option<String>,
+    #[serde(default)]
+    basic: Vec<String>,
+    #[serde(default)]
+    bearer_tokens: Vec<String>,
+}
+
+/// An upstream to proxy to, and its share of the traffic relative to the other upstreams
+#[derive(Debug, Clone)]
+pub struct Upstream {
+    pub address: String,
+    pub weight: usize,
+}
+
+/// Where to load the certificate and key for terminating TLS from
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    pub cert_path: String,
+    pub key_path: String,
+}
+
+/// balancebeam's settings, merged from the command line and config file and checked for
+/// consistency. This is what ProxyState is built from.
+#[derive(Debug)]
+pub struct Config {
+    /// Where to listen for clients (at least one address)
+    pub binds: Vec<String>,
+    /// How many accept loops to run (at least one)
+    pub workers: usize,
+    /// Distinct upstreams (the default ones, then the ones only used by routes, virtual hosts or
+    /// the canary), in the order they were given
+    pub upstreams: Vec<Upstream>,
+    pub routes: RoutingTable,
+    pub strategy: StrategyKind,
+    pub sticky_sessions: bool,
+    /// None = never look upstreams up again
+    pub dns_refresh_interval: Option<Duration>,
+    pub upstream_source_addr: Option<IpAddr>,
+    /// None = verify https:// upstreams against the Mozilla root store
+    pub upstream_ca_cert: Option<String>,
+    /// None = accept plain HTTP connections
+    pub tls: Option<TlsFiles>,
+    pub http2: bool,
+    pub trusted_proxies: Vec<Cidr>,
+    pub upstream_override_from: Vec<Cidr>,
+    pub access_list: AccessList,
+    pub deny_silently: bool,
+    pub accept_proxy_protocol: bool,
+    pub send_proxy_protocol: bool,
+    pub status_bind: Option<String>,
+    pub metrics_bind: Option<String>,
+    pub admin_bind: Option<String>,
+    pub access_log_format: AccessLogFormat,
+    /// None = write the access log through the regular log
+    pub access_log_file: Option<String>,
+    /// None = no periodic stats summaries
+    pub stats_log_interval: Option<Duration>,
+    /// None = no active health checks
+    pub active_health_check_interval: Option<Duration>,
+    pub health_webhook: Option<String>,
+    /// How the default upstreams are health-checked
+    pub health_check: HealthCheck,
+    /// Health checks for upstreams whose pool has its own (the rest get health_check)
+    pub health_checks: HashMap<String, HealthCheck>,
+    /// 0 = unlimited
+    pub max_requests_per_minute: usize,
+    pub rate_limit_burst: usize,
+    /// Request limits for paths under these prefixes (without a trailing '/'), which replace
+    /// the default one
+    pub route_rate_limits: Vec<(String, RequestLimit)>,
+    /// None = keep request limit counts in memory
+    pub rate_limit_redis: Option<String>,
+    /// 0 = unlimited
+    pub max_bytes_per_minute_per_ip: usize,
+    pub rate_limit_window: Duration,
+    /// 0 = unlimited
+    pub max_connections: usize,
+    pub max_queued_connections: usize,
+    /// 0 = unlimited
+    pub max_connections_per_ip: usize,
+    pub slow_request_threshold: Option<Duration>,
+    pub upstream_header_timeout: Option<Duration>,
+    pub upstream_connect_timeout: Option<Duration>,
+    pub upstream_body_timeout: Option<Duration>,
+    pub request_timeout: Option<Duration>,
+    pub client_idle_timeout: Option<Duration>,
+    pub client_header_timeout: Option<Duration>,
+    /// 0 = no pooling
+    pub upstream_pool_max_idle: usize,
+    pub upstream_pool_idle_timeout: Duration,
+    pub body_high_water_mark: usize,
+    /// 0 = unlimited
+    pub max_body_size: usize,
+    pub header_limits: HeaderLimits,
+    pub normalize_paths: bool,
+    pub reject_suspicious_paths: bool,
+    pub max_retries: usize,
+    /// 0 = no circuit breaker
+    pub circuit_breaker_failures: usize,
+    pub circuit_breaker_cooldown: Duration,
+    pub circuit_breaker_slow_threshold: Option<Duration>,
+    /// 0 = no outlier detection
+    pub outlier_error_percent: usize,
+    pub outlier_window: Duration,
+    pub outlier_min_responses: usize,
+    pub outlier_ejection_time: Duration,
+    pub shadow_upstream: Option<String>,
+    pub shadow_percent: u8,
+    pub shutdown_drain_timeout: Duration,
+    /// 0 = no caching
+    pub cache_max_bytes: usize,
+    pub cache_ttl: Duration,
+    pub inject_before_body_end: Option<String>,
+    pub inject_max_body_size: usize,
+    pub error_pages: ErrorPages,
+    pub expose_timing_header: bool,
+    pub compress: bool,
+    /// Media types to compress
+    pub compress_types: Vec<String>,
+    pub compress_min_size: usize,
+    pub header_rules: HeaderRules,
+    pub auth_rules: AuthRules,
+}
+
+impl Config {
+    /// Returns the default per-IP request limit (None = unlimited).
+    pub fn request_limit(&self) -> Option<RequestLimit> {
+        match self.max_requests_per_minute {
+            0 => None,
+            max_requests => Some(RequestLimit {
+                max_requests,
+                burst: self.rate_limit_burst,
+            }),
+        }
+    }
+
+    /// Builds the configuration from the command line (and the config file, if one was given).
+    /// Returns a message describing the problem if the configuration can't be used.
+    pub fn load(options: CmdOptions) -> Result<Config, String> {
+        let file = match &options.config {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .map_err(|err| format!("Could not read config file {}: {}", path, err))?;
+                toml::from_str(&contents)
+                    .map_err(|err| format!("Could not parse config file {}: {}", path, err))?
+            }
+            None => FileConfig::default(),
+        };
+
+        let default_upstreams: Vec<Upstream> = if options.upstream.is_empty() {
+            file.upstreams
+                .into_iter()
+                .map(FileUpstream::into_upstream)
+                .collect()
+        } else {
+            options
+                .upstream
+                .into_iter()
+                .map(|address| Upstream { address, weight: 1 })
+                .collect()
+        };
+        let mut health_check = build_health_check(
+            &HealthCheck::new("/".to_string()),
+            &file.health_check,
+            "[health_check]",
+        )?;
+        if let Some(path) = options.active_health_check_path {
+            health_check.path = path;
+        }
+        // Pools of upstreams can have health checks of their own; if an upstream is in several,
+        // the first one's wins
+        let mut pools: Vec<(String, &[FileUpstream], FileHealthCheck)> = Vec::new();
+        if options.route.is_empty() {
+            for route in &file.routes {
+                if let Some(check) = &route.health_check {
+                    let name = format!("route {}", route.prefix);
+                    pools.push((name, &route.upstreams, check.clone()));
+                }
+            }
+        }
+        for virtual_host in &file.virtual_hosts {
+            let mut check = virtual_host.health_check.clone();
+            if let Some(path) = &virtual_host.health_check_path {
+                let check = check.get_or_insert_with(FileHealthCheck::default);
+                check.path = check.path.take().or_else(|| Some(path.clone()));
+            }
+            if let Some(check) = check {
+                let name = format!("virtual host {}", virtual_host.hosts.join(", "));
+                pools.push((name, &virtual_host.upstreams, check));
+            }
+        }
+        if let Some(canary) = &file.canary {
+            if let Some(check) = &canary.health_check {
+                pools.push(("the canary".to_string(), &canary.upstreams, check.clone()));
+            }
+        }
+        let mut health_checks = HashMap::new();
+        for (name, upstreams, check) in pools {
+            if check.interval.is_some() {
+                return Err(format!(
+                    "The health check for {} can't set an interval; only [health_check] can",
+                    name
+                ));
+            }
+            let check = build_health_check(&health_check, &check, &name)?;
+            for upstream in upstreams {
+                health_checks
+                    .entry(upstream.clone().into_upstream().address)
+                    .or_insert_with(|| check.clone());
+            }
+        }
+        let routes: Vec<(String, Vec<Upstream>)> = if options.route.is_empty() {
+            file.routes
+                .into_iter()
+                .map(|route| {
+                    let upstreams = route
+                        .upstreams
+                        .into_iter()
+                        .map(FileUpstream::into_upstream)
+                        .collect();
+                    (route.prefix, upstreams)
+                })
+                .collect()
+        } else {
+            options
+                .route
+                .iter()
+                .map(|route| parse_route_option(route))
+                .collect::<Result<_, _>>()?
+        };
+        let virtual_hosts: Vec<(Vec<String>, Vec<Upstream>)> = file
+            .virtual_hosts
+            .iter()
+            .map(|virtual_host| {
+                let upstreams = virtual_host
+                    .upstreams
+                    .iter()
+                    .map(|upstream| upstream.clone().into_upstream())
+                    .collect();
+                (virtual_host.hosts.clone(), upstreams)
+            })
+            .collect();
+        if default_upstreams.is_empty() && routes.is_empty() && virtual_hosts.is_empty() {
+            return Err(
+                "At least one upstream server must be specified using the --upstream option."
+                    .to_string(),
+            );
+        }
+        for (prefix, upstreams) in &routes {
+            if !prefix.starts_with('/') {
+                return Err(format!("Route prefix {} must start with a /", prefix));
+            }
+            if upstreams.is_empty() {
+                return Err(format!("Route {} has no upstreams", prefix));
+            }
+        }
+        for (hosts, upstreams) in &virtual_hosts {
+            if hosts.is_empty() {
+                return Err("Every virtual host needs at least one entry in hosts".to_string());
+            }
+            if upstreams.is_empty() {
+                return Err(format!(
+                    "Virtual host {} has no upstreams",
+                    hosts.join(", ")
+                ));
+            }
+        }
+        let canary: Option<(Vec<Upstream>, u8)> = file.canary.map(|canary| {
+            let upstreams = canary
+                .upstreams
+                .into_iter()
+                .map(FileUpstream::into_upstream)
+                .collect();
+            (upstreams, canary.percent)
+        });
+        if let Some((upstreams, percent)) = &canary {
+            if upstreams.is_empty() {
+                return Err("The canary has no upstreams".to_string());
+            }
+            if *percent > 100 {
+                return Err(format!("Canary percent {} is over 100", percent));
+            }
+            if default_upstreams.is_empty() {
+                return Err("A canary needs default upstreams to take requests from".to_string());
+            }
+        }
+        let all_upstreams = default_upstreams
+            .iter()
+            .chain(routes.iter().flat_map(|(_, upstreams)| upstreams))
+            .chain(virtual_hosts.iter().flat_map(|(_, upstreams)| upstreams))
+            .chain(canary.iter().flat_map(|(upstreams, _)| upstreams));
+        if let Some(upstream) = all_upstreams.clone().find(|upstream| upstream.weight == 0) {
+            return Err(format!(
+                "Upstream {} has a weight of 0; weights must be at least 1",
+                upstream.address
+            ));
+        }
+        // An upstream can serve several routes, but only gets one weight (the first one given)
+        let mut upstreams: Vec<Upstream> = Vec::new();
+        for upstream in all_upstreams {
+            if !upstreams
+                .iter()
+                .any(|other| other.address == upstream.address)
+            {
+                upstreams.push(upstream.clone());
+            }
+        }
+        let default_upstreams = dedup_upstreams(default_upstreams);
+        let routes = routes
+            .into_iter()
+            .map(|(prefix, upstreams)| Route {
+                prefix,
+                upstreams: dedup_upstreams(upstreams)
+                    .into_iter()
+                    .map(|upstream| upstream.address)
+                    .collect(),
+            })
+            .collect();
+        let virtual_hosts = virtual_hosts
+            .into_iter()
+            .map(|(hosts, upstreams)| VirtualHost {
+                hosts,
+                upstreams: dedup_upstreams(upstreams)
+                    .into_iter()
+                    .map(|upstream| upstream.address)
+                    .collect(),
+            })
+            .collect();
+        let canary = canary.map(|(upstreams, percent)| Canary {
+            upstreams: dedup_upstreams(upstreams)
+                .into_iter()
+                .map(|upstream| upstream.address)
+                .collect(),
+            percent,
+        });
+        if let Some(max_upstreams) = options.max_upstreams {
+            if upstreams.len() > max_upstreams {
+                return Err(format!(
+                    "{} upstreams were specified, but --max-upstreams is {}",
+                    upstreams.len(),
+                    max_upstreams
+                ));
+            }
+        }
+
+        let tls = match (options.tls_cert, options.tls_key) {
+            (Some(cert_path), Some(key_path)) => Some(TlsFiles {
+                cert_path,
+                key_path,
+            }),
+            (None, None) => None,
+            _ => return Err("--tls-cert and --tls-key must be given together".to_string()),
+        };
+        // A pooled connection's PROXY header names the client it was opened for, not whichever
+        // client picks it up next
+        if options.send_proxy_protocol && options.upstream_pool_max_idle > 0 {
+            return Err(
+                "--send-proxy-protocol can't be used with --upstream-pool-max-idle".to_string(),
+            );
+        }
+
+        let header_rules = HeaderRules {
+            request: build_header_rules(file.headers.request)?,
+            response: build_header_rules(file.headers.response)?,
+        };
+        let error_pages = ErrorPages::load(options.error_pages_dir.as_deref())?;
+
+        let max_requests_per_minute = options
+            .max_requests_per_minute
+            .or(file.rate_limit.max_requests_per_minute)
+            .unwrap_or(0);
+        let rate_limit_burst = options
+            .rate_limit_burst
+            .or(file.rate_limit.burst)
+            .unwrap_or(max_requests_per_minute);
+        let route_rate_limits = file
+            .rate_limit
+            .routes
+            .into_iter()
+            .map(|route| {
+                if !route.prefix.starts_with('/') {
+                    return Err(format!(
+                        "Rate limit prefix {} must start with a /",
+                        route.prefix
+                    ));
+                }
+                if route.max_requests_per_minute == 0 || route.burst == Some(0) {
+                    return Err(format!(
+                        "Rate limit for {} must allow at least one request",
+                        route.prefix
+                    ));
+                }
+                let limit = RequestLimit {
+                    max_requests: route.max_requests_per_minute,
+                    burst: route.burst.unwrap_or(route.max_requests_per_minute),
+                };
+                Ok((route.prefix.trim_end_matches('/').to_string(), limit))
+            })
+            .collect::<Result<_, String>>()?;
+        let auth_rules = AuthRules::new(
+            file.auth
+                .into_iter()
+                .map(build_auth_rule)
+                .collect::<Result<_, String>>()?,
+        );
+        if max_requests_per_minute > 0 && rate_limit_burst == 0 {
+            return Err("The rate limit burst must be at least 1".to_string());
+        }
+        if options.max_request_header_bytes == 0 || options.max_request_headers == 0 {
+            return Err(
+                "--max-request-header-bytes and --max-request-headers must be at least 1"
+                    .to_string(),
+            );
+        }
+        if options.workers == 0 {
+            return Err("--workers must be at least 1".to_string());
+        }
+        let binds = match (options.bind, file.bind) {
+            (binds, _) if !binds.is_empty() => binds,
+            (_, Some(FileBind::One(bind))) => vec![bind],
+            (_, Some(FileBind::Many(binds))) if !binds.is_empty() => binds,
+            (_, Some(FileBind::Many(_))) => {
+                return Err("bind in the config file can't be an empty list".to_string())
+            }
+            (_, None) => vec!["0.0.0.0:1100".to_string()],
+        };
+
+        let active_health_check_interval = options
+            .active_health_check_interval
+            .or(file.health_check.interval)
+            .unwrap_or(10);
+        Ok(Config {
+            binds,
+            workers: options.workers,
+            upstreams,
+            routes: RoutingTable::new(
+                default_upstreams
+                    .into_iter()
+                    .map(|upstream| upstream.address)
+                    .collect(),
+                routes,
+                virtual_hosts,
+                canary,
+            ),
+            strategy: options
+                .strategy
+                .or(file.strategy)
+                .unwrap_or(StrategyKind::Random),
+            sticky_sessions: options.sticky_sessions,
+            dns_refresh_interval: match options.dns_refresh_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            upstream_source_addr: options.upstream_source_addr,
+            upstream_ca_cert: options.upstream_ca_cert,
+            tls,
+            http2: options.http2,
+            trusted_proxies: options.trusted_proxy,
+            upstream_override_from: options.upstream_override_from,
+            access_list: AccessList {
+                allow: options.allow,
+                deny: options.deny,
+            },
+            deny_silently: options.deny_silently,
+            accept_proxy_protocol: options.accept_proxy_protocol,
+            send_proxy_protocol: options.send_proxy_protocol,
+            status_bind: options.status_bind,
+            metrics_bind: options.metrics_bind,
+            admin_bind: options.admin_bind,
+            access_log_format: options.access_log_format,
+            access_log_file: options.access_log_file,
+            stats_log_interval: match options.stats_log_secs {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            active_health_check_interval: match active_health_check_interval {
+                0 => None,
+                interval => Some(Duration::from_secs(interval as u64)),
+            },
+            health_webhook: options.health_webhook,
+            health_check,
+            health_checks,
+            max_requests_per_minute,
+            rate_limit_burst,
+            route_rate_limits,
+            rate_limit_redis: options.rate_limit_redis.or(file.rate_limit.redis),
+            max_bytes_per_minute_per_ip: options
+                .max_bytes_per_minute_per_ip
+                .or(file.rate_limit.max_bytes_per_minute_per_ip)
+                .unwrap_or(0),
+            rate_limit_window: Duration::from_secs(options.rate_limit_window_secs),
+            max_connections: options.max_connections,
+            max_queued_connections: options.max_queued_connections,
+            max_connections_per_ip: options.max_connections_per_ip,
+            slow_request_threshold: match options.slow_request_threshold_ms {
+                0 => None,
+                threshold_ms => Some(Duration::from_millis(threshold_ms)),
+            },
+            upstream_header_timeout: match options.upstream_header_timeout_ms {
+                0 => None,
+                timeout_ms => Some(Duration::from_millis(timeout_ms)),
+            },
+            upstream_connect_timeout: match options.upstream_connect_timeout_ms {
+                0 => None,
+                timeout_ms => Some(Duration::from_millis(timeout_ms)),
+            },
+            upstream_body_timeout: match options.upstream_body_timeout_ms {
+                0 => None,
+                timeout_ms => Some(Duration::from_millis(timeout_ms)),
+            },
+            request_timeout: match options.request_timeout_ms {
+                0 => None,
+                timeout_ms => Some(Duration::from_millis(timeout_ms)),
+            },
+            client_idle_timeout: match options.client_idle_timeout_ms {
+                0 => None,
+                timeout_ms => Some(Duration::from_millis(timeout_ms)),
+            },
+            client_header_timeout: match options.client_header_timeout_ms {
+                0 => None,
+                timeout_ms => Some(Duration::from_millis(timeout_ms)),
+            },
+            upstream_pool_max_idle: options.upstream_pool_max_idle,
+            upstream_pool_idle_timeout: Duration::from_secs(
+                options.upstream_pool_idle_timeout_secs,
+            ),
+            body_high_water_mark: options.body_high_water_mark,
+            max_body_size: options.max_body_size,
+            header_limits: HeaderLimits {
+                max_bytes: options.max_request_header_bytes,
+                max_headers: options.max_request_headers,
+            },
+            normalize_paths: options.normalize_paths,
+            reject_suspicious_paths: options.reject_suspicious_paths,
+            max_retries: options.max_retries,
+            circuit_breaker_failures: options.circuit_breaker_failures,
+            circuit_breaker_cooldown: Duration::from_secs(options.circuit_breaker_cooldown_secs),
+            circuit_breaker_slow_threshold: match options.circuit_breaker_slow_ms {
+                0 => None,
+                threshold_ms => Some(Duration::from_millis(threshold_ms)),
+            },
+            outlier_error_percent: options.outlier_error_percent,
+            outlier_window: Duration::from_secs(options.outlier_window_secs),
+            outlier_min_responses: options.outlier_min_responses,
+            outlier_ejection_time: Duration::from_secs(options.outlier_ejection_secs),
+            shadow_upstream: options.shadow_upstream,
+            shadow_percent: options.shadow_percent,
+            shutdown_drain_timeout: Duration::from_secs(options.shutdown_drain_timeout_secs),
+            cache_max_bytes: options.cache_max_bytes,
+            cache_ttl: Duration::from_secs(options.cache_ttl),
+            inject_before_body_end: options.inject_before_body_end,
+            inject_max_body_size: options.inject_max_body_size,
+            error_pages,
+            expose_timing_header: options.expose_timing_header,
+            compress: options.compress,
+            compress_types: if options.compress_type.is_empty() {
+                compression::DEFAULT_TYPES
+                    .iter()
+                    .map(|media_type| media_type.to_string())
+                    .collect()
+            } else {
+                options.compress_type
+            },
+            compress_min_size: options.compress_min_size,
+            header_rules,
+            auth_rules,
+        })
+    }
+}
+
+/// Checks an `[[auth]]` table: it has to protect a path and accept some credentials.
+fn build_auth_rule(auth: FileAuth) -> Result<AuthRule, String> {
+    if !auth.prefix.starts_with('/') {
+        return Err(format!("Auth prefix {} must start with a /", auth.prefix));
+    }
+    if auth.basic.is_empty() && auth.bearer_tokens.is_empty() {
+        return Err(format!(
+            "Auth for {} must accept some basic credentials or bearer tokens",
+            auth.prefix
+        ));
+    }
+    if auth
+        .basic
+        .iter()
+        .any(|credentials| !credentials.contains(':'))
+    {
+        return Err(format!(
+            "Basic credentials for {} must be user:password pairs",
+            auth.prefix
+        ));
+    }
+    Ok(AuthRule::new(
+        auth.prefix.trim_end_matches('/').to_string(),
+        auth.realm.unwrap_or_else(|| "balancebeam".to_string()),
+        &auth.basic,
+        auth.bearer_tokens,
+    ))
+}
+
+/// Checks that the config file's header rules name valid headers and values.
+fn build_header_rules(rules: Vec<FileHeaderRule>) -> Result<Vec<HeaderRule>, String> {
+    let header_name = |name: &str| {
+        http::header::HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("Invalid header name {:?} in the header rules", name))
+    };
+    let header_value = |value: &str| {
+        http::HeaderValue::from_str(value)
+            .map_err(|_| format!("Invalid header value {:?} in the header rules", value))
+    };
+    rules
+        .into_iter()
+        .map(|rule| {
+            Ok(match rule {
+                FileHeaderRule::Add { name, value } => {
+                    HeaderRule::Add(header_name(&name)?, header_value(&value)?)
+                }
+                FileHeaderRule::Replace { name, value } => {
+                    HeaderRule::Replace(header_name(&name)?, header_value(&value)?)
+                }
+                FileHeaderRule::Remove { name } => HeaderRule::Remove(header_name(&name)?),
+            })
+        })
+        .collect()
+}
+
+/// Parses a size in bytes, which may be given with a unit: KB, MB and GB go up in powers of 1000,
+/// and KiB, MiB and GiB in powers of 1024 (e.g. 10MB is 10000000 bytes). Units can be in any case.
+fn parse_byte_size(size: &str) -> Result<usize, String> {
+    let size = size.trim();
+    let digits = size
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(digits);
+    let number: usize = number
+        .parse()
+        .map_err(|_| format!("{:?} is not a size (e.g. 1048576, 512KB or 10MB)", size))?;
+    let multiplier: usize = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "kb" => 1_000,
+        "mb" => 1_000_000,
+        "gb" => 1_000_000_000,
+        "kib" => 1 << 10,
+        "mib" => 1 << 20,
+        "gib" => 1 << 30,
+        _ => {
+            return Err(format!(
+                "unknown unit {:?} (expected B, KB, MB, GB, KiB, MiB or GiB)",
+                unit.trim()
+            ))
+        }
+    };
+    number
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("{} is too big", size))
+}
+
+/// Parses a --route option (PREFIX=UPSTREAM[,UPSTREAM...]).
+fn parse_route_option(route: &str) -> Result<(String, Vec<Upstream>), String> {
+    let (prefix, upstreams) = route.split_once('=').ok_or_else(|| {
+        format!(
+            "--route {} should look like PREFIX=UPSTREAM[,UPSTREAM...]",
+            route
+        )
+    })?;
+    let upstreams = upstreams
+        .split(',')
+        .filter(|address| !address.is_empty())
+        .map(|address| Upstream {
+            address: address.to_string(),
+            weight: 1,
+        })
+        .collect();
+    Ok((prefix.to_string(), upstreams))
+}
+
+/// Removes repeated upstream addresses (which would otherwise get a bigger share of the load),
+/// keeping the first occurrence of each and warning about the rest.
+fn dedup_upstreams(upstreams: Vec<Upstream>) -> Vec<Upstream> {
+    let mut unique: Vec<Upstream> = Vec::with_capacity(upstreams.len());
+    for upstream in upstreams {
+        if unique.iter().any(|other| other.address == upstream.address) {
+            log::warn!("Ignoring duplicate upstream {}", upstream.address);
+        } else {
+            unique.push(upstream);
+        }
+    }
+    unique
+}
+
+/// Builds a health check from a `[health_check]` (or pool) table, starting from `base` for
+/// whatever the table leaves out. `name` says where the table is, for error messages.
+fn build_health_check(
+    base: &HealthCheck,
+    file: &FileHealthCheck,
+    name: &str,
+) -> Result<HealthCheck, String> {
+    let mut check = base.clone();
+    if let Some(path) = &file.path {
+        check.path = path.clone();
+    }
+    if let Some(expected_status) = &file.expected_status {
+        check.expected_statuses = health_check::parse_status_ranges(expected_status)
+            .map_err(|message| format!("Bad expected_status for {}: {}", name, message))?;
+    }
+    if let Some(text) = &file.body_contains {
+        check.body_contains = Some(text.clone());
+    }
+    if let Some(pattern) = &file.body_regex {
+        let regex =
+            Regex::new(pattern).map_err(|err| format!("Bad body_regex for {}: {}", name, err))?;
+        check.body_regex = Some(regex);
+    }
+    if let Some(host) = &file.host {
+        check.host = Some(host.clone());
+    }
+    if let Some(timeout_ms) = file.timeout_ms {
+        check.timeout = Some(Duration::from_millis(timeout_ms)).filter(|_| timeout_ms > 0);
+    }
+    Ok(check)
+}
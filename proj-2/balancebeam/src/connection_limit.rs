@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Caps how many connections each client IP can have open at once, so that one busy client can't
+/// use up all of balancebeam's file descriptors.
+pub struct ConnectionLimiter {
+    max_per_ip: usize,
+    /// Open connections for each client that has any
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(max_per_ip: usize) -> ConnectionLimiter {
+        ConnectionLimiter {
+            max_per_ip,
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn max_per_ip(&self) -> usize {
+        self.max_per_ip
+    }
+
+    /// Counts a new connection from `client_ip`, which lasts until the returned guard is dropped.
+    /// Returns None if the client is already at its limit.
+    pub fn try_acquire(self: &Arc<Self>, client_ip: IpAddr) -> Option<ClientConnectionGuard> {
+        let mut open = self.open.lock().unwrap();
+        let count = open.entry(client_ip).or_insert(0);
+        if *count >= self.max_per_ip {
+            return None;
+        }
+        *count += 1;
+        Some(ClientConnectionGuard {
+            limiter: Arc::clone(self),
+            client_ip,
+        })
+    }
+}
+
+/// Counts a connection against its client's limit until it is dropped.
+pub struct ClientConnectionGuard {
+    limiter: Arc<ConnectionLimiter>,
+    client_ip: IpAddr,
+}
+
+impl Drop for ClientConnectionGuard {
+    fn drop(&mut self) {
+        let mut open = self.limiter.open.lock().unwrap();
+        if let Some(count) = open.get_mut(&self.client_ip) {
+            *count -= 1;
+            // Forget clients once they have no connections, so that the map only holds active ones
+            if *count == 0 {
+                open.remove(&self.client_ip);
+            }
+        }
+    }
+}
@@ -0,0 +1,65 @@
+use crate::tls;
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// Whether `upstream`'s host is a name that has to be looked up, rather than an IP address (or a
+/// UNIX socket).
+pub fn is_hostname(upstream: &str) -> bool {
+    if upstream.starts_with("unix:") {
+        return false;
+    }
+    let (_, address) = tls::split_upstream_scheme(upstream);
+    let host = address.rsplitn(2, ':').last().unwrap_or(address);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    host.parse::<IpAddr>().is_err()
+}
+
+/// Looks up every address `upstream`'s hostname resolves to, and returns an upstream for each one,
+/// written like `upstream` but with the IP address in place of the name (so https://backend:443
+/// might give https://10.0.0.5:443 and https://10.0.0.6:443). They're sorted, so that lookups can
+/// be compared.
+async fn resolve(upstream: &str) -> std::io::Result<Vec<String>> {
+    let (_, address) = tls::split_upstream_scheme(upstream);
+    let scheme = &upstream[..upstream.len() - address.len()];
+    let mut resolved: Vec<String> = tokio::net::lookup_host(address)
+        .await?
+        .map(|addr| format!("{}{}", scheme, addr))
+        .collect();
+    resolved.sort();
+    resolved.dedup();
+    Ok(resolved)
+}
+
+/// Returns the upstreams that each of `upstreams` stands for: the addresses it resolves to if it's
+/// given by hostname, or just itself otherwise. A hostname that can't be looked up keeps the
+/// addresses it had in `previous` (or gets none, if it's new there), so that a DNS hiccup doesn't
+/// take it out of rotation.
+pub async fn resolve_all(
+    upstreams: &[String],
+    previous: &HashMap<String, Vec<String>>,
+) -> HashMap<String, Vec<String>> {
+    let mut members = HashMap::new();
+    for upstream in upstreams {
+        if !is_hostname(upstream) {
+            members.insert(upstream.clone(), vec![upstream.clone()]);
+            continue;
+        }
+        let old = previous.get(upstream);
+        let resolved = match resolve(upstream).await {
+            Ok(resolved) if !resolved.is_empty() => resolved,
+            Ok(_) => {
+                log::warn!("Upstream {} resolves to no addresses", upstream);
+                old.cloned().unwrap_or_default()
+            }
+            Err(err) => {
+                log::warn!("Could not look up upstream {}: {}", upstream, err);
+                old.cloned().unwrap_or_default()
+            }
+        };
+        if old != Some(&resolved) && !resolved.is_empty() {
+            log::info!("Upstream {} resolves to {}", upstream, resolved.join(", "));
+        }
+        members.insert(upstream.clone(), resolved);
+    }
+    members
+}
@@ -0,0 +1,171 @@
+use crate::body;
+use balancebeam::parse;
+
+/// Why a request couldn't be proxied, sorted by where things went wrong. The class decides how the
+/// client is answered, and how loudly the failure is logged: a client sending garbage is worth a
+/// note, but an upstream that can't be reached or speaks broken HTTP needs an operator's attention.
+///
+/// request::read_from_stream and response::read_from_stream fail with these, as does everything
+/// in main that talks to upstreams.
+#[derive(Debug)]
+pub enum ProxyError {
+    /// The client's request couldn't be used: it was malformed, over one of the limits, too slow
+    /// to arrive, or cut off partway through
+    Client(ClientError),
+    /// No upstream could be connected to: every one that was tried refused, or none were available
+    /// to try (all of them were dead, drained, ejected or had open circuits)
+    UpstreamConnect,
+    /// The request couldn't be sent to the upstream, or what came back isn't a usable response
+    UpstreamProtocol(UpstreamError),
+    /// An upstream took too long
+    Timeout(Timeout),
+}
+
+/// What was wrong with a client's request
+#[derive(Debug)]
+pub enum ClientError {
+    /// Client hung up before sending a complete request. IncompleteRequest contains the number of
+    /// bytes that were successfully read before the client hung up
+    IncompleteRequest(usize),
+    /// Client went quiet for longer than the idle timeout, or took longer than the header timeout
+    /// to send its headers. HeaderTimeout contains the number of bytes of headers that had
+    /// arrived by then (0 if the client never started a request)
+    HeaderTimeout(usize),
+    /// Client stopped sending its request body for longer than the idle timeout
+    BodyTimeout,
+    /// The request body is bigger than the maximum body size
+    RequestBodyTooLarge,
+    /// The request's headers are bigger, or more numerous, than the header limits allow
+    RequestHeadersTooLarge,
+    /// Client sent an invalid HTTP request. httparse::Error contains more details
+    MalformedRequest(httparse::Error),
+    /// Client sent a valid HTTP request whose URI can't be represented by an http::Uri
+    InvalidUri,
+    /// The Content-Length header is present, but does not contain a valid numeric value
+    InvalidContentLength,
+    /// The Transfer-Encoding header names a coding other than chunked last, so there's no telling
+    /// where the body ends
+    UnsupportedTransferEncoding,
+    /// The Content-Length header does not match the size of the request body that was sent
+    ContentLengthMismatch,
+    /// The request body uses chunked transfer encoding, but the chunks are malformed or the client
+    /// hung up before sending the last one
+    InvalidChunkedBody,
+    /// Encountered an I/O error when reading/writing a TcpStream
+    ConnectionError(std::io::Error),
+}
+
+/// What was wrong with an upstream's response (other than it being too slow; see Timeout)
+#[derive(Debug)]
+pub enum UpstreamError {
+    /// Upstream hung up before sending a complete set of headers. IncompleteResponse contains the
+    /// number of bytes that were successfully read before the upstream hung up (0 if the upstream
+    /// closed the connection without sending anything at all)
+    IncompleteResponse(usize),
+    /// Upstream sent an invalid HTTP response. httparse::Error contains more details
+    MalformedResponse(httparse::Error),
+    /// The Content-Length header is present, but does not contain a valid numeric value
+    InvalidContentLength,
+    /// The Content-Length header does not match the size of the response body that was sent
+    ContentLengthMismatch,
+    /// The response body uses chunked transfer encoding, but the chunks are malformed or the
+    /// upstream hung up before sending the last one
+    InvalidChunkedBody,
+    /// Encountered an I/O error when reading/writing a TcpStream
+    ConnectionError(std::io::Error),
+}
+
+/// What an upstream took too long to do
+#[derive(Debug)]
+pub enum Timeout {
+    /// Accept a connection, within --upstream-connect-timeout-ms
+    Connect,
+    /// Send its headers, within --upstream-header-timeout-ms. Contains the number of bytes of
+    /// headers that had arrived by then
+    Headers(usize),
+    /// Keep sending its response body, within --upstream-body-timeout-ms
+    Body,
+    /// Answer, within --request-timeout-ms
+    Request,
+}
+
+impl ProxyError {
+    /// The status to answer the client with, or None if there's no one left to answer (the
+    /// client's connection broke).
+    pub fn status(&self) -> Option<http::StatusCode> {
+        match self {
+            ProxyError::Client(error) => match error {
+                ClientError::ConnectionError(_) => None,
+                ClientError::HeaderTimeout(_) | ClientError::BodyTimeout => {
+                    Some(http::StatusCode::REQUEST_TIMEOUT)
+                }
+                ClientError::RequestBodyTooLarge => Some(http::StatusCode::PAYLOAD_TOO_LARGE),
+                ClientError::RequestHeadersTooLarge => {
+                    Some(http::StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+                }
+                ClientError::IncompleteRequest(_)
+                | ClientError::MalformedRequest(_)
+                | ClientError::InvalidUri
+                | ClientError::InvalidContentLength
+                | ClientError::UnsupportedTransferEncoding
+                | ClientError::ContentLengthMismatch
+                | ClientError::InvalidChunkedBody => Some(http::StatusCode::BAD_REQUEST),
+            },
+            ProxyError::UpstreamConnect | ProxyError::UpstreamProtocol(_) => {
+                Some(http::StatusCode::BAD_GATEWAY)
+            }
+            ProxyError::Timeout(_) => Some(http::StatusCode::GATEWAY_TIMEOUT),
+        }
+    }
+
+    /// How loudly to log the failure. Slow upstreams are warned about rather than logged as
+    /// errors, since they're often just busy.
+    pub fn log_level(&self) -> log::Level {
+        match self {
+            ProxyError::Client(_) => log::Level::Info,
+            ProxyError::UpstreamConnect | ProxyError::UpstreamProtocol(_) => log::Level::Error,
+            ProxyError::Timeout(_) => log::Level::Warn,
+        }
+    }
+}
+
+impl From<ClientError> for ProxyError {
+    fn from(error: ClientError) -> ProxyError {
+        ProxyError::Client(error)
+    }
+}
+
+impl From<UpstreamError> for ProxyError {
+    fn from(error: UpstreamError) -> ProxyError {
+        ProxyError::UpstreamProtocol(error)
+    }
+}
+
+impl From<parse::Error> for ClientError {
+    fn from(err: parse::Error) -> ClientError {
+        match err {
+            parse::Error::Malformed(err) => ClientError::MalformedRequest(err),
+            parse::Error::TooLarge => ClientError::RequestHeadersTooLarge,
+            parse::Error::InvalidUri => ClientError::InvalidUri,
+        }
+    }
+}
+
+/// For a request body streamed from the client (after the first part of it was read by
+/// request::read_from_stream)
+impl From<body::CopyError> for ClientError {
+    fn from(err: body::CopyError) -> ClientError {
+        match err {
+            body::CopyError::Read(err) if err.kind() == std::io::ErrorKind::TimedOut => {
+                ClientError::BodyTimeout
+            }
+            body::CopyError::Read(err) | body::CopyError::Write(err) => {
+                ClientError::ConnectionError(err)
+            }
+            body::CopyError::TooLarge => ClientError::RequestBodyTooLarge,
+            // (The body either ended early, or had broken chunk framing; both are a mismatch
+            // between what the client said it would send and what it sent)
+            body::CopyError::Malformed => ClientError::ContentLengthMismatch,
+        }
+    }
+}
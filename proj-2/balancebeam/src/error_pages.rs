@@ -0,0 +1,149 @@
+use crate::access_log::format_time;
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+/// The page sent for statuses that have no template of their own
+const BUILT_IN_PAGE: &str = "<!DOCTYPE html>
+<html>
+<head><title>{{status}} {{reason}}</title></head>
+<body>
+<h1>{{status}} {{reason}}</h1>
+<p>Request ID: {{request_id}}<br>Time: {{timestamp}}</p>
+</body>
+</html>
+";
+
+/// The pages balancebeam answers with when it can't (or won't) proxy a request. With
+/// --error-pages-dir, a file named after a status code (e.g. `502.html`) is used for that status;
+/// anything else gets a built-in page. Pages can contain these placeholders:
+///
+/// - `{{status}}`: the status code, e.g. 502
+/// - `{{reason}}`: its reason phrase, e.g. Bad Gateway
+/// - `{{request_id}}`: the request's ID, or `-` if the request couldn't be read
+/// - `{{timestamp}}`: when the page was sent, e.g. 2020-06-01T12:00:00Z
+#[derive(Debug, Default)]
+pub struct ErrorPages {
+    /// Templates read from the directory, by status code
+    templates: HashMap<u16, String>,
+}
+
+impl ErrorPages {
+    /// Reads the templates in `dir`, if there is one. Returns a message describing the problem if
+    /// the directory or one of its templates can't be read.
+    pub fn load(dir: Option<&str>) -> Result<ErrorPages, String> {
+        let dir = match dir {
+            Some(dir) => dir,
+            None => return Ok(ErrorPages::default()),
+        };
+        let entries = std::fs::read_dir(dir)
+            .map_err(|err| format!("Could not read --error-pages-dir {}: {}", dir, err))?;
+        let mut templates = HashMap::new();
+        for entry in entries {
+            let path = entry
+                .map_err(|err| format!("Could not read --error-pages-dir {}: {}", dir, err))?
+                .path();
+            let status = match (
+                path.file_stem().and_then(|stem| stem.to_str()),
+                path.extension().and_then(|extension| extension.to_str()),
+            ) {
+                (Some(stem), Some("html")) => match stem.parse::<u16>() {
+                    Ok(status) if (400..=599).contains(&status) => status,
+                    _ => continue,
+                },
+                _ => continue,
+            };
+            let template = std::fs::read_to_string(&path)
+                .map_err(|err| format!("Could not read error page {}: {}", path.display(), err))?;
+            templates.insert(status, template);
+        }
+        Ok(ErrorPages { templates })
+    }
+
+    /// Makes the response for an error, for the request with ID `request_id` (if it got that far).
+    pub fn response(
+        &self,
+        status: http::StatusCode,
+        request_id: Option<&str>,
+    ) -> http::Response<Vec<u8>> {
+        self.response_with_headers(status, request_id, &[])
+    }
+
+    /// Like response, with extra headers (e.g. Retry-After) added to the response.
+    pub fn response_with_headers(
+        &self,
+        status: http::StatusCode,
+        request_id: Option<&str>,
+        headers: &[(&'static str, String)],
+    ) -> http::Response<Vec<u8>> {
+        let template = self
+            .templates
+            .get(&status.as_u16())
+            .map_or(BUILT_IN_PAGE, String::as_str);
+        let body = render(template, |name| match name {
+            "status" => Some(status.as_u16().to_string()),
+            "reason" => Some(status.canonical_reason().unwrap_or("").to_string()),
+            "request_id" => Some(escape_html(request_id.unwrap_or("-"))),
+            "timestamp" => Some(format_time(SystemTime::now())),
+            _ => None,
+        })
+        .into_bytes();
+        let mut response = http::Response::builder()
+            .status(status)
+            .header("Content-Type", "text/html; charset=utf-8")
+            .header("Content-Length", body.len().to_string())
+            .version(http::Version::HTTP_11)
+            .body(body)
+            .unwrap();
+        for (name, value) in headers {
+            response
+                .headers_mut()
+                .append(*name, http::HeaderValue::from_str(value).unwrap());
+        }
+        response
+    }
+}
+
+/// Replaces each `{{name}}` in `template` with `value(name)`. Unknown placeholders are left as
+/// they are. (This is done in one pass, so that values never have placeholders replaced in them.)
+fn render<F>(template: &str, value: F) -> String
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        rendered.push_str(&rest[..start]);
+        let placeholder = &rest[start..];
+        match placeholder
+            .find("}}")
+            .and_then(|end| value(placeholder[2..end].trim()).map(|value| (value, end + 2)))
+        {
+            Some((value, length)) => {
+                rendered.push_str(&value);
+                rest = &placeholder[length..];
+            }
+            None => {
+                rendered.push_str("{{");
+                rest = &placeholder[2..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+/// Escapes text for use in HTML (request IDs can come from clients)
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,86 @@
+use crate::request;
+use std::net::IpAddr;
+
+/// The forwarding headers we set. Unless the client is a trusted proxy, any values the client
+/// sent for these are dropped, since they could say anything.
+const FORWARDING_HEADERS: [&str; 4] = [
+    "forwarded",
+    "x-forwarded-for",
+    "x-forwarded-proto",
+    "x-forwarded-port",
+];
+
+/// Tells the upstream who the client is and how it reached us: its IP address goes in
+/// X-Forwarded-For, the scheme and port it connected to in X-Forwarded-Proto and
+/// X-Forwarded-Port, and all of that (plus the Host it asked for) in a Forwarded header
+/// (RFC 7239).
+///
+/// If the client is a trusted proxy (`from_trusted_proxy`), it's passing along requests from
+/// further away, so we append to its X-Forwarded-For and Forwarded rather than replacing them,
+/// and leave its X-Forwarded-Proto and X-Forwarded-Port alone, since they describe the original
+/// client's connection rather than the proxy's.
+pub fn add_forwarding_headers(
+    request: &mut http::Request<Vec<u8>>,
+    client_ip: IpAddr,
+    from_trusted_proxy: bool,
+    proto: &str,
+    port: u16,
+) {
+    if !from_trusted_proxy {
+        for name in &FORWARDING_HEADERS {
+            request.headers_mut().remove(*name);
+        }
+    }
+
+    request::extend_header_value(request, "x-forwarded-for", &client_ip.to_string());
+    let headers = request.headers_mut();
+    if !headers.contains_key("x-forwarded-proto") {
+        headers.insert(
+            "x-forwarded-proto",
+            http::HeaderValue::from_str(proto).unwrap(),
+        );
+    }
+    if !headers.contains_key("x-forwarded-port") {
+        headers.insert("x-forwarded-port", http::HeaderValue::from(port));
+    }
+
+    let mut element = format!("for={};proto={}", forwarded_node(client_ip), proto);
+    if let Some(host) = request
+        .headers()
+        .get("host")
+        .and_then(|host| host.to_str().ok())
+    {
+        element += ";host=";
+        element += &forwarded_value(host);
+    }
+    request::extend_header_value(request, "forwarded", &element);
+}
+
+/// Formats `ip` as a Forwarded node: IPv6 addresses go in brackets, which have to be quoted.
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    }
+}
+
+/// Returns `value` as a token if it is one, or else as a quoted-string (RFC 7230 section 3.2.6).
+fn forwarded_value(value: &str) -> String {
+    let is_token = !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    if is_token {
+        return value.to_string();
+    }
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
@@ -0,0 +1,99 @@
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+
+/// A change to make to the headers of every proxied request or response
+#[derive(Debug)]
+pub enum HeaderRule {
+    /// Adds a value, keeping any the header already has
+    Add(HeaderName, HeaderValue),
+    /// Replaces any values the header already has (or adds it, if it has none)
+    Replace(HeaderName, HeaderValue),
+    Remove(HeaderName),
+}
+
+/// Header rules from the config file's `[headers]` section
+#[derive(Debug, Default)]
+pub struct HeaderRules {
+    /// Applied to requests just before they're forwarded to an upstream
+    pub request: Vec<HeaderRule>,
+    /// Applied to responses from upstreams (or the cache) just before they're sent to the client
+    pub response: Vec<HeaderRule>,
+}
+
+/// Applies `rules` to `headers`, in order.
+pub fn apply(rules: &[HeaderRule], headers: &mut HeaderMap) {
+    for rule in rules {
+        match rule {
+            HeaderRule::Add(name, value) => {
+                headers.append(name.clone(), value.clone());
+            }
+            HeaderRule::Replace(name, value) => {
+                headers.insert(name.clone(), value.clone());
+            }
+            HeaderRule::Remove(name) => {
+                headers.remove(name);
+            }
+        }
+    }
+}
+
+/// Headers that describe a single connection rather than the message, so they aren't passed on
+/// from one side of the proxy to the other (RFC 7230 section 6.1). Transfer-Encoding is one too,
+/// but bodies are passed on in the framing they arrived in, so it's left alone.
+pub const HOP_BY_HOP_HEADERS: [&str; 6] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "upgrade",
+];
+
+/// Returns the options listed in the Connection header(s), lowercased.
+pub fn connection_options(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all("connection")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|option| option.trim().to_ascii_lowercase())
+        .filter(|option| !option.is_empty())
+        .collect()
+}
+
+/// Returns true if a message with these headers leaves its connection open for another one after
+/// it: HTTP/1.1 connections persist unless Connection says close, while HTTP/1.0 ones close
+/// unless it says keep-alive.
+pub fn is_persistent(headers: &HeaderMap, version: http::Version) -> bool {
+    let options = connection_options(headers);
+    if options.iter().any(|option| option == "close") {
+        false
+    } else if version == http::Version::HTTP_10 {
+        options.iter().any(|option| option == "keep-alive")
+    } else {
+        true
+    }
+}
+
+/// Removes the hop-by-hop headers from a message that is about to be passed on, along with any
+/// other headers its Connection header names. Protocol upgrades (Connection: upgrade, with an
+/// Upgrade header) keep those two, since the upgrade is passed through to the other side.
+pub fn remove_hop_by_hop(headers: &mut HeaderMap) {
+    let options = connection_options(headers);
+    let upgrade = if options.iter().any(|option| option == "upgrade") {
+        headers.get("upgrade").cloned()
+    } else {
+        None
+    };
+    for option in &options {
+        if option != "transfer-encoding" {
+            headers.remove(option.as_str());
+        }
+    }
+    for name in &HOP_BY_HOP_HEADERS {
+        headers.remove(*name);
+    }
+    if let Some(upgrade) = upgrade {
+        headers.insert("connection", HeaderValue::from_static("upgrade"));
+        headers.insert("upgrade", upgrade);
+    }
+}
@@ -0,0 +1,93 @@
+use regex::Regex;
+use std::time::Duration;
+
+/// How much of a response body is read to match it against body_contains/body_regex
+pub const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// What an active health check requests from an upstream, and what it takes to pass. There's one
+/// for the default upstreams (from --active-health-check-path and the `[health_check]` table), and
+/// any pool of upstreams in the config file can have its own.
+#[derive(Debug, Clone)]
+pub struct HealthCheck {
+    pub path: String,
+    /// Inclusive ranges of the response statuses that pass (by default, just 200)
+    pub expected_statuses: Vec<(u16, u16)>,
+    /// Text the response body has to contain
+    pub body_contains: Option<String>,
+    /// A pattern the response body has to match
+    pub body_regex: Option<Regex>,
+    /// None = the upstream's own host:port
+    pub host: Option<String>,
+    /// How long the upstream has to answer (None = until the next check is due)
+    pub timeout: Option<Duration>,
+}
+
+impl HealthCheck {
+    /// The check that's made if nothing else is configured: GET `path`, expecting a 200.
+    pub fn new(path: String) -> HealthCheck {
+        HealthCheck {
+            path,
+            expected_statuses: vec![(200, 200)],
+            body_contains: None,
+            body_regex: None,
+            host: None,
+            timeout: None,
+        }
+    }
+
+    /// Whether the response body has to be read to judge the check
+    pub fn checks_body(&self) -> bool {
+        self.body_contains.is_some() || self.body_regex.is_some()
+    }
+
+    /// Returns a description of what's wrong with `response`, if it doesn't pass.
+    pub fn judge(&self, response: &http::Response<Vec<u8>>) -> Result<(), String> {
+        let status = response.status().as_u16();
+        if !self
+            .expected_statuses
+            .iter()
+            .any(|(low, high)| (*low..=*high).contains(&status))
+        {
+            return Err(format!(
+                "responded to GET {} with {}",
+                self.path,
+                response.status()
+            ));
+        }
+        let body = String::from_utf8_lossy(response.body());
+        if let Some(text) = &self.body_contains {
+            if !body.contains(text.as_str()) {
+                return Err(format!("response body doesn't contain {:?}", text));
+            }
+        }
+        if let Some(regex) = &self.body_regex {
+            if !regex.is_match(&body) {
+                return Err(format!("response body doesn't match /{}/", regex));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Parses a list of expected statuses, like "200-299,301": comma-separated codes and inclusive
+/// ranges of codes.
+pub fn parse_status_ranges(list: &str) -> Result<Vec<(u16, u16)>, String> {
+    let parse_status = |status: &str| match status.trim().parse::<u16>() {
+        Ok(status) if (100..=599).contains(&status) => Ok(status),
+        _ => Err(format!("{:?} is not an HTTP status code", status.trim())),
+    };
+    let ranges = list
+        .split(',')
+        .map(|item| match item.split_once('-') {
+            Some((low, high)) => {
+                let (low, high) = (parse_status(low)?, parse_status(high)?);
+                if low > high {
+                    return Err(format!("Status range {} is backwards", item.trim()));
+                }
+                Ok((low, high))
+            }
+            None => parse_status(item).map(|status| (status, status)),
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    Ok(ranges)
+}
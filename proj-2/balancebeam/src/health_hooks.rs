@@ -0,0 +1,164 @@
+use crate::access_log::{format_time, json_string};
+use crate::stats::Stats;
+use crate::{open_connection, request, response};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc;
+use tokio_rustls::TlsConnector;
+
+/// How long the webhook gets to take each event, connecting included
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most events that can be waiting to be sent to the webhook. Events that come along while this
+/// many are waiting (because the webhook is slow or down) are dropped.
+const WEBHOOK_QUEUE_SIZE: usize = 100;
+
+/// Lets people know when an upstream is marked dead or comes back to life, so that backends that
+/// keep flapping get noticed: each change is logged as a warning and counted in the metrics, and,
+/// with --health-webhook, POSTed to the webhook as JSON, like
+/// `{"upstream":"10.0.0.1:80","state":"dead","reason":"...","time":"2020-06-01T12:00:00Z"}`.
+pub struct HealthHooks {
+    stats: Arc<Stats>,
+    /// Events waiting for the webhook task to send them, in the order they happened
+    webhook: Option<mpsc::Sender<String>>,
+}
+
+impl HealthHooks {
+    /// Starts a task to send events to `webhook_url` (an http:// or https:// URL), if there is
+    /// one. Returns a message describing the problem if the URL can't be used.
+    pub fn new(
+        stats: Arc<Stats>,
+        webhook_url: Option<&str>,
+        tls_connector: TlsConnector,
+    ) -> Result<HealthHooks, String> {
+        let webhook = match webhook_url {
+            Some(url) => {
+                let webhook = Webhook::parse(url, tls_connector)?;
+                let (sender, receiver) = mpsc::channel(WEBHOOK_QUEUE_SIZE);
+                tokio::spawn(webhook.run(receiver));
+                Some(sender)
+            }
+            None => None,
+        };
+        Ok(HealthHooks { stats, webhook })
+    }
+
+    /// Reports that `upstream` was marked dead (or alive, if `alive`) because of `reason`.
+    pub fn upstream_changed(&self, upstream: &str, alive: bool, reason: &str) {
+        let state = if alive { "alive" } else { "dead" };
+        log::warn!("Marked upstream {} as {}: {}", upstream, state, reason);
+        self.stats.record_health_transition(upstream, alive);
+        if let Some(webhook) = &self.webhook {
+            let event = format!(
+                "{{\"upstream\":{},\"state\":\"{}\",\"reason\":{},\"time\":\"{}\"}}",
+                json_string(Some(upstream)),
+                state,
+                json_string(Some(reason)),
+                format_time(SystemTime::now())
+            );
+            if webhook.clone().try_send(event).is_err() {
+                log::warn!(
+                    "Not sending upstream {} going {} to --health-webhook: too many events are \
+                    waiting on it",
+                    upstream,
+                    state
+                );
+            }
+        }
+    }
+}
+
+/// Where health events are POSTed
+struct Webhook {
+    /// The webhook's host:port, as an upstream address (with https:// if it's to be used)
+    upstream: String,
+    /// Host header to send
+    host: String,
+    /// Path (and query) to POST to
+    path: String,
+    tls_connector: TlsConnector,
+}
+
+impl Webhook {
+    fn parse(url: &str, tls_connector: TlsConnector) -> Result<Webhook, String> {
+        let uri: http::Uri = url
+            .parse()
+            .map_err(|_| format!("--health-webhook {} is not a URL", url))?;
+        let https = match uri.scheme_str() {
+            Some("http") => false,
+            Some("https") => true,
+            _ => {
+                return Err(format!(
+                    "--health-webhook {} should start with http:// or https://",
+                    url
+                ))
+            }
+        };
+        let authority = uri
+            .authority()
+            .ok_or_else(|| format!("--health-webhook {} has no host", url))?;
+        let port = authority.port_u16().unwrap_or(if https { 443 } else { 80 });
+        let host_port = format!("{}:{}", authority.host(), port);
+        Ok(Webhook {
+            upstream: if https {
+                format!("https://{}", host_port)
+            } else {
+                host_port
+            },
+            host: authority.as_str().to_string(),
+            path: uri
+                .path_and_query()
+                .map_or("/", |path_and_query| path_and_query.as_str())
+                .to_string(),
+            tls_connector,
+        })
+    }
+
+    /// Sends events as they arrive, one at a time (so the webhook sees them in order), until
+    /// there won't be any more.
+    async fn run(self, mut events: mpsc::Receiver<String>) {
+        while let Some(event) = events.recv().await {
+            match tokio::time::timeout(WEBHOOK_TIMEOUT, self.post(event)).await {
+                Ok(Ok(status)) if status.is_success() => {}
+                Ok(Ok(status)) => log::warn!("--health-webhook answered an event with {}", status),
+                Ok(Err(message)) => {
+                    log::warn!("Could not send an event to --health-webhook: {}", message)
+                }
+                Err(_) => log::warn!(
+                    "--health-webhook did not take an event within {}s",
+                    WEBHOOK_TIMEOUT.as_secs()
+                ),
+            }
+        }
+    }
+
+    async fn post(&self, event: String) -> Result<http::StatusCode, String> {
+        let mut stream = open_connection(
+            &self.upstream,
+            &self.upstream,
+            None,
+            &self.tls_connector,
+            None,
+            None,
+        )
+        .await
+        .map_err(|err| format!("could not connect: {}", err))?;
+        let request = http::Request::builder()
+            .method(http::Method::POST)
+            .uri(&self.path)
+            .header("Host", &self.host)
+            .header("Content-Type", "application/json")
+            .header("Content-Length", event.len())
+            .header("Connection", "close")
+            .body(event.into_bytes())
+            .unwrap();
+        request::write_to_stream(&request, &mut stream)
+            .await
+            .map_err(|err| format!("could not send request: {}", err))?;
+        let (response, _) =
+            response::read_from_stream(&mut stream, request.method(), None, None, 0)
+                .await
+                .map_err(|err| format!("could not read response: {:?}", err))?;
+        Ok(response.status())
+    }
+}
@@ -0,0 +1,503 @@
+use crate::shutdown::Shutdown;
+use crate::splice::Splice;
+use crate::{body, chunked, headers, request, response, ProxyState};
+use bytes::Bytes;
+use h2::server::SendResponse;
+use h2::{Reason, RecvStream, SendStream};
+use std::net::SocketAddr;
+use std::os::unix::io::RawFd;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc, RwLock};
+
+/// What an HTTP/2 client sends first on a connection when it knows the server speaks HTTP/2
+/// without being told (RFC 7540, section 3.5)
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+/// How many pieces of a message can be waiting in a pipe before the side writing to it has to wait
+const PIPE_CAPACITY: usize = 16;
+
+/// Reads the start of a plain HTTP connection to see whether the client is opening with the
+/// HTTP/2 preface. Bytes are only read for as long as they match it, so an HTTP/1.x request
+/// costs a byte or two; whatever was read is handed back through the returned stream either way.
+pub async fn detect_preface<S>(mut stream: S) -> std::io::Result<(bool, Rewind<S>)>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut read = Vec::with_capacity(PREFACE.len());
+    while read.len() < PREFACE.len() {
+        let mut byte = [0_u8; 1];
+        if stream.read(&mut byte).await? == 0 {
+            break;
+        }
+        read.push(byte[0]);
+        if byte[0] != PREFACE[read.len() - 1] {
+            break;
+        }
+    }
+    Ok((read == PREFACE, Rewind::new(read, stream)))
+}
+
+/// A stream that gives back some bytes that were already read from it before reading any more
+/// (more can be given back later with give_back)
+pub struct Rewind<S> {
+    prefix: Vec<u8>,
+    pos: usize,
+    inner: S,
+}
+
+impl<S> Rewind<S> {
+    pub fn new(prefix: Vec<u8>, inner: S) -> Rewind<S> {
+        Rewind {
+            prefix,
+            pos: 0,
+            inner,
+        }
+    }
+
+    /// Has `bytes` read again, ahead of anything else.
+    pub fn give_back(&mut self, mut bytes: Vec<u8>) {
+        if bytes.is_empty() {
+            return;
+        }
+        bytes.extend_from_slice(&self.prefix[self.pos..]);
+        self.prefix = bytes;
+        self.pos = 0;
+    }
+}
+
+impl<S: Splice> Splice for Rewind<S> {
+    fn socket(&self) -> Option<RawFd> {
+        // (Until the bytes already read have been given back, they'd be skipped)
+        if self.pos < self.prefix.len() {
+            return None;
+        }
+        self.inner.socket()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Rewind<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.pos < this.prefix.len() {
+            let len = buf.len().min(this.prefix.len() - this.pos);
+            buf[..len].copy_from_slice(&this.prefix[this.pos..this.pos + len]);
+            this.pos += len;
+            return Poll::Ready(Ok(len));
+        }
+        Pin::new(&mut this.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Rewind<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Proxies requests from an HTTP/2 client connection. Every stream the client opens is handled in
+/// a task of its own, by handle_connection talking HTTP/1.1 over in-memory pipes: the request is
+/// written into one as HTTP/1.1, and the response handle_connection writes into the other is read
+/// back and sent on the stream. Everything handle_connection does for HTTP/1.x clients (routing,
+/// limits, caching, upstream connection reuse and so on) applies to each stream the same way.
+///
+/// When `shutdown` fires, the client is told (with a GOAWAY) not to open any more streams, and this
+/// returns once the ones already open are done.
+pub async fn serve<S>(
+    stream: S,
+    client_addr: SocketAddr,
+    local_addr: SocketAddr,
+    state: Arc<RwLock<ProxyState>>,
+    mut shutdown: Shutdown,
+) where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let client_ip = client_addr.ip().to_string();
+    let mut connection = match h2::server::handshake(stream).await {
+        Ok(connection) => connection,
+        Err(err) => {
+            log::info!("HTTP/2 handshake with {} failed: {}", client_ip, err);
+            return;
+        }
+    };
+    log::debug!("Speaking HTTP/2 with {}", client_ip);
+    // Each stream's task holds a clone of streams_sender, so streams_receiver sees the channel
+    // close once they have all finished
+    let (streams_sender, mut streams_receiver) = mpsc::channel::<()>(1);
+    let mut draining = false;
+    loop {
+        let next = tokio::select! {
+            next = connection.accept() => next,
+            _ = shutdown.recv(), if !draining => {
+                log::debug!("Shutting down; no more HTTP/2 streams from {}", client_ip);
+                connection.graceful_shutdown();
+                draining = true;
+                continue;
+            }
+        };
+        match next {
+            Some(Ok((request, respond))) => {
+                let state = Arc::clone(&state);
+                let streams_sender = streams_sender.clone();
+                tokio::spawn(async move {
+                    let _streams_sender = streams_sender;
+                    serve_stream(request, respond, client_addr, local_addr, state).await;
+                });
+            }
+            Some(Err(err)) => {
+                log::debug!("HTTP/2 connection from {} failed: {}", client_ip, err);
+                break;
+            }
+            None => break,
+        }
+    }
+    drop(streams_sender);
+    let _ = streams_receiver.recv().await;
+}
+
+/// Proxies the request on one HTTP/2 stream.
+async fn serve_stream(
+    request: http::Request<RecvStream>,
+    respond: SendResponse<Bytes>,
+    client_addr: SocketAddr,
+    local_addr: SocketAddr,
+    state: Arc<RwLock<ProxyState>>,
+) {
+    let high_water = state.read().await.body_high_water_mark;
+    let method = request.method().clone();
+    let (request_writer, request_reader) = pipe();
+    let (response_writer, response_reader) = pipe();
+    // The stream's request has to be answered even if balancebeam starts shutting down meanwhile
+    // (serve takes care of refusing new streams), so handle_connection gets a Shutdown that never
+    // fires
+    let (_never_shut_down, shutdown) = broadcast::channel(1);
+    let exchange = Exchange {
+        request: request_reader,
+        response: response_writer,
+    };
+    let proxy = crate::handle_connection(
+        exchange,
+        client_addr,
+        local_addr,
+        state,
+        Shutdown::new(shutdown),
+    );
+    let forward = async {
+        if let Err(message) = forward_request(request, request_writer).await {
+            log::debug!(
+                "Could not read HTTP/2 request from {}: {}",
+                client_addr.ip(),
+                message
+            );
+        }
+    };
+    let answer = async {
+        if let Err(message) = send_response(response_reader, &method, respond, high_water).await {
+            log::debug!(
+                "Could not send HTTP/2 response to {}: {}",
+                client_addr.ip(),
+                message
+            );
+        }
+    };
+    tokio::join!(proxy, forward, answer);
+}
+
+/// Writes `request` to `to` as HTTP/1.1, streaming its body as the client sends it. A body of
+/// unknown length is sent chunked.
+async fn forward_request(
+    request: http::Request<RecvStream>,
+    mut to: PipeWriter,
+) -> Result<(), String> {
+    let (parts, mut body) = request.into_parts();
+    let mut head = http::Request::new(Vec::new());
+    *head.method_mut() = parts.method;
+    *head.version_mut() = http::Version::HTTP_11;
+    let target = match (parts.uri.path_and_query(), parts.uri.authority()) {
+        (Some(path_and_query), _) => path_and_query.as_str().to_string(),
+        // (CONNECT requests name only the authority)
+        (None, Some(authority)) => authority.as_str().to_string(),
+        (None, None) => String::from("/"),
+    };
+    *head.uri_mut() = target.parse().map_err(|_| "invalid :path")?;
+    *head.headers_mut() = parts.headers;
+    let headers = head.headers_mut();
+    if !headers.contains_key(http::header::HOST) {
+        if let Some(authority) = parts.uri.authority() {
+            let host = http::HeaderValue::from_str(authority.as_str()).unwrap();
+            headers.insert(http::header::HOST, host);
+        }
+    }
+    // HTTP/2 clients may split cookies over several headers, which HTTP/1.1 doesn't allow
+    let cookies: Vec<&[u8]> = headers
+        .get_all(http::header::COOKIE)
+        .iter()
+        .map(|value| value.as_bytes())
+        .collect();
+    if cookies.len() > 1 {
+        let joined = http::HeaderValue::from_bytes(&cookies.join(&b"; "[..])).unwrap();
+        headers.insert(http::header::COOKIE, joined);
+    }
+    let chunked = !body.is_end_stream() && !headers.contains_key(http::header::CONTENT_LENGTH);
+    if chunked {
+        headers.insert(
+            http::header::TRANSFER_ENCODING,
+            http::HeaderValue::from_static("chunked"),
+        );
+    }
+    request::write_to_stream(&head, &mut to)
+        .await
+        .map_err(|err| err.to_string())?;
+    while let Some(data) = body.data().await {
+        let data = data.map_err(|err| err.to_string())?;
+        let _ = body.flow_control().release_capacity(data.len());
+        // (An empty chunk would mark the end of the body)
+        if data.is_empty() {
+            continue;
+        }
+        let written = if chunked {
+            to.write_all(&chunked::frame_chunk(&data)).await
+        } else {
+            to.write_all(&data).await
+        };
+        written.map_err(|err| err.to_string())?;
+    }
+    if chunked {
+        to.write_all(b"0\r\n\r\n")
+            .await
+            .map_err(|err| err.to_string())?;
+    }
+    Ok(())
+}
+
+/// Reads the HTTP/1.1 response handle_connection wrote to `from`, and sends it on the stream. If
+/// there's no usable response, the stream is reset instead.
+async fn send_response(
+    mut from: PipeReader,
+    request_method: &http::Method,
+    mut respond: SendResponse<Bytes>,
+    high_water: usize,
+) -> Result<(), String> {
+    let (mut response, unread) =
+        match response::read_from_stream(&mut from, request_method, None, None, high_water).await {
+            Ok((response, _)) if response.status() == http::StatusCode::SWITCHING_PROTOCOLS => {
+                respond.send_reset(Reason::PROTOCOL_ERROR);
+                return Err(String::from(
+                    "protocol upgrades aren't possible over HTTP/2",
+                ));
+            }
+            Ok(exchange) => exchange,
+            Err(err) => {
+                respond.send_reset(Reason::INTERNAL_ERROR);
+                return Err(format!("no response: {:?}", err));
+            }
+        };
+    let mut body = std::mem::take(response.body_mut());
+    let unread = unread.map(|unread| body::unchunk(response.headers_mut(), &mut body, unread));
+    // HTTP/2 has no connection-specific headers (and h2 refuses to send them)
+    for name in headers::HOP_BY_HOP_HEADERS
+        .iter()
+        .chain(&["transfer-encoding"])
+    {
+        response.headers_mut().remove(*name);
+    }
+    *response.version_mut() = http::Version::HTTP_2;
+    let end_of_stream = body.is_empty() && unread.is_none();
+    let send = respond
+        .send_response(response.map(|_| ()), end_of_stream)
+        .map_err(|err| err.to_string())?;
+    if end_of_stream {
+        return Ok(());
+    }
+    let mut to = ResponseBody { send };
+    to.write_all(&body).await.map_err(|err| err.to_string())?;
+    if let Some(unread) = unread {
+        body::copy(unread, &mut from, &mut to)
+            .await
+            .map_err(|err| format!("{:?}", err))?;
+    }
+    to.shutdown().await.map_err(|err| err.to_string())
+}
+
+/// One end of an in-memory byte stream (the other being a PipeReader)
+struct PipeWriter {
+    sender: mpsc::Sender<Vec<u8>>,
+}
+
+struct PipeReader {
+    receiver: mpsc::Receiver<Vec<u8>>,
+    /// The piece being read, and how much of it has been
+    piece: Vec<u8>,
+    pos: usize,
+}
+
+fn pipe() -> (PipeWriter, PipeReader) {
+    let (sender, receiver) = mpsc::channel(PIPE_CAPACITY);
+    let reader = PipeReader {
+        receiver,
+        piece: Vec::new(),
+        pos: 0,
+    };
+    (PipeWriter { sender }, reader)
+}
+
+impl AsyncRead for PipeReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        while this.pos == this.piece.len() {
+            match this.receiver.poll_recv(cx) {
+                Poll::Ready(Some(piece)) => {
+                    this.piece = piece;
+                    this.pos = 0;
+                }
+                // The writer is gone: end of stream
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let len = buf.len().min(this.piece.len() - this.pos);
+        buf[..len].copy_from_slice(&this.piece[this.pos..this.pos + len]);
+        this.pos += len;
+        Poll::Ready(Ok(len))
+    }
+}
+
+impl AsyncWrite for PipeWriter {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        // (The reader having gone away looks like a broken pipe, as it would with a socket)
+        match this.sender.poll_ready(cx) {
+            Poll::Ready(Ok(())) => {}
+            Poll::Ready(Err(_)) => return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into())),
+            Poll::Pending => return Poll::Pending,
+        }
+        this.sender
+            .try_send(buf.to_vec())
+            .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// The stream handle_connection is given for an HTTP/2 stream: it reads the request from one pipe
+/// and writes the response to another
+struct Exchange {
+    request: PipeReader,
+    response: PipeWriter,
+}
+
+impl Splice for Exchange {
+    fn socket(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+impl AsyncRead for Exchange {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().request).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for Exchange {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().response).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().response).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().response).poll_shutdown(cx)
+    }
+}
+
+/// Writes a response body to an HTTP/2 stream, waiting for the client to give the stream flow
+/// control capacity as needed
+struct ResponseBody {
+    send: SendStream<Bytes>,
+}
+
+impl AsyncWrite for ResponseBody {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        this.send.reserve_capacity(buf.len());
+        while this.send.capacity() == 0 {
+            match this.send.poll_capacity(cx) {
+                Poll::Ready(Some(Ok(_))) => {}
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Err(to_io_error(err))),
+                Poll::Ready(None) => {
+                    return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into()))
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let len = buf.len().min(this.send.capacity());
+        this.send
+            .send_data(Bytes::copy_from_slice(&buf[..len]), false)
+            .map_err(to_io_error)?;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    /// Ends the stream
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let result = self.get_mut().send.send_data(Bytes::new(), true);
+        Poll::Ready(result.map_err(to_io_error))
+    }
+}
+
+fn to_io_error(err: h2::Error) -> std::io::Error {
+    std::io::Error::other(err)
+}
@@ -0,0 +1,4 @@
+//! The parts of balancebeam that work on plain bytes, without a runtime or sockets, so that they
+//! can be used (and fuzzed) on their own. See fuzz/ for the fuzz targets.
+
+pub mod parse;
@@ -0,0 +1,199 @@
+use crate::splice::Splice;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream, UnixListener, UnixStream};
+
+/// Stands in for the address of clients (and of our end of their connections) on a UNIX socket,
+/// which have none. A PROXY protocol header can still say who the client really is.
+const UNIX_CLIENT_ADDR: ([u8; 4], u16) = ([127, 0, 0, 1], 0);
+
+/// Where client connections come in: a TCP port, or a UNIX socket (given to --bind as unix:PATH)
+pub enum Listener {
+    Tcp(TcpListener),
+    /// The socket file is removed again when the listener is dropped
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Binds to `address`, which is either an IP/port or unix: followed by the path of a socket to
+    /// create. A socket file left behind by an earlier run is replaced.
+    pub async fn bind(address: &str) -> std::io::Result<Listener> {
+        match address.strip_prefix("unix:") {
+            Some(path) => {
+                let is_socket = std::fs::symlink_metadata(path)
+                    .is_ok_and(|metadata| metadata.file_type().is_socket());
+                if is_socket {
+                    std::fs::remove_file(path)?;
+                }
+                Ok(Listener::Unix(
+                    UnixListener::bind(path)?,
+                    PathBuf::from(path),
+                ))
+            }
+            None => Ok(Listener::Tcp(TcpListener::bind(address).await?)),
+        }
+    }
+
+    /// Binds `count` listeners to the same IP/port with SO_REUSEPORT, so that the kernel spreads new
+    /// connections between them (one for each of the --workers). If `address` asks for port 0,
+    /// they all get the port the first one was given.
+    pub fn bind_reuse_port(address: &str, count: usize) -> std::io::Result<Vec<Listener>> {
+        let mut addr = address.to_socket_addrs()?.next().ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::AddrNotAvailable, "no addresses found")
+        })?;
+        let mut listeners = Vec::with_capacity(count);
+        for _ in 0..count {
+            let domain = if addr.is_ipv4() {
+                Domain::ipv4()
+            } else {
+                Domain::ipv6()
+            };
+            let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+            socket.set_reuse_address(true)?;
+            socket.set_reuse_port(true)?;
+            socket.bind(&addr.into())?;
+            // (The same backlog tokio's TcpListener::bind uses)
+            socket.listen(1024)?;
+            let listener = TcpListener::from_std(socket.into_tcp_listener())?;
+            addr = listener.local_addr()?;
+            listeners.push(Listener::Tcp(listener));
+        }
+        Ok(listeners)
+    }
+
+    /// Describes where the listener is bound, e.g. 127.0.0.1:1100 or unix:/run/balancebeam.sock.
+    /// (For TCP, this gives the port that was actually bound, if port 0 was asked for.)
+    pub fn local_description(&self) -> String {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr().unwrap().to_string(),
+            Listener::Unix(_, path) => format!("unix:{}", path.display()),
+        }
+    }
+
+    fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<ClientStream>> {
+        match self {
+            Listener::Tcp(listener) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, _)| ClientStream::Tcp(stream)),
+            Listener::Unix(listener, _) => listener
+                .poll_accept(cx)
+                .map_ok(|(stream, _)| ClientStream::Unix(stream)),
+        }
+    }
+}
+
+/// Every listener given with --bind, accepted from as one
+pub struct Listeners {
+    listeners: Vec<Listener>,
+    /// Which listener gets to accept first next time, so that a busy one can't starve the others
+    next: usize,
+}
+
+impl Listeners {
+    pub fn new(listeners: Vec<Listener>) -> Listeners {
+        Listeners { listeners, next: 0 }
+    }
+
+    /// Accepts the next client connection to come in on any of the listeners.
+    pub async fn accept(&mut self) -> std::io::Result<ClientStream> {
+        std::future::poll_fn(|cx| {
+            let count = self.listeners.len();
+            for i in 0..count {
+                let index = (self.next + i) % count;
+                if let Poll::Ready(result) = self.listeners[index].poll_accept(cx) {
+                    self.next = (index + 1) % count;
+                    return Poll::Ready(result);
+                }
+            }
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Listener::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// A client connection, accepted by a Listener
+pub enum ClientStream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl ClientStream {
+    /// Returns the client's address and the address it connected to. Clients on a UNIX socket
+    /// get UNIX_CLIENT_ADDR for both.
+    pub fn addrs(&self) -> std::io::Result<(SocketAddr, SocketAddr)> {
+        match self {
+            ClientStream::Tcp(stream) => Ok((stream.peer_addr()?, stream.local_addr()?)),
+            ClientStream::Unix(_) => Ok((UNIX_CLIENT_ADDR.into(), UNIX_CLIENT_ADDR.into())),
+        }
+    }
+}
+
+impl Splice for ClientStream {
+    fn socket(&self) -> Option<RawFd> {
+        match self {
+            ClientStream::Tcp(stream) => Some(stream.as_raw_fd()),
+            ClientStream::Unix(stream) => Some(stream.as_raw_fd()),
+        }
+    }
+}
+
+/// TLS streams encrypt what goes through them, so can't be spliced
+impl Splice for tokio_rustls::server::TlsStream<ClientStream> {
+    fn socket(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+impl AsyncRead for ClientStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for ClientStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            ClientStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            ClientStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
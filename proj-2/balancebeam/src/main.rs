@@ -1,41 +1,88 @@
+mod access_log;
+mod admin;
+mod auth;
+mod body;
+mod cache;
+mod chunked;
+mod cidr;
+mod circuit_breaker;
+mod compression;
+mod config;
+mod connection_limit;
+mod dns;
+mod error;
+mod error_pages;
+mod forwarded;
+mod headers;
+mod health_check;
+mod health_hooks;
+mod http2;
+mod listener;
+mod normalize;
+mod outlier;
+mod pool;
+mod proxy_protocol;
+mod rate_limit;
+mod redis_store;
 mod request;
+mod request_id;
 mod response;
+mod routing;
+mod shadow;
+mod shutdown;
+mod splice;
+mod stats;
+mod status;
+mod sticky;
+mod strategy;
+mod timeout;
+mod tls;
+mod tunnel;
+mod upstream_override;
 
+use access_log::AccessLog;
+use auth::AuthRules;
+use body::Unread;
+use cache::ResponseCache;
+use cidr::{AccessList, Cidr};
+use circuit_breaker::CircuitBreaker;
 use clap::Parser;
-use rand::{Rng, SeedableRng};
+use compression::Compressor;
+use config::{CmdOptions, Config, Upstream};
+use connection_limit::ConnectionLimiter;
+use error::{ClientError, ProxyError, Timeout, UpstreamError};
+use error_pages::ErrorPages;
+use headers::HeaderRules;
+use health_check::HealthCheck;
+use health_hooks::HealthHooks;
+use listener::{ClientStream, Listener, Listeners};
+use outlier::OutlierDetector;
+use pool::ConnectionPool;
+use rate_limit::{ByteLimiter, MemoryStore, RateLimitStore, RequestLimits};
+use redis_store::RedisStore;
+use request::HeaderLimits;
+use routing::RoutingTable;
+use shadow::Shadow;
+use shutdown::Shutdown;
+use splice::Splice;
+use stats::{QueuedGuard, Stats};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::AtomicUsize;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use strategy::{
+    LatencyEwma, LoadBalancingStrategy, UpstreamConnectionGuard, UpstreamInfo, UpstreamInfoMap,
+};
+use tls::UpstreamStream;
 use tokio::{
-    net::{TcpListener, TcpStream},
-    stream::StreamExt,
-    sync::RwLock,
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UnixStream},
+    signal::unix::{signal, SignalKind},
+    sync::{broadcast, mpsc, OwnedSemaphorePermit, RwLock, Semaphore},
 };
-
-/// Contains information parsed from the command-line invocation of balancebeam. The Clap macros
-/// provide a fancy way to automatically construct a command-line argument parser.
-#[derive(Parser, Debug)]
-struct CmdOptions {
-    /// IP/port to bind to
-    #[clap(
-        short,
-        long,
-        // 表示本机上所有的ipv4地址，在1100端口上监听
-        // 之后用浏览器请求localhost:1100就会把http请求发到该进程
-        default_value = "0.0.0.0:1100"
-    )]
-    bind: String,
-    /// Upstream host to forward requests to
-    #[clap(short, long)]
-    upstream: Vec<String>,
-    #[clap(long, default_value = "10")]
-    /// Perform active health checks on this interval (in seconds)
-    active_health_check_interval: usize,
-    #[clap(long, default_value = "/")]
-    /// Path to send request to for active health checks
-    active_health_check_path: String,
-    /// Maximum number of requests to accept per IP per minute (0 = unlimited)
-    #[clap(long, default_value = "0")]
-    max_requests_per_minute: usize,
-}
+use tokio_rustls::rustls::Session;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
 
 /// Contains information about the state of balancebeam (e.g. what servers we are currently proxying
 /// to, what servers have failed, rate limiting counts, etc.)
@@ -43,19 +90,158 @@ struct CmdOptions {
 /// You should add fields to this struct in later milestones.
 struct ProxyState {
     /// How frequently we check whether upstream servers are alive (Milestone 4)
-    #[allow(dead_code)]
-    active_health_check_interval: usize,
-    /// Where we should send requests when doing active health checks (Milestone 4)
-    #[allow(dead_code)]
-    active_health_check_path: String,
-    /// Maximum number of requests an individual IP can make in a minute (Milestone 5)
-    #[allow(dead_code)]
-    max_requests_per_minute: usize,
+    active_health_check_interval: Duration,
+    /// Where we should send requests when doing active health checks, and what counts as passing
+    /// (Milestone 4)
+    health_check: HealthCheck,
+    /// Health checks for upstreams whose pool has its own
+    health_checks: HashMap<String, HealthCheck>,
+    /// Limits how many requests an individual IP can make in a minute, overall and under
+    /// particular path prefixes (Milestone 5; None = unlimited)
+    request_limits: Option<Arc<RequestLimits>>,
+    /// Per-IP bandwidth budget (None = unlimited)
+    byte_limiter: Option<Arc<ByteLimiter>>,
+    /// Holds a permit for each open client connection (None = unlimited)
+    connection_semaphore: Option<Arc<Semaphore>>,
+    /// Per-IP cap on open client connections (None = unlimited)
+    connection_limiter: Option<Arc<ConnectionLimiter>>,
     /// Addresses of servers that we are proxying to
     upstream_addresses: Vec<String>,
+    /// Upstreams as given in the configuration (some of which may be hostnames), with their weights
+    configured_upstreams: Vec<Upstream>,
+    /// The addresses in upstream_addresses that each configured upstream stands for: the ones its
+    /// hostname currently resolves to, or just the upstream itself
+    upstream_members: HashMap<String, Vec<String>>,
+    /// Which configured upstreams each request may go to, based on its path
+    routes: RoutingTable,
 
     /// Record each server in upstream_addresse's validation
     valid_upstream_addresses: Vec<String>,
+    /// Told whenever an upstream is marked dead or alive
+    health_hooks: HealthHooks,
+    /// Upstreams that an operator has taken out of rotation through the admin API. They get no new
+    /// requests, however healthy they are, until they're re-enabled
+    drained_upstreams: HashSet<String>,
+    /// Picks which of valid_upstream_addresses each client connection goes to
+    strategy: Box<dyn LoadBalancingStrategy>,
+    /// Whether clients are sent back to the upstream named by their sticky cookie
+    sticky_sessions: bool,
+    /// Keeps connections away from upstreams that keep failing (None = circuit breaking is off)
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    /// Keeps connections away from upstreams with more errors than the rest (None = outlier
+    /// detection is off)
+    outlier_detector: Option<Arc<OutlierDetector>>,
+    /// Where to send copies of requests (None = mirroring is off)
+    shadow: Option<Arc<Shadow>>,
+    /// Weight and number of active connections for each upstream in upstream_addresses
+    upstream_info: UpstreamInfoMap,
+    /// Requests that take longer than this to proxy are logged as slow (None = never)
+    slow_request_threshold: Option<Duration>,
+    /// How long an upstream gets to send its response headers (None = no limit)
+    upstream_header_timeout: Option<Duration>,
+    /// How long connecting to an upstream may take (None = up to the OS)
+    upstream_connect_timeout: Option<Duration>,
+    /// How long an upstream's response body may stop arriving for (None = no limit)
+    upstream_body_timeout: Option<Duration>,
+    /// How long connecting, forwarding a request and reading its response may take altogether
+    /// (None = no limit)
+    request_timeout: Option<Duration>,
+    /// How long a client may go without sending anything (None = no limit)
+    client_idle_timeout: Option<Duration>,
+    /// How long a client gets to send a request's headers once it has started (None = no limit)
+    client_header_timeout: Option<Duration>,
+    /// Request and response bodies bigger than this are streamed rather than buffered
+    body_high_water_mark: usize,
+    /// Requests with bodies bigger than this are refused with a 413 (None = no limit)
+    max_body_size: Option<usize>,
+    /// Requests with bigger or more headers than this are refused with a 431
+    header_limits: HeaderLimits,
+    /// Whether request paths are normalized before they're routed and forwarded
+    normalize_paths: bool,
+    /// Whether requests with paths that need normalizing are refused with a 400 instead
+    reject_suspicious_paths: bool,
+    /// How many other upstreams an idempotent request is retried on if its upstream connection
+    /// dies partway through forwarding it
+    max_retries: usize,
+    /// Idle upstream connections kept for reuse (None = pooling is off)
+    upstream_pool: Option<Arc<ConnectionPool>>,
+    /// Upstream responses kept for answering repeated GETs (None = caching is off)
+    response_cache: Option<Arc<ResponseCache>>,
+    /// Snippet to insert before </body> in HTML responses (None = leave responses alone)
+    inject_before_body_end: Option<String>,
+    /// HTML responses bigger than this are passed through without injection
+    inject_max_body_size: usize,
+    /// Pages to answer clients with when their requests can't be proxied
+    error_pages: Arc<ErrorPages>,
+    /// Whether to tell clients how long the upstream took via a Server-Timing header
+    expose_timing_header: bool,
+    /// Compresses responses for clients that accept it (None = compression is off)
+    compressor: Option<Arc<Compressor>>,
+    /// Changes to make to the headers of proxied requests and responses
+    header_rules: Arc<HeaderRules>,
+    /// Credentials that requests for protected paths have to carry
+    auth_rules: Arc<AuthRules>,
+    /// Clients whose forwarding headers are appended to rather than replaced
+    trusted_proxies: Vec<Cidr>,
+    /// Clients that may pick the upstream for a request with an X-Balancebeam-Upstream header
+    upstream_override_from: Vec<Cidr>,
+    /// Clients that may have their requests proxied (the rest get a 403)
+    access_list: Arc<AccessList>,
+    /// Scheme ("http" or "https") that clients connect to us with, for X-Forwarded-Proto and
+    /// friends. (The port they connected to is each connection's own.)
+    listen_proto: &'static str,
+    /// Whether upstream connections start with a PROXY protocol header naming the client
+    send_proxy_protocol: bool,
+    /// Local address that upstream connections are bound to before connecting
+    upstream_source_addr: Option<IpAddr>,
+    /// Runs TLS handshakes with https:// upstreams
+    upstream_tls: TlsConnector,
+    /// Traffic counters, reported when balancebeam shuts down
+    stats: Arc<Stats>,
+    /// Gets a line for every request answered
+    access_log: Arc<AccessLog>,
+    /// The configuration currently in effect, as shown by the admin API
+    config_dump: String,
+}
+
+impl ProxyState {
+    /// Returns the addresses that `upstreams` (configured upstreams, e.g. from the routing table)
+    /// currently stand for.
+    fn addresses_for(&self, upstreams: &[String]) -> Vec<String> {
+        upstreams
+            .iter()
+            .filter_map(|upstream| self.upstream_members.get(upstream))
+            .flatten()
+            .cloned()
+            .collect()
+    }
+
+    /// Returns the addresses an upstream override header value stands for: the members of the
+    /// configured upstream it names, or just itself if it's one of upstream_addresses (or none, if
+    /// it's neither).
+    fn override_addresses(&self, upstream: &str) -> Vec<String> {
+        match self.upstream_members.get(upstream) {
+            Some(members) => members.clone(),
+            None if self.upstream_addresses.iter().any(|address| address == upstream) => {
+                vec![upstream.to_string()]
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the configured upstream that `address` stands for: the hostname it was resolved
+    /// from, or `address` itself.
+    fn configured_upstream<'a>(&'a self, address: &'a str) -> &'a str {
+        self.configured_upstreams
+            .iter()
+            .map(|upstream| upstream.address.as_str())
+            .find(|upstream| {
+                self.upstream_members
+                    .get(*upstream)
+                    .is_some_and(|members| members.iter().any(|member| member == address))
+            })
+            .unwrap_or(address)
+    }
 }
 
 #[tokio::main]
@@ -68,174 +254,2335 @@ async fn main() {
     }
     pretty_env_logger::init();
 
-    // Parse the command line arguments passed to this program
+    // Parse the command line arguments passed to this program, and the config file if there is one
     let options = CmdOptions::parse();
-    if options.upstream.len() < 1 {
-        log::error!("At least one upstream server must be specified using the --upstream option.");
-        std::process::exit(1);
+    let config = match Config::load(options.clone()) {
+        Ok(config) => config,
+        Err(message) => {
+            log::error!("{}", message);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(source_addr) = config.upstream_source_addr {
+        // Fail now rather than on every upstream connection if the address isn't usable here
+        if let Err(err) = std::net::UdpSocket::bind(SocketAddr::new(source_addr, 0)) {
+            log::error!(
+                "Cannot use {} as the upstream source address: {}",
+                source_addr,
+                err
+            );
+            std::process::exit(1);
+        }
     }
 
-    // Start listening for connections
-    let mut listener = match TcpListener::bind(&options.bind).await {
-        Ok(listener) => listener,
-        Err(err) => {
-            log::error!("Could not bind to {}: {}", options.bind, err);
+    // Start listening for connections. With several --workers, each gets a socket of its own
+    // for every TCP address (UNIX sockets can't be shared that way, so the first worker takes them)
+    let mut worker_listeners: Vec<Vec<Listener>> =
+        (0..config.workers).map(|_| Vec::new()).collect();
+    for bind in &config.binds {
+        let bound = if config.workers > 1 && !bind.starts_with("unix:") {
+            Listener::bind_reuse_port(bind, config.workers)
+        } else {
+            Listener::bind(bind).await.map(|listener| vec![listener])
+        };
+        let bound = match bound {
+            Ok(bound) => bound,
+            Err(err) => {
+                log::error!("Could not bind to {}: {}", bind, err);
+                std::process::exit(1);
+            }
+        };
+        // Log the address we actually bound, so that binding to port 0 reports the chosen port
+        log::info!("Listening for requests on {}", bound[0].local_description());
+        for (worker, listener) in bound.into_iter().enumerate() {
+            worker_listeners[worker].push(listener);
+        }
+    }
+    // (Workers left with nothing to accept from aren't started)
+    worker_listeners.retain(|listeners| !listeners.is_empty());
+    if config.workers > 1 {
+        log::info!(
+            "Accepting connections with {} workers",
+            worker_listeners.len()
+        );
+    }
+
+    let tls_acceptor = match &config.tls {
+        Some(files) => match tls::build_acceptor(files, config.http2) {
+            Ok(acceptor) => {
+                log::info!("Terminating TLS with certificate {}", files.cert_path);
+                Some(acceptor)
+            }
+            Err(message) => {
+                log::error!("{}", message);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    let upstream_tls = match tls::build_connector(config.upstream_ca_cert.as_deref()) {
+        Ok(connector) => connector,
+        Err(message) => {
+            log::error!("{}", message);
+            std::process::exit(1);
+        }
+    };
+
+    let access_log = match AccessLog::open(
+        config.access_log_format,
+        config.access_log_file.as_deref(),
+    ) {
+        Ok(access_log) => access_log,
+        Err(message) => {
+            log::error!("{}", message);
             std::process::exit(1);
         }
     };
-    log::info!("Listening for requests on {}", options.bind);
+
+    let stats = Arc::new(Stats::new());
+    let health_hooks = match HealthHooks::new(
+        Arc::clone(&stats),
+        config.health_webhook.as_deref(),
+        upstream_tls.clone(),
+    ) {
+        Ok(hooks) => hooks,
+        Err(message) => {
+            log::error!("{}", message);
+            std::process::exit(1);
+        }
+    };
+    if let Some(status_bind) = &config.status_bind {
+        let status_listener = match TcpListener::bind(status_bind).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                log::error!("Could not bind status endpoint to {}: {}", status_bind, err);
+                std::process::exit(1);
+            }
+        };
+        log::info!(
+            "Serving status on {}",
+            status_listener.local_addr().unwrap()
+        );
+        tokio::spawn(status::serve(
+            status_listener,
+            Arc::clone(&stats),
+            status::status_routes,
+        ));
+    }
+    if let Some(metrics_bind) = &config.metrics_bind {
+        let metrics_listener = match TcpListener::bind(metrics_bind).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                log::error!("Could not bind metrics endpoint to {}: {}", metrics_bind, err);
+                std::process::exit(1);
+            }
+        };
+        log::info!(
+            "Serving metrics on {}",
+            metrics_listener.local_addr().unwrap()
+        );
+        tokio::spawn(status::serve(
+            metrics_listener,
+            Arc::clone(&stats),
+            status::metrics_routes,
+        ));
+    }
+
+    let admin_listener = match &config.admin_bind {
+        Some(admin_bind) => match TcpListener::bind(admin_bind).await {
+            Ok(listener) => {
+                log::info!("Serving admin API on {}", listener.local_addr().unwrap());
+                Some(listener)
+            }
+            Err(err) => {
+                log::error!("Could not bind admin API to {}: {}", admin_bind, err);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
 
     // Handle incoming connections
-    let state = Arc::new(RwLock::new(ProxyState {
-        upstream_addresses: options.upstream.clone(),
-        active_health_check_interval: options.active_health_check_interval,
-        active_health_check_path: options.active_health_check_path,
-        max_requests_per_minute: options.max_requests_per_minute,
-        valid_upstream_addresses: options.upstream,
-    }));
+    let config_dump = format!("{:#?}\n", config);
+    let upstream_addresses: Vec<String> = config
+        .upstreams
+        .iter()
+        .map(|upstream| upstream.address.clone())
+        .collect();
+    let upstream_members = dns::resolve_all(&upstream_addresses, &HashMap::new()).await;
+    let mut proxy_state = ProxyState {
+        request_limits: build_request_limits(&config),
+        byte_limiter: build_byte_limiter(&config),
+        connection_semaphore: match config.max_connections {
+            0 => None,
+            max_connections => Some(Arc::new(Semaphore::new(max_connections))),
+        },
+        connection_limiter: match config.max_connections_per_ip {
+            0 => None,
+            max_per_ip => Some(Arc::new(ConnectionLimiter::new(max_per_ip))),
+        },
+        // (The upstreams are filled in by set_upstreams below)
+        upstream_info: UpstreamInfoMap::new(),
+        upstream_addresses: Vec::new(),
+        configured_upstreams: Vec::new(),
+        upstream_members: HashMap::new(),
+        routes: config.routes,
+        active_health_check_interval: config
+            .active_health_check_interval
+            .unwrap_or(Duration::from_secs(0)),
+        health_check: config.health_check,
+        health_checks: config.health_checks,
+        valid_upstream_addresses: Vec::new(),
+        health_hooks,
+        drained_upstreams: HashSet::new(),
+        strategy: config.strategy.build(),
+        sticky_sessions: config.sticky_sessions,
+        circuit_breaker: match config.circuit_breaker_failures {
+            0 => None,
+            failure_threshold => Some(Arc::new(CircuitBreaker::new(
+                failure_threshold,
+                config.circuit_breaker_slow_threshold,
+                config.circuit_breaker_cooldown,
+            ))),
+        },
+        outlier_detector: match config.outlier_error_percent {
+            0 => None,
+            error_percent => Some(Arc::new(OutlierDetector::new(
+                error_percent,
+                config.outlier_min_responses,
+                config.outlier_window,
+                config.outlier_ejection_time,
+            ))),
+        },
+        shadow: match config.shadow_upstream {
+            Some(upstream) => Some(Arc::new(Shadow::new(
+                upstream,
+                config.shadow_percent,
+                upstream_tls.clone(),
+            ))),
+            None => None,
+        },
+        slow_request_threshold: config.slow_request_threshold,
+        upstream_header_timeout: config.upstream_header_timeout,
+        upstream_connect_timeout: config.upstream_connect_timeout,
+        upstream_body_timeout: config.upstream_body_timeout,
+        request_timeout: config.request_timeout,
+        client_idle_timeout: config.client_idle_timeout,
+        client_header_timeout: config.client_header_timeout,
+        body_high_water_mark: config.body_high_water_mark,
+        max_body_size: match config.max_body_size {
+            0 => None,
+            max_body_size => Some(max_body_size),
+        },
+        header_limits: config.header_limits,
+        normalize_paths: config.normalize_paths,
+        reject_suspicious_paths: config.reject_suspicious_paths,
+        max_retries: config.max_retries,
+        upstream_pool: match config.upstream_pool_max_idle {
+            0 => None,
+            max_idle => Some(Arc::new(ConnectionPool::new(
+                max_idle,
+                config.upstream_pool_idle_timeout,
+            ))),
+        },
+        response_cache: match config.cache_max_bytes {
+            0 => None,
+            max_bytes => Some(Arc::new(ResponseCache::new(max_bytes, config.cache_ttl))),
+        },
+        inject_before_body_end: config.inject_before_body_end,
+        inject_max_body_size: config.inject_max_body_size,
+        error_pages: Arc::new(config.error_pages),
+        expose_timing_header: config.expose_timing_header,
+        compressor: if config.compress {
+            Some(Arc::new(Compressor::new(
+                &config.compress_types,
+                config.compress_min_size,
+            )))
+        } else {
+            None
+        },
+        header_rules: Arc::new(config.header_rules),
+        auth_rules: Arc::new(config.auth_rules),
+        trusted_proxies: config.trusted_proxies,
+        upstream_override_from: config.upstream_override_from,
+        access_list: Arc::new(config.access_list),
+        listen_proto: if config.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        },
+        send_proxy_protocol: config.send_proxy_protocol,
+        upstream_source_addr: config.upstream_source_addr,
+        upstream_tls,
+        stats: Arc::clone(&stats),
+        access_log: Arc::new(access_log),
+        config_dump,
+    };
+    set_upstreams(&mut proxy_state, config.upstreams, upstream_members);
+    let state = Arc::new(RwLock::new(proxy_state));
+    if let Some(admin_listener) = admin_listener {
+        tokio::spawn(admin::serve(admin_listener, Arc::clone(&state)));
+    }
+    if config.active_health_check_interval.is_some() {
+        tokio::spawn(active_health_check(Arc::clone(&state)));
+    }
+    if let Some(interval) = config.dns_refresh_interval {
+        tokio::spawn(refresh_dns(Arc::clone(&state), interval));
+    }
+    if config.outlier_error_percent > 0 {
+        tokio::spawn(detect_outliers(Arc::clone(&state)));
+    }
+    if let Some(interval) = config.stats_log_interval {
+        tokio::spawn(log_stats(Arc::clone(&stats), interval));
+    }
+
     // let n_workers = 4;
     // let pool = ThreadPool::new(n_workers);
     // 不能用for in next.await...
-    while let Some(stream) = listener.next().await {
-        if let Ok(stream) = stream {
-            // Handle the connection!
-            let state_cloned = state.clone();
-            // pool.execute(move || handle_connection(stream, state_cloned));
-            tokio::spawn(async move {
-                // Process each socket concurrently.
-                handle_connection(stream, state_cloned).await;
-            });
+    // Connection handlers are told to finish up through shutdown_sender. Each one also holds a
+    // clone of drain_sender, so drain_receiver sees the channel close once they have all returned
+    let (shutdown_sender, _) = broadcast::channel(1);
+    let (drain_sender, mut drain_receiver) = mpsc::channel::<()>(1);
+    let mut terminate = signal(SignalKind::terminate()).expect("Could not listen for SIGTERM");
+    let mut hangup = signal(SignalKind::hangup()).expect("Could not listen for SIGHUP");
+    let deny_silently = config.deny_silently;
+    let acceptor = {
+        let state_read = state.read().await;
+        Acceptor {
+            state: Arc::clone(&state),
+            stats: Arc::clone(&stats),
+            tls_acceptor,
+            connection_semaphore: state_read.connection_semaphore.clone(),
+            max_queued_connections: config.max_queued_connections,
+            connection_limiter: state_read.connection_limiter.clone(),
+            silently_denied: Some(Arc::clone(&state_read.access_list)).filter(|_| deny_silently),
+            accept_proxy_protocol: config.accept_proxy_protocol,
+            http2: config.http2,
+            shutdown_sender: shutdown_sender.clone(),
+            drain_sender,
+        }
+    };
+    for (worker, listeners) in worker_listeners.into_iter().enumerate() {
+        tokio::spawn(accept_loop(
+            worker,
+            Listeners::new(listeners),
+            acceptor.clone(),
+        ));
+    }
+    loop {
+        tokio::select! {
+            _ = hangup.recv() => {
+                log::info!("Received SIGHUP, reloading configuration");
+                reload_config(&state, &options).await;
+            }
+            _ = terminate.recv() => {
+                log::info!("Received SIGTERM, shutting down");
+                break;
+            }
+            _ = tokio::signal::ctrl_c() => {
+                log::info!("Received SIGINT, shutting down");
+                break;
+            }
+        }
+    }
+
+    // Stop accepting connections (the accept loops drop their listeners as they return), and give
+    // the ones already open a chance to finish
+    let _ = shutdown_sender.send(());
+    drop(acceptor);
+    let open_connections = stats.active_connections();
+    if open_connections > 0 {
+        log::info!(
+            "Waiting up to {}s for {} open connections to finish",
+            config.shutdown_drain_timeout.as_secs(),
+            open_connections
+        );
+    }
+    if tokio::time::timeout(config.shutdown_drain_timeout, drain_receiver.recv())
+        .await
+        .is_err()
+    {
+        log::warn!(
+            "Exiting with {} connections still open after the drain timeout",
+            stats.active_connections()
+        );
+    }
+    stats.log_summary();
+}
+
+fn build_request_limits(config: &Config) -> Option<Arc<RequestLimits>> {
+    let store: Arc<dyn RateLimitStore> = match &config.rate_limit_redis {
+        Some(address) => Arc::new(RedisStore::new(address)),
+        None => Arc::new(MemoryStore::new()),
+    };
+    RequestLimits::new(
+        config.request_limit(),
+        &config.route_rate_limits,
+        config.rate_limit_window,
+        store,
+    )
+    .map(Arc::new)
+}
+
+fn build_byte_limiter(config: &Config) -> Option<Arc<ByteLimiter>> {
+    match config.max_bytes_per_minute_per_ip {
+        0 => None,
+        max_bytes => Some(Arc::new(ByteLimiter::new(max_bytes, config.rate_limit_window))),
+    }
+}
+
+/// Re-reads the command line's config file and swaps in its upstreams, routes, header rules and
+/// rate limits. The rest of the configuration (bind addresses, strategy, etc.) only takes effect
+/// on restart. Connections that are already open carry on with the upstream, rules and limits
+/// they started with, and a broken config file leaves everything as it was.
+async fn reload_config(state: &RwLock<ProxyState>, options: &CmdOptions) {
+    let config = match Config::load(options.clone()) {
+        Ok(config) => config,
+        Err(message) => {
+            log::error!("Not reloading configuration: {}", message);
+            return;
+        }
+    };
+
+    // Hostnames are looked up before taking the lock, so that requests aren't held up meanwhile
+    let upstream_addresses: Vec<String> = config
+        .upstreams
+        .iter()
+        .map(|upstream| upstream.address.clone())
+        .collect();
+    let previous_members = state.read().await.upstream_members.clone();
+    let upstream_members = dns::resolve_all(&upstream_addresses, &previous_members).await;
+
+    let mut state_write = state.write().await;
+    state_write.config_dump = format!("{:#?}\n", config);
+
+    // Only start the limiters over (forgetting what clients have used so far) if the limit changed
+    let same_request_limits = match &state_write.request_limits {
+        Some(limits) => limits.has_limits(config.request_limit(), &config.route_rate_limits),
+        None => config.request_limit().is_none() && config.route_rate_limits.is_empty(),
+    };
+    if !same_request_limits {
+        state_write.request_limits = build_request_limits(&config);
+    }
+    let old_max_bytes = state_write
+        .byte_limiter
+        .as_ref()
+        .map_or(0, |limiter| limiter.max_bytes());
+    if old_max_bytes != config.max_bytes_per_minute_per_ip {
+        state_write.byte_limiter = build_byte_limiter(&config);
+    }
+    set_upstreams(&mut state_write, config.upstreams, upstream_members);
+    state_write.routes = config.routes;
+    state_write.health_check = config.health_check;
+    state_write.health_checks = config.health_checks;
+    state_write.header_rules = Arc::new(config.header_rules);
+    state_write.auth_rules = Arc::new(config.auth_rules);
+    state_write.error_pages = Arc::new(config.error_pages);
+    log::info!(
+        "Reloaded configuration: upstreams {}",
+        state_write.upstream_addresses.join(", ")
+    );
+}
+
+/// Swaps in a new set of configured upstreams, along with the addresses each one stands for (from
+/// dns::resolve_all). Addresses that stay keep their connection counts and their health: ones that
+/// are currently marked dead stay that way until a health check revives them, while new ones start
+/// out alive. Drained addresses stay drained, unless they're gone altogether.
+fn set_upstreams(
+    state: &mut ProxyState,
+    upstreams: Vec<Upstream>,
+    members: HashMap<String, Vec<String>>,
+) {
+    let mut upstream_info = UpstreamInfoMap::new();
+    let mut upstream_addresses = Vec::new();
+    for upstream in &upstreams {
+        for address in &members[&upstream.address] {
+            // An address that several upstreams stand for gets the first one's weight
+            if upstream_info.contains_key(address) {
+                continue;
+            }
+            // Upstreams that stay keep their counter, since open connections' guards share it, and
+            // their latency average
+            let info = match state.upstream_info.get(address) {
+                Some(info) => UpstreamInfo {
+                    weight: upstream.weight,
+                    active_connections: Arc::clone(&info.active_connections),
+                    latency: Arc::clone(&info.latency),
+                },
+                None => UpstreamInfo {
+                    weight: upstream.weight,
+                    active_connections: Arc::new(AtomicUsize::new(0)),
+                    latency: Arc::new(LatencyEwma::default()),
+                },
+            };
+            upstream_info.insert(address.clone(), info);
+            upstream_addresses.push(address.clone());
+        }
+    }
+    let valid_upstream_addresses = upstream_addresses
+        .iter()
+        .filter(|address| {
+            !state.upstream_addresses.contains(address)
+                || state.valid_upstream_addresses.contains(address)
+        })
+        .cloned()
+        .collect();
+    let stats = &state.stats;
+    state.drained_upstreams.retain(|address| {
+        let kept = upstream_addresses.contains(address);
+        if !kept {
+            stats.set_upstream_drained(address, false);
+        }
+        kept
+    });
+    state.upstream_info = upstream_info;
+    state.upstream_addresses = upstream_addresses;
+    state.valid_upstream_addresses = valid_upstream_addresses;
+    state.configured_upstreams = upstreams;
+    state.upstream_members = members;
+}
+
+/// Looks up the upstreams given by hostname every `interval`, and swaps in the addresses they
+/// resolve to now (see set_upstreams). Runs forever, so it should be spawned as its own task.
+async fn refresh_dns(state: Arc<RwLock<ProxyState>>, interval: Duration) {
+    loop {
+        tokio::time::delay_for(interval).await;
+        let (upstreams, previous_members) = {
+            let state_read = state.read().await;
+            (
+                state_read.configured_upstreams.clone(),
+                state_read.upstream_members.clone(),
+            )
+        };
+        let upstream_addresses: Vec<String> = upstreams
+            .iter()
+            .map(|upstream| upstream.address.clone())
+            .collect();
+        if !upstream_addresses.iter().any(|address| dns::is_hostname(address)) {
+            continue;
+        }
+        let upstream_members = dns::resolve_all(&upstream_addresses, &previous_members).await;
+        if upstream_members == previous_members {
+            continue;
+        }
+        let mut state_write = state.write().await;
+        // The configuration may have been reloaded (and looked up afresh) in the meantime
+        if state_write.upstream_members != previous_members {
+            continue;
+        }
+        set_upstreams(&mut state_write, upstreams, upstream_members);
+    }
+}
+
+/// Logs a summary of the traffic handled so far every `interval`.
+async fn log_stats(stats: Arc<Stats>, interval: Duration) {
+    loop {
+        tokio::time::delay_for(interval).await;
+        stats.log_periodic_summary();
+    }
+}
+
+/// Has the outlier detector look over the upstreams' recent responses every
+/// outlier::ANALYSIS_INTERVAL. Runs forever, so it should be spawned as its own task.
+async fn detect_outliers(state: Arc<RwLock<ProxyState>>) {
+    loop {
+        tokio::time::delay_for(outlier::ANALYSIS_INTERVAL).await;
+        let state_read = state.read().await;
+        if let Some(detector) = &state_read.outlier_detector {
+            detector.analyze(&state_read.upstream_addresses);
+        }
+    }
+}
+
+/// Everything the accept loops need to serve the connections they accept
+#[derive(Clone)]
+struct Acceptor {
+    state: Arc<RwLock<ProxyState>>,
+    stats: Arc<Stats>,
+    tls_acceptor: Option<TlsAcceptor>,
+    connection_semaphore: Option<Arc<Semaphore>>,
+    max_queued_connections: usize,
+    connection_limiter: Option<Arc<ConnectionLimiter>>,
+    /// Clients that are dropped as soon as they're accepted, rather than answered with a 403
+    silently_denied: Option<Arc<AccessList>>,
+    accept_proxy_protocol: bool,
+    http2: bool,
+    shutdown_sender: broadcast::Sender<()>,
+    drain_sender: mpsc::Sender<()>,
+}
+
+/// Accepts client connections on `listeners` until shutdown, serving each in a task of its own.
+/// One of these runs for each of the --workers, on that worker's own listeners.
+async fn accept_loop(worker: usize, mut listeners: Listeners, acceptor: Acceptor) {
+    let mut shutdown = Shutdown::new(acceptor.shutdown_sender.subscribe());
+    loop {
+        let accept = accept_connection(
+            &mut listeners,
+            &acceptor.connection_semaphore,
+            acceptor.max_queued_connections,
+            &acceptor.stats,
+        );
+        tokio::select! {
+            (stream, slot) = accept => {
+                if let Ok(stream) = stream {
+                    // Handle the connection!
+                    acceptor.stats.record_worker_connection(worker);
+                    let shutdown = Shutdown::new(acceptor.shutdown_sender.subscribe());
+                    tokio::spawn(serve_connection(stream, slot, acceptor.clone(), shutdown));
+                }
+            }
+            _ = shutdown.recv() => return,
+        }
+    }
+}
+
+/// Serves an accepted client connection: reads its PROXY protocol header and checks it against
+/// the access list and per-IP limit (if need be), then hands it to the HTTP/1.1 or HTTP/2 handler.
+async fn serve_connection(
+    mut stream: ClientStream,
+    slot: Slot,
+    acceptor: Acceptor,
+    mut shutdown: Shutdown,
+) {
+    let Acceptor {
+        state,
+        tls_acceptor,
+        connection_semaphore,
+        connection_limiter,
+        silently_denied,
+        accept_proxy_protocol,
+        http2,
+        drain_sender: _drain_sender,
+        ..
+    } = acceptor;
+    let _connection_permit = match slot {
+        Slot::Free(permit) => permit,
+        Slot::Queued(_queued_guard) => {
+            let semaphore = connection_semaphore.unwrap();
+            tokio::select! {
+                permit = semaphore.acquire_owned() => Some(permit),
+                _ = shutdown.recv() => return,
+            }
+        }
+    };
+    // Process each socket concurrently.
+    let (mut client_addr, mut local_addr) = match stream.addrs() {
+        Ok(addrs) => addrs,
+        Err(_) => return,
+    };
+    if accept_proxy_protocol {
+        let header = tokio::time::timeout(
+            proxy_protocol::HEADER_TIMEOUT,
+            proxy_protocol::read_header(&mut stream),
+        );
+        match header.await {
+            Ok(Ok(Some(addrs))) => {
+                log::debug!(
+                    "PROXY protocol header from {} gives client {}",
+                    client_addr.ip(),
+                    addrs.0
+                );
+                client_addr = addrs.0;
+                local_addr = addrs.1;
+            }
+            Ok(Ok(None)) => {}
+            Ok(Err(message)) => {
+                log::info!(
+                    "Bad PROXY protocol header from {}: {}",
+                    client_addr.ip(),
+                    message
+                );
+                return;
+            }
+            Err(_) => {
+                log::info!(
+                    "Timed out waiting for a PROXY protocol header from {}",
+                    client_addr.ip()
+                );
+                return;
+            }
+        }
+    }
+    if silently_denied
+        .as_ref()
+        .is_some_and(|access_list| !access_list.permits(client_addr.ip()))
+    {
+        log::info!(
+            "Dropping connection from {}: not allowed by --allow/--deny",
+            client_addr.ip()
+        );
+        return;
+    }
+    let _client_connection_guard = match &connection_limiter {
+        Some(limiter) => match limiter.try_acquire(client_addr.ip()) {
+            Some(guard) => Some(guard),
+            None => {
+                log::warn!(
+                    "Refusing connection from {}: it already has {} open",
+                    client_addr.ip(),
+                    limiter.max_per_ip()
+                );
+                return;
+            }
+        },
+        None => None,
+    };
+    match tls_acceptor {
+        Some(acceptor) => match acceptor.accept(stream).await {
+            Ok(stream) if stream.get_ref().1.get_alpn_protocol() == Some(b"h2") => {
+                http2::serve(stream, client_addr, local_addr, state, shutdown).await
+            }
+            Ok(stream) => handle_connection(stream, client_addr, local_addr, state, shutdown).await,
+            Err(err) => log::info!("TLS handshake with {} failed: {}", client_addr.ip(), err),
+        },
+        // Without TLS, HTTP/2 clients have to know to use it from the start
+        None if http2 => {
+            let idle_timeout = state.read().await.client_idle_timeout;
+            let detect = http2::detect_preface(stream);
+            let detected = match idle_timeout {
+                Some(idle_timeout) => tokio::time::timeout(idle_timeout, detect).await,
+                None => Ok(detect.await),
+            };
+            match detected {
+                Ok(Ok((true, stream))) => {
+                    http2::serve(stream, client_addr, local_addr, state, shutdown).await
+                }
+                Ok(Ok((false, stream))) => {
+                    handle_connection(stream, client_addr, local_addr, state, shutdown).await
+                }
+                Ok(Err(_)) | Err(_) => log::debug!(
+                    "Closing connection from {}: nothing received",
+                    client_addr.ip()
+                ),
+            }
+        }
+        None => handle_connection(stream, client_addr, local_addr, state, shutdown).await,
+    }
+}
+
+/// How an accepted connection stands against --max-connections
+enum Slot {
+    /// It can be served right away (holding its permit, if there's a limit)
+    Free(Option<OwnedSemaphorePermit>),
+    /// It has to wait for a permit, counted as queued until it gets one
+    Queued(QueuedGuard),
+}
+
+/// Accepts the next client connection. If there's a limit on open connections and it has been
+/// reached, what happens depends on --max-queued-connections: without a queue, this waits for
+/// `semaphore` to have a permit to spare before accepting (leaving new connections waiting in the
+/// listen backlog); with one, connections are accepted and queued for a permit, and closed as soon
+/// as they're accepted once the queue is full, so that a flood of connections can't pile up.
+async fn accept_connection(
+    listeners: &mut Listeners,
+    semaphore: &Option<Arc<Semaphore>>,
+    max_queued: usize,
+    stats: &Arc<Stats>,
+) -> (std::io::Result<ClientStream>, Slot) {
+    let semaphore = match semaphore {
+        Some(semaphore) => semaphore,
+        None => return (listeners.accept().await, Slot::Free(None)),
+    };
+    if max_queued == 0 {
+        if semaphore.available_permits() == 0 {
+            log::warn!("Reached --max-connections; new connections will wait for one to close");
+        }
+        let permit = Arc::clone(semaphore).acquire_owned().await;
+        return (listeners.accept().await, Slot::Free(Some(permit)));
+    }
+    loop {
+        let stream = match listeners.accept().await {
+            Ok(stream) => stream,
+            Err(err) => return (Err(err), Slot::Free(None)),
+        };
+        if let Ok(permit) = Arc::clone(semaphore).try_acquire_owned() {
+            return (Ok(stream), Slot::Free(Some(permit)));
+        }
+        if stats.queued_connections() < max_queued {
+            return (Ok(stream), Slot::Queued(stats.connection_queued()));
+        }
+        stats.record_rejected_connection();
+        log::warn!(
+            "Closing new connection: reached --max-connections, with {} more already queued",
+            max_queued
+        );
+    }
+}
+
+/// Opens a connection to `upstream` that originates from `source_addr` (with an OS-assigned port).
+async fn connect_from(source_addr: IpAddr, upstream: &str) -> std::io::Result<TcpStream> {
+    let upstream_addr = tokio::net::lookup_host(upstream)
+        .await?
+        .find(|addr| addr.is_ipv4() == source_addr.is_ipv4())
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::AddrNotAvailable,
+                format!(
+                    "{} has no address of the same family as {}",
+                    upstream, source_addr
+                ),
+            )
+        })?;
+    let domain = if source_addr.is_ipv4() {
+        socket2::Domain::ipv4()
+    } else {
+        socket2::Domain::ipv6()
+    };
+    let socket = socket2::Socket::new(
+        domain,
+        socket2::Type::stream(),
+        Some(socket2::Protocol::tcp()),
+    )?;
+    socket.bind(&SocketAddr::new(source_addr, 0).into())?;
+    TcpStream::connect_std(socket.into_tcp_stream(), &upstream_addr).await
+}
+
+/// Opens a connection to `upstream`, from `source_addr` if one was configured, and sends
+/// `proxy_header` (a PROXY protocol header) down it if given. The connection is encrypted (using
+/// `tls_connector`) if the upstream was given as https://, and the certificate is checked against
+/// the hostname in `configured` (the upstream as configured, which `upstream` may have been
+/// resolved from). Fails with ErrorKind::TimedOut if all that takes longer than `connect_timeout`.
+async fn open_connection(
+    upstream: &str,
+    configured: &str,
+    source_addr: Option<IpAddr>,
+    tls_connector: &TlsConnector,
+    proxy_header: Option<&str>,
+    connect_timeout: Option<Duration>,
+) -> std::io::Result<UpstreamStream> {
+    let connect =
+        connect_upstream_stream(upstream, configured, source_addr, tls_connector, proxy_header);
+    match connect_timeout {
+        Some(connect_timeout) => tokio::time::timeout(connect_timeout, connect)
+            .await
+            .unwrap_or_else(|_| {
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("timed out after {}ms", connect_timeout.as_millis()),
+                ))
+            }),
+        None => connect.await,
+    }
+}
+
+async fn connect_upstream_stream(
+    upstream: &str,
+    configured: &str,
+    source_addr: Option<IpAddr>,
+    tls_connector: &TlsConnector,
+    proxy_header: Option<&str>,
+) -> std::io::Result<UpstreamStream> {
+    if let Some(path) = upstream.strip_prefix("unix:") {
+        let mut stream = UnixStream::connect(path).await?;
+        if let Some(header) = proxy_header {
+            stream.write_all(header.as_bytes()).await?;
+        }
+        return Ok(UpstreamStream::Unix(stream));
+    }
+    let (use_tls, address) = tls::split_upstream_scheme(upstream);
+    let mut stream = match source_addr {
+        Some(source_addr) => connect_from(source_addr, address).await?,
+        None => TcpStream::connect(address).await?,
+    };
+    if let Some(header) = proxy_header {
+        stream.write_all(header.as_bytes()).await?;
+    }
+    if use_tls {
+        let (_, name) = tls::split_upstream_scheme(configured);
+        tls::connect(tls_connector, name, stream).await
+    } else {
+        Ok(UpstreamStream::Plain(stream))
+    }
+}
+
+/// Periodically sends each upstream (including ones that are currently marked dead) its health
+/// check (the one from health_checks for upstreams whose pool has its own, health_check for the
+/// rest), and rebuilds valid_upstream_addresses from the ones that passed. Runs forever, so it
+/// should be spawned as its own task.
+async fn active_health_check(state: Arc<RwLock<ProxyState>>) {
+    loop {
+        let (
+            interval,
+            default_check,
+            checks,
+            upstreams,
+            source_addr,
+            tls_connector,
+            proxy_header,
+            connect_timeout,
+        ) = {
+            let state_read = state.read().await;
+            (
+                state_read.active_health_check_interval,
+                state_read.health_check.clone(),
+                state_read.health_checks.clone(),
+                // (Paired with the upstream they stand for, which health checks are made as)
+                state_read
+                    .upstream_addresses
+                    .iter()
+                    .map(|address| {
+                        let configured = state_read.configured_upstream(address).to_string();
+                        (address.clone(), configured)
+                    })
+                    .collect::<Vec<_>>(),
+                state_read.upstream_source_addr,
+                state_read.upstream_tls.clone(),
+                // Health checks aren't made on any client's behalf
+                Some(proxy_protocol::V1_UNKNOWN_HEADER).filter(|_| state_read.send_proxy_protocol),
+                state_read.upstream_connect_timeout,
+            )
+        };
+        tokio::time::delay_for(interval).await;
+
+        // Check every upstream at once, so that one slow upstream doesn't hold up the rest. A
+        // check that is still going when its timeout (or the next round) is due counts as a
+        // failure.
+        let checks: Vec<_> = upstreams
+            .iter()
+            .map(|(upstream, configured)| {
+                let check = checks.get(configured).unwrap_or(&default_check).clone();
+                let timeout = check.timeout.map_or(interval, |timeout| timeout.min(interval));
+                let check = check_upstream_health(
+                    upstream.clone(),
+                    configured.clone(),
+                    check,
+                    source_addr,
+                    tls_connector.clone(),
+                    proxy_header,
+                    connect_timeout,
+                );
+                tokio::spawn(tokio::time::timeout(timeout, check))
+            })
+            .collect();
+        let mut healthy = Vec::new();
+        // Why each of the rest failed
+        let mut failures = HashMap::new();
+        for ((upstream, _), check) in upstreams.iter().zip(checks) {
+            let failure = match check.await {
+                Ok(Ok(Ok(()))) => {
+                    healthy.push(upstream.clone());
+                    continue;
+                }
+                Ok(Ok(Err(reason))) => reason,
+                Ok(Err(_)) => "timed out".to_string(),
+                Err(err) => {
+                    log::error!("Health check task for {} failed: {}", upstream, err);
+                    err.to_string()
+                }
+            };
+            log::debug!("Upstream {} failed its health check: {}", upstream, failure);
+            failures.insert(upstream, failure);
+        }
+
+        let mut state_write = state.write().await;
+        for (upstream, _) in &upstreams {
+            let was_alive = state_write.valid_upstream_addresses.contains(upstream);
+            let is_alive = healthy.contains(upstream);
+            if was_alive && !is_alive {
+                let reason = format!("failed its health check ({})", failures[upstream]);
+                state_write
+                    .health_hooks
+                    .upstream_changed(upstream, false, &reason);
+            } else if !was_alive && is_alive {
+                state_write.health_hooks.upstream_changed(
+                    upstream,
+                    true,
+                    "passed its health check; sending it requests again",
+                );
+            }
         }
+        state_write.valid_upstream_addresses = healthy;
     }
 }
 
-async fn connect_to_upstream(state: Arc<RwLock<ProxyState>>) -> Result<TcpStream, request::Error> {
+/// Sends `check` to `upstream` (with a Host header naming `configured`, the upstream it was
+/// resolved from, unless the check has a Host of its own), returning a description of what went
+/// wrong unless the response passed.
+async fn check_upstream_health(
+    upstream: String,
+    configured: String,
+    check: HealthCheck,
+    source_addr: Option<IpAddr>,
+    tls_connector: TlsConnector,
+    proxy_header: Option<&str>,
+    connect_timeout: Option<Duration>,
+) -> Result<(), String> {
+    let connection = open_connection(
+        &upstream,
+        &configured,
+        source_addr,
+        &tls_connector,
+        proxy_header,
+        connect_timeout,
+    );
+    let mut conn = connection
+        .await
+        .map_err(|err| format!("could not connect: {}", err))?;
+    let host = check
+        .host
+        .as_deref()
+        .unwrap_or_else(|| health_check_host(&configured));
+    let request = http::Request::builder()
+        .method(http::Method::GET)
+        .uri(&check.path)
+        .header("Host", host)
+        .body(Vec::new())
+        .unwrap();
+    request::write_to_stream(&request, &mut conn)
+        .await
+        .map_err(|err| format!("could not send request: {}", err))?;
+    // (Only as much of the body is read as there is to look at)
+    let high_water = if check.checks_body() {
+        health_check::MAX_BODY_SIZE
+    } else {
+        0
+    };
+    let (response, _) =
+        response::read_from_stream(&mut conn, request.method(), None, None, high_water)
+            .await
+            .map_err(|err| format!("could not read response: {:?}", err))?;
+    check.judge(&response)
+}
+
+/// Returns the Host header to send `upstream` health checks with: its host:port, or localhost for
+/// UNIX sockets.
+fn health_check_host(upstream: &str) -> &str {
+    if upstream.starts_with("unix:") {
+        "localhost"
+    } else {
+        tls::split_upstream_scheme(upstream).1
+    }
+}
+
+/// A connection to an upstream, as handed out by connect_to_upstream
+struct UpstreamConnection {
+    stream: UpstreamStream,
+    /// The upstream's address (which the pool and strategies go by)
+    upstream: String,
+    /// The upstream as configured, which `upstream` was resolved from if it's a hostname
+    configured: String,
+    /// Whether the stream came out of the pool, and hasn't carried a request for us yet
+    reused: bool,
+    /// The upstream's latency average, for recording how long its responses take
+    latency: Arc<LatencyEwma>,
+    /// Counts this connection against the upstream for as long as it's open
+    _guard: UpstreamConnectionGuard,
+}
+
+/// How connect_to_upstream picks among the upstreams it's given
+#[derive(Clone, Copy)]
+enum Pick<'a> {
+    /// By the configured strategy
+    Strategy,
+    /// The one a sticky cookie with this value names, while it's available (and otherwise by the
+    /// strategy)
+    Sticky(&'a str),
+    /// By the strategy, from all of them, whatever their health (they were named by an upstream
+    /// override header)
+    Forced,
+}
+
+/// Connects to one of `upstreams` (leaving out the ones in `exclude`), picked as `pick` says,
+/// reusing an idle pooled connection if there is one. New connections start with `proxy_header`,
+/// if given. Upstreams that can't be reached are marked dead (removed from
+/// valid_upstream_addresses, so no other connection tries them either) and another upstream is
+/// tried, until one works or none are left. (Forced upstreams are left to the health checks, and
+/// aren't tried again.) If the last one tried timed out, that's reported as a connect timeout.
+async fn connect_to_upstream(
+    state: Arc<RwLock<ProxyState>>,
+    client_ip: IpAddr,
+    upstreams: &[String],
+    exclude: &[String],
+    proxy_header: Option<&str>,
+    mut pick: Pick<'_>,
+) -> Result<UpstreamConnection, ProxyError> {
+    let mut timed_out = false;
+    let mut tried: Vec<String> = Vec::new();
     loop {
         let state_read = state.read().await;
-        if state_read.valid_upstream_addresses.is_empty() {
-            break Err(request::Error::NoValidUpstreamServer);
+        // Dead, drained, ejected and excluded upstreams, and ones whose circuits are open, are left
+        // out before the strategy gets to choose
+        let breaker = state_read.circuit_breaker.as_ref();
+        let outliers = state_read.outlier_detector.as_ref();
+        let candidates: Vec<String> = if matches!(pick, Pick::Forced) {
+            upstreams
+                .iter()
+                .filter(|upstream| !exclude.contains(upstream) && !tried.contains(upstream))
+                .cloned()
+                .collect()
+        } else {
+            state_read
+                .valid_upstream_addresses
+                .iter()
+                .filter(|upstream| upstreams.contains(upstream) && !exclude.contains(upstream))
+                .filter(|upstream| !state_read.drained_upstreams.contains(*upstream))
+                .filter(|upstream| breaker.is_none_or(|breaker| breaker.is_available(upstream)))
+                .filter(|upstream| outliers.is_none_or(|outliers| !outliers.is_ejected(upstream)))
+                .cloned()
+                .collect()
+        };
+        if candidates.is_empty() {
+            break Err(if timed_out {
+                ProxyError::Timeout(Timeout::Connect)
+            } else {
+                ProxyError::UpstreamConnect
+            });
+        }
+        let sticky = match pick {
+            Pick::Sticky(value) => sticky::find(value, &candidates),
+            _ => None,
+        };
+        let upstream_ip = match sticky {
+            Some(upstream) => upstream.clone(),
+            None => {
+                let upstream_idx =
+                    state_read
+                        .strategy
+                        .choose(&candidates, &state_read.upstream_info, client_ip);
+                candidates[upstream_idx].clone()
+            }
+        };
+        let forced = matches!(pick, Pick::Forced);
+        if let Some(breaker) = breaker.filter(|_| !forced) {
+            // Another connection may have just taken the upstream's probe (in which case the
+            // strategy gets to pick, rather than waiting for the probe to finish)
+            if !breaker.try_acquire(&upstream_ip) {
+                pick = Pick::Strategy;
+                continue;
+            }
+        }
+        // Count the connection before it's made, so that connections arriving at the same time
+        // see each other
+        let info = &state_read.upstream_info[&upstream_ip];
+        let guard = UpstreamConnectionGuard::new(&info.active_connections);
+        let latency = Arc::clone(&info.latency);
+        let configured = state_read.configured_upstream(&upstream_ip).to_string();
+        let pooled = state_read
+            .upstream_pool
+            .as_ref()
+            .and_then(|pool| pool.take(&upstream_ip));
+        if let Some(stream) = pooled {
+            log::debug!("Reusing a pooled connection to {}", upstream_ip);
+            return Ok(UpstreamConnection {
+                stream,
+                upstream: upstream_ip,
+                configured,
+                reused: true,
+                latency,
+                _guard: guard,
+            });
         }
-        let mut rng = rand::rngs::StdRng::from_entropy();
-        let upstream_idx = rng.gen_range(0, state_read.valid_upstream_addresses.len());
-        let upstream_ip = state_read.valid_upstream_addresses[upstream_idx].clone();
+        let source_addr = state_read.upstream_source_addr;
+        let tls_connector = state_read.upstream_tls.clone();
+        let connect_timeout = state_read.upstream_connect_timeout;
         drop(state_read);
-        match TcpStream::connect(&upstream_ip).await {
+        let connection = open_connection(
+            &upstream_ip,
+            &configured,
+            source_addr,
+            &tls_connector,
+            proxy_header,
+            connect_timeout,
+        );
+        match connection.await {
             Ok(stream) => {
-                return Ok(stream);
+                return Ok(UpstreamConnection {
+                    stream,
+                    upstream: upstream_ip,
+                    configured,
+                    reused: false,
+                    latency,
+                    _guard: guard,
+                });
             }
-            Err(_) => {
+            Err(err) => {
+                log::error!("Failed to connect to upstream {}: {}", upstream_ip, err);
+                timed_out = err.kind() == std::io::ErrorKind::TimedOut;
+                let address = tls::split_upstream_scheme(&upstream_ip).1;
+                state.read().await.stats.record_upstream_error(address);
+                if forced {
+                    tried.push(upstream_ip);
+                    continue;
+                }
                 let mut proxy_state_write = state.write().await;
+                if let Some(breaker) = &proxy_state_write.circuit_breaker {
+                    breaker.record_failure(&upstream_ip);
+                }
+                // Another connection may have already marked it dead while we were connecting
                 if let Some(idx) = proxy_state_write
                     .valid_upstream_addresses
                     .iter()
                     .position(|x| *x == upstream_ip)
                 {
                     proxy_state_write.valid_upstream_addresses.remove(idx);
+                    let reason = format!(
+                        "could not connect ({} upstreams left)",
+                        proxy_state_write.valid_upstream_addresses.len()
+                    );
+                    proxy_state_write
+                        .health_hooks
+                        .upstream_changed(&upstream_ip, false, &reason);
                 }
             }
         };
-        // TODO: implement failover (milestone 3)
     }
 }
 
-async fn send_response(client_conn: &mut TcpStream, response: &http::Response<Vec<u8>>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
-    log::info!(
+/// Settings for talking to upstreams, read out of ProxyState once per client connection
+struct UpstreamSettings {
+    /// How long an upstream gets to send its response headers (None = no limit)
+    header_timeout: Option<Duration>,
+    /// How long an upstream's response body may stop arriving for (None = no limit)
+    body_timeout: Option<Duration>,
+    connect_timeout: Option<Duration>,
+    /// How long each request's trip to the upstream may take altogether (None = no limit)
+    request_timeout: Option<Duration>,
+    /// Response bodies bigger than this are streamed to the client rather than buffered
+    body_high_water_mark: usize,
+    source_addr: Option<IpAddr>,
+    tls_connector: TlsConnector,
+    /// How many other upstreams a request is sent to if its upstream connection dies
+    max_retries: usize,
+    /// PROXY protocol header to start new upstream connections with (None = don't send one)
+    proxy_header: Option<String>,
+}
+
+/// Everything a client connection needs from ProxyState, read out under a single lock when the
+/// connection starts
+struct ConnectionSettings {
+    stats: Arc<Stats>,
+    access_log: Arc<AccessLog>,
+    request_limits: Option<Arc<RequestLimits>>,
+    byte_limiter: Option<Arc<ByteLimiter>>,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    outlier_detector: Option<Arc<OutlierDetector>>,
+    slow_request_threshold: Option<Duration>,
+    /// Snippet to inject before </body>, and the biggest body it's injected into
+    injection: Option<(String, usize)>,
+    expose_timing_header: bool,
+    header_rules: Arc<HeaderRules>,
+    compressor: Option<Arc<Compressor>>,
+    error_pages: Arc<ErrorPages>,
+    /// Whether the client is a trusted proxy (whose forwarding headers are believed)
+    from_trusted_proxy: bool,
+    listen_proto: &'static str,
+    /// Whether the access list lets the client in at all
+    client_allowed: bool,
+    /// Whether the client may pick its upstream with the override header
+    override_allowed: bool,
+    client_idle_timeout: Option<Duration>,
+    client_header_timeout: Option<Duration>,
+    max_body_size: Option<usize>,
+    header_limits: HeaderLimits,
+    normalize_paths: bool,
+    reject_suspicious_paths: bool,
+    auth_rules: Arc<AuthRules>,
+    sticky_sessions: bool,
+    upstream_settings: UpstreamSettings,
+    upstream_pool: Option<Arc<ConnectionPool>>,
+    response_cache: Option<Arc<ResponseCache>>,
+    shadow: Option<Arc<Shadow>>,
+}
+
+impl ConnectionSettings {
+    fn new(
+        state: &ProxyState,
+        client_addr: SocketAddr,
+        local_addr: SocketAddr,
+    ) -> ConnectionSettings {
+        let client_in = |cidrs: &[Cidr]| cidrs.iter().any(|cidr| cidr.contains(client_addr.ip()));
+        ConnectionSettings {
+            stats: Arc::clone(&state.stats),
+            access_log: Arc::clone(&state.access_log),
+            request_limits: state.request_limits.clone(),
+            byte_limiter: state.byte_limiter.clone(),
+            circuit_breaker: state.circuit_breaker.clone(),
+            outlier_detector: state.outlier_detector.clone(),
+            slow_request_threshold: state.slow_request_threshold,
+            injection: state
+                .inject_before_body_end
+                .clone()
+                .map(|snippet| (snippet, state.inject_max_body_size)),
+            expose_timing_header: state.expose_timing_header,
+            header_rules: state.header_rules.clone(),
+            compressor: state.compressor.clone(),
+            error_pages: Arc::clone(&state.error_pages),
+            from_trusted_proxy: client_in(&state.trusted_proxies),
+            listen_proto: state.listen_proto,
+            client_allowed: state.access_list.permits(client_addr.ip()),
+            override_allowed: client_in(&state.upstream_override_from),
+            client_idle_timeout: state.client_idle_timeout,
+            client_header_timeout: state.client_header_timeout,
+            max_body_size: state.max_body_size,
+            header_limits: state.header_limits,
+            normalize_paths: state.normalize_paths,
+            reject_suspicious_paths: state.reject_suspicious_paths,
+            auth_rules: Arc::clone(&state.auth_rules),
+            sticky_sessions: state.sticky_sessions,
+            upstream_settings: UpstreamSettings {
+                header_timeout: state.upstream_header_timeout,
+                body_timeout: state.upstream_body_timeout,
+                connect_timeout: state.upstream_connect_timeout,
+                request_timeout: state.request_timeout,
+                body_high_water_mark: state.body_high_water_mark,
+                source_addr: state.upstream_source_addr,
+                tls_connector: state.upstream_tls.clone(),
+                max_retries: state.max_retries,
+                proxy_header: if state.send_proxy_protocol {
+                    Some(proxy_protocol::v1_header(client_addr, local_addr))
+                } else {
+                    None
+                },
+            },
+            upstream_pool: state.upstream_pool.clone(),
+            response_cache: state.response_cache.clone(),
+            shadow: state.shadow.clone(),
+        }
+    }
+}
+
+/// Why forward_request failed
+enum ForwardError {
+    /// Couldn't send the request to the upstream
+    Send(std::io::Error),
+    /// Couldn't read the rest of a streamed request body from the client
+    RequestBody(body::CopyError),
+    /// Sent the request, but didn't get a usable response back (in time)
+    Receive(ProxyError),
+    /// The request timeout ran out before the response arrived
+    Timeout,
+}
+
+impl ForwardError {
+    /// Returns true if the upstream connection broke (as opposed to the upstream answering with
+    /// something we couldn't use, or the client's side going wrong).
+    fn is_connection_failure(&self) -> bool {
+        matches!(
+            self,
+            ForwardError::Send(_)
+                | ForwardError::Receive(ProxyError::UpstreamProtocol(
+                    UpstreamError::IncompleteResponse(_) | UpstreamError::ConnectionError(_)
+                ))
+        )
+    }
+}
+
+/// A broken streamed request body is the client's doing; the rest is the upstream's (a failed send
+/// is a broken connection, like one that breaks while the response is read)
+impl From<ForwardError> for ProxyError {
+    fn from(error: ForwardError) -> ProxyError {
+        match error {
+            ForwardError::Send(err) => {
+                ProxyError::UpstreamProtocol(UpstreamError::ConnectionError(err))
+            }
+            ForwardError::RequestBody(err) => ProxyError::Client(err.into()),
+            ForwardError::Receive(err) => err,
+            ForwardError::Timeout => ProxyError::Timeout(Timeout::Request),
+        }
+    }
+}
+
+/// Sends `request` to the upstream, followed by the rest of its body (streamed from the client)
+/// if it was too big to buffer, and returns the number of bytes sent.
+async fn send_request<S: AsyncRead + Splice + Unpin>(
+    stream: &mut UpstreamStream,
+    request: &http::Request<Vec<u8>>,
+    unread_body: Option<Unread>,
+    client_conn: &mut S,
+) -> Result<usize, ForwardError> {
+    let mut bytes_written = request::write_to_stream(request, stream)
+        .await
+        .map_err(ForwardError::Send)?;
+    if let Some(unread) = unread_body {
+        log::debug!("Streaming the rest of the request body to upstream");
+        bytes_written += splice::copy(unread, client_conn, stream)
+            .await
+            .map_err(|err| match err {
+                body::CopyError::Write(err) => ForwardError::Send(err),
+                err => ForwardError::RequestBody(err),
+            })?;
+    }
+    Ok(bytes_written)
+}
+
+/// Sends `request` to the upstream and reads its response, returning the number of request bytes
+/// sent along with the response. If the response body was too big to buffer, the rest of it is
+/// returned as Unread, still waiting in the upstream connection.
+///
+/// A pooled connection may have been closed by the upstream just as we picked it up. If a reused
+/// connection fails before any of the response arrives, an idempotent request is sent again, once,
+/// on a fresh connection to the same upstream. (Requests with a streamed body can't be sent again,
+/// since the body is gone once it has been sent.)
+async fn forward_request<S: AsyncRead + Splice + Unpin>(
+    conn: &mut UpstreamConnection,
+    request: &http::Request<Vec<u8>>,
+    mut unread_body: Option<Unread>,
+    client_conn: &mut S,
+    settings: &UpstreamSettings,
+    request_id: &str,
+) -> Result<(usize, http::Response<Vec<u8>>, Option<Unread>), ForwardError> {
+    let can_retry = unread_body.is_none() && request.method().is_idempotent();
+    loop {
+        let result = match send_request(&mut conn.stream, request, unread_body.take(), client_conn)
+            .await
+        {
+            Ok(request_bytes) => response::read_from_stream(
+                &mut conn.stream,
+                request.method(),
+                settings.header_timeout,
+                settings.body_timeout,
+                settings.body_high_water_mark,
+            )
+            .await
+            .map(|(response, unread)| (request_bytes, response, unread))
+            .map_err(ForwardError::Receive),
+            Err(error) => Err(error),
+        };
+        let stale = matches!(
+            result,
+            Err(ForwardError::Send(_))
+                | Err(ForwardError::Receive(ProxyError::UpstreamProtocol(
+                    UpstreamError::IncompleteResponse(0) | UpstreamError::ConnectionError(_)
+                )))
+        );
+        if !(conn.reused && stale && can_retry) {
+            conn.reused = false;
+            return result;
+        }
+        log::debug!(
+            "[{}] Pooled connection to {} was no longer usable; retrying on a new connection",
+            request_id,
+            conn.upstream
+        );
+        conn.reused = false;
+        conn.stream = open_connection(
+            &conn.upstream,
+            &conn.configured,
+            settings.source_addr,
+            &settings.tls_connector,
+            settings.proxy_header.as_deref(),
+            settings.connect_timeout,
+        )
+        .await
+        .map_err(ForwardError::Send)?;
+    }
+}
+
+/// Sends a response to the client, returning how many bytes made it out (0 if sending failed).
+async fn send_response<S: AsyncWrite + Unpin>(
+    client_conn: &mut S,
+    client_ip: &str,
+    response: &http::Response<Vec<u8>>,
+    stats: &Stats,
+) -> usize {
+    stats.record_response(response.status());
+    log::debug!(
         "{} <- {}",
         client_ip,
         response::format_response_line(&response)
     );
-    if let Err(error) = response::write_to_stream(&response, client_conn).await {
-        log::warn!("Failed to send response to client: {}", error);
-        return;
+    match response::write_to_stream(&response, client_conn).await {
+        Ok(bytes_written) => {
+            stats.record_bytes_out(bytes_written);
+            bytes_written
+        }
+        Err(error) => {
+            log::warn!("Failed to send response to client: {}", error);
+            0
+        }
     }
 }
 
-async fn handle_connection(mut client_conn: TcpStream, state: Arc<RwLock<ProxyState>>) {
-    let client_ip = client_conn.peer_addr().unwrap().ip().to_string();
+/// Proxies requests from one client connection, which may be a plain TCP stream or a TLS stream
+/// wrapped around one (in which case `client_addr` is the address of the underlying TCP peer).
+/// `local_addr` is the address the client connected to. If a PROXY protocol header was accepted,
+/// both addresses are the ones from the header.
+async fn handle_connection<S>(
+    client_conn: S,
+    client_addr: SocketAddr,
+    local_addr: SocketAddr,
+    state: Arc<RwLock<ProxyState>>,
+    mut shutdown: Shutdown,
+) where
+    S: AsyncRead + AsyncWrite + Splice + Unpin,
+{
+    let client_ip = client_addr.ip().to_string();
     log::info!("Connection received from {}", client_ip);
+    // (So that whatever is read past the end of a request can be read again as the next one)
+    let mut client_conn = http2::Rewind::new(Vec::new(), client_conn);
+    let ConnectionSettings {
+        stats,
+        access_log,
+        request_limits,
+        byte_limiter,
+        circuit_breaker,
+        outlier_detector,
+        slow_request_threshold,
+        injection,
+        expose_timing_header,
+        header_rules,
+        compressor,
+        error_pages,
+        from_trusted_proxy,
+        listen_proto,
+        client_allowed,
+        override_allowed,
+        client_idle_timeout,
+        client_header_timeout,
+        max_body_size,
+        header_limits,
+        normalize_paths,
+        reject_suspicious_paths,
+        auth_rules,
+        sticky_sessions,
+        upstream_settings,
+        upstream_pool,
+        response_cache,
+        shadow,
+    } = ConnectionSettings::new(&*state.read().await, client_addr, local_addr);
+    let _connection_guard = stats.connection_opened();
 
-    // Open a connection to a random destination server
-    let mut upstream_conn = match connect_to_upstream(Arc::clone(&state)).await {
-        Ok(stream) => stream,
-        Err(_error) => {
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
-        }
-    };
-
-    let upstream_ip = client_conn.peer_addr().unwrap().ip().to_string();
+    // The upstream connection is opened once the first request arrives (and replaced if a later
+    // request is routed to different upstreams, or the upstream didn't keep it open)
+    let mut upstream_conn: Option<UpstreamConnection> = None;
+    let mut upstream_ip = String::new();
+    // Whether the upstream connection can carry another request (for this client or another one)
+    let mut upstream_reusable = true;
 
     // The client may now send us one or more requests. Keep trying to read requests until the
     // client hangs up or we get an error.
     loop {
-        // Read a request from the client
-        let mut request = match request::read_from_stream(&mut client_conn).await {
-            Ok(request) => request,
+        // Read a request from the client, unless balancebeam is shutting down (in which case
+        // there's no point waiting for one)
+        if shutdown.is_shutdown() {
+            log::debug!("Shutting down; closing connection from {}", client_ip);
+            return;
+        }
+        let high_water = upstream_settings.body_high_water_mark;
+        let read = request::read_from_stream(
+            &mut client_conn,
+            client_idle_timeout,
+            client_header_timeout,
+            high_water,
+            max_body_size,
+            header_limits,
+        );
+        let result = tokio::select! {
+            result = read => result,
+            _ = shutdown.recv() => {
+                log::debug!("Shutting down; closing idle connection from {}", client_ip);
+                return;
+            }
+        };
+        let (mut request, unread_body) = match result {
+            Ok((request, unread_body, leftover)) => {
+                client_conn.give_back(leftover);
+                (request, unread_body)
+            }
             // Handle case where client closed connection and is no longer sending requests
-            Err(request::Error::IncompleteRequest(0)) => {
+            Err(ProxyError::Client(ClientError::IncompleteRequest(0))) => {
                 log::debug!("Client finished sending requests. Shutting down connection");
+                if let (Some(pool), Some(conn)) = (&upstream_pool, upstream_conn) {
+                    if upstream_reusable {
+                        pool.put(&conn.upstream, conn.stream);
+                    }
+                }
                 return;
             }
-            // Handle I/O error in reading from the client
-            Err(request::Error::ConnectionError(io_err)) => {
-                log::info!("Error reading request from client stream: {}", io_err);
+            // Handle case where the client went quiet between requests
+            Err(ProxyError::Client(ClientError::HeaderTimeout(0))) => {
+                log::debug!(
+                    "Connection from {} was idle for too long; closing it",
+                    client_ip
+                );
+                if let (Some(pool), Some(conn)) = (&upstream_pool, upstream_conn) {
+                    if upstream_reusable {
+                        pool.put(&conn.upstream, conn.stream);
+                    }
+                }
                 return;
             }
             Err(error) => {
-                log::debug!("Error parsing request: {:?}", error);
-                let response = response::make_http_error(match error {
-                    request::Error::IncompleteRequest(_)
-                    | request::Error::MalformedRequest(_)
-                    | request::Error::InvalidContentLength
-                    | request::Error::ContentLengthMismatch => http::StatusCode::BAD_REQUEST,
-                    request::Error::RequestBodyTooLarge => http::StatusCode::PAYLOAD_TOO_LARGE,
-                    request::Error::ConnectionError(_) => http::StatusCode::SERVICE_UNAVAILABLE,
-                    request::Error::NoValidUpstreamServer => unreachable!(),
-                });
-                send_response(&mut client_conn, &response).await;
+                let level = error.log_level();
+                // Requests that are too slow or too big are left partly unread, so their
+                // connections can't be used for another request
+                let close = match &error {
+                    // Handle case where the client is trickling a request in too slowly (or has
+                    // stalled partway through one). The connection is closed rather than left to it
+                    ProxyError::Client(ClientError::HeaderTimeout(_))
+                    | ProxyError::Client(ClientError::BodyTimeout) => {
+                        log::log!(level, "Timed out waiting for a request from {}", client_ip);
+                        true
+                    }
+                    ProxyError::Client(ClientError::RequestBodyTooLarge) => {
+                        log::log!(
+                            level,
+                            "Refusing a request from {} with a body over {} bytes",
+                            client_ip,
+                            max_body_size.unwrap()
+                        );
+                        true
+                    }
+                    ProxyError::Client(ClientError::RequestHeadersTooLarge) => {
+                        log::log!(
+                            level,
+                            "Refusing a request from {} with headers over {} bytes or {} headers",
+                            client_ip,
+                            header_limits.max_bytes,
+                            header_limits.max_headers
+                        );
+                        true
+                    }
+                    // Handle I/O error in reading from the client
+                    ProxyError::Client(ClientError::ConnectionError(io_err)) => {
+                        log::log!(
+                            level,
+                            "Error reading request from client stream: {}",
+                            io_err
+                        );
+                        true
+                    }
+                    error => {
+                        log::log!(
+                            level,
+                            "Error parsing request from {}: {:?}",
+                            client_ip,
+                            error
+                        );
+                        false
+                    }
+                };
+                let status = match error.status() {
+                    Some(status) => status,
+                    None => return,
+                };
+                let mut response = error_pages.response(status, None);
+                if close {
+                    response::set_connection_close(&mut response);
+                }
+                send_response(&mut client_conn, &client_ip, &response, &stats).await;
+                if close {
+                    return;
+                }
                 continue;
             }
         };
-        log::info!(
-            "{} -> {}: {}",
+        let request_id = request_id::assign(&mut request, from_trusted_proxy);
+        let access_entry = access_log::Entry::new(&client_ip, &request);
+        // Whether to keep the client connection open after this request, as far as the client is
+        // concerned (it can still end up closed, e.g. if the response ends at close)
+        let client_keep_alive = headers::is_persistent(request.headers(), request.version());
+        let client_version = request.version();
+        if !client_allowed {
+            log::info!(
+                "[{}] Refusing {} from {}: not allowed by --allow/--deny",
+                request_id,
+                request::format_request_line(&request),
+                client_ip
+            );
+            let mut response = error_pages.response(http::StatusCode::FORBIDDEN, Some(&request_id));
+            response::set_connection_close(&mut response);
+            request_id::set(response.headers_mut(), &request_id);
+            let response_bytes =
+                send_response(&mut client_conn, &client_ip, &response, &stats).await;
+            access_log.record(&access_entry, None, response.status(), response_bytes);
+            return;
+        }
+        // The path is normalized before anything goes by it (so that e.g. /api//admin can't get
+        // around a route or rate limit for /api/admin). The access log keeps the original. Paths
+        // that can't be normalized are always refused when there are auth rules, since there's no
+        // telling which rule they fall under
+        let check_path = normalize_paths || reject_suspicious_paths || !auth_rules.is_empty();
+        let suspicious_path = if check_path {
+            match normalize::normalize(request.uri().path()) {
+                Ok(None) => None,
+                Ok(Some(_)) if !normalize_paths && !reject_suspicious_paths => None,
+                Ok(Some(path)) if !reject_suspicious_paths => {
+                    log::debug!(
+                        "[{}] Normalized path {} to {}",
+                        request_id,
+                        request.uri().path(),
+                        path
+                    );
+                    normalize::set_path(&mut request, &path);
+                    None
+                }
+                Ok(Some(_)) => Some("it isn't normalized"),
+                Err(problem) => Some(problem),
+            }
+        } else {
+            None
+        };
+        if let Some(problem) = suspicious_path {
+            log::info!(
+                "[{}] Refusing {} from {}: suspicious path ({})",
+                request_id,
+                request::format_request_line(&request),
+                client_ip,
+                problem
+            );
+            let keep_alive = client_keep_alive && unread_body.is_none();
+            let mut response =
+                error_pages.response(http::StatusCode::BAD_REQUEST, Some(&request_id));
+            response::set_connection_header(&mut response, keep_alive, client_version);
+            request_id::set(response.headers_mut(), &request_id);
+            let response_bytes =
+                send_response(&mut client_conn, &client_ip, &response, &stats).await;
+            access_log.record(&access_entry, None, response.status(), response_bytes);
+            if !keep_alive {
+                return;
+            }
+            continue;
+        }
+        // A rejected request is answered with headers saying when the client can come back
+        let rate_limit = match request_limits
+            .as_ref()
+            .and_then(|limits| limits.limiter_for(request.uri().path()))
+        {
+            Some((prefix, limiter)) => Some((prefix, limiter.try_acquire(&client_ip).await)),
+            None => None,
+        };
+        let over_limit = match rate_limit {
+            Some((prefix, status)) if !status.allowed => {
+                let limit = match prefix {
+                    Some(prefix) => format!("request limit for {}", prefix),
+                    None => "request limit".to_string(),
+                };
+                Some((limit, status.headers()))
+            }
+            _ => byte_limiter
+                .as_ref()
+                .and_then(|limiter| limiter.exhausted_for(&client_ip))
+                .map(|wait| {
+                    let retry_after = rate_limit::whole_seconds(wait).to_string();
+                    ("byte budget".to_string(), vec![("retry-after", retry_after)])
+                }),
+        };
+        if let Some((limit, limit_headers)) = over_limit {
+            log::info!(
+                "[{}] {} is over its {}; rejecting {}",
+                request_id,
+                client_ip,
+                limit,
+                request::format_request_line(&request)
+            );
+            stats.record_rate_limited();
+            // (If the rest of the body is still on its way, we can't find the next request)
+            let keep_alive = client_keep_alive && unread_body.is_none();
+            let mut response = error_pages.response_with_headers(
+                http::StatusCode::TOO_MANY_REQUESTS,
+                Some(&request_id),
+                &limit_headers,
+            );
+            response::set_connection_header(&mut response, keep_alive, client_version);
+            request_id::set(response.headers_mut(), &request_id);
+            let response_bytes =
+                send_response(&mut client_conn, &client_ip, &response, &stats).await;
+            access_log.record(&access_entry, None, response.status(), response_bytes);
+            if !keep_alive {
+                return;
+            }
+            continue;
+        }
+        // (Checked before the cache, which mustn't hand protected responses to anyone who asks)
+        if let Err(denied) = auth_rules.authorize(&mut request) {
+            log::info!(
+                "[{}] Refusing {} from {}: no valid credentials for {}",
+                request_id,
+                request::format_request_line(&request),
+                client_ip,
+                if denied.prefix.is_empty() {
+                    "/"
+                } else {
+                    denied.prefix
+                }
+            );
+            let keep_alive = client_keep_alive && unread_body.is_none();
+            let mut response = error_pages.response_with_headers(
+                http::StatusCode::UNAUTHORIZED,
+                Some(&request_id),
+                &denied.challenges,
+            );
+            response::set_connection_header(&mut response, keep_alive, client_version);
+            request_id::set(response.headers_mut(), &request_id);
+            let response_bytes =
+                send_response(&mut client_conn, &client_ip, &response, &stats).await;
+            access_log.record(&access_entry, None, response.status(), response_bytes);
+            if !keep_alive {
+                return;
+            }
+            continue;
+        }
+        // (Worked out now, before the request's headers are rewritten for the upstream)
+        let encoding = compressor
+            .as_ref()
+            .and_then(|_| compression::negotiate(request.headers()));
+        let cached = match (&response_cache, &unread_body) {
+            (Some(cache), None) => cache.get(&request),
+            _ => None,
+        };
+        if let Some(mut response) = cached {
+            log::debug!(
+                "[{}] {} answered from cache: {}",
+                request_id,
+                client_ip,
+                request::format_request_line(&request)
+            );
+            if let Some((snippet, max_body_size)) = &injection {
+                response::inject_before_body_end(&mut response, snippet, *max_body_size);
+            }
+            if let (Some(compressor), Some(encoding)) = (&compressor, encoding) {
+                compressor.compress(&mut response, encoding);
+            }
+            request_id::set(response.headers_mut(), &request_id);
+            headers::apply(&header_rules.response, response.headers_mut());
+            response::set_connection_header(&mut response, client_keep_alive, client_version);
+            let response_bytes =
+                send_response(&mut client_conn, &client_ip, &response, &stats).await;
+            access_log.record(&access_entry, None, response.status(), response_bytes);
+            if let Some(limiter) = &byte_limiter {
+                limiter.record(&client_ip, response_bytes);
+            }
+            if !client_keep_alive {
+                return;
+            }
+            continue;
+        }
+
+        // The request timeout covers everything from here until the response has been read
+        let deadline = upstream_settings
+            .request_timeout
+            .map(|timeout| tokio::time::Instant::now() + timeout);
+
+        // Find the upstreams that serve this request (or the one an upstream override header
+        // names), and connect to one of them (unless the upstream connection we already have goes
+        // to one, and is still open, and its upstream hasn't been drained since, or the client's
+        // sticky cookie names another)
+        let forced = upstream_override::take(request.headers_mut(), override_allowed);
+        let sticky = if sticky_sessions && forced.is_none() {
+            sticky::requested(request.headers())
+        } else {
+            None
+        };
+        let (unrouted, upstreams, drained) = {
+            let state_read = state.read().await;
+            let drained = forced.is_none()
+                && upstream_conn
+                    .as_ref()
+                    .is_some_and(|conn| state_read.drained_upstreams.contains(&conn.upstream));
+            match &forced {
+                Some(upstream) => (false, state_read.override_addresses(upstream), drained),
+                None => {
+                    let routed = state_read.routes.upstreams_for(&request);
+                    (routed.is_empty(), state_read.addresses_for(routed), drained)
+                }
+            }
+        };
+        let pick = match (&forced, &sticky) {
+            (Some(_), _) => Pick::Forced,
+            (None, Some(value)) => Pick::Sticky(value),
+            (None, None) => Pick::Strategy,
+        };
+        let connected = upstream_reusable
+            && !drained
+            && upstream_conn.as_ref().is_some_and(|conn| {
+                upstreams.contains(&conn.upstream)
+                    && sticky
+                        .as_ref()
+                        .is_none_or(|value| *value == sticky::cookie_value(&conn.upstream))
+            });
+        let error_status = if unrouted {
+            log::info!(
+                "[{}] No upstreams for {}; responding 404",
+                request_id,
+                request::format_request_line(&request)
+            );
+            Some(http::StatusCode::NOT_FOUND)
+        } else if let (Some(upstream), true) = (&forced, upstreams.is_empty()) {
+            log::info!(
+                "[{}] {} names {}, which is not an upstream; responding 400",
+                request_id,
+                upstream_override::HEADER_NAME,
+                upstream
+            );
+            Some(http::StatusCode::BAD_REQUEST)
+        } else if connected {
+            None
+        } else {
+            if let (Some(pool), Some(conn)) = (&upstream_pool, upstream_conn.take()) {
+                if upstream_reusable && !drained {
+                    pool.put(&conn.upstream, conn.stream);
+                }
+            }
+            let connection = connect_to_upstream(
+                Arc::clone(&state),
+                client_addr.ip(),
+                &upstreams,
+                &[],
+                upstream_settings.proxy_header.as_deref(),
+                pick,
+            );
+            match timeout::before_deadline(deadline, connection).await {
+                Some(Ok(connection)) => {
+                    upstream_ip = connection.upstream.clone();
+                    upstream_conn = Some(connection);
+                    upstream_reusable = true;
+                    None
+                }
+                Some(Err(error)) => {
+                    log::log!(
+                        error.log_level(),
+                        "[{}] Failed to connect to an upstream for {} {}: {:?}",
+                        request_id,
+                        client_ip,
+                        request::format_request_line(&request),
+                        error
+                    );
+                    error.status()
+                }
+                None => {
+                    let error = ProxyError::Timeout(Timeout::Request);
+                    log::log!(
+                        error.log_level(),
+                        "[{}] Request timeout ran out while connecting to an upstream for {} {}",
+                        request_id,
+                        client_ip,
+                        request::format_request_line(&request)
+                    );
+                    error.status()
+                }
+            }
+        };
+        if let Some(status) = error_status {
+            let keep_alive = client_keep_alive && unread_body.is_none();
+            let mut response = error_pages.response(status, Some(&request_id));
+            response::set_connection_header(&mut response, keep_alive, client_version);
+            request_id::set(response.headers_mut(), &request_id);
+            let response_bytes =
+                send_response(&mut client_conn, &client_ip, &response, &stats).await;
+            access_log.record(&access_entry, None, status, response_bytes);
+            if !keep_alive {
+                return;
+            }
+            continue;
+        }
+        let upstream_conn = upstream_conn.as_mut().unwrap();
+        log::debug!(
+            "[{}] {} -> {}: {}",
+            request_id,
             client_ip,
             upstream_ip,
             request::format_request_line(&request)
         );
+        let request_start = Instant::now();
 
-        // Add X-Forwarded-For header so that the upstream server knows the client's IP address.
-        // (We're the ones connecting directly to the upstream server, so without this header, the
-        // upstream server will only know our IP, not the client's.)
-        request::extend_header_value(&mut request, "x-forwarded-for", &client_ip);
-
-        // Forward the request to the server
-        if let Err(error) = request::write_to_stream(&request, &mut upstream_conn).await {
-            log::error!(
-                "Failed to send request to upstream {}: {}",
-                upstream_ip,
-                error
-            );
-            let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-            send_response(&mut client_conn, &response).await;
-            return;
+        // The client's Connection header (and the headers it names) only applies to its own
+        // connection. Without them, the upstream connection is kept open as usual for HTTP/1.1
+        headers::remove_hop_by_hop(request.headers_mut());
+        // The upstream connection is ours, so it speaks HTTP/1.1 even for an HTTP/1.0 client
+        // (whose response is put back into terms it understands below). HTTP/1.1 requires a Host
+        if request.version() == http::Version::HTTP_10 {
+            *request.version_mut() = http::Version::HTTP_11;
+            if !request.headers().contains_key("host") {
+                let host = health_check_host(&upstream_conn.configured);
+                request
+                    .headers_mut()
+                    .insert("host", http::HeaderValue::from_str(host).unwrap());
+            }
+        }
+        // Add X-Forwarded-For (and friends) so that the upstream server knows the client's IP
+        // address. (We're the ones connecting directly to the upstream server, so without this
+        // header, the upstream server will only know our IP, not the client's.)
+        forwarded::add_forwarding_headers(
+            &mut request,
+            client_addr.ip(),
+            from_trusted_proxy,
+            listen_proto,
+            local_addr.port(),
+        );
+        headers::apply(&header_rules.request, request.headers_mut());
+        if let (Some(shadow), None) = (&shadow, &unread_body) {
+            shadow.maybe_mirror(&request, &request_id);
         }
-        log::debug!("Forwarded request to server");
 
-        // Read the server's response
-        let response = match response::read_from_stream(&mut upstream_conn, request.method()).await
-        {
-            Ok(response) => response,
+        // Forward the request to the server, and read its response. If the upstream connection
+        // dies along the way, an idempotent request (whose body we still have all of) can be sent
+        // to another upstream instead.
+        let can_retry = unread_body.is_none() && request.method().is_idempotent();
+        let mut unread_body = unread_body;
+        let mut tried_upstreams = Vec::new();
+        let result = loop {
+            // (The client side is only read from for a streamed request body)
+            let mut client_reader =
+                timeout::IdleTimeout::new(&mut client_conn, client_idle_timeout);
+            let forward = forward_request(
+                upstream_conn,
+                &request,
+                unread_body.take(),
+                &mut client_reader,
+                &upstream_settings,
+                &request_id,
+            );
+            let result = timeout::before_deadline(deadline, forward)
+                .await
+                .unwrap_or(Err(ForwardError::Timeout));
+            if !matches!(
+                result,
+                Err(ForwardError::Send(_)) | Err(ForwardError::RequestBody(_))
+            ) {
+                log::debug!("[{}] Forwarded request to server", request_id);
+                stats.record_forwarded(&upstream_ip);
+            }
+            // (A broken request body is the client's doing, not the upstream's)
+            if let Err(error) = &result {
+                if !matches!(error, ForwardError::RequestBody(_)) {
+                    stats.record_upstream_error(&upstream_ip);
+                    if let Some(breaker) = &circuit_breaker {
+                        breaker.record_failure(&upstream_conn.upstream);
+                    }
+                    if let Some(detector) = &outlier_detector {
+                        detector.record(&upstream_conn.upstream, true);
+                    }
+                }
+            }
+            let connection_died = result
+                .as_ref()
+                .err()
+                .is_some_and(ForwardError::is_connection_failure);
+            let retries_left = tried_upstreams.len() < upstream_settings.max_retries;
+            if !(connection_died && can_retry && retries_left) {
+                break result;
+            }
+            tried_upstreams.push(upstream_conn.upstream.clone());
+            let connection = connect_to_upstream(
+                Arc::clone(&state),
+                client_addr.ip(),
+                &upstreams,
+                &tried_upstreams,
+                upstream_settings.proxy_header.as_deref(),
+                pick,
+            );
+            match timeout::before_deadline(deadline, connection).await {
+                Some(Ok(connection)) => {
+                    log::warn!(
+                        "[{}] Connection to upstream {} died while forwarding {}; retrying on {} \
+                        (retry {} of {})",
+                        request_id,
+                        upstream_ip,
+                        request::format_request_line(&request),
+                        connection.upstream,
+                        tried_upstreams.len(),
+                        upstream_settings.max_retries
+                    );
+                    *upstream_conn = connection;
+                    upstream_ip = upstream_conn.upstream.clone();
+                }
+                Some(Err(_)) => break result,
+                None => break Err(ForwardError::Timeout),
+            }
+        };
+        let (request_bytes, mut response, mut unread_response) = match result {
+            Ok(exchange) => {
+                stats.record_bytes_in(exchange.0);
+                exchange
+            }
             Err(error) => {
-                log::error!("Error reading response from server: {:?}", error);
-                let response = response::make_http_error(http::StatusCode::BAD_GATEWAY);
-                send_response(&mut client_conn, &response).await;
+                let error = ProxyError::from(error);
+                let level = error.log_level();
+                match &error {
+                    // Handle case where the client stalled partway through a streamed request
+                    // body
+                    ProxyError::Client(ClientError::BodyTimeout) => log::log!(
+                        level,
+                        "[{}] Client {} stopped sending the request body for more than {}ms",
+                        request_id,
+                        client_ip,
+                        client_idle_timeout.unwrap().as_millis()
+                    ),
+                    // Handle case where a streamed chunked request body went over the limit
+                    ProxyError::Client(ClientError::RequestBodyTooLarge) => log::log!(
+                        level,
+                        "[{}] Cut off a request from {} with a body over {} bytes",
+                        request_id,
+                        client_ip,
+                        max_body_size.unwrap()
+                    ),
+                    // Handle case where the client hung up partway through a streamed request body
+                    ProxyError::Client(ClientError::ConnectionError(io_err)) => log::log!(
+                        level,
+                        "[{}] Error reading request body from client stream: {}",
+                        request_id,
+                        io_err
+                    ),
+                    ProxyError::Client(error) => log::log!(
+                        level,
+                        "[{}] Error in streamed request body: {:?}",
+                        request_id,
+                        error
+                    ),
+                    // Handle case where the upstream hung up before sending a full set of headers
+                    // (e.g. it closed the connection right after the status line, or without
+                    // sending anything)
+                    ProxyError::UpstreamProtocol(UpstreamError::IncompleteResponse(bytes_read)) => {
+                        log::log!(
+                            level,
+                            "[{}] Upstream {} closed connection prematurely while handling {} {} \
+                            ({} bytes of response received)",
+                            request_id,
+                            upstream_ip,
+                            request.method(),
+                            request.uri().path(),
+                            bytes_read
+                        )
+                    }
+                    ProxyError::UpstreamProtocol(UpstreamError::ConnectionError(io_err)) => {
+                        log::log!(
+                            level,
+                            "[{}] Connection to upstream {} failed while handling {} {}: {}",
+                            request_id,
+                            upstream_ip,
+                            request.method(),
+                            request.uri().path(),
+                            io_err
+                        )
+                    }
+                    ProxyError::UpstreamProtocol(error) => log::log!(
+                        level,
+                        "[{}] Error reading response from upstream {} for {} {}: {:?}",
+                        request_id,
+                        upstream_ip,
+                        request.method(),
+                        request.uri().path(),
+                        error
+                    ),
+                    // Handle case where the upstream is connected but stalled partway through (or
+                    // before) its headers
+                    ProxyError::Timeout(Timeout::Headers(bytes_read)) => log::log!(
+                        level,
+                        "[{}] Upstream {} did not send complete headers within {}ms while \
+                        handling {} {} ({} bytes of headers received)",
+                        request_id,
+                        upstream_ip,
+                        upstream_settings.header_timeout.unwrap().as_millis(),
+                        request.method(),
+                        request.uri().path(),
+                        bytes_read
+                    ),
+                    ProxyError::Timeout(Timeout::Body) => log::log!(
+                        level,
+                        "[{}] Upstream {} stopped sending the response body for more than {}ms \
+                        while handling {} {}",
+                        request_id,
+                        upstream_ip,
+                        upstream_settings.body_timeout.unwrap().as_millis(),
+                        request.method(),
+                        request.uri().path()
+                    ),
+                    ProxyError::Timeout(_) => log::log!(
+                        level,
+                        "[{}] Upstream {} did not answer {} {} within the request timeout \
+                        ({}ms)",
+                        request_id,
+                        upstream_ip,
+                        request.method(),
+                        request.uri().path(),
+                        upstream_settings.request_timeout.unwrap().as_millis()
+                    ),
+                    // (forward_request doesn't connect)
+                    ProxyError::UpstreamConnect => unreachable!(),
+                }
+                let status = error.status();
+                if let Some(status) = status {
+                    let mut response = error_pages.response(status, Some(&request_id));
+                    response::set_connection_close(&mut response);
+                    request_id::set(response.headers_mut(), &request_id);
+                    let response_bytes =
+                        send_response(&mut client_conn, &client_ip, &response, &stats).await;
+                    access_log.record(&access_entry, Some(&upstream_ip), status, response_bytes);
+                }
                 return;
             }
         };
+        upstream_reusable = response::connection_reusable(&response, request.method());
+        let ends_at_close = response::ends_at_close(&response, request.method());
+        let upstream_duration = request_start.elapsed();
+        stats.record_upstream_latency(&upstream_ip, upstream_duration);
+        upstream_conn.latency.observe(upstream_duration);
+        if let Some(breaker) = &circuit_breaker {
+            breaker.record_response(
+                &upstream_conn.upstream,
+                response.status(),
+                upstream_duration,
+            );
+        }
+        if response.status().is_server_error() {
+            stats.record_upstream_error(&upstream_ip);
+        }
+        if let Some(detector) = &outlier_detector {
+            detector.record(&upstream_conn.upstream, response.status().is_server_error());
+        }
+        // (Streamed bodies are too big to cache, and we don't have all of them anyway)
+        if let (Some(cache), None) = (&response_cache, &unread_response) {
+            cache.put(&request, &response);
+        }
+        // (After caching, since the cookie is just for this client)
+        if sticky_sessions && forced.is_none() {
+            let upstream = &upstream_conn.upstream;
+            if sticky.as_deref() != Some(sticky::cookie_value(upstream).as_str()) {
+                sticky::set_cookie(&mut response, upstream);
+            }
+        }
+        if expose_timing_header {
+            // https://www.w3.org/TR/server-timing/; append so any upstream entries are kept
+            let timing = format!(
+                "upstream;dur={:.1}",
+                upstream_duration.as_secs_f64() * 1000.0
+            );
+            response
+                .headers_mut()
+                .append("server-timing", http::HeaderValue::from_str(&timing).unwrap());
+        }
+        // (A streamed body is too big to inject into, and we don't have all of it anyway)
+        if let (Some((snippet, max_body_size)), None) = (&injection, &unread_response) {
+            if response::inject_before_body_end(&mut response, snippet, *max_body_size) {
+                log::debug!("[{}] Injected snippet into HTML response", request_id);
+            }
+        }
+        // (A streamed body would have to be compressed as it goes, which isn't supported)
+        if let (Some(compressor), Some(encoding)) = (&compressor, encoding) {
+            if unread_response.is_none() && compressor.compress(&mut response, encoding) {
+                log::debug!("[{}] Compressed response with {:?}", request_id, encoding);
+            }
+        }
+        // Likewise, the upstream's Connection header is about the upstream connection. The client
+        // is told whether its own connection stays open instead (in balancebeam's HTTP version,
+        // whatever the upstream spoke)
+        headers::remove_hop_by_hop(response.headers_mut());
+        *response.version_mut() = http::Version::HTTP_11;
+        // An HTTP/1.0 client doesn't understand chunked bodies. One that was read in full already
+        // has a Content-Length instead, but one that's still on its way has to be sent without its
+        // framing, ending when the connection closes
+        let unchunked = client_version == http::Version::HTTP_10
+            && matches!(unread_response, Some(Unread::Chunked(..)));
+        if unchunked {
+            let unread = unread_response.take().unwrap();
+            let mut body = std::mem::take(response.body_mut());
+            unread_response = Some(body::unchunk(response.headers_mut(), &mut body, unread));
+            *response.body_mut() = body;
+        }
+        let keep_alive = client_keep_alive && !ends_at_close && !unchunked;
+        if response.status() != http::StatusCode::SWITCHING_PROTOCOLS {
+            response::set_connection_header(&mut response, keep_alive, client_version);
+        }
+        request_id::set(response.headers_mut(), &request_id);
+        headers::apply(&header_rules.response, response.headers_mut());
         // Forward the response to the client
-        send_response(&mut client_conn, &response).await;
-        log::debug!("Forwarded response to client");
+        let mut response_bytes =
+            send_response(&mut client_conn, &client_ip, &response, &stats).await;
+        if response.status() == http::StatusCode::SWITCHING_PROTOCOLS {
+            if response_bytes == 0 {
+                return;
+            }
+            // The connection doesn't speak HTTP anymore (it's WebSocket or the like), so stop
+            // parsing requests and just pass bytes along until both sides are done
+            log::debug!(
+                "[{}] Upstream switched protocols; tunneling the rest of the connection",
+                request_id
+            );
+            let tunnel = tunnel::copy_bidirectional(&mut client_conn, &mut upstream_conn.stream);
+            let (sent, received) = match tunnel.await {
+                Ok((sent, received)) => (sent as usize, received as usize),
+                Err(error) => {
+                    log::info!(
+                        "[{}] Tunnel to upstream {} closed: {}",
+                        request_id,
+                        upstream_ip,
+                        error
+                    );
+                    (0, 0)
+                }
+            };
+            response_bytes += received;
+            stats.record_bytes_in(sent);
+            stats.record_bytes_out(received);
+            access_log.record(
+                &access_entry,
+                Some(&upstream_ip),
+                response.status(),
+                response_bytes,
+            );
+            if let Some(limiter) = &byte_limiter {
+                limiter.record(&client_ip, request_bytes + sent + response_bytes);
+            }
+            return;
+        }
+        if let Some(unread) = unread_response {
+            if response_bytes == 0 {
+                return;
+            }
+            log::debug!(
+                "[{}] Streaming the rest of the response body to client",
+                request_id
+            );
+            let body_timeout = upstream_settings.body_timeout;
+            let mut upstream_stream =
+                timeout::IdleTimeout::new(&mut upstream_conn.stream, body_timeout);
+            match splice::copy(unread, &mut upstream_stream, &mut client_conn).await {
+                Ok(bytes_written) => {
+                    stats.record_bytes_out(bytes_written);
+                    response_bytes += bytes_written;
+                }
+                Err(error) => {
+                    log::warn!(
+                        "[{}] Failed to stream response body from upstream {} to client: {:?}",
+                        request_id,
+                        upstream_ip,
+                        error
+                    );
+                    access_log.record(
+                        &access_entry,
+                        Some(&upstream_ip),
+                        response.status(),
+                        response_bytes,
+                    );
+                    return;
+                }
+            }
+        }
+        log::debug!("[{}] Forwarded response to client", request_id);
+        access_log.record(
+            &access_entry,
+            Some(&upstream_ip),
+            response.status(),
+            response_bytes,
+        );
+        if let Some(limiter) = &byte_limiter {
+            limiter.record(&client_ip, request_bytes + response_bytes);
+        }
+
+        let latency = request_start.elapsed();
+        if let Some(threshold) = slow_request_threshold {
+            if latency > threshold {
+                log::warn!(
+                    "[{}] Slow request: {} {} via upstream {} took {}ms (threshold {}ms)",
+                    request_id,
+                    request.method(),
+                    request.uri().path(),
+                    upstream_ip,
+                    latency.as_millis(),
+                    threshold.as_millis()
+                );
+            }
+        }
+        if !keep_alive {
+            log::debug!(
+                "[{}] Closing connection from {} after its last request",
+                request_id,
+                client_ip
+            );
+            return;
+        }
     }
 }
@@ -0,0 +1,134 @@
+/// Most times a segment is percent-decoded while looking for traversal sequences, to catch ones
+/// encoded more than once (e.g. `%252e%252e`)
+const MAX_DECODES: usize = 3;
+
+/// Normalizes a request path (collapsing repeated slashes and resolving `.` and `..` segments, as
+/// the upstream would), so that routing, rate limiting and the upstream all see the same path.
+/// `..` segments that would go above the root are dropped.
+///
+/// Returns Ok(None) if the path is already normal, and Ok(Some(path)) with the normalized path
+/// otherwise. Paths with traversal sequences hidden in percent-encoding (encoded `.`/`..`
+/// segments, or encoded slashes or backslashes), which the upstream might decode into a path
+/// balancebeam never saw, can't be normalized; a description of the problem is returned for them.
+pub fn normalize(path: &str) -> Result<Option<String>, &'static str> {
+    if !path.starts_with('/') {
+        // (e.g. OPTIONS *)
+        return Ok(None);
+    }
+    let mut segments: Vec<&str> = Vec::new();
+    let mut changed = false;
+    let raw_segments: Vec<&str> = path[1..].split('/').collect();
+    for (i, segment) in raw_segments.iter().enumerate() {
+        check_encoding(segment)?;
+        let last = i + 1 == raw_segments.len();
+        match *segment {
+            // (A trailing slash is kept: /dir/ and /dir can be different resources)
+            "" if last => segments.push(""),
+            "" | "." => changed = true,
+            ".." => {
+                segments.pop();
+                changed = true;
+            }
+            segment => segments.push(segment),
+        }
+    }
+    if !changed {
+        return Ok(None);
+    }
+    // `..` or `.` as the last segment names a directory, so keeps its slash
+    if matches!(raw_segments.last(), Some(&".") | Some(&"..")) && segments.last() != Some(&"") {
+        segments.push("");
+    }
+    Ok(Some(format!("/{}", segments.join("/"))))
+}
+
+/// The path the upstream will most likely take a request path to be: normalized, with
+/// percent-escapes of unreserved characters decoded (so /%61dmin is /admin, and /%2e%2e/ is /../).
+/// Access checks go by this whether or not --normalize-paths is on, since the upstream resolves
+/// these paths either way. Fails for the same paths as normalize().
+pub fn canonical(path: &str) -> Result<String, &'static str> {
+    let decoded = decode_unreserved(path);
+    Ok(normalize(&decoded)?.unwrap_or(decoded))
+}
+
+/// Replaces the path of a request's URI, keeping its query (and scheme and authority, if any).
+pub fn set_path(request: &mut http::Request<Vec<u8>>, path: &str) {
+    let mut parts = request.uri().clone().into_parts();
+    let path_and_query = match request.uri().query() {
+        Some(query) => format!("{}?{}", path, query),
+        None => path.to_string(),
+    };
+    // (The path came from a valid URI, and normalizing it only takes characters out)
+    parts.path_and_query = Some(path_and_query.parse().unwrap());
+    *request.uri_mut() = http::Uri::from_parts(parts).unwrap();
+}
+
+/// Checks a path segment for traversal sequences hidden in percent-encoding.
+fn check_encoding(segment: &str) -> Result<(), &'static str> {
+    let mut decoded = segment.to_string();
+    for _ in 0..MAX_DECODES {
+        if !decoded.contains('%') {
+            break;
+        }
+        decoded = percent_decode(&decoded);
+        if decoded.contains('/') || decoded.contains('\\') {
+            return Err("encoded slash or backslash");
+        }
+        if decoded == "." || decoded == ".." {
+            return Err("encoded dot segment");
+        }
+    }
+    Ok(())
+}
+
+/// Decodes %XX sequences (leaving invalid ones as they are). Bytes that don't make valid UTF-8
+/// are replaced, which is fine for spotting dots and slashes.
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Decodes the %XX sequences that stand for unreserved characters (letters, digits, `-`, `.`, `_`
+/// and `~`), which mean the same encoded or not. Others are left as they are.
+fn decode_unreserved(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = String::with_capacity(path.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = bytes
+            .get(i + 1..i + 3)
+            .filter(|hex| bytes[i] == b'%' && hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match byte {
+            Some(byte) if byte.is_ascii_alphanumeric() || b"-._~".contains(&byte) => {
+                decoded.push(byte as char);
+                i += 3;
+            }
+            _ => {
+                decoded.push(bytes[i] as char);
+                i += 1;
+            }
+        }
+    }
+    decoded
+}
@@ -0,0 +1,178 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often the counts are rolled over and looked at for outliers
+pub const ANALYSIS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// An upstream that keeps getting ejected stays out twice as long each time, up to this many
+/// doublings of the base ejection time
+const MAX_BACKOFF_DOUBLINGS: u32 = 4;
+
+#[derive(Default, Clone, Copy)]
+struct Counts {
+    responses: usize,
+    errors: usize,
+}
+
+impl Counts {
+    fn add(&mut self, other: Counts) {
+        self.responses += other.responses;
+        self.errors += other.errors;
+    }
+
+    fn error_rate(&self) -> f64 {
+        self.errors as f64 / self.responses as f64
+    }
+}
+
+#[derive(Default)]
+struct UpstreamRecord {
+    /// Responses since the last analysis
+    current: Counts,
+    /// Responses in each analysis interval of the window, oldest first
+    history: VecDeque<Counts>,
+    /// When an ejected upstream is let back in (None = it isn't ejected)
+    ejected_until: Option<Instant>,
+    /// How many times the upstream has been ejected since it last stayed in for a whole window
+    ejections: u32,
+    readmitted_at: Option<Instant>,
+}
+
+/// Takes upstreams whose share of 5xx responses stands out from the rest of the pool out of
+/// rotation for a while. Where the circuit breaker reacts to an upstream failing on its own terms,
+/// this compares upstreams with each other, so an upstream that errors on one request in three
+/// while the others hardly ever do is caught, and a backend outage that makes every upstream fail
+/// alike doesn't empty the pool.
+///
+/// Responses are counted as they come in, and `analyze` (run every ANALYSIS_INTERVAL by its own
+/// task) looks over the last window's worth of them. An ejected upstream is readmitted with a clean
+/// slate after the ejection time, which doubles each time it's ejected again soon after. No more
+/// than half of the upstreams are ever ejected at once.
+pub struct OutlierDetector {
+    /// How far above the rest of the pool's error rate an upstream's has to be to get it ejected
+    threshold: f64,
+    /// Upstreams with fewer responses than this in the window (or pools with fewer besides them)
+    /// aren't judged
+    min_responses: usize,
+    window: Duration,
+    ejection_time: Duration,
+    /// Keyed by upstream address
+    upstreams: Mutex<HashMap<String, UpstreamRecord>>,
+}
+
+impl OutlierDetector {
+    /// `error_percent` is the threshold, in percentage points.
+    pub fn new(
+        error_percent: usize,
+        min_responses: usize,
+        window: Duration,
+        ejection_time: Duration,
+    ) -> OutlierDetector {
+        OutlierDetector {
+            threshold: error_percent as f64 / 100.0,
+            min_responses,
+            window,
+            ejection_time,
+            upstreams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts a response from `upstream`. `is_error` should be true for a 5xx, and for an upstream
+    /// that failed to answer at all.
+    pub fn record(&self, upstream: &str, is_error: bool) {
+        let mut upstreams = self.upstreams.lock().unwrap();
+        let current = &mut upstreams.entry(upstream.to_string()).or_default().current;
+        current.responses += 1;
+        if is_error {
+            current.errors += 1;
+        }
+    }
+
+    pub fn is_ejected(&self, upstream: &str) -> bool {
+        self.upstreams
+            .lock()
+            .unwrap()
+            .get(upstream)
+            .is_some_and(|record| record.ejected_until.is_some())
+    }
+
+    /// Rolls the counts for `upstreams` (every upstream in the pool, dead or alive) over into the
+    /// window, readmits upstreams whose ejection is up, and ejects the ones that stand out now.
+    pub fn analyze(&self, upstreams: &[String]) {
+        let now = Instant::now();
+        let intervals = (self.window.as_millis() / ANALYSIS_INTERVAL.as_millis()).max(1) as usize;
+        let mut records = self.upstreams.lock().unwrap();
+        records.retain(|upstream, _| upstreams.contains(upstream));
+        for upstream in upstreams {
+            let record = records.entry(upstream.clone()).or_default();
+            let counts = std::mem::take(&mut record.current);
+            record.history.push_back(counts);
+            while record.history.len() > intervals {
+                record.history.pop_front();
+            }
+            if record.ejected_until.is_some_and(|until| until <= now) {
+                log::info!("Readmitting upstream {} after its ejection", upstream);
+                record.ejected_until = None;
+                record.history.clear();
+                record.readmitted_at = Some(now);
+            }
+            if record
+                .readmitted_at
+                .is_some_and(|readmitted| now - readmitted >= self.window)
+            {
+                record.ejections = 0;
+                record.readmitted_at = None;
+            }
+        }
+
+        let mut ejected = records
+            .values()
+            .filter(|record| record.ejected_until.is_some())
+            .count();
+        let mut candidates: Vec<(String, Counts)> = records
+            .iter()
+            .filter(|(_, record)| record.ejected_until.is_none())
+            .map(|(upstream, record)| {
+                let mut window = Counts::default();
+                record.history.iter().for_each(|counts| window.add(*counts));
+                (upstream.clone(), window)
+            })
+            .collect();
+        let mut pool = Counts::default();
+        candidates.iter().for_each(|(_, counts)| pool.add(*counts));
+        // Worst first, so that if only some can be ejected, it's those
+        candidates.retain(|(_, counts)| counts.responses >= self.min_responses);
+        candidates.sort_by(|(_, a), (_, b)| b.error_rate().partial_cmp(&a.error_rate()).unwrap());
+        for (upstream, counts) in candidates {
+            if ejected + 1 > upstreams.len() / 2 {
+                break;
+            }
+            let rest = Counts {
+                responses: pool.responses - counts.responses,
+                errors: pool.errors - counts.errors,
+            };
+            if rest.responses < self.min_responses
+                || counts.error_rate() - rest.error_rate() < self.threshold
+            {
+                continue;
+            }
+            let record = records.get_mut(&upstream).unwrap();
+            let ejection_time =
+                self.ejection_time * 2_u32.pow(record.ejections.min(MAX_BACKOFF_DOUBLINGS));
+            record.ejections += 1;
+            record.ejected_until = Some(now + ejection_time);
+            record.readmitted_at = None;
+            ejected += 1;
+            log::warn!(
+                "Ejected upstream {} as an outlier: {:.0}% of its last {} responses were errors, \
+                against {:.0}% for the rest of the pool; readmitting it in {}s",
+                upstream,
+                counts.error_rate() * 100.0,
+                counts.responses,
+                rest.error_rate() * 100.0,
+                ejection_time.as_secs()
+            );
+        }
+    }
+}
@@ -0,0 +1,84 @@
+/// Default for --max-request-header-bytes
+pub const MAX_HEADERS_SIZE: usize = 8000;
+/// Default for --max-request-headers
+pub const MAX_NUM_HEADERS: usize = 32;
+
+/// How big a request's header block (request line included) may be, and how many headers it may
+/// have. Requests over either limit get a 431.
+#[derive(Debug, Clone, Copy)]
+pub struct HeaderLimits {
+    pub max_bytes: usize,
+    pub max_headers: usize,
+}
+
+impl Default for HeaderLimits {
+    fn default() -> HeaderLimits {
+        HeaderLimits {
+            max_bytes: MAX_HEADERS_SIZE,
+            max_headers: MAX_NUM_HEADERS,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// The bytes aren't a valid HTTP request. httparse::Error contains more details
+    Malformed(httparse::Error),
+    /// The header block is bigger, or has more headers, than the limits allow
+    TooLarge,
+    /// The request is valid HTTP, but its URI can't be represented by an http::Uri (e.g. its path
+    /// has non-ASCII bytes in it)
+    InvalidUri,
+}
+
+/// How much of a request head has arrived
+#[derive(Debug)]
+pub enum Parsed {
+    /// Not all of it yet; it's valid so far
+    Partial,
+    /// All of it: the request (with an empty body), and the length of its head. Anything in the
+    /// buffer past that is the start of the body.
+    Complete(Box<http::Request<Vec<u8>>>, usize),
+}
+
+/// Parses the request head (request line and headers) at the start of `buffer`, which holds
+/// everything the client has sent so far. The caller reads more into the buffer and tries again
+/// for as long as this returns Parsed::Partial.
+///
+/// This does no I/O, and returns an error rather than panicking whatever `buffer` holds, so that it
+/// can be fuzzed.
+pub fn parse_request_head(buffer: &[u8], limits: HeaderLimits) -> Result<Parsed, Error> {
+    let mut headers = vec![httparse::EMPTY_HEADER; limits.max_headers];
+    let mut req = httparse::Request::new(&mut headers);
+    let status = req.parse(buffer).map_err(|err| match err {
+        httparse::Error::TooManyHeaders => Error::TooLarge,
+        err => Error::Malformed(err),
+    })?;
+    let len = match status {
+        // (A head that's still incomplete once it's as big as it can be is never going to fit)
+        httparse::Status::Partial if buffer.len() >= limits.max_bytes => {
+            return Err(Error::TooLarge)
+        }
+        httparse::Status::Partial => return Ok(Parsed::Partial),
+        httparse::Status::Complete(len) if len > limits.max_bytes => return Err(Error::TooLarge),
+        httparse::Status::Complete(len) => len,
+    };
+    // (httparse only says the request is complete once it has all of these)
+    let uri: http::Uri = req.path.unwrap().parse().map_err(|_| Error::InvalidUri)?;
+    let mut request = http::Request::builder()
+        .method(req.method.unwrap())
+        .uri(uri)
+        .version(if req.version == Some(0) {
+            http::Version::HTTP_10
+        } else {
+            http::Version::HTTP_11
+        });
+    for header in req.headers.iter() {
+        request = request.header(header.name, header.value);
+    }
+    // (Headers the http crate won't take, should httparse let any through, are malformed too)
+    let request = request
+        .body(Vec::new())
+        .map_err(|_| Error::Malformed(httparse::Error::HeaderValue))?;
+    Ok(Parsed::Complete(Box::new(request), len))
+}
@@ -0,0 +1,70 @@
+use crate::tls::UpstreamStream;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncRead;
+
+/// Keeps upstream connections that are done with their client around, so that the next client
+/// connection to the same upstream can skip the TCP handshake.
+pub struct ConnectionPool {
+    /// Most idle connections kept per upstream
+    max_idle: usize,
+    /// Idle connections older than this are closed rather than reused
+    idle_timeout: Duration,
+    /// Idle connections for each upstream (keyed by address), oldest first
+    idle: Mutex<HashMap<String, Vec<(UpstreamStream, Instant)>>>,
+}
+
+impl ConnectionPool {
+    pub fn new(max_idle: usize, idle_timeout: Duration) -> ConnectionPool {
+        ConnectionPool {
+            max_idle,
+            idle_timeout,
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the most recently used idle connection to `upstream` that still looks usable, if
+    /// there is one. Expired and closed connections found along the way are dropped.
+    pub fn take(&self, upstream: &str) -> Option<UpstreamStream> {
+        let mut idle = self.idle.lock().unwrap();
+        let connections = idle.get_mut(upstream)?;
+        while let Some((mut stream, idle_since)) = connections.pop() {
+            if idle_since.elapsed() < self.idle_timeout && is_open_and_quiet(&mut stream) {
+                return Some(stream);
+            }
+        }
+        None
+    }
+
+    /// Keeps `stream` for a later client connection to `upstream`, closing the oldest idle
+    /// connection if there are already max_idle of them.
+    pub fn put(&self, upstream: &str, stream: UpstreamStream) {
+        let mut idle = self.idle.lock().unwrap();
+        let connections = idle.entry(upstream.to_string()).or_default();
+        connections.retain(|(_, idle_since)| idle_since.elapsed() < self.idle_timeout);
+        if connections.len() >= self.max_idle {
+            connections.remove(0);
+        }
+        connections.push((stream, Instant::now()));
+    }
+}
+
+/// Checks, without blocking, that the upstream hasn't closed this connection (or sent something
+/// unprompted, which would get mixed up with the next response). For TLS connections this looks at
+/// the raw TCP stream, so a TLS close_notify also counts as the upstream closing the connection.
+fn is_open_and_quiet(stream: &mut UpstreamStream) -> bool {
+    let mut cx = Context::from_waker(Waker::noop());
+    let mut buffer = [0_u8; 1];
+    match stream.tcp_mut() {
+        Some(tcp) => matches!(tcp.poll_peek(&mut cx, &mut buffer), Poll::Pending),
+        // UNIX sockets can't be peeked at, but a connection with anything to read is thrown away
+        // anyway, so reading from it does just as well
+        None => matches!(
+            Pin::new(stream).poll_read(&mut cx, &mut buffer),
+            Poll::Pending
+        ),
+    }
+}
@@ -0,0 +1,186 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How long a fronting load balancer gets to send the header once it has connected
+pub const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest allowed version 1 header, CRLF included
+const MAX_V1_HEADER_LEN: usize = 107;
+
+/// Every version 2 header starts with this
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Sent to upstreams in place of a real header on connections that aren't made for a client
+/// (health checks)
+pub const V1_UNKNOWN_HEADER: &str = "PROXY UNKNOWN\r\n";
+
+/// Reads the PROXY protocol header (version 1 or 2) that a fronting load balancer sends at the
+/// start of each connection, and returns the source and destination addresses of the client's
+/// original connection. Returns None if the load balancer didn't pass on any addresses (e.g. for
+/// its own health checks), in which case the connection's own addresses should be used.
+///
+/// Only the header is read, so whatever comes after it (the client's request, or a TLS handshake)
+/// is left in the stream. See https://www.haproxy.org/download/2.3/doc/proxy-protocol.txt
+pub async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> Result<Option<(SocketAddr, SocketAddr)>, String> {
+    let mut header = vec![0_u8; 8];
+    stream
+        .read_exact(&mut header)
+        .await
+        .map_err(|err| format!("could not read header: {}", err))?;
+    if header.starts_with(b"PROXY ") {
+        read_v1_header(stream, header).await
+    } else if header[..] == V2_SIGNATURE[..8] {
+        read_v2_header(stream, header).await
+    } else {
+        Err("connection did not start with a PROXY protocol header".to_string())
+    }
+}
+
+/// Reads the rest of a version 1 (text) header, which `header` has the start of.
+async fn read_v1_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    mut header: Vec<u8>,
+) -> Result<Option<(SocketAddr, SocketAddr)>, String> {
+    // Read a byte at a time, so as not to take any of the request with it
+    while !header.ends_with(b"\r\n") {
+        if header.len() == MAX_V1_HEADER_LEN {
+            return Err("version 1 header is too long".to_string());
+        }
+        let byte = stream
+            .read_u8()
+            .await
+            .map_err(|err| format!("could not read header: {}", err))?;
+        header.push(byte);
+    }
+    let header = std::str::from_utf8(&header[..header.len() - 2])
+        .map_err(|_| "version 1 header is not valid text".to_string())?;
+    let fields: Vec<&str> = header.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4", source, destination, source_port, destination_port]
+        | ["PROXY", "TCP6", source, destination, source_port, destination_port] => {
+            let parse = |ip: &str, port: &str| -> Result<SocketAddr, String> {
+                let ip: IpAddr = ip
+                    .parse()
+                    .map_err(|_| format!("invalid address {:?} in version 1 header", ip))?;
+                let port: u16 = port
+                    .parse()
+                    .map_err(|_| format!("invalid port {:?} in version 1 header", port))?;
+                if ip.is_ipv4() != (fields[1] == "TCP4") {
+                    return Err(format!("{} is not a {} address", ip, fields[1]));
+                }
+                Ok(SocketAddr::new(ip, port))
+            };
+            Ok(Some((
+                parse(source, source_port)?,
+                parse(destination, destination_port)?,
+            )))
+        }
+        _ => Err(format!("malformed version 1 header {:?}", header)),
+    }
+}
+
+/// Reads the rest of a version 2 (binary) header, which `header` has the first 8 bytes of.
+async fn read_v2_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    mut header: Vec<u8>,
+) -> Result<Option<(SocketAddr, SocketAddr)>, String> {
+    header.resize(16, 0);
+    stream
+        .read_exact(&mut header[8..])
+        .await
+        .map_err(|err| format!("could not read header: {}", err))?;
+    if header[..12] != V2_SIGNATURE {
+        return Err("connection did not start with a PROXY protocol header".to_string());
+    }
+    let version_and_command = header[12];
+    let family_and_protocol = header[13];
+    let address_len = usize::from(u16::from_be_bytes([header[14], header[15]]));
+    let mut addresses = vec![0_u8; address_len];
+    stream
+        .read_exact(&mut addresses)
+        .await
+        .map_err(|err| format!("could not read header: {}", err))?;
+
+    if version_and_command >> 4 != 2 {
+        return Err(format!(
+            "unsupported PROXY protocol version {}",
+            version_and_command >> 4
+        ));
+    }
+    match version_and_command & 0xf {
+        // LOCAL: the load balancer's own connection, not a client's
+        0 => return Ok(None),
+        1 => {}
+        command => return Err(format!("unsupported version 2 command {}", command)),
+    }
+    let too_short = || "version 2 header is too short for its addresses".to_string();
+    match family_and_protocol {
+        // TCP over IPv4
+        0x11 => {
+            let addresses = addresses.get(..12).ok_or_else(too_short)?;
+            let ip = |at: usize| {
+                IpAddr::V4(Ipv4Addr::new(
+                    addresses[at],
+                    addresses[at + 1],
+                    addresses[at + 2],
+                    addresses[at + 3],
+                ))
+            };
+            let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+            Ok(Some((
+                SocketAddr::new(ip(0), port(8)),
+                SocketAddr::new(ip(4), port(10)),
+            )))
+        }
+        // TCP over IPv6
+        0x21 => {
+            let addresses = addresses.get(..36).ok_or_else(too_short)?;
+            let ip = |at: usize| {
+                let mut octets = [0_u8; 16];
+                octets.copy_from_slice(&addresses[at..at + 16]);
+                IpAddr::V6(Ipv6Addr::from(octets))
+            };
+            let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+            Ok(Some((
+                SocketAddr::new(ip(0), port(32)),
+                SocketAddr::new(ip(16), port(34)),
+            )))
+        }
+        // Unspecified, UDP, or Unix sockets: nothing we can use as a client address
+        _ => Ok(None),
+    }
+}
+
+/// Builds the version 1 header to send an upstream on behalf of a client that connected from
+/// `source` to `destination`.
+pub fn v1_header(source: SocketAddr, destination: SocketAddr) -> String {
+    // Clients connecting over IPv6 to a dual-stack listener show up as ::ffff:a.b.c.d, which
+    // can't be mixed with an IPv4 destination in one header
+    let unmap = |ip: IpAddr| match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+        IpAddr::V4(_) => ip,
+    };
+    let (source_ip, destination_ip) = match (source.ip(), destination.ip()) {
+        (source_ip, destination_ip) if source_ip.is_ipv4() == destination_ip.is_ipv4() => {
+            (source_ip, destination_ip)
+        }
+        (source_ip, destination_ip) => (unmap(source_ip), unmap(destination_ip)),
+    };
+    let protocol = match (source_ip, destination_ip) {
+        (IpAddr::V4(_), IpAddr::V4(_)) => "TCP4",
+        (IpAddr::V6(_), IpAddr::V6(_)) => "TCP6",
+        _ => return V1_UNKNOWN_HEADER.to_string(),
+    };
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        protocol,
+        source_ip,
+        destination_ip,
+        source.port(),
+        destination.port()
+    )
+}
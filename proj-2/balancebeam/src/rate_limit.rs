@@ -0,0 +1,365 @@
+use async_trait::async_trait;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How many bytes one client has transferred in its current window
+struct Usage {
+    window_started: Instant,
+    bytes: usize,
+}
+
+/// Caps how many bytes (request plus response) each client IP can move through balancebeam per
+/// window. Windows are fixed and per-client: a client's window starts with the first transfer after
+/// its previous window ran out, and its count starts over from zero.
+pub struct ByteLimiter {
+    max_bytes: usize,
+    window: Duration,
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+impl ByteLimiter {
+    pub fn new(max_bytes: usize, window: Duration) -> ByteLimiter {
+        ByteLimiter {
+            max_bytes,
+            window,
+            usage: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn max_bytes(&self) -> usize {
+        self.max_bytes
+    }
+
+    /// If this client has already used up its budget for the current window, returns how long
+    /// until the window runs out (None if it has budget left).
+    pub fn exhausted_for(&self, client_ip: &str) -> Option<Duration> {
+        let usage = self.usage.lock().unwrap();
+        let usage = usage.get(client_ip)?;
+        let elapsed = usage.window_started.elapsed();
+        if elapsed < self.window && usage.bytes >= self.max_bytes {
+            Some(self.window - elapsed)
+        } else {
+            None
+        }
+    }
+
+    /// Returns how many bytes each client has transferred in its current window, for clients
+    /// whose windows haven't run out yet.
+    pub fn usage(&self) -> Vec<(String, usize)> {
+        let usage = self.usage.lock().unwrap();
+        let mut usage: Vec<(String, usize)> = usage
+            .iter()
+            .filter(|(_, usage)| usage.window_started.elapsed() < self.window)
+            .map(|(client_ip, usage)| (client_ip.clone(), usage.bytes))
+            .collect();
+        usage.sort();
+        usage
+    }
+
+    /// Counts bytes transferred on behalf of this client against its budget.
+    pub fn record(&self, client_ip: &str, bytes: usize) {
+        let mut usage = self.usage.lock().unwrap();
+        // Forget clients whose windows have run out, so that the map only holds active clients
+        let window = self.window;
+        usage.retain(|_, usage| usage.window_started.elapsed() < window);
+        usage
+            .entry(client_ip.to_string())
+            .or_insert_with(|| Usage {
+                window_started: Instant::now(),
+                bytes: 0,
+            })
+            .bytes += bytes;
+    }
+}
+
+/// How many requests a client may make: `max_requests` per window on average, with up to `burst` of
+/// them at once
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RequestLimit {
+    pub max_requests: usize,
+    pub burst: usize,
+}
+
+/// Where a client stands against its request limit, as of its latest request
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimitStatus {
+    /// Whether the request was let through (and took a token)
+    pub allowed: bool,
+    /// How many tokens the client's bucket holds when full
+    pub limit: usize,
+    /// How many whole tokens are left in the bucket
+    pub remaining: usize,
+    /// How long until the bucket is full again
+    pub reset: Duration,
+    /// How long until the bucket has a token for another request (zero if it has one now)
+    pub retry_after: Duration,
+}
+
+impl RateLimitStatus {
+    /// Returns the headers telling a client where it stands: Retry-After, plus X-RateLimit-Limit,
+    /// -Remaining and -Reset (the last in seconds from now).
+    pub fn headers(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("retry-after", whole_seconds(self.retry_after).to_string()),
+            ("x-ratelimit-limit", self.limit.to_string()),
+            ("x-ratelimit-remaining", self.remaining.to_string()),
+            ("x-ratelimit-reset", whole_seconds(self.reset).to_string()),
+        ]
+    }
+}
+
+/// Rounds `duration` up to whole seconds, so that a client waiting that long won't be too early.
+pub fn whole_seconds(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+impl RateLimitStatus {
+    /// Works out a client's status from whether its request was allowed and how many tokens its
+    /// bucket holds afterwards.
+    pub fn new(
+        allowed: bool,
+        tokens: f64,
+        limit: RequestLimit,
+        window: Duration,
+    ) -> RateLimitStatus {
+        let seconds_per_token = window.as_secs_f64() / limit.max_requests as f64;
+        RateLimitStatus {
+            allowed,
+            limit: limit.burst,
+            remaining: tokens as usize,
+            reset: Duration::from_secs_f64((limit.burst as f64 - tokens) * seconds_per_token),
+            retry_after: Duration::from_secs_f64((1.0 - tokens).max(0.0) * seconds_per_token),
+        }
+    }
+}
+
+/// Holds the clients' token buckets. A bucket holds up to `burst` tokens and refills at
+/// `max_requests` per window, so a client that has been quiet can make a burst of requests at
+/// once, but can't keep going faster than the limit. Buckets start out full.
+///
+/// By default they're kept in memory (MemoryStore), but replicas of balancebeam can share them
+/// through Redis (RedisStore) so that a client can't get a separate allowance from each one.
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    /// Takes a token from the bucket under `key`, unless it's empty (in which case the returned
+    /// status says the request isn't allowed).
+    async fn try_acquire(
+        &self,
+        key: &str,
+        limit: RequestLimit,
+        window: Duration,
+    ) -> RateLimitStatus;
+
+    /// Returns how many tokens have been taken (and not yet gotten back, counting partly refilled
+    /// ones) from each bucket whose key starts with `key_prefix`, by the rest of its key, for
+    /// buckets that have any out. Stores that can't list their buckets return nothing.
+    fn usage(
+        &self,
+        key_prefix: &str,
+        limit: RequestLimit,
+        window: Duration,
+    ) -> Vec<(String, usize)>;
+}
+
+/// A token bucket: each request takes a token, and tokens come back at a steady rate
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+    /// When the bucket will be full again, if nothing more is taken from it
+    full_at: Instant,
+}
+
+/// Returns how many tokens `bucket` holds now, counting the ones that have come back since it was
+/// last refilled.
+fn refilled_tokens(bucket: &Bucket, limit: RequestLimit, window: Duration) -> f64 {
+    let rate = limit.max_requests as f64 / window.as_secs_f64();
+    let tokens = bucket.tokens + bucket.refilled.elapsed().as_secs_f64() * rate;
+    tokens.min(limit.burst as f64)
+}
+
+/// Keeps token buckets in this process's memory
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore {
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for MemoryStore {
+    async fn try_acquire(
+        &self,
+        key: &str,
+        limit: RequestLimit,
+        window: Duration,
+    ) -> RateLimitStatus {
+        let mut buckets = self.buckets.lock().unwrap();
+        let now = Instant::now();
+        // Forget buckets that have filled back up, since a new bucket starts full anyway
+        buckets.retain(|_, bucket| bucket.full_at > now);
+        let burst = limit.burst as f64;
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: burst,
+            refilled: now,
+            full_at: now,
+        });
+        bucket.tokens = refilled_tokens(bucket, limit, window);
+        bucket.refilled = now;
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        let status = RateLimitStatus::new(allowed, bucket.tokens, limit, window);
+        bucket.full_at = now + status.reset;
+        status
+    }
+
+    fn usage(
+        &self,
+        key_prefix: &str,
+        limit: RequestLimit,
+        window: Duration,
+    ) -> Vec<(String, usize)> {
+        let buckets = self.buckets.lock().unwrap();
+        let mut usage: Vec<(String, usize)> = buckets
+            .iter()
+            .filter_map(|(key, bucket)| {
+                let rest = key.strip_prefix(key_prefix)?;
+                let used = limit.burst as f64 - refilled_tokens(bucket, limit, window);
+                Some((rest.to_string(), used.ceil() as usize))
+            })
+            .filter(|(_, used)| *used > 0)
+            .collect();
+        usage.sort();
+        usage
+    }
+}
+
+/// Caps how many requests each client IP can make, with a token bucket per client (kept in
+/// `store`, under `scope` followed by the client's IP).
+pub struct RequestLimiter {
+    limit: RequestLimit,
+    window: Duration,
+    scope: String,
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl RequestLimiter {
+    pub fn new(
+        limit: RequestLimit,
+        window: Duration,
+        scope: String,
+        store: Arc<dyn RateLimitStore>,
+    ) -> RequestLimiter {
+        RequestLimiter {
+            limit,
+            window,
+            scope,
+            store,
+        }
+    }
+
+    pub fn limit(&self) -> RequestLimit {
+        self.limit
+    }
+
+    /// Returns how many tokens each client has used and not yet gotten back (counting partly
+    /// refilled ones), for clients that have any out.
+    pub fn usage(&self) -> Vec<(String, usize)> {
+        self.store.usage(&self.scope, self.limit, self.window)
+    }
+
+    /// Takes a token from this client's bucket, unless the bucket is empty (in which case the
+    /// returned status says the request isn't allowed).
+    pub async fn try_acquire(&self, client_ip: &str) -> RateLimitStatus {
+        let key = format!("{}{}", self.scope, client_ip);
+        self.store.try_acquire(&key, self.limit, self.window).await
+    }
+}
+
+/// The request limits in effect: a default one, and ones for requests whose path is under a
+/// particular prefix (e.g. a stricter one for /login). A request only counts against the limit of
+/// the longest prefix it matches, or the default limit if it matches none.
+pub struct RequestLimits {
+    default: Option<RequestLimiter>,
+    /// Longest prefix first
+    routes: Vec<(String, RequestLimiter)>,
+}
+
+impl RequestLimits {
+    /// Returns None if no requests are limited at all. Prefixes are expected without a trailing
+    /// '/'.
+    pub fn new(
+        default: Option<RequestLimit>,
+        routes: &[(String, RequestLimit)],
+        window: Duration,
+        store: Arc<dyn RateLimitStore>,
+    ) -> Option<RequestLimits> {
+        if default.is_none() && routes.is_empty() {
+            return None;
+        }
+        // Each limit keeps its buckets apart from the others' (route prefixes are empty or start
+        // with a '/', so they can't clash with the default limit's scope)
+        let mut routes: Vec<(String, RequestLimiter)> = routes
+            .iter()
+            .map(|(prefix, limit)| {
+                let scope = format!("{} ", prefix);
+                let limiter = RequestLimiter::new(*limit, window, scope, Arc::clone(&store));
+                (prefix.clone(), limiter)
+            })
+            .collect();
+        routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        Some(RequestLimits {
+            default: default
+                .map(|limit| RequestLimiter::new(limit, window, "default ".to_string(), store)),
+            routes,
+        })
+    }
+
+    /// Returns the limiter that requests for `path` count against (None if they're unlimited),
+    /// along with the route prefix it belongs to (None for the default limit).
+    pub fn limiter_for(&self, path: &str) -> Option<(Option<&str>, &RequestLimiter)> {
+        let route = self.routes.iter().find(|(prefix, _)| {
+            path.strip_prefix(prefix.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+        });
+        match route {
+            Some((prefix, limiter)) => Some((Some(prefix.as_str()), limiter)),
+            None => self.default.as_ref().map(|limiter| (None, limiter)),
+        }
+    }
+
+    pub fn default_limiter(&self) -> Option<&RequestLimiter> {
+        self.default.as_ref()
+    }
+
+    pub fn route_limiters(&self) -> &[(String, RequestLimiter)] {
+        &self.routes
+    }
+
+    /// Returns true if these are the limits that `default` and `routes` would give, so that the
+    /// clients' buckets can be kept across a configuration reload.
+    pub fn has_limits(
+        &self,
+        default: Option<RequestLimit>,
+        routes: &[(String, RequestLimit)],
+    ) -> bool {
+        let mut routes = routes.to_vec();
+        routes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        self.default.as_ref().map(RequestLimiter::limit) == default
+            && self.routes.len() == routes.len()
+            && self
+                .routes
+                .iter()
+                .zip(&routes)
+                .all(|((prefix, limiter), (other_prefix, limit))| {
+                    prefix == other_prefix && limiter.limit() == *limit
+                })
+    }
+}
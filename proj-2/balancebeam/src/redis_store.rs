@@ -0,0 +1,215 @@
+use crate::rate_limit::{RateLimitStatus, RateLimitStore, RequestLimit};
+use async_trait::async_trait;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// How long a call to Redis (connecting included) may take before the request is let through
+/// without it
+const REDIS_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Most connections to Redis kept open between calls. Calls that find none idle open their own, so
+/// none of them waits for another's reply
+const MAX_IDLE_CONNECTIONS: usize = 8;
+
+/// Prefix for the keys that buckets are stored under, so they stay out of the way of anything
+/// else in the same Redis database
+const KEY_PREFIX: &str = "balancebeam:ratelimit:";
+
+/// Refills and takes a token from the bucket in KEYS[1] (a hash of its tokens and when they were
+/// counted), given the burst in ARGV[1] and the refill rate in tokens per millisecond in ARGV[2].
+/// Returns whether a token was taken, and how many are left (as a string, since Redis would cut a
+/// Lua number down to an integer). Time comes from the Redis server, so that replicas with
+/// different clocks agree, and buckets expire once they're full again.
+const TAKE_TOKEN_SCRIPT: &str = r#"
+local burst = tonumber(ARGV[1])
+local rate = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + tonumber(time[2]) / 1000
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'ts')
+local tokens = tonumber(bucket[1]) or burst
+local counted = tonumber(bucket[2]) or now
+tokens = math.min(burst, tokens + math.max(0, now - counted) * rate)
+local allowed = 0
+if tokens >= 1 then
+    tokens = tokens - 1
+    allowed = 1
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'ts', tostring(now))
+redis.call('PEXPIRE', KEYS[1], math.ceil((burst - tokens) / rate) + 1)
+return {allowed, tostring(tokens)}
+"#;
+
+/// Keeps token buckets in Redis, so that every balancebeam replica pointed at the same Redis
+/// server shares them. Each call takes a connection of its own out of a small pool (opening one if
+/// there are none idle), and only puts it back if the call went through.
+///
+/// If Redis can't be reached (or is slow), requests are let through rather than refused, so that
+/// an outage of Redis doesn't take the proxy down with it. Usage can't be listed through the admin
+/// API, since the buckets aren't kept here.
+pub struct RedisStore {
+    address: String,
+    /// Connections that aren't in use by a call, most recently used last
+    idle: Mutex<Vec<BufReader<TcpStream>>>,
+}
+
+impl RedisStore {
+    /// `address` is the Redis server's HOST:PORT.
+    pub fn new(address: &str) -> RedisStore {
+        RedisStore {
+            address: address.to_string(),
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Runs TAKE_TOKEN_SCRIPT on `key`, returning whether a token was taken and how many are left.
+    async fn take_token(
+        &self,
+        key: &str,
+        limit: RequestLimit,
+        window: Duration,
+    ) -> Result<(bool, f64), String> {
+        let idle = self.idle.lock().unwrap().pop();
+        let mut stream = match idle {
+            Some(stream) => stream,
+            None => TcpStream::connect(&self.address)
+                .await
+                .map(BufReader::new)
+                .map_err(|err| format!("could not connect: {}", err))?,
+        };
+        let rate = limit.max_requests as f64 / window.as_millis() as f64;
+        let key = format!("{}{}", KEY_PREFIX, key);
+        let command = [
+            "EVAL",
+            TAKE_TOKEN_SCRIPT,
+            "1",
+            &key,
+            &limit.burst.to_string(),
+            &rate.to_string(),
+        ];
+        // (If the call fails, the connection may be out of step with its replies, so it's dropped
+        // rather than put back; the same goes for one whose call timed out)
+        let reply = call(&mut stream, &command).await?;
+        {
+            let mut idle = self.idle.lock().unwrap();
+            if idle.len() < MAX_IDLE_CONNECTIONS {
+                idle.push(stream);
+            }
+        }
+        match reply.as_slice() {
+            [Reply::Integer(allowed), Reply::Bulk(tokens)] => {
+                let tokens = tokens
+                    .parse()
+                    .map_err(|_| format!("unexpected token count {:?}", tokens))?;
+                Ok((*allowed == 1, tokens))
+            }
+            _ => Err(format!("unexpected reply {:?}", reply)),
+        }
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for RedisStore {
+    async fn try_acquire(
+        &self,
+        key: &str,
+        limit: RequestLimit,
+        window: Duration,
+    ) -> RateLimitStatus {
+        let take_token = tokio::time::timeout(REDIS_TIMEOUT, self.take_token(key, limit, window));
+        match take_token.await {
+            Ok(Ok((allowed, tokens))) => RateLimitStatus::new(allowed, tokens, limit, window),
+            Ok(Err(message)) => {
+                log::warn!(
+                    "Rate limit store at {} failed ({}); letting the request through",
+                    self.address,
+                    message
+                );
+                RateLimitStatus::new(true, limit.burst as f64, limit, window)
+            }
+            Err(_) => {
+                log::warn!(
+                    "Rate limit store at {} timed out; letting the request through",
+                    self.address
+                );
+                RateLimitStatus::new(true, limit.burst as f64, limit, window)
+            }
+        }
+    }
+
+    fn usage(
+        &self,
+        _key_prefix: &str,
+        _limit: RequestLimit,
+        _window: Duration,
+    ) -> Vec<(String, usize)> {
+        Vec::new()
+    }
+}
+
+/// A value in a Redis reply (https://redis.io/docs/reference/protocol-spec/). Only the kinds that
+/// TAKE_TOKEN_SCRIPT's reply is made of are supported.
+#[derive(Debug)]
+enum Reply {
+    Integer(i64),
+    Bulk(String),
+}
+
+/// Sends a command to Redis and reads its reply, which must be an array of integers and bulk
+/// strings.
+async fn call(stream: &mut BufReader<TcpStream>, command: &[&str]) -> Result<Vec<Reply>, String> {
+    let mut request = format!("*{}\r\n", command.len());
+    for argument in command {
+        request += &format!("${}\r\n{}\r\n", argument.len(), argument);
+    }
+    stream
+        .get_mut()
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|err| format!("could not send command: {}", err))?;
+
+    let line = read_line(stream).await?;
+    let count: usize = match line.strip_prefix('*') {
+        Some(count) => count.parse().map_err(|_| format!("bad reply {:?}", line))?,
+        None => return Err(format!("Redis replied {:?}", line)),
+    };
+    let mut values = Vec::with_capacity(count);
+    for _ in 0..count {
+        let line = read_line(stream).await?;
+        let value = if let Some(integer) = line.strip_prefix(':') {
+            Reply::Integer(
+                integer
+                    .parse()
+                    .map_err(|_| format!("bad reply {:?}", line))?,
+            )
+        } else if let Some(len) = line.strip_prefix('$') {
+            let len: usize = len.parse().map_err(|_| format!("bad reply {:?}", line))?;
+            // (The string is followed by a CRLF)
+            let mut bulk = vec![0_u8; len + 2];
+            stream
+                .read_exact(&mut bulk)
+                .await
+                .map_err(|err| format!("could not read reply: {}", err))?;
+            bulk.truncate(len);
+            Reply::Bulk(String::from_utf8_lossy(&bulk).to_string())
+        } else {
+            return Err(format!("unsupported reply {:?}", line));
+        };
+        values.push(value);
+    }
+    Ok(values)
+}
+
+/// Reads one CRLF-terminated line of a reply, without the CRLF.
+async fn read_line(stream: &mut BufReader<TcpStream>) -> Result<String, String> {
+    let mut line = String::new();
+    let bytes_read = stream
+        .read_line(&mut line)
+        .await
+        .map_err(|err| format!("could not read reply: {}", err))?;
+    if bytes_read == 0 {
+        return Err("connection closed".to_string());
+    }
+    Ok(line.trim_end_matches("\r\n").to_string())
+}
@@ -1,45 +1,29 @@
+use crate::body::{self, Unread};
+use crate::chunked;
+use crate::error::{ClientError, ProxyError};
+use crate::timeout::IdleTimeout;
+use balancebeam::parse::{self, Parsed, MAX_HEADERS_SIZE};
 use std::cmp::min;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::net::TcpStream;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
-const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
-const MAX_NUM_HEADERS: usize = 32;
-
-#[derive(Debug)]
-pub enum Error {
-    /// Client hung up before sending a complete request. IncompleteRequest contains the number of
-    /// bytes that were successfully read before the client hung up
-    IncompleteRequest(usize),
-    /// Client sent an invalid HTTP request. httparse::Error contains more details
-    MalformedRequest(httparse::Error),
-    /// The Content-Length header is present, but does not contain a valid numeric value
-    InvalidContentLength,
-    /// The Content-Length header does not match the size of the request body that was sent
-    ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
-    RequestBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
-    ConnectionError(std::io::Error),
-    /// All upstream servers are in-valid
-    NoValidUpstreamServer,
-}
+pub use balancebeam::parse::HeaderLimits;
 
 /// Extracts the Content-Length header value from the provided request. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
-/// Err(Error) if Content-Length is present but invalid.
+/// Err(ClientError) if Content-Length is present but invalid.
 ///
 /// You won't need to touch this function.
-fn get_content_length(request: &http::Request<Vec<u8>>) -> Result<Option<usize>, Error> {
+fn get_content_length(request: &http::Request<Vec<u8>>) -> Result<Option<usize>, ClientError> {
     // Look for content-length header
     if let Some(header_value) = request.headers().get("content-length") {
         // If it exists, parse it as a usize (or return InvalidContentLength if it can't be parsed as such)
         Ok(Some(
             header_value
                 .to_str()
-                .or(Err(Error::InvalidContentLength))?
+                .or(Err(ClientError::InvalidContentLength))?
                 .parse::<usize>()
-                .or(Err(Error::InvalidContentLength))?,
+                .or(Err(ClientError::InvalidContentLength))?,
         ))
     } else {
         // If it doesn't exist, return None
@@ -69,62 +53,65 @@ pub fn extend_header_value(
         .insert(name, http::HeaderValue::from_bytes(&new_value).unwrap());
 }
 
-/// Attempts to parse the data in the supplied buffer as an HTTP request. Returns one of the
-/// following:
-///
-/// * If there is a complete and valid request in the buffer, returns Ok(Some(http::Request))
-/// * If there is an incomplete but valid-so-far request in the buffer, returns Ok(None)
-/// * If there is data in the buffer that is definitely not a valid HTTP request, returns Err(Error)
-///
-/// You won't need to touch this function.
-fn parse_request(buffer: &[u8]) -> Result<Option<(http::Request<Vec<u8>>, usize)>, Error> {
-    let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
-    let mut req = httparse::Request::new(&mut headers);
-    let res = req
-        .parse(buffer)
-        .or_else(|err| Err(Error::MalformedRequest(err)))?;
-
-    if let httparse::Status::Complete(len) = res {
-        let mut request = http::Request::builder()
-            .method(req.method.unwrap())
-            .uri(req.path.unwrap())
-            .version(http::Version::HTTP_11);
-        for header in req.headers {
-            request = request.header(header.name, header.value);
-        }
-        let request = request.body(Vec::new()).unwrap();
-        Ok(Some((request, len)))
-    } else {
-        Ok(None)
-    }
-}
-
 /// Reads an HTTP request from the provided stream, waiting until a complete set of headers is sent.
 /// This function only reads the request line and headers; the read_body function can subsequently
 /// be called in order to read the request body (for a POST request).
 ///
-/// Returns Ok(http::Request) if a valid request is received, or Error if not.
+/// Returns Ok(http::Request) if a valid request is received, or ClientError if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+///
+/// If header_timeout is given, the rest of the header block must arrive within that long of its
+/// first byte; otherwise, ClientError::HeaderTimeout is returned. The clock only starts with the
+/// first byte so that a connection waiting for its next request isn't cut off by it.
+///
+/// The buffer grows as headers arrive, up to `limits.max_bytes`; if the header block doesn't fit
+/// in that, ClientError::RequestHeadersTooLarge is returned.
+async fn read_headers<S>(
+    stream: &mut S,
+    header_timeout: Option<Duration>,
+    limits: HeaderLimits,
+) -> Result<http::Request<Vec<u8>>, ClientError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut deadline = None;
     // Try reading the headers from the request. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a request, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP request
-    let mut request_buffer = [0_u8; MAX_HEADERS_SIZE];
+    // (The buffer starts out at the default limit, which most header blocks fit in)
+    let mut request_buffer = vec![0_u8; min(MAX_HEADERS_SIZE, limits.max_bytes)];
     let mut bytes_read = 0;
     loop {
+        // (parse_request_head fails once a partial header block fills the limit, so there's
+        // always room to grow)
+        if bytes_read == request_buffer.len() {
+            request_buffer.resize(min(bytes_read * 2, limits.max_bytes), 0);
+        }
         // Read bytes from the connection into the buffer, starting at position bytes_read
-        let new_bytes = stream
-            .read(&mut request_buffer[bytes_read..]).await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+        let read = stream.read(&mut request_buffer[bytes_read..]);
+        let read_result = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, read)
+                .await
+                .or(Err(ClientError::HeaderTimeout(bytes_read)))?,
+            None => read.await,
+        };
+        let new_bytes = read_result.map_err(|err| match err.kind() {
+            std::io::ErrorKind::TimedOut => ClientError::HeaderTimeout(bytes_read),
+            _ => ClientError::ConnectionError(err),
+        })?;
         if new_bytes == 0 {
             // We didn't manage to read a complete request
-            return Err(Error::IncompleteRequest(bytes_read));
+            return Err(ClientError::IncompleteRequest(bytes_read));
+        }
+        if bytes_read == 0 {
+            deadline = header_timeout.map(|timeout| tokio::time::Instant::now() + timeout);
         }
         bytes_read += new_bytes;
 
         // See if we've read a valid request so far
-        if let Some((mut request, headers_len)) = parse_request(&request_buffer[..bytes_read])? {
+        let parsed = parse::parse_request_head(&request_buffer[..bytes_read], limits)?;
+        if let Parsed::Complete(mut request, headers_len) = parsed {
             // We've read a complete set of headers. However, if this was a POST request, a request
             // body might have been included as well, and we might have read part of the body out of
             // the stream into header_buffer. We need to add those bytes to the Request body so that
@@ -132,21 +119,21 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>,
             request
                 .body_mut()
                 .extend_from_slice(&request_buffer[headers_len..bytes_read]);
-            return Ok(request);
+            return Ok(*request);
         }
     }
 }
 
 /// This function reads the body for a request from the stream. The client only sends a body if the
 /// Content-Length header is present; this function reads that number of bytes from the stream. It
-/// returns Ok(()) if successful, or Err(Error) if Content-Length bytes couldn't be read.
+/// returns Ok(()) if successful, or Err(ClientError) if Content-Length bytes couldn't be read.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_body(
-    stream: &mut TcpStream,
+async fn read_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
     request: &mut http::Request<Vec<u8>>,
     content_length: usize,
-) -> Result<(), Error> {
+) -> Result<(), ClientError> {
     // Keep reading data until we read the full body length, or until we hit an error.
     while request.body().len() < content_length {
         // Read up to 512 bytes at a time. (If the client only sent a small body, then only allocate
@@ -154,7 +141,7 @@ async fn read_body(
         let mut buffer = vec![0_u8; min(512, content_length)];
         let bytes_read = stream
             .read(&mut buffer).await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .or_else(|err| Err(ClientError::ConnectionError(err)))?;
 
         // Make sure the client is still sending us bytes
         if bytes_read == 0 {
@@ -164,7 +151,7 @@ async fn read_body(
                 request.body().len(),
                 content_length
             );
-            return Err(Error::ContentLengthMismatch);
+            return Err(ClientError::ContentLengthMismatch);
         }
 
         // Make sure the client didn't send us *too many* bytes
@@ -172,7 +159,7 @@ async fn read_body(
             log::debug!(
                 "Client sent more bytes than we expected based on the given content length!"
             );
-            return Err(Error::ContentLengthMismatch);
+            return Err(ClientError::ContentLengthMismatch);
         }
 
         // Store the received bytes in the request body
@@ -181,43 +168,158 @@ async fn read_body(
     Ok(())
 }
 
-/// This function reads and returns an HTTP request from a stream, returning an Error if the client
-/// closes the connection prematurely or sends an invalid request.
+/// Reads and decodes a chunked request body, and rewrites the headers to describe the decoded
+/// body by its Content-Length. If the body is bigger than `high_water`, it is left chunked and
+/// returns the Unread rest instead (which is only allowed to take the body up to `max_body_size`,
+/// if given). A body that is already known to be bigger than `max_body_size` is an error.
+///
+/// A body that's read in full also comes back with whatever was read past its end.
+async fn read_chunked_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    request: &mut http::Request<Vec<u8>>,
+    high_water: usize,
+    max_body_size: Option<usize>,
+) -> Result<(Option<Unread>, Vec<u8>), ClientError> {
+    let limit = max_body_size.map_or(high_water, |max_body_size| max_body_size.min(high_water));
+    let rest = chunked::read_body(stream, request.body_mut(), limit)
+        .await
+        .map_err(|err| match err {
+            chunked::Error::InvalidFraming => ClientError::InvalidChunkedBody,
+            chunked::Error::Io(err) => ClientError::ConnectionError(err),
+        })?;
+    match rest {
+        chunked::Decoded::Complete(leftover) => {
+            let body_len = request.body().len();
+            chunked::replace_with_content_length(request.headers_mut(), body_len);
+            Ok((None, leftover))
+        }
+        // (If the limit was the maximum body size rather than the high-water mark, the body won't
+        // fit)
+        chunked::Decoded::TooBig(_)
+            if max_body_size.is_some_and(|max_body_size| max_body_size <= high_water) =>
+        {
+            Err(ClientError::RequestBodyTooLarge)
+        }
+        chunked::Decoded::TooBig(rest) => {
+            let mut body = std::mem::take(request.body_mut());
+            let unread =
+                body::resume_chunked(request.headers_mut(), &mut body, rest, max_body_size);
+            *request.body_mut() = body;
+            Ok((Some(unread), Vec::new()))
+        }
+    }
+}
+
+/// This function reads and returns an HTTP request from a stream, returning a ProxyError::Client if
+/// the client closes the connection prematurely or sends an invalid request.
+///
+/// Bodies of up to `high_water` bytes are read in full. Only the start of a bigger body is read,
+/// and the rest is returned as Unread, to be streamed to the upstream after the request has been
+/// sent.
+///
+/// If the client sends nothing for `idle_timeout`, ClientError::HeaderTimeout or
+/// ClientError::BodyTimeout is returned, depending on how far it got; see read_headers for
+/// `header_timeout`.
+///
+/// If the body is bigger than `max_body_size` (when given), ClientError::RequestBodyTooLarge is
+/// returned without reading the body, as far as that can be known up front. (The rest of a
+/// streamed chunked body is checked as it is copied.)
+///
+/// If the headers are over `header_limits`, ClientError::RequestHeadersTooLarge is returned, with
+/// the rest of them left unread. A Transfer-Encoding other than chunked is a
+/// ClientError::UnsupportedTransferEncoding.
+///
+/// Whatever was read past the end of the request (the start of the next one, if the client
+/// pipelined it) is returned as well; it has to be read before anything else left in the stream.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(stream: &mut TcpStream) -> Result<http::Request<Vec<u8>>, Error> {
+pub async fn read_from_stream<S>(
+    stream: &mut S,
+    idle_timeout: Option<Duration>,
+    header_timeout: Option<Duration>,
+    high_water: usize,
+    max_body_size: Option<usize>,
+    header_limits: HeaderLimits,
+) -> Result<(http::Request<Vec<u8>>, Option<Unread>, Vec<u8>), ProxyError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut stream = IdleTimeout::new(stream, idle_timeout);
     // Read headers
-    let mut request = read_headers(stream).await?;
+    let mut request = read_headers(&mut stream, header_timeout, header_limits).await?;
+    let body_timed_out = |err| match err {
+        ClientError::ConnectionError(err) if err.kind() == std::io::ErrorKind::TimedOut => {
+            ClientError::BodyTimeout
+        }
+        err => err,
+    };
     // Read body if the client supplied the Content-Length header (which it does for POST requests)
+    // or sent it in chunks. Transfer-Encoding takes precedence if both are present
+    if chunked::has_unknown_length(request.headers()) {
+        return Err(ClientError::UnsupportedTransferEncoding.into());
+    }
+    if chunked::is_chunked(request.headers()) {
+        let (unread, leftover) =
+            read_chunked_body(&mut stream, &mut request, high_water, max_body_size)
+                .await
+                .map_err(body_timed_out)?;
+        return Ok((request, unread, leftover));
+    }
     if let Some(content_length) = get_content_length(&request)? {
-        if content_length > MAX_BODY_SIZE {
-            return Err(Error::RequestBodyTooLarge);
-        } else {
-            read_body(stream, &mut request, content_length).await?;
+        if max_body_size.is_some_and(|max_body_size| content_length > max_body_size) {
+            return Err(ClientError::RequestBodyTooLarge.into());
+        }
+        if content_length > high_water {
+            let already_read = request.body().len();
+            if already_read > content_length {
+                return Err(ClientError::ContentLengthMismatch.into());
+            }
+            return Ok((
+                request,
+                Some(Unread::Length(content_length - already_read)),
+                Vec::new(),
+            ));
         }
+        read_body(&mut stream, &mut request, content_length)
+            .await
+            .map_err(body_timed_out)?;
     }
-    Ok(request)
+    Ok((request, None, Vec::new()))
 }
 
-/// This function serializes a request to bytes and writes those bytes to the provided stream.
+/// This function serializes a request to bytes and writes those bytes to the provided stream,
+/// returning how many bytes were written.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
     request: &http::Request<Vec<u8>>,
-    stream: &mut TcpStream,
-) -> Result<(), std::io::Error> {
-    stream.write(&format_request_line(request).into_bytes()).await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
-    for (header_name, header_value) in request.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes()).await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream: &mut S,
+) -> Result<usize, std::io::Error> {
+    // (write_all, since a single write may take only part of what it's given)
+    let mut bytes_written = 0;
+    let line = format_request_line(request);
+    for piece in &[line.as_bytes(), b"\r\n"] {
+        stream.write_all(piece).await?;
+        bytes_written += piece.len();
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if request.body().len() > 0 {
-        stream.write(request.body()).await?;
+    for (header_name, header_value) in request.headers() {
+        for piece in &[
+            header_name.as_str().as_bytes(),
+            b": ",
+            header_value.as_bytes(),
+            b"\r\n",
+        ] {
+            stream.write_all(piece).await?;
+            bytes_written += piece.len();
+        }
     }
-    Ok(())
+    stream.write_all(b"\r\n").await?;
+    bytes_written += 2;
+    stream.write_all(request.body()).await?;
+    bytes_written += request.body().len();
+    // Streams that buffer (like TLS streams) don't send anything until they're flushed
+    stream.flush().await?;
+    Ok(bytes_written)
 }
 
 pub fn format_request_line(request: &http::Request<Vec<u8>>) -> String {
@@ -0,0 +1,47 @@
+use rand::Rng;
+
+pub const HEADER: &str = "x-request-id";
+
+/// Longest X-Request-Id that's passed along from a trusted proxy (longer ones are replaced)
+const MAX_LEN: usize = 128;
+
+/// Returns a new random request ID, formatted as a version 4 UUID (e.g.
+/// 0f8fad5b-d9cb-469f-a165-70867728950e).
+pub fn generate() -> String {
+    let mut bytes: [u8; 16] = rand::thread_rng().gen();
+    // (The version and variant bits that make it a version 4 UUID)
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Returns the ID for `request`, and sets it as the request's X-Request-Id. A request from a
+/// trusted proxy keeps the ID it came with (if it has a usable one), so that the proxy's logs line
+/// up with ours; anyone else gets a new one.
+pub fn assign(request: &mut http::Request<Vec<u8>>, from_trusted_proxy: bool) -> String {
+    let existing = request
+        .headers()
+        .get(HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| !id.is_empty() && id.len() <= MAX_LEN && !id.contains(' '))
+        .filter(|_| from_trusted_proxy);
+    let id = match existing {
+        Some(id) => id.to_string(),
+        None => generate(),
+    };
+    set(request.headers_mut(), &id);
+    id
+}
+
+/// Sets X-Request-Id in `headers` to `id` (which came from `assign`).
+pub fn set(headers: &mut http::HeaderMap, id: &str) {
+    headers.insert(HEADER, http::HeaderValue::from_str(id).unwrap());
+}
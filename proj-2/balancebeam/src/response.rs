@@ -1,40 +1,29 @@
-use tokio::{net::TcpStream, io::{AsyncReadExt, AsyncWriteExt}};
+use crate::body::{self, Unread};
+use crate::chunked;
+use crate::error::{ProxyError, Timeout, UpstreamError};
+use crate::headers;
+use crate::timeout::IdleTimeout;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
 const MAX_HEADERS_SIZE: usize = 8000;
-const MAX_BODY_SIZE: usize = 10000000;
 const MAX_NUM_HEADERS: usize = 32;
 
-#[derive(Debug)]
-pub enum Error {
-    /// Client hung up before sending a complete request
-    IncompleteResponse,
-    /// Client sent an invalid HTTP request. httparse::Error contains more details
-    MalformedResponse(httparse::Error),
-    /// The Content-Length header is present, but does not contain a valid numeric value
-    InvalidContentLength,
-    /// The Content-Length header does not match the size of the request body that was sent
-    ContentLengthMismatch,
-    /// The request body is bigger than MAX_BODY_SIZE
-    ResponseBodyTooLarge,
-    /// Encountered an I/O error when reading/writing a TcpStream
-    ConnectionError(std::io::Error),
-}
-
 /// Extracts the Content-Length header value from the provided response. Returns Ok(Some(usize)) if
 /// the Content-Length is present and valid, Ok(None) if Content-Length is not present, or
-/// Err(Error) if Content-Length is present but invalid.
+/// Err(UpstreamError) if Content-Length is present but invalid.
 ///
 /// You won't need to touch this function.
-fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize>, Error> {
+fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize>, UpstreamError> {
     // Look for content-length header
     if let Some(header_value) = response.headers().get("content-length") {
         // If it exists, parse it as a usize (or return InvalidResponseFormat if it can't be parsed as such)
         Ok(Some(
             header_value
                 .to_str()
-                .or(Err(Error::InvalidContentLength))?
+                .or(Err(UpstreamError::InvalidContentLength))?
                 .parse::<usize>()
-                .or(Err(Error::InvalidContentLength))?,
+                .or(Err(UpstreamError::InvalidContentLength))?,
         ))
     } else {
         // If it doesn't exist, return None
@@ -48,20 +37,26 @@ fn get_content_length(response: &http::Response<Vec<u8>>) -> Result<Option<usize
 /// * If there is a complete and valid response in the buffer, returns Ok(Some(http::Request))
 /// * If there is an incomplete but valid-so-far response in the buffer, returns Ok(None)
 /// * If there is data in the buffer that is definitely not a valid HTTP response, returns
-///   Err(Error)
+///   Err(UpstreamError)
 ///
 /// You won't need to touch this function.
-fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usize)>, Error> {
+fn parse_response(
+    buffer: &[u8],
+) -> Result<Option<(http::Response<Vec<u8>>, usize)>, UpstreamError> {
     let mut headers = [httparse::EMPTY_HEADER; MAX_NUM_HEADERS];
     let mut resp = httparse::Response::new(&mut headers);
     let res = resp
         .parse(buffer)
-        .or_else(|err| Err(Error::MalformedResponse(err)))?;
+        .or_else(|err| Err(UpstreamError::MalformedResponse(err)))?;
 
     if let httparse::Status::Complete(len) = res {
         let mut response = http::Response::builder()
             .status(resp.code.unwrap())
-            .version(http::Version::HTTP_11);
+            .version(if resp.version == Some(0) {
+                http::Version::HTTP_10
+            } else {
+                http::Version::HTTP_11
+            });
         for header in resp.headers {
             response = response.header(header.name, header.value);
         }
@@ -76,23 +71,52 @@ fn parse_response(buffer: &[u8]) -> Result<Option<(http::Response<Vec<u8>>, usiz
 /// sent. This function only reads the response line and headers; the read_body function can
 /// subsequently be called in order to read the response body.
 ///
-/// Returns Ok(http::Response) if a valid response is received, or Error if not.
+/// Returns Ok(http::Response) if a valid response is received, or a ProxyError if not.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>, Error> {
+///
+/// If header_timeout is given, the whole header block must arrive within that long of this
+/// function being called; otherwise, Timeout::Headers is returned. (This bounds only the headers,
+/// so a large body that is still trickling in isn't cut off.)
+///
+/// `already_read` holds bytes of the response that were read out of the stream earlier (after an
+/// interim response), which are parsed before anything more is read.
+async fn read_headers<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    header_timeout: Option<Duration>,
+    already_read: &[u8],
+) -> Result<http::Response<Vec<u8>>, ProxyError> {
+    let deadline = header_timeout.map(|timeout| tokio::time::Instant::now() + timeout);
     // Try reading the headers from the response. We may not receive all the headers in one shot
     // (e.g. we might receive the first few bytes of a response, and then the rest follows later).
     // Try parsing repeatedly until we read a valid HTTP response
     let mut response_buffer = [0_u8; MAX_HEADERS_SIZE];
-    let mut bytes_read = 0;
+    if already_read.len() > MAX_HEADERS_SIZE {
+        return Err(UpstreamError::MalformedResponse(httparse::Error::TooManyHeaders).into());
+    }
+    response_buffer[..already_read.len()].copy_from_slice(already_read);
+    let mut bytes_read = already_read.len();
+    if bytes_read > 0 {
+        if let Some((mut response, headers_len)) = parse_response(&response_buffer[..bytes_read])? {
+            response
+                .body_mut()
+                .extend_from_slice(&response_buffer[headers_len..bytes_read]);
+            return Ok(response);
+        }
+    }
     loop {
         // Read bytes from the connection into the buffer, starting at position bytes_read
-        let new_bytes = stream
-            .read(&mut response_buffer[bytes_read..]).await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+        let read = stream.read(&mut response_buffer[bytes_read..]);
+        let read_result = match deadline {
+            Some(deadline) => tokio::time::timeout_at(deadline, read)
+                .await
+                .or(Err(ProxyError::Timeout(Timeout::Headers(bytes_read))))?,
+            None => read.await,
+        };
+        let new_bytes = read_result.or_else(|err| Err(UpstreamError::ConnectionError(err)))?;
         if new_bytes == 0 {
             // We didn't manage to read a complete response
-            return Err(Error::IncompleteResponse);
+            return Err(UpstreamError::IncompleteResponse(bytes_read).into());
         }
         bytes_read += new_bytes;
 
@@ -111,19 +135,42 @@ async fn read_headers(stream: &mut TcpStream) -> Result<http::Response<Vec<u8>>,
 
 /// This function reads the body for a response from the stream. If the Content-Length header is
 /// present, it reads that many bytes; otherwise, it reads bytes until the connection is closed.
+/// Bodies bigger than `high_water` are left in the stream, and returned as Unread.
 ///
 /// You will need to modify this function in Milestone 2.
-async fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>>) -> Result<(), Error> {
+async fn read_body<S>(
+    stream: &mut S,
+    response: &mut http::Response<Vec<u8>>,
+    high_water: usize,
+) -> Result<Option<Unread>, UpstreamError>
+where
+    S: AsyncRead + Unpin,
+{
     // The response may or may not supply a Content-Length header. If it provides the header, then
     // we want to read that number of bytes; if it does not, we want to keep reading bytes until
     // the connection is closed.
     let content_length = get_content_length(response)?;
+    if let Some(content_length) = content_length {
+        if content_length > high_water {
+            let already_read = response.body().len();
+            if already_read > content_length {
+                return Err(UpstreamError::ContentLengthMismatch);
+            }
+            return Ok(Some(Unread::Length(content_length - already_read)));
+        }
+    }
 
     while content_length.is_none() || response.body().len() < content_length.unwrap() {
+        // Without a length, we can't tell how big the body is going to get, so once it passes the
+        // high-water mark, stream the rest instead
+        if content_length.is_none() && response.body().len() > high_water {
+            return Ok(Some(Unread::UntilClose));
+        }
+
         let mut buffer = [0_u8; 512];
         let bytes_read = stream
             .read(&mut buffer).await
-            .or_else(|err| Err(Error::ConnectionError(err)))?;
+            .or_else(|err| Err(UpstreamError::ConnectionError(err)))?;
         if bytes_read == 0 {
             // The server has hung up!
             if content_length.is_none() {
@@ -132,67 +179,220 @@ async fn read_body(stream: &mut TcpStream, response: &mut http::Response<Vec<u8>
             } else {
                 // Content-Length was set, but the server hung up before we managed to read that
                 // number of bytes
-                return Err(Error::ContentLengthMismatch);
+                return Err(UpstreamError::ContentLengthMismatch);
             }
         }
 
         // Make sure the server doesn't send more bytes than it promised to send
         if content_length.is_some() && response.body().len() + bytes_read > content_length.unwrap()
         {
-            return Err(Error::ContentLengthMismatch);
-        }
-
-        // Make sure server doesn't send more bytes than we allow
-        if response.body().len() + bytes_read > MAX_BODY_SIZE {
-            return Err(Error::ResponseBodyTooLarge);
+            return Err(UpstreamError::ContentLengthMismatch);
         }
 
         // Append received bytes to the response body
         response.body_mut().extend_from_slice(&buffer[..bytes_read]);
     }
-    Ok(())
+    Ok(None)
+}
+
+/// Reads and decodes a chunked response body, and rewrites the headers to describe the decoded
+/// body by its Content-Length. If the body is bigger than `high_water`, it is left chunked and
+/// returns the Unread rest instead.
+async fn read_chunked_body<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    response: &mut http::Response<Vec<u8>>,
+    high_water: usize,
+) -> Result<Option<Unread>, UpstreamError> {
+    let rest = chunked::read_body(stream, response.body_mut(), high_water)
+        .await
+        .map_err(|err| match err {
+            chunked::Error::InvalidFraming => UpstreamError::InvalidChunkedBody,
+            chunked::Error::Io(err) => UpstreamError::ConnectionError(err),
+        })?;
+    match rest {
+        // (Upstreams don't get to send anything after a response, so whatever follows it is
+        // dropped)
+        chunked::Decoded::Complete(_) => {
+            let body_len = response.body().len();
+            chunked::replace_with_content_length(response.headers_mut(), body_len);
+            Ok(None)
+        }
+        chunked::Decoded::TooBig(rest) => {
+            let mut body = std::mem::take(response.body_mut());
+            let unread = body::resume_chunked(response.headers_mut(), &mut body, rest, None);
+            *response.body_mut() = body;
+            Ok(Some(unread))
+        }
+    }
 }
 
-/// This function reads and returns an HTTP response from a stream, returning an Error if the server
-/// closes the connection prematurely or sends an invalid response.
+/// This function reads and returns an HTTP response from a stream, returning a ProxyError if the
+/// server closes the connection prematurely or sends an invalid response (UpstreamProtocol), or
+/// takes longer than header_timeout (if given) to send its headers or leaves a gap longer than
+/// body_timeout (if given) in its body (Timeout).
+///
+/// Bodies of up to `high_water` bytes are read in full. Only the start of a bigger body is read,
+/// and the rest is returned as Unread, to be streamed to the client after the response has been
+/// sent.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn read_from_stream(
-    stream: &mut TcpStream,
+pub async fn read_from_stream<S: AsyncRead + Unpin>(
+    stream: &mut S,
     request_method: &http::Method,
-) -> Result<http::Response<Vec<u8>>, Error> {
-    let mut response = read_headers(stream).await?;
-    // A response may have a body as long as it is not responding to a HEAD request and as long as
-    // the response status code is not 1xx, 204 (no content), or 304 (not modified).
-    if !(request_method == http::Method::HEAD
-        || response.status().as_u16() < 200
-        || response.status() == http::StatusCode::NO_CONTENT
-        || response.status() == http::StatusCode::NOT_MODIFIED)
+    header_timeout: Option<Duration>,
+    body_timeout: Option<Duration>,
+    high_water: usize,
+) -> Result<(http::Response<Vec<u8>>, Option<Unread>), ProxyError> {
+    let mut response = read_headers(stream, header_timeout, &[]).await?;
+    // Interim responses (100 Continue and the like) come ahead of the real one, and have no body,
+    // so whatever was read after their headers is the start of the next response. (101 Switching
+    // Protocols is the last response on its connection, so it's passed on.)
+    while response.status().is_informational()
+        && response.status() != http::StatusCode::SWITCHING_PROTOCOLS
     {
-        read_body(stream, &mut response).await?;
+        log::debug!(
+            "Skipping interim response from upstream: {}",
+            format_response_line(&response)
+        );
+        let already_read = std::mem::take(response.body_mut());
+        response = read_headers(stream, header_timeout, &already_read).await?;
+    }
+    if !may_have_body(request_method, response.status()) {
+        // Any bytes after the headers aren't part of this response, and leave the connection out
+        // of step with the next one, so they're dropped along with the connection
+        if !response.body().is_empty() {
+            log::debug!("Upstream sent a body with a response that can't have one");
+            response.body_mut().clear();
+            set_connection_close(&mut response);
+        }
+        return Ok((response, None));
     }
-    Ok(response)
+    let mut stream = IdleTimeout::new(stream, body_timeout);
+    let unread = if chunked::is_chunked(response.headers()) {
+        read_chunked_body(&mut stream, &mut response, high_water).await
+    } else {
+        read_body(&mut stream, &mut response, high_water).await
+    }
+    .map_err(|err| match err {
+        UpstreamError::ConnectionError(err) if err.kind() == std::io::ErrorKind::TimedOut => {
+            ProxyError::Timeout(Timeout::Body)
+        }
+        err => err.into(),
+    })?;
+    Ok((response, unread))
+}
+
+/// A response may have a body as long as it is not responding to a HEAD request and as long as
+/// the response status code is not 1xx, 204 (no content), or 304 (not modified).
+fn may_have_body(request_method: &http::Method, status: http::StatusCode) -> bool {
+    !(request_method == http::Method::HEAD
+        || status.as_u16() < 200
+        || status == http::StatusCode::NO_CONTENT
+        || status == http::StatusCode::NOT_MODIFIED)
+}
+
+/// Returns true if the end of the response is marked by the connection closing, rather than by its
+/// Content-Length or chunk framing (or by it having no body). The client can only find the end of
+/// such a response if we close its connection after it, too.
+pub fn ends_at_close(response: &http::Response<Vec<u8>>, request_method: &http::Method) -> bool {
+    !(response.headers().contains_key("content-length")
+        || chunked::is_chunked(response.headers())
+        || !may_have_body(request_method, response.status()))
 }
 
-/// This function serializes a response to bytes and writes those bytes to the provided stream.
+/// Returns true if the connection this response was read from can carry another request: the end
+/// of the response didn't depend on the upstream closing the connection, and the upstream didn't
+/// ask for the connection to be closed. (HTTP/1.0 upstreams close after every response unless
+/// they say otherwise with Connection: keep-alive.)
+pub fn connection_reusable(
+    response: &http::Response<Vec<u8>>,
+    request_method: &http::Method,
+) -> bool {
+    headers::is_persistent(response.headers(), response.version())
+        && !ends_at_close(response, request_method)
+}
+
+/// This function serializes a response to bytes and writes those bytes to the provided stream,
+/// returning how many bytes were written.
 ///
 /// You will need to modify this function in Milestone 2.
-pub async fn write_to_stream(
+pub async fn write_to_stream<S: AsyncWrite + Unpin>(
     response: &http::Response<Vec<u8>>,
-    stream: &mut TcpStream,
-) -> Result<(), std::io::Error> {
-    stream.write(&format_response_line(response).into_bytes()).await?;
-    stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+    stream: &mut S,
+) -> Result<usize, std::io::Error> {
+    // (write_all, since a single write may take only part of what it's given)
+    let mut bytes_written = 0;
+    let line = format_response_line(response);
+    for piece in &[line.as_bytes(), b"\r\n"] {
+        stream.write_all(piece).await?;
+        bytes_written += piece.len();
+    }
     for (header_name, header_value) in response.headers() {
-        stream.write(&format!("{}: ", header_name).as_bytes()).await?;
-        stream.write(header_value.as_bytes()).await?;
-        stream.write(&['\r' as u8, '\n' as u8]).await?; // \r\n
+        for piece in &[
+            header_name.as_str().as_bytes(),
+            b": ",
+            header_value.as_bytes(),
+            b"\r\n",
+        ] {
+            stream.write_all(piece).await?;
+            bytes_written += piece.len();
+        }
     }
-    stream.write(&['\r' as u8, '\n' as u8]).await?;
-    if response.body().len() > 0 {
-        stream.write(response.body()).await?;
+    stream.write_all(b"\r\n").await?;
+    bytes_written += 2;
+    stream.write_all(response.body()).await?;
+    bytes_written += response.body().len();
+    // Streams that buffer (like TLS streams) don't send anything until they're flushed
+    stream.flush().await?;
+    Ok(bytes_written)
+}
+
+/// Inserts `snippet` immediately before the last `</body>` tag of an HTML response, updating
+/// Content-Length to match. Responses that aren't `text/html`, that use a Transfer-Encoding or
+/// Content-Encoding (so the body bytes can't be edited in place), that are larger than
+/// `max_body_size`, or that have no `</body>` tag are left untouched.
+///
+/// Returns true if the snippet was injected.
+pub fn inject_before_body_end(
+    response: &mut http::Response<Vec<u8>>,
+    snippet: &str,
+    max_body_size: usize,
+) -> bool {
+    let is_html = response
+        .headers()
+        .get("content-type")
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| {
+            value.trim_start().to_ascii_lowercase().starts_with("text/html")
+        });
+    if !is_html
+        || response.headers().contains_key("transfer-encoding")
+        || response.headers().contains_key("content-encoding")
+        || response.body().len() > max_body_size
+    {
+        return false;
     }
-    Ok(())
+
+    const BODY_END_TAG: &[u8] = b"</body>";
+    let insert_at = match response
+        .body()
+        .windows(BODY_END_TAG.len())
+        .rposition(|window| window.eq_ignore_ascii_case(BODY_END_TAG))
+    {
+        Some(index) => index,
+        None => return false,
+    };
+    response
+        .body_mut()
+        .splice(insert_at..insert_at, snippet.bytes());
+    if response.headers().contains_key("content-length") {
+        let content_length = response.body().len().to_string();
+        response.headers_mut().insert(
+            "content-length",
+            http::HeaderValue::from_str(&content_length).unwrap(),
+        );
+    }
+    true
 }
 
 pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
@@ -205,14 +405,47 @@ pub fn format_response_line(response: &http::Response<Vec<u8>>) -> String {
 }
 
 /// This is a helper function that creates an http::Response containing an HTTP error that can be
-/// sent to a client.
+/// sent to a client. (Errors in proxying requests get pages from error_pages instead.)
 pub fn make_http_error(status: http::StatusCode) -> http::Response<Vec<u8>> {
-    let body = format!(
-        "HTTP {} {}",
-        status.as_u16(),
-        status.canonical_reason().unwrap_or("")
+    make_text_response(
+        status,
+        format!(
+            "HTTP {} {}",
+            status.as_u16(),
+            status.canonical_reason().unwrap_or("")
+        ),
     )
-    .into_bytes();
+}
+
+/// Marks a response as the last on its connection, for when balancebeam is going to hang up after
+/// sending it (e.g. because it gave up on reading the rest of the client's request).
+pub fn set_connection_close(response: &mut http::Response<Vec<u8>>) {
+    response
+        .headers_mut()
+        .insert("connection", http::HeaderValue::from_static("close"));
+}
+
+/// Sets the Connection header of a response that is about to be sent to a client: close if
+/// balancebeam is going to hang up after it, or keep-alive if an HTTP/1.0 client asked for the
+/// connection to be kept open (it would assume otherwise without one).
+pub fn set_connection_header(
+    response: &mut http::Response<Vec<u8>>,
+    keep_alive: bool,
+    client_version: http::Version,
+) {
+    if !keep_alive {
+        set_connection_close(response);
+    } else if client_version == http::Version::HTTP_10 {
+        response
+            .headers_mut()
+            .insert("connection", http::HeaderValue::from_static("keep-alive"));
+    }
+}
+
+/// Creates a plain-text http::Response with the given status and body, for responses that
+/// balancebeam generates itself.
+pub fn make_text_response(status: http::StatusCode, body: String) -> http::Response<Vec<u8>> {
+    let body = body.into_bytes();
     http::Response::builder()
         .status(status)
         .header("Content-Type", "text/plain")
@@ -0,0 +1,124 @@
+use rand::Rng;
+
+/// Requests whose path is `prefix`, or starts with `prefix` followed by a '/', go to `upstreams`
+#[derive(Debug)]
+pub struct Route {
+    pub prefix: String,
+    pub upstreams: Vec<String>,
+}
+
+/// A group of upstreams that takes `percent` of the requests that would otherwise go to the default
+/// upstreams, so that a new version can be rolled out to a few clients at a time
+#[derive(Debug)]
+pub struct Canary {
+    pub upstreams: Vec<String>,
+    pub percent: u8,
+}
+
+/// Requests whose Host is one of `hosts` go to `upstreams`, whatever their path
+#[derive(Debug)]
+pub struct VirtualHost {
+    pub hosts: Vec<String>,
+    pub upstreams: Vec<String>,
+}
+
+/// Decides which upstreams may serve each request, so that one balancebeam can front several
+/// services. A request for one of the virtual hosts goes to that host's upstreams. Otherwise, the
+/// route with the longest matching path prefix wins, and requests that match no route go to the
+/// default upstreams (the ones given with --upstream, or `upstreams` in the config file), or, for
+/// the canary's share of them, to the canary upstreams.
+#[derive(Debug)]
+pub struct RoutingTable {
+    virtual_hosts: Vec<VirtualHost>,
+    /// Longest prefix first, with any trailing '/' removed
+    routes: Vec<Route>,
+    default_upstreams: Vec<String>,
+    canary: Option<Canary>,
+}
+
+impl RoutingTable {
+    pub fn new(
+        default_upstreams: Vec<String>,
+        mut routes: Vec<Route>,
+        virtual_hosts: Vec<VirtualHost>,
+        canary: Option<Canary>,
+    ) -> RoutingTable {
+        for route in &mut routes {
+            // (This turns a prefix of "/" into "", which matches every path)
+            while route.prefix.ends_with('/') {
+                route.prefix.pop();
+            }
+        }
+        routes.sort_by_key(|route| std::cmp::Reverse(route.prefix.len()));
+        RoutingTable {
+            virtual_hosts,
+            routes,
+            default_upstreams,
+            canary,
+        }
+    }
+
+    pub fn canary(&self) -> Option<&Canary> {
+        self.canary.as_ref()
+    }
+
+    /// Changes the canary's share of requests, returning false if there's no canary.
+    pub fn set_canary_percent(&mut self, percent: u8) -> bool {
+        match &mut self.canary {
+            Some(canary) => {
+                canary.percent = percent;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the upstreams that `request` can be sent to (which is empty if nothing matches and
+    /// there are no default upstreams). Whether a request goes to the canary is picked at random
+    /// each time.
+    pub fn upstreams_for(&self, request: &http::Request<Vec<u8>>) -> &[String] {
+        if let Some(host) = request_host(request) {
+            let virtual_host = self.virtual_hosts.iter().find(|virtual_host| {
+                virtual_host
+                    .hosts
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(host))
+            });
+            if let Some(virtual_host) = virtual_host {
+                return &virtual_host.upstreams;
+            }
+        }
+        let path = request.uri().path();
+        self.routes
+            .iter()
+            .find(|route| {
+                path.strip_prefix(&route.prefix)
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+            })
+            .map_or_else(|| self.default_or_canary(), |route| &route.upstreams)
+    }
+
+    fn default_or_canary(&self) -> &[String] {
+        match &self.canary {
+            Some(canary) if rand::thread_rng().gen_range(0, 100) < canary.percent => {
+                &canary.upstreams
+            }
+            _ => &self.default_upstreams,
+        }
+    }
+}
+
+/// Returns the host `request` is for (from an absolute-form request target, or else the Host
+/// header), without any port.
+fn request_host(request: &http::Request<Vec<u8>>) -> Option<&str> {
+    if let Some(host) = request.uri().host() {
+        return Some(host);
+    }
+    let host = request.headers().get("host")?.to_str().ok()?;
+    match host.rsplit_once(':') {
+        Some((name, port)) if !port.is_empty() && port.bytes().all(|b| b.is_ascii_digit()) => {
+            Some(name)
+        }
+        _ => Some(host),
+    }
+}
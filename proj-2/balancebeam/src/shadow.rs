@@ -0,0 +1,127 @@
+use crate::{open_connection, request, response};
+use rand::Rng;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio_rustls::TlsConnector;
+
+/// How long a mirrored request may take altogether, connecting included
+const SHADOW_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most mirrored requests that can be waiting on the shadow upstream at once. Requests that come
+/// along while this many are outstanding aren't mirrored, so that a slow shadow can't pile up
+/// work in the proxy.
+const MAX_IN_FLIGHT: usize = 100;
+
+/// Response bodies bigger than this are left unread (the connection is closed instead)
+const BODY_HIGH_WATER_MARK: usize = 64 * 1024;
+
+/// Sends copies of some of the requests clients make to a shadow upstream, to try out a new
+/// backend on real traffic. Copies are sent in the background once the request has been rewritten
+/// for the upstreams, and the shadow's responses are thrown away, so clients never wait for it or
+/// see what it answered. Requests whose bodies are streamed (being too big to buffer) aren't
+/// mirrored.
+pub struct Shadow {
+    upstream: String,
+    /// Share of requests to mirror, as a percentage
+    percent: u8,
+    tls_connector: TlsConnector,
+    in_flight: Arc<Semaphore>,
+}
+
+impl Shadow {
+    pub fn new(upstream: String, percent: u8, tls_connector: TlsConnector) -> Shadow {
+        Shadow {
+            upstream,
+            percent,
+            tls_connector,
+            in_flight: Arc::new(Semaphore::new(MAX_IN_FLIGHT)),
+        }
+    }
+
+    /// Picks whether to mirror a request (at random, at the configured rate), and if so, sends a
+    /// copy of it to the shadow upstream in a task of its own.
+    pub fn maybe_mirror(&self, request: &http::Request<Vec<u8>>, request_id: &str) {
+        if rand::thread_rng().gen_range(0, 100) >= self.percent {
+            return;
+        }
+        let permit = match Arc::clone(&self.in_flight).try_acquire_owned() {
+            Ok(permit) => permit,
+            Err(_) => {
+                log::debug!(
+                    "[{}] Not mirroring request: too many are waiting on shadow upstream {}",
+                    request_id,
+                    self.upstream
+                );
+                return;
+            }
+        };
+        let upstream = self.upstream.clone();
+        let tls_connector = self.tls_connector.clone();
+        let request = copy_request(request);
+        let request_id = request_id.to_string();
+        tokio::spawn(async move {
+            let mirror = mirror(&upstream, &tls_connector, &request);
+            let result = tokio::time::timeout(SHADOW_TIMEOUT, mirror).await;
+            match result {
+                Ok(Ok(status)) => log::debug!(
+                    "[{}] Shadow upstream {} answered {} with {}",
+                    request_id,
+                    upstream,
+                    request::format_request_line(&request),
+                    status
+                ),
+                Ok(Err(message)) => log::warn!(
+                    "[{}] Could not mirror {} to shadow upstream {}: {}",
+                    request_id,
+                    request::format_request_line(&request),
+                    upstream,
+                    message
+                ),
+                Err(_) => log::warn!(
+                    "[{}] Shadow upstream {} did not answer {} within {}s",
+                    request_id,
+                    upstream,
+                    request::format_request_line(&request),
+                    SHADOW_TIMEOUT.as_secs()
+                ),
+            }
+            drop(permit);
+        });
+    }
+}
+
+/// (http::Request isn't Clone, since extensions can't be cloned, and we don't use any)
+fn copy_request(request: &http::Request<Vec<u8>>) -> http::Request<Vec<u8>> {
+    let mut copy = http::Request::new(request.body().clone());
+    *copy.method_mut() = request.method().clone();
+    *copy.uri_mut() = request.uri().clone();
+    *copy.version_mut() = request.version();
+    *copy.headers_mut() = request.headers().clone();
+    copy
+}
+
+/// Sends `request` to the shadow upstream on a new connection, and returns the status it answered
+/// with.
+async fn mirror(
+    upstream: &str,
+    tls_connector: &TlsConnector,
+    request: &http::Request<Vec<u8>>,
+) -> Result<http::StatusCode, String> {
+    let mut stream = open_connection(upstream, upstream, None, tls_connector, None, None)
+        .await
+        .map_err(|err| format!("could not connect: {}", err))?;
+    request::write_to_stream(request, &mut stream)
+        .await
+        .map_err(|err| format!("could not send request: {}", err))?;
+    let (response, _) = response::read_from_stream(
+        &mut stream,
+        request.method(),
+        None,
+        None,
+        BODY_HIGH_WATER_MARK,
+    )
+    .await
+    .map_err(|err| format!("could not read response: {:?}", err))?;
+    Ok(response.status())
+}
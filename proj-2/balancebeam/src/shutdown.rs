@@ -0,0 +1,36 @@
+use tokio::sync::broadcast;
+
+/// A connection handler's view of whether balancebeam is shutting down. Handlers finish the request
+/// they're working on, but stop waiting for new ones once this fires.
+pub struct Shutdown {
+    /// Set once the notification has been seen, since it is only delivered once
+    shutdown: bool,
+    notify: broadcast::Receiver<()>,
+}
+
+impl Shutdown {
+    pub fn new(notify: broadcast::Receiver<()>) -> Shutdown {
+        Shutdown {
+            shutdown: false,
+            notify,
+        }
+    }
+
+    /// Returns true if shutdown has started, without waiting.
+    pub fn is_shutdown(&mut self) -> bool {
+        if !self.shutdown {
+            // Anything other than "nothing yet" (including the sender having gone away) means
+            // we're shutting down
+            self.shutdown = !matches!(self.notify.try_recv(), Err(broadcast::TryRecvError::Empty));
+        }
+        self.shutdown
+    }
+
+    /// Waits until shutdown starts.
+    pub async fn recv(&mut self) {
+        if !self.shutdown {
+            let _ = self.notify.recv().await;
+            self.shutdown = true;
+        }
+    }
+}
@@ -0,0 +1,232 @@
+use crate::body::{self, CopyError, Unread};
+use std::os::unix::io::RawFd;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+/// Streams that bodies can be spliced to or from
+pub trait Splice {
+    /// Returns the socket underneath the stream, if reading from or writing to the socket directly
+    /// is the same as reading from or writing to the stream (that is, if the stream doesn't
+    /// encrypt, buffer or hand back anything of its own).
+    fn socket(&self) -> Option<RawFd>;
+
+    /// How long reads from the stream may wait for data before failing (see IdleTimeout)
+    fn idle_timeout(&self) -> Option<Duration> {
+        None
+    }
+}
+
+/// Copies the unread part of a body from `from` to `to`, like body::copy. On Linux, when both are
+/// plain sockets and the body doesn't have to be looked at on the way (that is, it isn't chunked),
+/// this is done with splice(2): the body goes from one socket to the other through a pipe in the
+/// kernel, without being copied into balancebeam's memory and back out, which saves a good deal of
+/// CPU time on big bodies. Everything else is copied by body::copy.
+pub async fn copy<R, W>(unread: Unread, from: &mut R, to: &mut W) -> Result<usize, CopyError>
+where
+    R: AsyncRead + Splice + Unpin,
+    W: AsyncWrite + Splice + Unpin,
+{
+    #[cfg(target_os = "linux")]
+    {
+        // (Some(None) = until the sender closes the connection)
+        let len = match unread {
+            Unread::Length(len) => Some(Some(len)),
+            Unread::UntilClose => Some(None),
+            _ => None,
+        };
+        if let (Some(len), Some(from_fd), Some(to_fd)) = (len, from.socket(), to.socket()) {
+            match linux::Splicer::new(from_fd, to_fd) {
+                Ok(splicer) => {
+                    log::debug!("Splicing the rest of the body");
+                    return splicer.copy(len, from.idle_timeout()).await;
+                }
+                Err(err) => log::warn!("Could not set up splicing, copying instead: {}", err),
+            }
+        }
+    }
+    body::copy(unread, from, to).await
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use crate::body::CopyError;
+    use mio::unix::EventedFd;
+    use mio::{Evented, PollOpt, Ready, Token};
+    use std::io;
+    use std::os::unix::io::RawFd;
+    use std::task::Poll;
+    use std::time::Duration;
+    use tokio::io::PollEvented;
+
+    /// Most bytes moved by one splice call (the default capacity of a pipe)
+    const SPLICE_CHUNK_SIZE: usize = 64 * 1024;
+
+    /// A file descriptor of our own, closed when dropped
+    struct Fd(RawFd);
+
+    impl Fd {
+        /// Duplicates `fd`. (The sockets being spliced belong to streams that are registered with
+        /// tokio already, and tokio 0.2's streams don't say when they're ready, so duplicates are
+        /// registered to find out instead.)
+        fn duplicate(fd: RawFd) -> io::Result<Fd> {
+            match unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) } {
+                -1 => Err(io::Error::last_os_error()),
+                fd => Ok(Fd(fd)),
+            }
+        }
+
+        /// Opens a non-blocking pipe, returning its read and write ends.
+        fn pipe() -> io::Result<(Fd, Fd)> {
+            let mut fds = [0; 2];
+            match unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } {
+                -1 => Err(io::Error::last_os_error()),
+                _ => Ok((Fd(fds[0]), Fd(fds[1]))),
+            }
+        }
+    }
+
+    impl Evented for Fd {
+        fn register(
+            &self,
+            poll: &mio::Poll,
+            token: Token,
+            interest: Ready,
+            opts: PollOpt,
+        ) -> io::Result<()> {
+            EventedFd(&self.0).register(poll, token, interest, opts)
+        }
+
+        fn reregister(
+            &self,
+            poll: &mio::Poll,
+            token: Token,
+            interest: Ready,
+            opts: PollOpt,
+        ) -> io::Result<()> {
+            EventedFd(&self.0).reregister(poll, token, interest, opts)
+        }
+
+        fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
+            EventedFd(&self.0).deregister(poll)
+        }
+    }
+
+    impl Drop for Fd {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.0) };
+        }
+    }
+
+    /// Moves bytes from one socket into a pipe, and from the pipe into another socket
+    pub struct Splicer {
+        from: PollEvented<Fd>,
+        to: PollEvented<Fd>,
+        pipe_read: Fd,
+        pipe_write: Fd,
+    }
+
+    impl Splicer {
+        pub fn new(from: RawFd, to: RawFd) -> io::Result<Splicer> {
+            let (pipe_read, pipe_write) = Fd::pipe()?;
+            Ok(Splicer {
+                from: PollEvented::new(Fd::duplicate(from)?)?,
+                to: PollEvented::new(Fd::duplicate(to)?)?,
+                pipe_read,
+                pipe_write,
+            })
+        }
+
+        /// Moves `len` bytes (or, if None, everything until the sender closes the connection),
+        /// failing with a TimedOut read error if the sender goes `idle_timeout` without sending
+        /// anything. Returns the number of bytes moved.
+        pub async fn copy(
+            self,
+            len: Option<usize>,
+            idle_timeout: Option<Duration>,
+        ) -> Result<usize, CopyError> {
+            let mut moved = 0;
+            while len.is_none_or(|len| moved < len) {
+                let want = len.map_or(SPLICE_CHUNK_SIZE, |len| {
+                    (len - moved).min(SPLICE_CHUNK_SIZE)
+                });
+                let fill = self.fill_pipe(want);
+                let filled = match idle_timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, fill).await.map_err(|_| {
+                        CopyError::Read(io::Error::new(
+                            io::ErrorKind::TimedOut,
+                            format!("no data for {}ms", timeout.as_millis()),
+                        ))
+                    })?,
+                    None => fill.await,
+                }
+                .map_err(CopyError::Read)?;
+                if filled == 0 {
+                    return match len {
+                        Some(_) => Err(CopyError::Malformed),
+                        None => Ok(moved),
+                    };
+                }
+                self.drain_pipe(filled).await.map_err(CopyError::Write)?;
+                moved += filled;
+            }
+            Ok(moved)
+        }
+
+        /// Moves up to `want` bytes from the sender into the (empty) pipe once there are some,
+        /// returning how many were moved (0 if the sender closed the connection).
+        async fn fill_pipe(&self, want: usize) -> io::Result<usize> {
+            let readable = Ready::readable();
+            std::future::poll_fn(|cx| loop {
+                if self.from.poll_read_ready(cx, readable)?.is_pending() {
+                    return Poll::Pending;
+                }
+                match splice(self.from.get_ref().0, self.pipe_write.0, want) {
+                    Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                        self.from.clear_read_ready(cx, readable)?;
+                    }
+                    result => return Poll::Ready(result),
+                }
+            })
+            .await
+        }
+
+        /// Moves `len` bytes from the pipe to the receiver.
+        async fn drain_pipe(&self, mut len: usize) -> io::Result<()> {
+            while len > 0 {
+                len -= std::future::poll_fn(|cx| loop {
+                    if self.to.poll_write_ready(cx)?.is_pending() {
+                        return Poll::Pending;
+                    }
+                    match splice(self.pipe_read.0, self.to.get_ref().0, len) {
+                        Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                            self.to.clear_write_ready(cx)?;
+                        }
+                        result => return Poll::Ready(result),
+                    }
+                })
+                .await?;
+            }
+            Ok(())
+        }
+    }
+
+    /// Moves up to `len` bytes from `from` to `to` (one of which has to be a pipe) without
+    /// blocking.
+    fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+        let flags = libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK;
+        let moved = unsafe {
+            libc::splice(
+                from,
+                std::ptr::null_mut(),
+                to,
+                std::ptr::null_mut(),
+                len,
+                flags,
+            )
+        };
+        match moved {
+            -1 => Err(io::Error::last_os_error()),
+            moved => Ok(moved as usize),
+        }
+    }
+}
@@ -0,0 +1,536 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Upper bounds (in seconds) of the buckets in the upstream latency histograms
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Counters describing the traffic balancebeam has handled since it started. Everything here is
+/// updated from many connection tasks at once, so it lives outside ProxyState's RwLock and only
+/// uses atomics and short-lived std mutexes.
+pub struct Stats {
+    started: Instant,
+    /// Responses sent to clients, including error responses generated by balancebeam itself
+    requests_served: AtomicUsize,
+    status_counts: Mutex<HashMap<u16, usize>>,
+    /// Requests forwarded to each upstream
+    upstream_counts: Mutex<HashMap<String, usize>>,
+    /// Failed connections to and exchanges with each upstream, and 5xx responses from it
+    upstream_errors: Mutex<HashMap<String, usize>>,
+    /// Bytes of requests forwarded to upstreams (and of anything clients sent through a tunnel)
+    bytes_in: AtomicUsize,
+    /// Bytes of responses sent to clients
+    bytes_out: AtomicUsize,
+    /// How long each upstream took to respond
+    upstream_latencies: Mutex<HashMap<String, LatencyHistogram>>,
+    /// Requests turned away by the per-IP rate limits
+    rate_limited: AtomicUsize,
+    /// Client connections that have been served (or are being served), counting ones that had
+    /// to wait for a --max-connections slot
+    connections_accepted: AtomicUsize,
+    /// Client connections accepted by each of the --workers
+    worker_connections: Mutex<HashMap<usize, usize>>,
+    active_connections: AtomicUsize,
+    peak_connections: AtomicUsize,
+    /// Like peak_connections, but can be reset (through the status endpoint) to measure the peak
+    /// over a particular period
+    peak_connections_since_reset: AtomicUsize,
+    /// Connections accepted past --max-connections that are waiting for one to close
+    queued_connections: AtomicUsize,
+    /// Connections closed as soon as they were accepted, because the queue was full
+    rejected_connections: AtomicUsize,
+    /// Times each upstream was marked dead or alive, keyed by upstream and "dead" or "alive"
+    health_transitions: Mutex<HashMap<(String, &'static str), usize>>,
+    /// Whether each upstream that has ever been drained through the admin API still is
+    drained_upstreams: Mutex<HashMap<String, bool>>,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            started: Instant::now(),
+            requests_served: AtomicUsize::new(0),
+            status_counts: Mutex::new(HashMap::new()),
+            upstream_counts: Mutex::new(HashMap::new()),
+            upstream_errors: Mutex::new(HashMap::new()),
+            bytes_in: AtomicUsize::new(0),
+            bytes_out: AtomicUsize::new(0),
+            upstream_latencies: Mutex::new(HashMap::new()),
+            rate_limited: AtomicUsize::new(0),
+            connections_accepted: AtomicUsize::new(0),
+            worker_connections: Mutex::new(HashMap::new()),
+            active_connections: AtomicUsize::new(0),
+            peak_connections: AtomicUsize::new(0),
+            peak_connections_since_reset: AtomicUsize::new(0),
+            queued_connections: AtomicUsize::new(0),
+            rejected_connections: AtomicUsize::new(0),
+            health_transitions: Mutex::new(HashMap::new()),
+            drained_upstreams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn record_response(&self, status: http::StatusCode) {
+        self.requests_served.fetch_add(1, Ordering::SeqCst);
+        *self
+            .status_counts
+            .lock()
+            .unwrap()
+            .entry(status.as_u16())
+            .or_insert(0) += 1;
+    }
+
+    pub fn record_forwarded(&self, upstream: &str) {
+        *self
+            .upstream_counts
+            .lock()
+            .unwrap()
+            .entry(upstream.to_string())
+            .or_insert(0) += 1;
+    }
+
+    /// Records a failed connection to or exchange with an upstream, or a 5xx response from it.
+    pub fn record_upstream_error(&self, upstream: &str) {
+        *self
+            .upstream_errors
+            .lock()
+            .unwrap()
+            .entry(upstream.to_string())
+            .or_insert(0) += 1;
+    }
+
+    pub fn record_worker_connection(&self, worker: usize) {
+        *self
+            .worker_connections
+            .lock()
+            .unwrap()
+            .entry(worker)
+            .or_insert(0) += 1;
+    }
+
+    pub fn record_bytes_in(&self, bytes: usize) {
+        self.bytes_in.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    pub fn record_bytes_out(&self, bytes: usize) {
+        self.bytes_out.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    /// Records how long an upstream took to respond, from sending the request to having read the
+    /// response.
+    pub fn record_upstream_latency(&self, upstream: &str, latency: Duration) {
+        self.upstream_latencies
+            .lock()
+            .unwrap()
+            .entry(upstream.to_string())
+            .or_default()
+            .observe(latency.as_secs_f64());
+    }
+
+    pub fn record_health_transition(&self, upstream: &str, alive: bool) {
+        let to = if alive { "alive" } else { "dead" };
+        *self
+            .health_transitions
+            .lock()
+            .unwrap()
+            .entry((upstream.to_string(), to))
+            .or_insert(0) += 1;
+    }
+
+    /// Records that an upstream was drained (or re-enabled, or dropped from the configuration
+    /// while drained, if not `drained`).
+    pub fn set_upstream_drained(&self, upstream: &str, drained: bool) {
+        self.drained_upstreams
+            .lock()
+            .unwrap()
+            .insert(upstream.to_string(), drained);
+    }
+
+    pub fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Counts a new client connection as active until the returned guard is dropped.
+    pub fn connection_opened(self: &Arc<Self>) -> ConnectionGuard {
+        self.connections_accepted.fetch_add(1, Ordering::SeqCst);
+        let active = self.active_connections.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak_connections.fetch_max(active, Ordering::SeqCst);
+        self.peak_connections_since_reset
+            .fetch_max(active, Ordering::SeqCst);
+        ConnectionGuard {
+            stats: Arc::clone(self),
+        }
+    }
+
+    pub fn active_connections(&self) -> usize {
+        self.active_connections.load(Ordering::SeqCst)
+    }
+
+    /// Counts a connection as waiting for a --max-connections slot until the returned guard is
+    /// dropped.
+    pub fn connection_queued(self: &Arc<Self>) -> QueuedGuard {
+        self.queued_connections.fetch_add(1, Ordering::SeqCst);
+        QueuedGuard {
+            stats: Arc::clone(self),
+        }
+    }
+
+    pub fn queued_connections(&self) -> usize {
+        self.queued_connections.load(Ordering::SeqCst)
+    }
+
+    pub fn record_rejected_connection(&self) {
+        self.rejected_connections.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Starts a new measurement period for peak_connections_since_reset. Connections that are
+    /// already open count towards the new period's peak.
+    pub fn reset_peak_connections(&self) {
+        self.peak_connections_since_reset.store(
+            self.active_connections.load(Ordering::SeqCst),
+            Ordering::SeqCst,
+        );
+    }
+
+    /// Renders the connection counters as "name value" lines for the status endpoint.
+    pub fn render_status(&self) -> String {
+        format!(
+            "active_connections {}\npeak_connections {}\npeak_connections_since_reset {}\n",
+            self.active_connections.load(Ordering::SeqCst),
+            self.peak_connections.load(Ordering::SeqCst),
+            self.peak_connections_since_reset.load(Ordering::SeqCst)
+        )
+    }
+
+    /// Renders everything in the Prometheus text exposition format, for the metrics endpoint.
+    pub fn render_metrics(&self) -> String {
+        let mut out = String::new();
+        write_metric_header(
+            &mut out,
+            "balancebeam_requests_total",
+            "counter",
+            "Responses sent to clients, including errors generated by balancebeam",
+        );
+        let requests_served = self.requests_served.load(Ordering::SeqCst);
+        writeln!(out, "balancebeam_requests_total {}", requests_served).unwrap();
+
+        write_metric_header(
+            &mut out,
+            "balancebeam_responses_total",
+            "counter",
+            "Responses sent to clients, by status code",
+        );
+        for (code, count) in sorted(&self.status_counts.lock().unwrap()) {
+            writeln!(
+                out,
+                "balancebeam_responses_total{{code=\"{}\"}} {}",
+                code, count
+            )
+            .unwrap();
+        }
+
+        write_metric_header(
+            &mut out,
+            "balancebeam_upstream_requests_total",
+            "counter",
+            "Requests forwarded to each upstream",
+        );
+        for (upstream, count) in sorted(&self.upstream_counts.lock().unwrap()) {
+            writeln!(
+                out,
+                "balancebeam_upstream_requests_total{{upstream=\"{}\"}} {}",
+                upstream, count
+            )
+            .unwrap();
+        }
+
+        write_metric_header(
+            &mut out,
+            "balancebeam_upstream_errors_total",
+            "counter",
+            "Failed connections to and exchanges with each upstream, and 5xx responses from it",
+        );
+        for (upstream, count) in sorted(&self.upstream_errors.lock().unwrap()) {
+            writeln!(
+                out,
+                "balancebeam_upstream_errors_total{{upstream=\"{}\"}} {}",
+                upstream, count
+            )
+            .unwrap();
+        }
+
+        write_metric_header(
+            &mut out,
+            "balancebeam_request_bytes_total",
+            "counter",
+            "Bytes of requests forwarded to upstreams, including ones sent through tunnels",
+        );
+        let bytes_in = self.bytes_in.load(Ordering::SeqCst);
+        writeln!(out, "balancebeam_request_bytes_total {}", bytes_in).unwrap();
+
+        write_metric_header(
+            &mut out,
+            "balancebeam_response_bytes_total",
+            "counter",
+            "Bytes of responses sent to clients",
+        );
+        let bytes_out = self.bytes_out.load(Ordering::SeqCst);
+        writeln!(out, "balancebeam_response_bytes_total {}", bytes_out).unwrap();
+
+        write_metric_header(
+            &mut out,
+            "balancebeam_upstream_latency_seconds",
+            "histogram",
+            "Time from sending a request to an upstream to having read its response",
+        );
+        for (upstream, histogram) in sorted(&self.upstream_latencies.lock().unwrap()) {
+            histogram.render(&mut out, "balancebeam_upstream_latency_seconds", upstream);
+        }
+
+        write_metric_header(
+            &mut out,
+            "balancebeam_upstream_health_transitions_total",
+            "counter",
+            "Times each upstream was marked dead or alive again",
+        );
+        for ((upstream, to), count) in sorted(&self.health_transitions.lock().unwrap()) {
+            writeln!(
+                out,
+                "balancebeam_upstream_health_transitions_total{{upstream=\"{}\",to=\"{}\"}} {}",
+                upstream, to, count
+            )
+            .unwrap();
+        }
+
+        write_metric_header(
+            &mut out,
+            "balancebeam_upstream_drained",
+            "gauge",
+            "Whether each upstream has been drained through the admin API (1) or not (0)",
+        );
+        for (upstream, drained) in sorted(&self.drained_upstreams.lock().unwrap()) {
+            writeln!(
+                out,
+                "balancebeam_upstream_drained{{upstream=\"{}\"}} {}",
+                upstream, *drained as u8
+            )
+            .unwrap();
+        }
+
+        write_metric_header(
+            &mut out,
+            "balancebeam_connections_total",
+            "counter",
+            "Client connections accepted (not counting ones rejected because the queue was full)",
+        );
+        let connections_accepted = self.connections_accepted.load(Ordering::SeqCst);
+        writeln!(
+            out,
+            "balancebeam_connections_total {}",
+            connections_accepted
+        )
+        .unwrap();
+
+        write_metric_header(
+            &mut out,
+            "balancebeam_worker_connections_total",
+            "counter",
+            "Client connections accepted by each worker (see --workers)",
+        );
+        for (worker, count) in sorted(&self.worker_connections.lock().unwrap()) {
+            writeln!(
+                out,
+                "balancebeam_worker_connections_total{{worker=\"{}\"}} {}",
+                worker, count
+            )
+            .unwrap();
+        }
+
+        write_metric_header(
+            &mut out,
+            "balancebeam_active_connections",
+            "gauge",
+            "Client connections currently open",
+        );
+        let active_connections = self.active_connections.load(Ordering::SeqCst);
+        writeln!(out, "balancebeam_active_connections {}", active_connections).unwrap();
+
+        write_metric_header(
+            &mut out,
+            "balancebeam_queued_connections",
+            "gauge",
+            "Client connections accepted past --max-connections, waiting for one to close",
+        );
+        let queued_connections = self.queued_connections.load(Ordering::SeqCst);
+        writeln!(out, "balancebeam_queued_connections {}", queued_connections).unwrap();
+
+        write_metric_header(
+            &mut out,
+            "balancebeam_rejected_connections_total",
+            "counter",
+            "Client connections closed on arrival because the connection queue was full",
+        );
+        let rejected_connections = self.rejected_connections.load(Ordering::SeqCst);
+        writeln!(
+            out,
+            "balancebeam_rejected_connections_total {}",
+            rejected_connections
+        )
+        .unwrap();
+
+        write_metric_header(
+            &mut out,
+            "balancebeam_rate_limited_requests_total",
+            "counter",
+            "Requests rejected with 429 by the per-IP rate limits",
+        );
+        let rate_limited = self.rate_limited.load(Ordering::SeqCst);
+        writeln!(
+            out,
+            "balancebeam_rate_limited_requests_total {}",
+            rate_limited
+        )
+        .unwrap();
+        out
+    }
+
+    /// Logs the traffic handled so far (totals since balancebeam started), every --stats-log-secs.
+    pub fn log_periodic_summary(&self) {
+        let upstream_counts = self.upstream_counts.lock().unwrap().clone();
+        log::info!(
+            "Stats: connections_accepted={} active_connections={} requests_proxied={} bytes_in={} \
+            bytes_out={}",
+            self.connections_accepted.load(Ordering::SeqCst),
+            self.active_connections.load(Ordering::SeqCst),
+            upstream_counts.values().sum::<usize>(),
+            self.bytes_in.load(Ordering::SeqCst),
+            self.bytes_out.load(Ordering::SeqCst)
+        );
+        log::info!(
+            "Stats: upstream_requests={} upstream_errors={}",
+            format_counts(&upstream_counts),
+            format_counts(&self.upstream_errors.lock().unwrap())
+        );
+    }
+
+    /// Logs a final report of everything balancebeam has done. Called on the way out during
+    /// shutdown.
+    pub fn log_summary(&self) {
+        log::info!(
+            "Shutdown summary: uptime={:.1}s requests_served={} peak_connections={}",
+            self.started.elapsed().as_secs_f64(),
+            self.requests_served.load(Ordering::SeqCst),
+            self.peak_connections.load(Ordering::SeqCst)
+        );
+        log::info!(
+            "Shutdown summary: status_counts={}",
+            format_counts(&self.status_counts.lock().unwrap())
+        );
+        log::info!(
+            "Shutdown summary: upstream_counts={}",
+            format_counts(&self.upstream_counts.lock().unwrap())
+        );
+    }
+}
+
+/// Returns the entries of a map sorted by key, so that output built from them is stable.
+fn sorted<K: Ord, V>(map: &HashMap<K, V>) -> Vec<(&K, &V)> {
+    let mut entries: Vec<(&K, &V)> = map.iter().collect();
+    entries.sort_by(|a, b| a.0.cmp(b.0));
+    entries
+}
+
+/// Formats counts as space-separated key=count pairs, sorted by key so the output is stable.
+fn format_counts<K: Ord + std::fmt::Display>(counts: &HashMap<K, usize>) -> String {
+    let counts = sorted(counts);
+    let pairs: Vec<String> = counts
+        .iter()
+        .map(|(key, count)| format!("{}={}", key, count))
+        .collect();
+    if pairs.is_empty() {
+        "none".to_string()
+    } else {
+        pairs.join(" ")
+    }
+}
+
+/// Marks a client connection as active for as long as it is alive, so the count stays right no
+/// matter which way handle_connection returns.
+pub struct ConnectionGuard {
+    stats: Arc<Stats>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.stats.active_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Counts a connection as queued for as long as it is alive
+pub struct QueuedGuard {
+    stats: Arc<Stats>,
+}
+
+impl Drop for QueuedGuard {
+    fn drop(&mut self) {
+        self.stats.queued_connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn write_metric_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// A Prometheus-style histogram: bucket counts are cumulative, so each one counts every
+/// observation less than or equal to its bound.
+#[derive(Default)]
+struct LatencyHistogram {
+    bucket_counts: [usize; LATENCY_BUCKETS.len()],
+    count: usize,
+    sum: f64,
+}
+
+impl LatencyHistogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bound, bucket_count) in LATENCY_BUCKETS.iter().zip(self.bucket_counts.iter_mut()) {
+            if seconds <= *bound {
+                *bucket_count += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    fn render(&self, out: &mut String, name: &str, upstream: &str) {
+        for (bound, bucket_count) in LATENCY_BUCKETS.iter().zip(self.bucket_counts.iter()) {
+            writeln!(
+                out,
+                "{}_bucket{{upstream=\"{}\",le=\"{}\"}} {}",
+                name, upstream, bound, bucket_count
+            )
+            .unwrap();
+        }
+        writeln!(
+            out,
+            "{}_bucket{{upstream=\"{}\",le=\"+Inf\"}} {}",
+            name, upstream, self.count
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "{}_sum{{upstream=\"{}\"}} {}",
+            name, upstream, self.sum
+        )
+        .unwrap();
+        writeln!(
+            out,
+            "{}_count{{upstream=\"{}\"}} {}",
+            name, upstream, self.count
+        )
+        .unwrap();
+    }
+}
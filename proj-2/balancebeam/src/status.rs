@@ -0,0 +1,81 @@
+use crate::request::{self, HeaderLimits};
+use crate::response;
+use crate::stats::Stats;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::stream::StreamExt;
+
+/// Bodies bigger than this are not read, and the connection is dropped instead
+const MAX_BODY_SIZE: usize = 4096;
+
+/// Decides how one of balancebeam's own endpoints responds to a request
+pub type Routes = fn(&http::Request<Vec<u8>>, &Stats) -> http::Response<Vec<u8>>;
+
+/// Serves one of balancebeam's own endpoints on a separate listener, so it never competes with (or
+/// gets proxied like) client traffic. `routes` is either status_routes or metrics_routes.
+pub async fn serve(mut listener: TcpListener, stats: Arc<Stats>, routes: Routes) {
+    while let Some(stream) = listener.next().await {
+        if let Ok(stream) = stream {
+            let stats = Arc::clone(&stats);
+            tokio::spawn(async move {
+                handle_connection(stream, stats, routes).await;
+            });
+        }
+    }
+}
+
+/// The status endpoint:
+///
+/// * `GET /status` returns the connection counters as plain text
+/// * `POST /status/reset` starts a new period for the since-reset peak
+pub fn status_routes(request: &http::Request<Vec<u8>>, stats: &Stats) -> http::Response<Vec<u8>> {
+    match (request.method(), request.uri().path()) {
+        (&http::Method::GET, "/status") => {
+            response::make_text_response(http::StatusCode::OK, stats.render_status())
+        }
+        (&http::Method::POST, "/status/reset") => {
+            stats.reset_peak_connections();
+            response::make_text_response(http::StatusCode::OK, stats.render_status())
+        }
+        _ => response::make_http_error(http::StatusCode::NOT_FOUND),
+    }
+}
+
+/// The metrics endpoint: `GET /metrics` returns everything in Stats in the Prometheus text format
+pub fn metrics_routes(request: &http::Request<Vec<u8>>, stats: &Stats) -> http::Response<Vec<u8>> {
+    match (request.method(), request.uri().path()) {
+        (&http::Method::GET, "/metrics") => {
+            let mut response =
+                response::make_text_response(http::StatusCode::OK, stats.render_metrics());
+            response.headers_mut().insert(
+                "content-type",
+                http::HeaderValue::from_static("text/plain; version=0.0.4"),
+            );
+            response
+        }
+        _ => response::make_http_error(http::StatusCode::NOT_FOUND),
+    }
+}
+
+async fn handle_connection(mut conn: TcpStream, stats: Arc<Stats>, routes: Routes) {
+    loop {
+        let read = request::read_from_stream(
+            &mut conn,
+            None,
+            None,
+            MAX_BODY_SIZE,
+            None,
+            HeaderLimits::default(),
+        );
+        let request = match read.await {
+            // (A request pipelined behind a chunked one isn't worth keeping the connection for)
+            Ok((request, None, leftover)) if leftover.is_empty() => request,
+            _ => return,
+        };
+        let response = routes(&request, &stats);
+        if let Err(error) = response::write_to_stream(&response, &mut conn).await {
+            log::warn!("Failed to send status response: {}", error);
+            return;
+        }
+    }
+}
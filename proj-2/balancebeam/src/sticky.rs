@@ -0,0 +1,48 @@
+use http::header::HeaderMap;
+
+/// The cookie that names a client's upstream
+pub const COOKIE_NAME: &str = "BALANCEBEAM_STICKY";
+
+/// With --sticky-sessions, each client is told which upstream served it through a cookie, and its
+/// later requests go back to that upstream for as long as it can take them (when it can't, another
+/// is picked, and the cookie is updated). The cookie holds a hash of the upstream's address rather
+/// than the address itself, so that clients don't learn where the upstreams are. (The hash is
+/// FNV-1a, which stays the same across balancebeam builds, so cookies survive restarts and work
+/// across several balancebeams in front of the same upstreams.)
+pub fn cookie_value(upstream: &str) -> String {
+    let hash = upstream.bytes().fold(0xcbf29ce484222325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    });
+    format!("{:016x}", hash)
+}
+
+/// Returns the value of the sticky cookie the client sent, if any.
+pub fn requested(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get_all("cookie")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| cookie.trim().split_once('='))
+        .find(|(name, _)| *name == COOKIE_NAME)
+        .map(|(_, value)| value.trim_matches('"').to_string())
+}
+
+/// Returns which of `candidates` the sticky cookie `value` names, if any.
+pub fn find<'a>(value: &str, candidates: &'a [String]) -> Option<&'a String> {
+    candidates
+        .iter()
+        .find(|upstream| cookie_value(upstream) == value)
+}
+
+/// Adds a Set-Cookie header to `response` pointing the client at `upstream`.
+pub fn set_cookie(response: &mut http::Response<Vec<u8>>, upstream: &str) {
+    let cookie = format!(
+        "{}={}; Path=/; HttpOnly",
+        COOKIE_NAME,
+        cookie_value(upstream)
+    );
+    response
+        .headers_mut()
+        .append("set-cookie", http::HeaderValue::from_str(&cookie).unwrap());
+}
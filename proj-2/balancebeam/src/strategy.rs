@@ -0,0 +1,321 @@
+use rand::{Rng, SeedableRng};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Points each upstream gets on the ip-hash ring per unit of weight. More points spread clients
+/// more evenly, at the cost of a bigger ring.
+const RING_POINTS_PER_WEIGHT: usize = 100;
+
+/// How quickly an upstream's latency average forgets: the weight of what it knew falls to 1/e over
+/// this long
+const EWMA_DECAY: Duration = Duration::from_secs(10);
+
+/// What strategies know about an upstream besides its address
+pub struct UpstreamInfo {
+    /// Relative share of connections this upstream should get (at least 1)
+    pub weight: usize,
+    /// Number of client connections currently being proxied to this upstream
+    pub active_connections: Arc<AtomicUsize>,
+    /// How long this upstream has been taking to respond lately
+    pub latency: Arc<LatencyEwma>,
+}
+
+/// UpstreamInfo for every configured upstream (dead or alive), keyed by address
+pub type UpstreamInfoMap = HashMap<String, UpstreamInfo>;
+
+/// Decides which upstream each new client connection is sent to. Strategies are shared by every
+/// connection task (and only ever borrowed from ProxyState under a read lock), so any bookkeeping
+/// they do has to go through atomics or their own locks.
+pub trait LoadBalancingStrategy: Send + Sync {
+    /// Returns the index of the upstream to use for a connection from `client_ip`. `upstreams` is
+    /// never empty, and every entry in it has an entry in `info`.
+    fn choose(&self, upstreams: &[String], info: &UpstreamInfoMap, client_ip: IpAddr) -> usize;
+}
+
+/// Maps `ticket` (which must be less than the upstreams' total weight) to an upstream, giving each
+/// upstream a run of tickets as long as its weight.
+fn pick_by_weight(upstreams: &[String], info: &UpstreamInfoMap, mut ticket: usize) -> usize {
+    for (idx, upstream) in upstreams.iter().enumerate() {
+        let weight = info[upstream].weight;
+        if ticket < weight {
+            return idx;
+        }
+        ticket -= weight;
+    }
+    unreachable!("ticket was larger than the total weight")
+}
+
+fn total_weight(upstreams: &[String], info: &UpstreamInfoMap) -> usize {
+    upstreams.iter().map(|upstream| info[upstream].weight).sum()
+}
+
+/// Values accepted by `--strategy` (and `strategy` in the config file)
+#[derive(clap::ValueEnum, serde::Deserialize, Clone, Copy, Debug)]
+#[serde(rename_all = "kebab-case")]
+pub enum StrategyKind {
+    /// Pick an upstream at random, in proportion to its weight
+    Random,
+    /// Cycle through the upstreams in order, giving each as many turns in a row as its weight
+    RoundRobin,
+    /// Pick the upstream with the fewest active connections relative to its weight
+    LeastConnections,
+    /// Send each client IP to the same upstream every time (as long as that upstream is alive)
+    IpHash,
+    /// Pick the upstream that has been responding fastest lately, allowing for the connections it
+    /// already has and its weight
+    Ewma,
+}
+
+impl StrategyKind {
+    pub fn build(self) -> Box<dyn LoadBalancingStrategy> {
+        match self {
+            StrategyKind::Random => Box::new(Random),
+            StrategyKind::RoundRobin => Box::new(RoundRobin::new()),
+            StrategyKind::LeastConnections => Box::new(LeastConnections::new()),
+            StrategyKind::IpHash => Box::new(IpHash::new()),
+            StrategyKind::Ewma => Box::new(Ewma::new()),
+        }
+    }
+}
+
+pub struct Random;
+
+impl LoadBalancingStrategy for Random {
+    fn choose(&self, upstreams: &[String], info: &UpstreamInfoMap, _client_ip: IpAddr) -> usize {
+        let mut rng = rand::rngs::StdRng::from_entropy();
+        let ticket = rng.gen_range(0, total_weight(upstreams, info));
+        pick_by_weight(upstreams, info, ticket)
+    }
+}
+
+/// Hands out upstreams in turn. If the list of upstreams shrinks (because one failed), the cycle
+/// just carries on over the shorter list.
+pub struct RoundRobin {
+    next: AtomicUsize,
+}
+
+impl RoundRobin {
+    pub fn new() -> RoundRobin {
+        RoundRobin {
+            next: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl LoadBalancingStrategy for RoundRobin {
+    fn choose(&self, upstreams: &[String], info: &UpstreamInfoMap, _client_ip: IpAddr) -> usize {
+        let ticket = self.next.fetch_add(1, Ordering::Relaxed) % total_weight(upstreams, info);
+        pick_by_weight(upstreams, info, ticket)
+    }
+}
+
+/// Sends each connection to the upstream that is currently handling the fewest, relative to its
+/// weight (so an upstream with weight 2 is as loaded with two connections as an upstream with
+/// weight 1 is with one). Ties are broken by
+/// rotating through the upstreams (starting with the first), so that an idle pool still gets used
+/// evenly.
+pub struct LeastConnections {
+    next_tiebreak: AtomicUsize,
+}
+
+impl LeastConnections {
+    pub fn new() -> LeastConnections {
+        LeastConnections {
+            next_tiebreak: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl LoadBalancingStrategy for LeastConnections {
+    fn choose(&self, upstreams: &[String], info: &UpstreamInfoMap, _client_ip: IpAddr) -> usize {
+        let start = self.next_tiebreak.fetch_add(1, Ordering::Relaxed);
+        let load = |idx: usize| {
+            let upstream = &info[&upstreams[idx]];
+            (upstream.active_connections.load(Ordering::SeqCst), upstream.weight)
+        };
+        // Compare active/weight ratios by cross-multiplying, to stay in integers
+        (0..upstreams.len())
+            .map(|offset| (start + offset) % upstreams.len())
+            .min_by(|&a, &b| {
+                let ((a_active, a_weight), (b_active, b_weight)) = (load(a), load(b));
+                (a_active * b_weight).cmp(&(b_active * a_weight))
+            })
+            .unwrap()
+    }
+}
+
+/// Places every configured upstream (dead or alive) on a consistent-hash ring, with a number of
+/// points proportional to its weight, and sends each client IP to the first upstream clockwise of
+/// the IP's hash that is alive. Dead upstreams are skipped rather than taken off the ring, so only
+/// their clients move (spread over the remaining upstreams), and those clients move back once the
+/// upstream recovers.
+pub struct IpHash {
+    /// Rebuilt whenever the configured upstreams change (on a config reload)
+    ring: Mutex<Ring>,
+}
+
+/// Ring points sorted by hash, each pointing at an upstream address
+#[derive(Default)]
+struct Ring {
+    /// The (address, weight) pairs the ring was built from
+    built_from: Vec<(String, usize)>,
+    points: Vec<(u64, String)>,
+}
+
+impl IpHash {
+    pub fn new() -> IpHash {
+        IpHash {
+            ring: Mutex::new(Ring::default()),
+        }
+    }
+}
+
+fn hash<T: Hash>(value: T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl Ring {
+    fn build(info: &UpstreamInfoMap) -> Ring {
+        let mut built_from: Vec<(String, usize)> = info
+            .iter()
+            .map(|(address, upstream)| (address.clone(), upstream.weight))
+            .collect();
+        built_from.sort();
+        let mut points: Vec<(u64, String)> = built_from
+            .iter()
+            .flat_map(|(address, weight)| {
+                (0..weight * RING_POINTS_PER_WEIGHT)
+                    .map(move |point| (hash((address, point)), address.clone()))
+            })
+            .collect();
+        points.sort();
+        Ring { built_from, points }
+    }
+
+    fn is_built_from(&self, info: &UpstreamInfoMap) -> bool {
+        self.built_from.len() == info.len()
+            && self.built_from.iter().all(|(address, weight)| {
+                info.get(address).map(|upstream| upstream.weight) == Some(*weight)
+            })
+    }
+}
+
+impl LoadBalancingStrategy for IpHash {
+    fn choose(&self, upstreams: &[String], info: &UpstreamInfoMap, client_ip: IpAddr) -> usize {
+        let mut ring = self.ring.lock().unwrap();
+        if !ring.is_built_from(info) {
+            *ring = Ring::build(info);
+        }
+        let start = ring
+            .points
+            .partition_point(|(point, _)| *point < hash(client_ip));
+        // Walk clockwise (wrapping around) until we reach an upstream that is alive
+        ring.points[start..]
+            .iter()
+            .chain(&ring.points[..start])
+            .find_map(|(_, address)| upstreams.iter().position(|upstream| upstream == address))
+            .unwrap()
+    }
+}
+
+/// An exponentially weighted moving average of an upstream's response latency, in seconds. Older
+/// samples count for less the longer ago they were taken (see EWMA_DECAY), rather than the more
+/// samples have come since, so that an upstream that's rarely picked isn't judged by stale numbers.
+///
+/// A sample above the average replaces it outright, so an upstream that's slowing down is avoided
+/// straight away rather than once enough slow responses have piled up. Between samples the average
+/// decays towards zero, so that an upstream that was avoided for being slow eventually gets
+/// another try.
+#[derive(Default)]
+pub struct LatencyEwma {
+    /// The average, and when it was last updated (None until the first sample)
+    average: Mutex<Option<(f64, Instant)>>,
+}
+
+/// How much of an average that's `elapsed` old is left
+fn decay(elapsed: Duration) -> f64 {
+    (-elapsed.as_secs_f64() / EWMA_DECAY.as_secs_f64()).exp()
+}
+
+impl LatencyEwma {
+    pub fn observe(&self, latency: Duration) {
+        let sample = latency.as_secs_f64();
+        let now = Instant::now();
+        let mut average = self.average.lock().unwrap();
+        let updated = match *average {
+            Some((old, updated)) if sample < old => {
+                let weight = decay(now - updated);
+                old * weight + sample * (1.0 - weight)
+            }
+            _ => sample,
+        };
+        *average = Some((updated, now));
+    }
+
+    /// Returns the current average (0 if the upstream hasn't responded yet)
+    pub fn get(&self) -> f64 {
+        match *self.average.lock().unwrap() {
+            Some((average, updated)) => average * decay(updated.elapsed()),
+            None => 0.0,
+        }
+    }
+}
+
+/// Sends each connection to the upstream with the lowest expected cost: its latency average times
+/// one more than its active connections (since they're all waiting their turn too), divided by its
+/// weight. Upstreams that haven't responded yet cost nothing, so each one is tried before the
+/// averages take over. Ties are broken by rotating through the upstreams, as with
+/// LeastConnections.
+pub struct Ewma {
+    next_tiebreak: AtomicUsize,
+}
+
+impl Ewma {
+    pub fn new() -> Ewma {
+        Ewma {
+            next_tiebreak: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl LoadBalancingStrategy for Ewma {
+    fn choose(&self, upstreams: &[String], info: &UpstreamInfoMap, _client_ip: IpAddr) -> usize {
+        let start = self.next_tiebreak.fetch_add(1, Ordering::Relaxed);
+        let cost = |idx: usize| {
+            let upstream = &info[&upstreams[idx]];
+            let pending = upstream.active_connections.load(Ordering::SeqCst) + 1;
+            upstream.latency.get() * pending as f64 / upstream.weight as f64
+        };
+        (0..upstreams.len())
+            .map(|offset| (start + offset) % upstreams.len())
+            .min_by(|&a, &b| cost(a).partial_cmp(&cost(b)).unwrap())
+            .unwrap()
+    }
+}
+
+/// Counts a client connection against an upstream's active_connections until it is dropped.
+pub struct UpstreamConnectionGuard {
+    count: Arc<AtomicUsize>,
+}
+
+impl UpstreamConnectionGuard {
+    pub fn new(count: &Arc<AtomicUsize>) -> UpstreamConnectionGuard {
+        count.fetch_add(1, Ordering::SeqCst);
+        UpstreamConnectionGuard {
+            count: Arc::clone(count),
+        }
+    }
+}
+
+impl Drop for UpstreamConnectionGuard {
+    fn drop(&mut self) {
+        self.count.fetch_sub(1, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,82 @@
+use crate::splice::Splice;
+use std::future::Future;
+use std::os::unix::io::RawFd;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::AsyncRead;
+use tokio::time::Delay;
+
+/// Wraps a stream so that reads fail (with io::ErrorKind::TimedOut) once the stream has gone
+/// `timeout` without any data arriving. The clock starts over whenever data arrives, so a peer
+/// that keeps sending can take as long as it likes overall. With no timeout, reads are passed
+/// straight through.
+pub struct IdleTimeout<'a, S> {
+    inner: &'a mut S,
+    timeout: Option<Duration>,
+    /// Running while a read is waiting for data
+    delay: Option<Delay>,
+}
+
+impl<'a, S> IdleTimeout<'a, S> {
+    pub fn new(inner: &'a mut S, timeout: Option<Duration>) -> IdleTimeout<'a, S> {
+        IdleTimeout {
+            inner,
+            timeout,
+            delay: None,
+        }
+    }
+}
+
+impl<S: Splice> Splice for IdleTimeout<'_, S> {
+    fn socket(&self) -> Option<RawFd> {
+        self.inner.socket()
+    }
+
+    fn idle_timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for IdleTimeout<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        if let Poll::Ready(result) = Pin::new(&mut *this.inner).poll_read(cx, buf) {
+            this.delay = None;
+            return Poll::Ready(result);
+        }
+        let timeout = match this.timeout {
+            Some(timeout) => timeout,
+            None => return Poll::Pending,
+        };
+        let delay = this
+            .delay
+            .get_or_insert_with(|| tokio::time::delay_for(timeout));
+        match Pin::new(delay).poll(cx) {
+            Poll::Ready(()) => {
+                this.delay = None;
+                Poll::Ready(Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    format!("no data for {}ms", timeout.as_millis()),
+                )))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+/// Runs `future` to completion, unless `deadline` (if there is one) passes first, in which case
+/// the future is dropped and None is returned.
+pub async fn before_deadline<F: Future>(
+    deadline: Option<tokio::time::Instant>,
+    future: F,
+) -> Option<F::Output> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
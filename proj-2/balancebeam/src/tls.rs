@@ -0,0 +1,182 @@
+use crate::config::TlsFiles;
+use crate::splice::Splice;
+use std::fs::File;
+use std::io::BufReader;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, UnixStream};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::internal::pemfile;
+use tokio_rustls::rustls::{Certificate, ClientConfig, NoClientAuth, PrivateKey, ServerConfig};
+use tokio_rustls::webpki::DNSNameRef;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+/// Builds the acceptor used to run TLS handshakes with clients, from the PEM files named in the
+/// config. With `http2`, h2 is offered to clients through ALPN ahead of HTTP/1.1. Returns a message
+/// describing the problem if the files can't be used.
+pub fn build_acceptor(files: &TlsFiles, http2: bool) -> Result<TlsAcceptor, String> {
+    let certs = load_certs(&files.cert_path)?;
+    let key = load_key(&files.key_path)?;
+    let mut config = ServerConfig::new(NoClientAuth::new());
+    config
+        .set_single_cert(certs, key)
+        .map_err(|err| format!("Could not use TLS certificate {}: {}", files.cert_path, err))?;
+    if http2 {
+        config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+    }
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+fn open(path: &str) -> Result<BufReader<File>, String> {
+    File::open(path)
+        .map(BufReader::new)
+        .map_err(|err| format!("Could not open {}: {}", path, err))
+}
+
+fn load_certs(path: &str) -> Result<Vec<Certificate>, String> {
+    let certs = pemfile::certs(&mut open(path)?)
+        .map_err(|_| format!("Could not parse certificates in {}", path))?;
+    if certs.is_empty() {
+        return Err(format!("No certificates found in {}", path));
+    }
+    Ok(certs)
+}
+
+/// Loads the first private key in the file, which may be in either PKCS#8 ("BEGIN PRIVATE KEY")
+/// or PKCS#1 ("BEGIN RSA PRIVATE KEY") form.
+fn load_key(path: &str) -> Result<PrivateKey, String> {
+    let parse_error = |_| format!("Could not parse private key in {}", path);
+    let mut keys = pemfile::pkcs8_private_keys(&mut open(path)?).map_err(parse_error)?;
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut open(path)?).map_err(parse_error)?;
+    }
+    keys.into_iter()
+        .next()
+        .ok_or_else(|| format!("No private key found in {}", path))
+}
+
+/// Builds the connector used to run TLS handshakes with https:// upstreams. Upstream certificates
+/// are checked against the CA certificates in `ca_cert_path` if one is given, or against the
+/// Mozilla root store otherwise.
+pub fn build_connector(ca_cert_path: Option<&str>) -> Result<TlsConnector, String> {
+    let mut config = ClientConfig::new();
+    match ca_cert_path {
+        Some(path) => {
+            let (added, _) = config
+                .root_store
+                .add_pem_file(&mut open(path)?)
+                .map_err(|_| format!("Could not parse CA certificates in {}", path))?;
+            if added == 0 {
+                return Err(format!("No usable CA certificates found in {}", path));
+            }
+        }
+        None => config
+            .root_store
+            .add_server_trust_anchors(&webpki_roots::TLS_SERVER_ROOTS),
+    }
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+/// Splits an upstream as given on the command line into whether to use TLS and the host:port to
+/// connect to. Upstreams may be written as https://host:port, http://host:port, or just host:port
+/// (which means plain HTTP). A unix:PATH upstream comes back whole, as plain HTTP.
+pub fn split_upstream_scheme(upstream: &str) -> (bool, &str) {
+    match upstream.strip_prefix("https://") {
+        Some(address) => (true, address),
+        None => (false, upstream.strip_prefix("http://").unwrap_or(upstream)),
+    }
+}
+
+/// Runs a TLS handshake over `stream`, verifying that the upstream's certificate is valid for the
+/// host in `address` (which must be a DNS name rather than an IP address).
+pub async fn connect(
+    connector: &TlsConnector,
+    address: &str,
+    stream: TcpStream,
+) -> std::io::Result<UpstreamStream> {
+    let host = address.rsplitn(2, ':').last().unwrap_or(address);
+    let name = DNSNameRef::try_from_ascii_str(host).map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{} is not a DNS name, so its certificate can't be verified", host),
+        )
+    })?;
+    let stream = connector.connect(name, stream).await?;
+    Ok(UpstreamStream::Tls(Box::new(stream)))
+}
+
+/// A connection to an upstream, which is encrypted if the upstream was given as https://, and
+/// goes over a UNIX socket if it was given as unix:PATH
+pub enum UpstreamStream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+    Unix(UnixStream),
+}
+
+impl UpstreamStream {
+    /// The TCP connection underneath any encryption (None for a UNIX socket)
+    pub fn tcp_mut(&mut self) -> Option<&mut TcpStream> {
+        match self {
+            UpstreamStream::Plain(stream) => Some(stream),
+            UpstreamStream::Tls(stream) => Some(stream.get_mut().0),
+            UpstreamStream::Unix(_) => None,
+        }
+    }
+}
+
+impl Splice for UpstreamStream {
+    fn socket(&self) -> Option<RawFd> {
+        match self {
+            UpstreamStream::Plain(stream) => Some(stream.as_raw_fd()),
+            UpstreamStream::Tls(_) => None,
+            UpstreamStream::Unix(stream) => Some(stream.as_raw_fd()),
+        }
+    }
+}
+
+impl AsyncRead for UpstreamStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for UpstreamStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_flush(cx),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            UpstreamStream::Plain(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+            UpstreamStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
@@ -0,0 +1,26 @@
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+/// Passes bytes between the client and the upstream in both directions, for connections that have
+/// switched from HTTP to another protocol (such as WebSocket). When one side stops sending, the
+/// other side's write half is shut down so that it sees the end too, and this returns once both
+/// directions are done. Returns how many bytes went each way (client to upstream, then upstream to
+/// client).
+pub async fn copy_bidirectional<C, U>(client: C, upstream: U) -> std::io::Result<(u64, u64)>
+where
+    C: AsyncRead + AsyncWrite,
+    U: AsyncRead + AsyncWrite,
+{
+    let (mut client_read, mut client_write) = tokio::io::split(client);
+    let (mut upstream_read, mut upstream_write) = tokio::io::split(upstream);
+    let client_to_upstream = async {
+        let bytes_copied = tokio::io::copy(&mut client_read, &mut upstream_write).await?;
+        upstream_write.shutdown().await?;
+        Ok(bytes_copied)
+    };
+    let upstream_to_client = async {
+        let bytes_copied = tokio::io::copy(&mut upstream_read, &mut client_write).await?;
+        client_write.shutdown().await?;
+        Ok(bytes_copied)
+    };
+    tokio::try_join!(client_to_upstream, upstream_to_client)
+}
@@ -0,0 +1,18 @@
+use http::header::HeaderMap;
+
+/// The header naming the upstream a request should go to
+pub const HEADER_NAME: &str = "x-balancebeam-upstream";
+
+/// With --upstream-override-from, clients in the given ranges can send a request to one upstream
+/// of their choosing (e.g. `X-Balancebeam-Upstream: 10.0.0.3:8080`), whatever the routes, the
+/// strategy and the upstream's health say, which helps when debugging a single misbehaving
+/// upstream. The header is taken out of every request, since it's meant for balancebeam (and
+/// shouldn't reach the upstream whether or not it was heeded). Returns the upstream named, if
+/// `allowed`.
+pub fn take(headers: &mut HeaderMap, allowed: bool) -> Option<String> {
+    let value = headers.remove(HEADER_NAME)?;
+    if !allowed {
+        return None;
+    }
+    value.to_str().ok().map(|value| value.trim().to_string())
+}
@@ -1,7 +1,11 @@
 mod common;
 
-use common::{init_logging, BalanceBeam, EchoServer, Server};
+use common::{init_logging, BalanceBeam, EchoServer, MockResponse, MockServer, RawServer, Server};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
+use tokio::time::delay_for;
 
 async fn setup() -> (BalanceBeam, EchoServer) {
     init_logging();
@@ -98,3 +102,2469 @@ async fn test_multiple_requests_per_connection() {
 
     log::info!("All done :)");
 }
+
+/// Make sure an upstream that hangs up partway through its headers results in a 502 for the
+/// client (rather than a hang or a dropped connection), and that the failure is logged as a
+/// premature close.
+#[tokio::test]
+async fn test_upstream_closes_mid_headers() {
+    init_logging();
+    let upstream = RawServer::new(b"HTTP/1.1 200 OK\r\nContent-Len").await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}/truncated", balancebeam.address))
+        .header("x-sent-by", "balancebeam-tests")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+
+    // Give the log line a moment to make its way through the output pipe
+    delay_for(Duration::from_millis(200)).await;
+    assert!(
+        balancebeam.output_contains("closed connection prematurely"),
+        "balancebeam did not log that the upstream closed the connection prematurely"
+    );
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+
+    log::info!("All done :)");
+}
+
+/// Make sure an upstream that sends part of its headers and then stalls (without hanging up) gets a
+/// prompt 504 once --upstream-header-timeout-ms runs out
+#[tokio::test]
+async fn test_upstream_stalls_mid_headers() {
+    init_logging();
+    let upstream = RawServer::new_stalled(b"HTTP/1.1 200 OK\r\nContent-Len").await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--upstream-header-timeout-ms", "300"],
+    )
+    .await;
+
+    let start = std::time::Instant::now();
+    balancebeam
+        .request(reqwest::Method::GET, "/stalled", "")
+        .await
+        .expect("Error sending request to balancebeam")
+        .expect_status(504);
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed < Duration::from_secs(2),
+        "balancebeam took {:?} to give up on a stalled upstream",
+        elapsed
+    );
+
+    // Give the log line a moment to make its way through the output pipe
+    delay_for(Duration::from_millis(200)).await;
+    assert!(
+        balancebeam.output_contains("did not send complete headers within 300ms"),
+        "balancebeam did not log that the upstream timed out sending headers"
+    );
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 1);
+}
+
+/// An upstream that stops partway through its response body should get a 504 once
+/// --upstream-body-timeout-ms runs out
+#[tokio::test]
+async fn test_upstream_stalls_mid_body() {
+    init_logging();
+    let upstream =
+        RawServer::new_stalled(b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nabc").await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--upstream-body-timeout-ms", "300"],
+    )
+    .await;
+
+    let start = std::time::Instant::now();
+    balancebeam
+        .request(reqwest::Method::GET, "/stalled", "")
+        .await
+        .expect("Error sending request to balancebeam")
+        .expect_status(504);
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed < Duration::from_secs(2),
+        "balancebeam took {:?} to give up on a stalled upstream",
+        elapsed
+    );
+    assert!(
+        balancebeam
+            .wait_for_output("stopped sending the response body for more than 300ms")
+            .await,
+        "balancebeam did not log that the upstream timed out sending the body"
+    );
+}
+
+/// A slow upstream should get a 504 once --request-timeout-ms runs out, even though it's not
+/// stalled partway through anything
+#[tokio::test]
+async fn test_request_timeout() {
+    init_logging();
+    let upstream = RawServer::new_delayed(
+        b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok",
+        Duration::from_secs(3),
+    )
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--request-timeout-ms", "300"],
+    )
+    .await;
+
+    let start = std::time::Instant::now();
+    balancebeam
+        .request(reqwest::Method::GET, "/slow", "")
+        .await
+        .expect("Error sending request to balancebeam")
+        .expect_status(504);
+    let elapsed = start.elapsed();
+    assert!(
+        elapsed < Duration::from_secs(2),
+        "balancebeam took {:?} to give up on a slow upstream",
+        elapsed
+    );
+    assert!(
+        balancebeam
+            .wait_for_output("within the request timeout (300ms)")
+            .await,
+        "balancebeam did not log that the request timed out"
+    );
+}
+
+/// Make sure a client that trickles its request in (or stalls partway through it) gets a 408 and
+/// has its connection closed, instead of tying it up forever
+#[tokio::test]
+async fn test_slow_client() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &[
+            "--client-idle-timeout-ms",
+            "400",
+            "--client-header-timeout-ms",
+            "1000",
+        ],
+    )
+    .await;
+
+    // Each byte arrives well within the idle timeout, but the headers as a whole take too long.
+    // (Stop sending as soon as the response arrives, since writing to a closed connection would
+    // reset it)
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let start = std::time::Instant::now();
+    let mut response = vec![0_u8; 1024];
+    let mut response_len = 0;
+    for byte in b"GET /slow HTTP/1.1\r\nHost: example.com\r\n".iter() {
+        stream.write_all(&[*byte]).await.unwrap();
+        let read = stream.read(&mut response);
+        if let Ok(result) = tokio::time::timeout(Duration::from_millis(100), read).await {
+            response_len = result.unwrap();
+            break;
+        }
+    }
+    response.truncate(response_len);
+    let response = String::from_utf8_lossy(&response);
+    assert!(
+        response.starts_with("HTTP/1.1 408"),
+        "Expected a 408 for a trickled request, got {:?}",
+        response
+    );
+    assert!(
+        start.elapsed() < Duration::from_secs(3),
+        "balancebeam took {:?} to give up on a slow client",
+        start.elapsed()
+    );
+
+    // A client that stops partway through its body is cut off after the idle timeout
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(b"POST /stall HTTP/1.1\r\nContent-Length: 10\r\n\r\nhalf")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response).await;
+    assert!(
+        response.starts_with("HTTP/1.1 408"),
+        "Expected a 408 for a stalled body, got {:?}",
+        response
+    );
+
+    // A connection that never sends anything is just closed
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let mut response = Vec::new();
+    let read = tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut response));
+    assert!(
+        read.await.is_ok(),
+        "balancebeam did not close an idle connection"
+    );
+    assert!(
+        response.is_empty(),
+        "Expected no response on an idle connection, got {:?}",
+        String::from_utf8_lossy(&response)
+    );
+}
+
+/// Make sure a client that moves more than --max-bytes-per-minute-per-ip through balancebeam gets
+/// 429s (without its requests reaching the upstream) until its window runs out
+#[tokio::test]
+async fn test_byte_rate_limiting() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    // Each request carries 2000 bytes up and gets them echoed back down, so the first request
+    // stays under the budget and the second goes over it
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &[
+            "--max-bytes-per-minute-per-ip",
+            "6000",
+            "--rate-limit-window-secs",
+            "2",
+        ],
+    )
+    .await;
+    let body = "x".repeat(2000);
+
+    for i in 0..2 {
+        balancebeam
+            .request(reqwest::Method::POST, &format!("/upload-{}", i), &body)
+            .await
+            .expect("Error sending request to balancebeam")
+            .expect_status(200)
+            .expect_body_contains(&body);
+    }
+    for i in 0..2 {
+        balancebeam
+            .request(reqwest::Method::POST, &format!("/overboard-{}", i), &body)
+            .await
+            .expect("Error sending rate limited request to balancebeam")
+            .expect_status(429);
+    }
+
+    log::info!("Waiting for the rate limiting window to run out");
+    delay_for(Duration::from_millis(2500)).await;
+    balancebeam
+        .request(reqwest::Method::POST, "/after-window", &body)
+        .await
+        .expect("Error sending request to balancebeam")
+        .expect_status(200);
+
+    let num_requests_received = Box::new(upstream).stop().await;
+    assert_eq!(num_requests_received, 3);
+}
+
+/// Make sure requests that take longer than --slow-request-threshold-ms are logged as slow
+#[tokio::test]
+async fn test_slow_request_warning() {
+    init_logging();
+    let upstream = RawServer::new_delayed(
+        b"HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\nslow",
+        Duration::from_millis(500),
+    )
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--slow-request-threshold-ms", "100"],
+    )
+    .await;
+
+    let response_text = balancebeam
+        .get("/sluggish")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response_text, "slow");
+
+    delay_for(Duration::from_millis(200)).await;
+    assert!(
+        balancebeam.output_contains("Slow request: GET /sluggish"),
+        "balancebeam did not warn about a request that exceeded the slow request threshold"
+    );
+
+    Box::new(upstream).stop().await;
+    log::info!("All done :)");
+}
+
+/// Make sure the upstream's status code, headers, and body are relayed to the client unchanged.
+#[tokio::test]
+async fn test_upstream_response_passed_through() {
+    init_logging();
+    let upstream = MockServer::new(
+        MockResponse::new(404)
+            .header("x-mock-upstream", "yes")
+            .body("nothing to see here"),
+    )
+    .await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    balancebeam
+        .request(reqwest::Method::GET, "/missing", "")
+        .await
+        .expect("Error sending request to balancebeam")
+        .expect_status(404)
+        .expect_header("x-mock-upstream", "yes")
+        .expect_body("nothing to see here");
+    assert_eq!(upstream.requests_received(), 1);
+}
+
+/// With --inject-before-body-end, the snippet should be added to HTML responses (with
+/// Content-Length updated) and JSON responses should be left alone.
+#[tokio::test]
+async fn test_inject_before_body_end() {
+    init_logging();
+    let snippet = "<script>track()</script>";
+    let html_upstream = MockServer::new(
+        MockResponse::new(200)
+            .header("content-type", "text/html; charset=utf-8")
+            .body("<html><body><p>Hi</p></body></html>"),
+    )
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&html_upstream.address],
+        None,
+        None,
+        &["--inject-before-body-end", snippet],
+    )
+    .await;
+    let expected_html = "<html><body><p>Hi</p><script>track()</script></body></html>";
+    balancebeam
+        .request(reqwest::Method::GET, "/page.html", "")
+        .await
+        .expect("Error sending request to balancebeam")
+        .expect_status(200)
+        .expect_header("content-length", &expected_html.len().to_string())
+        .expect_body(expected_html);
+
+    let json_body = r#"{"html": "</body>"}"#;
+    let json_upstream = MockServer::new(
+        MockResponse::new(200)
+            .header("content-type", "application/json")
+            .body(json_body),
+    )
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&json_upstream.address],
+        None,
+        None,
+        &["--inject-before-body-end", snippet],
+    )
+    .await;
+    balancebeam
+        .request(reqwest::Method::GET, "/data.json", "")
+        .await
+        .expect("Error sending request to balancebeam")
+        .expect_status(200)
+        .expect_body(json_body);
+}
+
+/// With --compress, responses should be gzipped (or deflated) for clients that accept it, and
+/// left alone for clients that don't
+#[tokio::test]
+async fn test_compression() {
+    init_logging();
+    let body = "<p>hello world</p>".repeat(200);
+    let upstream = MockServer::new(
+        MockResponse::new(200)
+            .header("content-type", "text/html; charset=utf-8")
+            .body(&body),
+    )
+    .await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], None, None, &["--compress"]).await;
+
+    // Returns the response's head (as text) and its raw body
+    let address = &balancebeam.address;
+    let fetch = |accept_encoding: &'static str| async move {
+        let mut stream = tokio::net::TcpStream::connect(address)
+            .await
+            .expect("Could not connect to balancebeam");
+        let request = format!(
+            "GET /page.html HTTP/1.1\r\nHost: test\r\nAccept-Encoding: {}\r\n\r\n",
+            accept_encoding
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await.unwrap();
+        let head_len = response
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .expect("Response has no end of headers")
+            + 4;
+        let body = response.split_off(head_len);
+        (String::from_utf8(response).unwrap(), body)
+    };
+
+    let (head, compressed) = fetch("deflate;q=0.5, gzip").await;
+    assert!(head.contains("content-encoding: gzip\r\n"), "{}", head);
+    assert!(head.contains("vary: accept-encoding\r\n"), "{}", head);
+    assert!(
+        head.contains(&format!("content-length: {}\r\n", compressed.len())),
+        "{}",
+        head
+    );
+    let mut decompressed = String::new();
+    std::io::Read::read_to_string(
+        &mut flate2::read::GzDecoder::new(&compressed[..]),
+        &mut decompressed,
+    )
+    .expect("Response is not valid gzip");
+    assert_eq!(decompressed, body);
+
+    let (head, compressed) = fetch("deflate").await;
+    assert!(head.contains("content-encoding: deflate\r\n"), "{}", head);
+    let mut decompressed = String::new();
+    std::io::Read::read_to_string(
+        &mut flate2::read::DeflateDecoder::new(&compressed[..]),
+        &mut decompressed,
+    )
+    .expect("Response is not valid deflate");
+    assert_eq!(decompressed, body);
+
+    // q=0 means the client can't take it
+    let (head, uncompressed) = fetch("gzip;q=0").await;
+    assert!(!head.contains("content-encoding"), "{}", head);
+    assert_eq!(uncompressed, body.as_bytes());
+}
+
+/// Error log lines for 502s should say which request failed, whether it was sent to the upstream
+/// or no upstream could be reached for it.
+#[tokio::test]
+async fn test_error_log_includes_request() {
+    init_logging();
+    let upstream = RawServer::new(b"not an http response\r\n\r\n").await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    balancebeam
+        .request(reqwest::Method::POST, "/broken/path", "payload")
+        .await
+        .expect("Error sending request to balancebeam")
+        .expect_status(502);
+
+    // Stopping the only upstream makes the next request fail to connect
+    Box::new(upstream).stop().await;
+    balancebeam
+        .request(reqwest::Method::GET, "/unreachable", "")
+        .await
+        .expect("Error sending request to balancebeam")
+        .expect_status(502);
+
+    // Give the log lines a moment to make their way through the output pipe
+    delay_for(Duration::from_millis(200)).await;
+    assert!(
+        balancebeam.output_contains("POST /broken/path"),
+        "balancebeam did not log the method and path of the failed request"
+    );
+    assert!(
+        balancebeam.output_contains("GET /unreachable"),
+        "balancebeam did not log the method and path of the request it couldn't connect for"
+    );
+}
+
+/// With --expose-timing-header, responses should carry a Server-Timing header whose duration
+/// reflects how long the upstream took.
+#[tokio::test]
+async fn test_expose_timing_header() {
+    init_logging();
+    let upstream = MockServer::new(
+        MockResponse::new(200)
+            .body("done")
+            .delay(Duration::from_millis(100)),
+    )
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--expose-timing-header"],
+    )
+    .await;
+
+    let response = balancebeam
+        .request(reqwest::Method::GET, "/timed", "")
+        .await
+        .expect("Error sending request to balancebeam");
+    response.expect_status(200).expect_body("done");
+    let timing = response
+        .header("server-timing")
+        .expect("Response is missing a Server-Timing header");
+    let duration_ms: f64 = timing
+        .strip_prefix("upstream;dur=")
+        .and_then(|duration| duration.parse().ok())
+        .unwrap_or_else(|| panic!("Malformed Server-Timing header: {}", timing));
+    assert!(
+        (100.0..5000.0).contains(&duration_ms),
+        "Implausible upstream duration in Server-Timing header: {}",
+        timing
+    );
+
+    // The header is off by default
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    balancebeam
+        .request(reqwest::Method::GET, "/timed", "")
+        .await
+        .expect("Error sending request to balancebeam")
+        .expect_no_header("server-timing");
+}
+
+/// With --upstream-source-addr, connections to the upstream should come from that address
+#[tokio::test]
+async fn test_upstream_source_addr() {
+    init_logging();
+    let upstream = MockServer::new(MockResponse::new(200).body("hello")).await;
+    // All of 127.0.0.0/8 is loopback on Linux, so this is a second local address we can bind to
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--upstream-source-addr", "127.0.0.2"],
+    )
+    .await;
+    balancebeam
+        .request(reqwest::Method::GET, "/", "")
+        .await
+        .expect("Error sending request to balancebeam")
+        .expect_status(200)
+        .expect_body("hello");
+    let peers = upstream.peer_addresses();
+    assert_eq!(peers.len(), 1);
+    assert_eq!(peers[0].ip().to_string(), "127.0.0.2");
+}
+
+/// An --upstream-source-addr that doesn't belong to this machine should be rejected at startup
+#[tokio::test]
+async fn test_upstream_source_addr_unusable() {
+    init_logging();
+    let mut cmd = Command::new(BalanceBeam::target_bin_path());
+    // 192.0.2.0/24 is reserved for documentation, so it won't be assigned to any interface
+    cmd.args(["--bind", "127.0.0.1:0", "--upstream", "127.0.0.1:1001"])
+        .args(["--upstream-source-addr", "192.0.2.1"]);
+    let output = cmd.output().await.expect("Could not run balancebeam");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Cannot use 192.0.2.1 as the upstream source address"),
+        "Unexpected output from balancebeam: {}",
+        stderr
+    );
+}
+
+/// On SIGTERM, balancebeam should log a summary of the traffic it handled before exiting
+#[tokio::test]
+async fn test_shutdown_summary() {
+    init_logging();
+    let upstream = MockServer::new(MockResponse::new(200).body("ok")).await;
+    let mut balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    for _ in 0..3 {
+        balancebeam
+            .request(reqwest::Method::GET, "/", "")
+            .await
+            .expect("Error sending request to balancebeam")
+            .expect_status(200);
+    }
+
+    let status = balancebeam.shutdown().await;
+    assert!(status.success(), "balancebeam exited with {}", status);
+    assert!(balancebeam.output_contains("requests_served=3 peak_connections="));
+    assert!(balancebeam.output_contains("status_counts=200=3"));
+    assert!(balancebeam.output_contains(&format!("upstream_counts={}=3", upstream.address)));
+}
+
+/// Reads a counter from the status endpoint's "name value" lines
+fn status_value(status: &str, name: &str) -> usize {
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(&format!("{} ", name)))
+        .and_then(|value| value.parse().ok())
+        .unwrap_or_else(|| panic!("No {} in status output: {}", name, status))
+}
+
+/// The status endpoint should report the most connections that were ever open at once, and let
+/// the since-reset peak be reset
+#[tokio::test]
+async fn test_peak_concurrency() {
+    init_logging();
+    let upstream = MockServer::new(
+        MockResponse::new(200)
+            .body("slow")
+            .delay(Duration::from_millis(500)),
+    )
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--status-bind", "127.0.0.1:0"],
+    )
+    .await;
+    let status_url = format!("http://{}/status", balancebeam.status_address().await);
+
+    // Each request is on its own connection, and they all overlap because the upstream is slow
+    let request = || balancebeam.request(reqwest::Method::GET, "/", "");
+    let (r1, r2, r3, r4) = tokio::join!(request(), request(), request(), request());
+    for response in [r1, r2, r3, r4] {
+        response
+            .expect("Error sending request to balancebeam")
+            .expect_status(200);
+    }
+    // Give balancebeam a moment to notice the client connections closing
+    delay_for(Duration::from_millis(200)).await;
+    let status = reqwest::get(&status_url)
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(status_value(&status, "active_connections"), 0);
+    assert_eq!(status_value(&status, "peak_connections"), 4);
+    assert_eq!(status_value(&status, "peak_connections_since_reset"), 4);
+
+    let status = reqwest::Client::new()
+        .post(&format!("{}/reset", status_url))
+        .send()
+        .await
+        .unwrap()
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(status_value(&status, "peak_connections"), 4);
+    assert_eq!(status_value(&status, "peak_connections_since_reset"), 0);
+}
+
+/// With --upstream-pool-max-idle, an upstream connection should outlive the client connection it
+/// was opened for and be reused by the next client, until it has been idle for too long
+#[tokio::test]
+async fn test_upstream_connection_pooling() {
+    init_logging();
+    let upstream = MockServer::new(MockResponse::new(200).body("pooled")).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &[
+            "--upstream-pool-max-idle",
+            "2",
+            "--upstream-pool-idle-timeout-secs",
+            "1",
+        ],
+    )
+    .await;
+
+    // Each get() is a separate client connection
+    for _ in 0..5 {
+        assert_eq!(balancebeam.get("/").await.unwrap(), "pooled");
+        // Give balancebeam a moment to notice the client hanging up and pool the connection
+        delay_for(Duration::from_millis(50)).await;
+    }
+    assert_eq!(upstream.requests_received(), 5);
+    assert_eq!(upstream.peer_addresses().len(), 1);
+
+    log::info!("Waiting for the pooled connection to expire");
+    delay_for(Duration::from_millis(1500)).await;
+    assert_eq!(balancebeam.get("/").await.unwrap(), "pooled");
+    assert_eq!(upstream.peer_addresses().len(), 2);
+}
+
+/// With --tls-cert and --tls-key, balancebeam should accept HTTPS connections and proxy them to
+/// the upstream as plain HTTP
+#[tokio::test]
+async fn test_tls_termination() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let certs_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs");
+    let cert_path = format!("{}/localhost.pem", certs_dir);
+    let key_path = format!("{}/localhost.key", certs_dir);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--tls-cert", &cert_path, "--tls-key", &key_path],
+    )
+    .await;
+
+    let cert = std::fs::read(&cert_path).unwrap();
+    let client = reqwest::Client::builder()
+        .add_root_certificate(reqwest::Certificate::from_pem(&cert).unwrap())
+        .build()
+        .unwrap();
+    for path in &["/first", "/second"] {
+        let response_text = client
+            .post(&format!("https://{}{}", balancebeam.address, path))
+            .body("encrypted body")
+            .send()
+            .await
+            .expect("Error sending HTTPS request to balancebeam")
+            .text()
+            .await
+            .unwrap();
+        assert!(response_text.starts_with(&format!("POST {} HTTP/1.1", path)));
+        assert!(response_text.ends_with("encrypted body"));
+    }
+
+    // Plain HTTP should be refused rather than proxied
+    assert!(balancebeam.get("/plaintext").await.is_err());
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// An https:// upstream should be connected to over TLS, with its certificate checked against
+/// --upstream-ca-cert (or the public roots if that isn't given). The HTTPS upstream here is another
+/// balancebeam terminating TLS in front of an echo server.
+#[tokio::test]
+async fn test_upstream_tls() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let certs_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/certs");
+    let cert_path = format!("{}/localhost.pem", certs_dir);
+    let key_path = format!("{}/localhost.key", certs_dir);
+    let tls_upstream = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--tls-cert", &cert_path, "--tls-key", &key_path],
+    )
+    .await;
+    // The certificate is for localhost, so the upstream has to be given by name
+    let port = tls_upstream.address.rsplit(':').next().unwrap();
+    let https_upstream = format!("https://localhost:{}", port);
+
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&https_upstream],
+        None,
+        None,
+        &["--upstream-ca-cert", &cert_path],
+    )
+    .await;
+    let response_text = balancebeam
+        .post("/reencrypted", "secret")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.starts_with("POST /reencrypted HTTP/1.1"));
+    assert!(response_text.ends_with("secret"));
+
+    // A self-signed certificate isn't trusted by default
+    let untrusting_balancebeam = BalanceBeam::new(&[&https_upstream], None, None).await;
+    untrusting_balancebeam
+        .request(reqwest::Method::GET, "/", "")
+        .await
+        .expect("Error sending request to balancebeam")
+        .expect_status(502);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
+
+/// Sends `raw_request` to balancebeam on a fresh connection, then hangs up its side and returns
+/// everything balancebeam sends back.
+async fn send_raw_request(balancebeam: &BalanceBeam, raw_request: &[u8]) -> String {
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream.write_all(raw_request).await.unwrap();
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+/// Requests that the HTTP parser accepts but that can't be represented (like a path with non-ASCII
+/// bytes in it) should get a 400, like any other malformed request
+#[tokio::test]
+async fn test_unrepresentable_request() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let response = send_raw_request(
+        &balancebeam,
+        b"GET /caf\xc3\xa9 HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+
+    // balancebeam should carry on as usual
+    let response_text = balancebeam
+        .get("/after")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.starts_with("GET /after HTTP/1.1"));
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
+
+/// A chunked response from the upstream should be decoded (chunk extensions and trailers
+/// included) and passed on to the client with a Content-Length
+#[tokio::test]
+async fn test_chunked_response() {
+    init_logging();
+    let upstream = RawServer::new(
+        b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+          5\r\nhello\r\n7;ext=1\r\n, world\r\n0\r\nX-Trailer: ignored\r\n\r\n",
+    )
+    .await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    balancebeam
+        .request(reqwest::Method::GET, "/chunked", "")
+        .await
+        .expect("Error sending request to balancebeam")
+        .expect_status(200)
+        .expect_header("content-length", "12")
+        .expect_no_header("transfer-encoding")
+        .expect_body("hello, world");
+}
+
+/// A chunked request from the client should reach the upstream decoded, and a request with broken
+/// chunk framing should be rejected
+#[tokio::test]
+async fn test_chunked_request() {
+    init_logging();
+    let (balancebeam, upstream) = setup().await;
+
+    let response = send_raw_request(
+        &balancebeam,
+        b"POST /upload HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\n\r\n\
+          6\r\nhello \r\n5\r\nworld\r\n0\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.contains("content-length: 11\n"), "{}", response);
+    assert!(!response.contains("transfer-encoding"), "{}", response);
+    assert!(response.ends_with("\n\nhello world"), "{}", response);
+
+    let response = send_raw_request(
+        &balancebeam,
+        b"POST /upload HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\n\r\n\
+          zz\r\nhello\r\n0\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
+
+/// A request pipelined behind a chunked one (in the same packet) should be answered too
+#[tokio::test]
+async fn test_pipelined_after_chunked_request() {
+    init_logging();
+    let (balancebeam, upstream) = setup().await;
+
+    let response = send_raw_request(
+        &balancebeam,
+        b"POST /upload HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\n\r\n\
+          5\r\nhello\r\n0\r\n\r\n\
+          GET /next HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+    )
+    .await;
+    assert_eq!(response.matches("HTTP/1.1 200").count(), 2, "{}", response);
+    assert!(response.contains("GET /next HTTP/1.1"), "{}", response);
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// A request with a Transfer-Encoding that doesn't end in chunked has no way of saying where its
+/// body ends, so it should be rejected rather than read by its Content-Length
+#[tokio::test]
+async fn test_unsupported_transfer_encoding() {
+    init_logging();
+    let (balancebeam, upstream) = setup().await;
+
+    for transfer_encoding in &["gzip", "chunked, gzip"] {
+        let request = format!(
+            "POST /upload HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: {}\r\n\
+             Content-Length: 5\r\n\r\nhello",
+            transfer_encoding
+        );
+        let response = send_raw_request(&balancebeam, request.as_bytes()).await;
+        assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+    }
+    assert_eq!(Box::new(upstream).stop().await, 0);
+}
+
+/// Request bodies over --body-high-water-mark should be streamed to the upstream rather than
+/// buffered, whether they come with a Content-Length or in chunks
+#[tokio::test]
+async fn test_streamed_request_body() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--body-high-water-mark", "1024"],
+    )
+    .await;
+
+    let body = "0123456789".repeat(20000);
+    let response_text = balancebeam
+        .post("/upload", &body)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("content-length: 200000\n"));
+    assert!(response_text.ends_with(&format!("\n\n{}", body)));
+    assert!(
+        balancebeam
+            .wait_for_output("Streaming the rest of the request body to upstream")
+            .await,
+        "balancebeam did not log that it streamed the request body"
+    );
+    assert!(
+        balancebeam
+            .wait_for_output("Streaming the rest of the response body to client")
+            .await,
+        "balancebeam did not log that it streamed the response body"
+    );
+
+    let chunk = "x".repeat(1000);
+    let mut raw_request =
+        b"POST /upload HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+    for _ in 0..3 {
+        raw_request.extend_from_slice(format!("3e8\r\n{}\r\n", chunk).as_bytes());
+    }
+    raw_request.extend_from_slice(b"0\r\n\r\n");
+    let response = send_raw_request(&balancebeam, &raw_request).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(
+        response.contains("transfer-encoding: chunked\n"),
+        "{}",
+        response
+    );
+    assert!(response.ends_with(&format!("\n\n{}", chunk.repeat(3))));
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// On Linux, big bodies between plain sockets should be spliced from one socket to the other, and
+/// arrive intact
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn test_spliced_body() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--body-high-water-mark", "1024"],
+    )
+    .await;
+
+    let body: String = (0..4_000_000)
+        .map(|i| (b'a' + (i % 26) as u8) as char)
+        .collect();
+    let response_text = balancebeam
+        .post("/upload", &body)
+        .await
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("content-length: 4000000\n"));
+    assert!(response_text.ends_with(&format!("\n\n{}", body)));
+    assert!(
+        balancebeam
+            .wait_for_output("Splicing the rest of the body")
+            .await,
+        "balancebeam did not log that it spliced the body"
+    );
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
+
+/// Requests with bodies over --max-body-size should get a 413 and have their connection closed,
+/// whether the size is known up front (from Content-Length) or only once too many chunks arrive
+#[tokio::test]
+async fn test_max_body_size() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--max-body-size", "2KB", "--body-high-water-mark", "1024"],
+    )
+    .await;
+
+    let response = send_raw_request(
+        &balancebeam,
+        b"POST /big HTTP/1.1\r\nHost: test\r\nContent-Length: 5000\r\n\r\n",
+    )
+    .await;
+    assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+    assert!(response.contains("connection: close\r\n"), "{}", response);
+
+    // Over the high-water mark, but under the limit
+    let body = "x".repeat(1500);
+    balancebeam
+        .request(reqwest::Method::POST, "/fits", &body)
+        .await
+        .expect("Error sending request to balancebeam")
+        .expect_status(200)
+        .expect_body_contains(&body);
+
+    // Streamed in chunks, going over the limit with the third
+    let chunk = "x".repeat(1000);
+    let mut raw_request =
+        b"POST /chunked HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+    for _ in 0..3 {
+        raw_request.extend_from_slice(format!("3e8\r\n{}\r\n", chunk).as_bytes());
+    }
+    raw_request.extend_from_slice(b"0\r\n\r\n");
+    let response = send_raw_request(&balancebeam, &raw_request).await;
+    assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+    assert!(
+        balancebeam
+            .wait_for_output("with a body over 2000 bytes")
+            .await,
+        "balancebeam did not log that it refused the request"
+    );
+
+    // Under the high-water mark, so the whole body is read before it's found to be too big
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--max-body-size", "1KiB"],
+    )
+    .await;
+    let chunk = "x".repeat(600);
+    let mut raw_request =
+        b"POST /chunked HTTP/1.1\r\nHost: test\r\nTransfer-Encoding: chunked\r\n\r\n".to_vec();
+    for _ in 0..2 {
+        raw_request.extend_from_slice(format!("258\r\n{}\r\n", chunk).as_bytes());
+    }
+    raw_request.extend_from_slice(b"0\r\n\r\n");
+    let response = send_raw_request(&balancebeam, &raw_request).await;
+    assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+    assert!(response.contains("connection: close\r\n"), "{}", response);
+}
+
+/// Response bodies over --body-high-water-mark should be streamed to the client, both chunked ones
+/// (which stay chunked) and ones that last until the upstream closes the connection
+#[tokio::test]
+async fn test_streamed_response_body() {
+    init_logging();
+    let upstream = RawServer::new(
+        b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+          5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n",
+    )
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--body-high-water-mark", "8"],
+    )
+    .await;
+    balancebeam
+        .request(reqwest::Method::GET, "/chunked", "")
+        .await
+        .expect("Error sending request to balancebeam")
+        .expect_status(200)
+        .expect_header("transfer-encoding", "chunked")
+        .expect_no_header("content-length")
+        .expect_body("hello, world");
+    assert!(
+        balancebeam
+            .wait_for_output("Streaming the rest of the response body to client")
+            .await,
+        "balancebeam did not log that it streamed the response body"
+    );
+
+    let body = "until close ".repeat(100);
+    let upstream = RawServer::new(format!("HTTP/1.1 200 OK\r\n\r\n{}", body).as_bytes()).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--body-high-water-mark", "8"],
+    )
+    .await;
+    balancebeam
+        .request(reqwest::Method::GET, "/until-close", "")
+        .await
+        .expect("Error sending request to balancebeam")
+        .expect_status(200)
+        .expect_body(&body);
+}
+
+/// Once the upstream answers an Upgrade request with 101 Switching Protocols, balancebeam should
+/// stop parsing HTTP and pass bytes through in both directions
+#[tokio::test]
+async fn test_upgrade_tunnel() {
+    init_logging();
+    // An upstream that accepts the upgrade and then echoes back whatever it receives
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = listener.local_addr().unwrap().to_string();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buffer = [0_u8; 512];
+        while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+            let bytes_read = stream.read(&mut buffer).await.unwrap();
+            request.extend_from_slice(&buffer[..bytes_read]);
+        }
+        stream
+            .write_all(
+                b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: echo\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        let (mut reader, mut writer) = stream.split();
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+    let balancebeam = BalanceBeam::new(&[&upstream_address], None, None).await;
+
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(
+            b"GET /ws HTTP/1.1\r\nHost: test\r\nConnection: Upgrade\r\nUpgrade: echo\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    let mut buffer = [0_u8; 512];
+    while !response.windows(4).any(|window| window == b"\r\n\r\n") {
+        let bytes_read = stream.read(&mut buffer).await.unwrap();
+        assert!(bytes_read > 0, "balancebeam hung up before responding");
+        response.extend_from_slice(&buffer[..bytes_read]);
+    }
+    assert!(response.starts_with(b"HTTP/1.1 101"));
+
+    // Not HTTP, so this would be rejected as a malformed request without the tunnel
+    stream.write_all(b"\x00ping\xff").await.unwrap();
+    let mut echoed = [0_u8; 6];
+    stream.read_exact(&mut echoed).await.unwrap();
+    assert_eq!(&echoed, b"\x00ping\xff");
+
+    // Hanging up our side should be passed on, so the upstream finishes and the tunnel closes
+    stream.shutdown(std::net::Shutdown::Write).unwrap();
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+}
+
+/// On SIGTERM, balancebeam should stop accepting connections but let a request that is already
+/// being proxied finish before exiting
+#[tokio::test]
+async fn test_graceful_shutdown() {
+    init_logging();
+    let upstream = MockServer::new(
+        MockResponse::new(200)
+            .body("done")
+            .delay(Duration::from_millis(500)),
+    )
+    .await;
+    let mut balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let address = balancebeam.address.clone();
+    let in_flight = tokio::spawn(async move {
+        reqwest::Client::new()
+            .get(&format!("http://{}/slow", address))
+            .send()
+            .await?
+            .text()
+            .await
+    });
+    assert!(balancebeam.wait_for_output("GET /slow").await);
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGTERM);
+    assert!(
+        balancebeam
+            .wait_for_output("Waiting up to 30s for 1 open connections to finish")
+            .await
+    );
+    assert!(
+        tokio::net::TcpStream::connect(&balancebeam.address)
+            .await
+            .is_err(),
+        "balancebeam accepted a connection after SIGTERM"
+    );
+
+    let response_text = in_flight
+        .await
+        .unwrap()
+        .expect("In-flight request failed during shutdown");
+    assert_eq!(response_text, "done");
+    let status = balancebeam.shutdown().await;
+    assert!(status.success(), "balancebeam exited with {}", status);
+    assert!(balancebeam.output_contains("status_counts=200=1"));
+}
+
+/// Connections that are still busy when --shutdown-drain-timeout-secs runs out shouldn't keep
+/// balancebeam from exiting
+#[tokio::test]
+async fn test_shutdown_drain_timeout() {
+    init_logging();
+    let upstream = RawServer::new_stalled(b"HTTP/1.1 200 OK\r\n").await;
+    let mut balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--shutdown-drain-timeout-secs", "1"],
+    )
+    .await;
+
+    let address = balancebeam.address.clone();
+    tokio::spawn(async move {
+        let _ = reqwest::get(&format!("http://{}/stalled", address)).await;
+    });
+    assert!(balancebeam.wait_for_output("GET /stalled").await);
+    let status = balancebeam.shutdown().await;
+    assert!(status.success(), "balancebeam exited with {}", status);
+    assert!(balancebeam.output_contains("connections still open after the drain timeout"));
+}
+
+/// The metrics endpoint should report requests, status codes, upstream latencies, and rate
+/// limiting in the Prometheus text format
+#[tokio::test]
+async fn test_metrics_endpoint() {
+    init_logging();
+    let upstream = MockServer::new(MockResponse::new(200).body("ok")).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        Some(2),
+        &["--metrics-bind", "127.0.0.1:0"],
+    )
+    .await;
+    for expected_status in &[200, 200, 429] {
+        balancebeam
+            .request(reqwest::Method::GET, "/", "")
+            .await
+            .expect("Error sending request to balancebeam")
+            .expect_status(*expected_status);
+    }
+
+    let metrics_address = balancebeam.metrics_address().await;
+    let metrics = reqwest::get(&format!("http://{}/metrics", metrics_address))
+        .await
+        .expect("Error fetching metrics")
+        .text()
+        .await
+        .unwrap();
+    for line in &[
+        "# TYPE balancebeam_requests_total counter".to_string(),
+        "balancebeam_requests_total 3".to_string(),
+        "balancebeam_responses_total{code=\"200\"} 2".to_string(),
+        "balancebeam_responses_total{code=\"429\"} 1".to_string(),
+        format!(
+            "balancebeam_upstream_requests_total{{upstream=\"{}\"}} 2",
+            upstream.address
+        ),
+        format!(
+            "balancebeam_upstream_latency_seconds_bucket{{upstream=\"{}\",le=\"+Inf\"}} 2",
+            upstream.address
+        ),
+        format!(
+            "balancebeam_upstream_latency_seconds_count{{upstream=\"{}\"}} 2",
+            upstream.address
+        ),
+        "balancebeam_rate_limited_requests_total 1".to_string(),
+        "balancebeam_connections_total 3".to_string(),
+    ] {
+        assert!(
+            metrics.lines().any(|metric| metric == line),
+            "Metrics are missing {:?}:\n{}",
+            line,
+            metrics
+        );
+    }
+    assert!(metrics.contains("balancebeam_active_connections "));
+
+    let response = reqwest::get(&format!("http://{}/status", metrics_address))
+        .await
+        .expect("Error fetching from the metrics endpoint");
+    assert_eq!(response.status().as_u16(), 404);
+}
+
+/// With --stats-log-secs, balancebeam should regularly log how much traffic it has handled, with
+/// requests and errors broken down by upstream
+#[tokio::test]
+async fn test_stats_log() {
+    init_logging();
+    let upstream = MockServer::new(MockResponse::new(500).body("oops")).await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], None, None, &["--stats-log-secs", "1"])
+            .await;
+    for _ in 0..3 {
+        balancebeam
+            .request(reqwest::Method::GET, "/", "")
+            .await
+            .expect("Error sending request to balancebeam")
+            .expect_status(500);
+    }
+
+    assert!(
+        balancebeam
+            .wait_for_output(
+                "Stats: connections_accepted=3 active_connections=0 requests_proxied=3"
+            )
+            .await
+    );
+    let upstreams = format!(
+        "Stats: upstream_requests={0}=3 upstream_errors={0}=3",
+        upstream.address
+    );
+    assert!(balancebeam.wait_for_output(&upstreams).await);
+}
+
+/// Every request should get an access log line: in the combined format through the regular log by
+/// default, or as JSON in its own file if asked
+#[tokio::test]
+async fn test_access_log() {
+    init_logging();
+    let upstream = MockServer::new(MockResponse::new(200).body("logged")).await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    balancebeam
+        .request(reqwest::Method::GET, "/combined", "")
+        .await
+        .expect("Error sending request to balancebeam")
+        .expect_status(200);
+    assert!(
+        balancebeam
+            .wait_for_output("\"GET /combined HTTP/1.1\" 200 ")
+            .await,
+        "balancebeam did not log the request in the combined format"
+    );
+    assert!(balancebeam.output_contains(&format!("upstream={} latency_ms=", upstream.address)));
+
+    let log_path = std::env::temp_dir().join(format!(
+        "balancebeam-access-{}.log",
+        upstream.address.replace(':', "-")
+    ));
+    let _ = std::fs::remove_file(&log_path);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &[
+            "--access-log-format",
+            "json",
+            "--access-log-file",
+            log_path.to_str().unwrap(),
+        ],
+    )
+    .await;
+    reqwest::Client::new()
+        .get(&format!("http://{}/json?format=1", balancebeam.address))
+        .header("user-agent", "tester \"quoted\"")
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    let log = std::fs::read_to_string(&log_path).expect("Could not read the access log");
+    std::fs::remove_file(&log_path).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 1, "{}", log);
+    for field in &[
+        "\"client_ip\":\"127.0.0.1\"".to_string(),
+        "\"method\":\"GET\"".to_string(),
+        "\"path\":\"/json?format=1\"".to_string(),
+        format!("\"upstream\":\"{}\"", upstream.address),
+        "\"status\":200".to_string(),
+        "\"user_agent\":\"tester \\\"quoted\\\"\"".to_string(),
+        "\"referer\":null".to_string(),
+    ] {
+        assert!(
+            lines[0].contains(field.as_str()),
+            "{} missing from {}",
+            field,
+            lines[0]
+        );
+    }
+    assert!(lines[0].starts_with("{\"time\":\"") && lines[0].ends_with('}'));
+}
+
+/// With caching on, a repeated GET should be answered without going to the upstream, unless the
+/// upstream said not to keep its response
+#[tokio::test]
+async fn test_response_cache() {
+    init_logging();
+    let upstream = MockServer::new(
+        MockResponse::new(200)
+            .header("cache-control", "max-age=60")
+            .body("cached"),
+    )
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--cache-max-bytes", "100000"],
+    )
+    .await;
+
+    balancebeam
+        .request(reqwest::Method::GET, "/page", "")
+        .await
+        .expect("Error sending request to balancebeam")
+        .expect_status(200)
+        .expect_no_header("age");
+    let response = balancebeam
+        .request(reqwest::Method::GET, "/page", "")
+        .await
+        .expect("Error sending request to balancebeam");
+    response.expect_status(200).expect_body("cached");
+    assert!(response.header("age").is_some());
+    assert_eq!(upstream.requests_received(), 1);
+
+    // Other paths and methods still go to the upstream
+    balancebeam
+        .request(reqwest::Method::GET, "/other-page", "")
+        .await
+        .expect("Error sending request to balancebeam");
+    balancebeam
+        .request(reqwest::Method::POST, "/page", "body")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(upstream.requests_received(), 3);
+
+    let upstream = MockServer::new(
+        MockResponse::new(200)
+            .header("cache-control", "no-store")
+            .body("not cached"),
+    )
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--cache-max-bytes", "100000"],
+    )
+    .await;
+    for _ in 0..2 {
+        balancebeam
+            .request(reqwest::Method::GET, "/page", "")
+            .await
+            .expect("Error sending request to balancebeam")
+            .expect_body("not cached");
+    }
+    assert_eq!(upstream.requests_received(), 2);
+}
+
+/// Forwarding headers sent by ordinary clients should be replaced with our own, while ones sent by
+/// a trusted proxy should be appended to.
+#[tokio::test]
+async fn test_forwarding_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let port =
+        |balancebeam: &BalanceBeam| balancebeam.address.rsplit(':').next().unwrap().to_string();
+    let send_spoofed = |balancebeam: &BalanceBeam| {
+        reqwest::Client::new()
+            .get(&format!("http://{}/whoami", balancebeam.address))
+            .header("x-forwarded-for", "203.0.113.7")
+            .header("x-forwarded-proto", "https")
+            .header("forwarded", "for=203.0.113.7;proto=https")
+            .send()
+    };
+
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    let response_text = send_spoofed(&balancebeam)
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .expect("Error reading response from balancebeam");
+    assert!(
+        !response_text.contains("203.0.113.7"),
+        "Untrusted forwarding headers were passed along: {}",
+        response_text
+    );
+    assert!(response_text.contains("x-forwarded-for: 127.0.0.1\n"));
+    assert!(response_text.contains("x-forwarded-proto: http\n"));
+    assert!(response_text.contains(&format!("x-forwarded-port: {}\n", port(&balancebeam))));
+    assert!(
+        response_text.contains(&format!(
+            "forwarded: for=127.0.0.1;proto=http;host=\"{}\"\n",
+            balancebeam.address
+        )),
+        "Missing or malformed Forwarded header: {}",
+        response_text
+    );
+    drop(balancebeam);
+
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--trusted-proxy", "127.0.0.0/8"],
+    )
+    .await;
+    let response_text = send_spoofed(&balancebeam)
+        .await
+        .expect("Error sending request to balancebeam")
+        .text()
+        .await
+        .expect("Error reading response from balancebeam");
+    assert!(response_text.contains("x-forwarded-for: 203.0.113.7, 127.0.0.1\n"));
+    assert!(response_text.contains("x-forwarded-proto: https\n"));
+    assert!(
+        response_text.contains(&format!(
+            "forwarded: for=203.0.113.7;proto=https, for=127.0.0.1;proto=http;host=\"{}\"\n",
+            balancebeam.address
+        )),
+        "Trusted Forwarded header was not appended to: {}",
+        response_text
+    );
+}
+
+/// With --accept-proxy-protocol, the client address from a PROXY protocol header (either version)
+/// should be used in place of the load balancer's
+#[tokio::test]
+async fn test_accept_proxy_protocol() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--accept-proxy-protocol"],
+    )
+    .await;
+
+    let v1_header = b"PROXY TCP4 203.0.113.7 127.0.0.1 51234 1100\r\n".to_vec();
+    let mut v2_header = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\x00\x0c".to_vec();
+    v2_header.extend_from_slice(&[198, 51, 100, 9, 127, 0, 0, 1, 0xc8, 0x22, 0x04, 0x4c]);
+    for (header, client_ip) in [(v1_header, "203.0.113.7"), (v2_header, "198.51.100.9")] {
+        let mut raw_request = header;
+        raw_request.extend_from_slice(b"GET /whoami HTTP/1.1\r\nHost: test\r\n\r\n");
+        let response = send_raw_request(&balancebeam, &raw_request).await;
+        assert!(
+            response.contains(&format!("x-forwarded-for: {}\n", client_ip)),
+            "Client address from the PROXY header was not used: {}",
+            response
+        );
+    }
+
+    // Connections without a header are hung up on (possibly with a reset, since the request is
+    // left unread)
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(b"GET /whoami HTTP/1.1\r\nHost: test\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response).await;
+    assert!(
+        response.is_empty(),
+        "Request without a PROXY header was answered"
+    );
+    assert!(
+        balancebeam
+            .wait_for_output("Bad PROXY protocol header")
+            .await
+    );
+}
+
+/// With --send-proxy-protocol, each upstream connection should start with a PROXY protocol header
+/// naming the client
+#[tokio::test]
+async fn test_send_proxy_protocol() {
+    init_logging();
+    // An upstream that passes along everything it receives before each request's blank line
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let upstream_address = listener.local_addr().unwrap().to_string();
+    let (received_sender, mut received_receiver) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let received_sender = received_sender.clone();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                let mut buffer = [0_u8; 512];
+                while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                    match stream.read(&mut buffer).await {
+                        Ok(0) | Err(_) => return,
+                        Ok(bytes_read) => request.extend_from_slice(&buffer[..bytes_read]),
+                    }
+                }
+                let _ = received_sender.send(String::from_utf8_lossy(&request).to_string());
+                let _ = stream
+                    .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok")
+                    .await;
+            });
+        }
+    });
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream_address], None, None, &["--send-proxy-protocol"])
+            .await;
+
+    let response_text = balancebeam
+        .get("/proxied")
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response_text, "ok");
+    let port = balancebeam.address.rsplit(':').next().unwrap();
+    loop {
+        let received = received_receiver.recv().await.unwrap();
+        if !received.contains("/proxied") {
+            // (A health check)
+            continue;
+        }
+        let expected_start = "PROXY TCP4 127.0.0.1 127.0.0.1 ";
+        assert!(
+            received.starts_with(expected_start),
+            "Upstream connection did not start with a PROXY header: {:?}",
+            received
+        );
+        let header = received.split("\r\n").next().unwrap();
+        assert!(
+            header.ends_with(&format!(" {}", port)),
+            "PROXY header has the wrong destination port: {:?}",
+            header
+        );
+        break;
+    }
+}
+
+/// A client at --max-connections-per-ip should have further connections refused, and once
+/// --max-connections are open, new connections should wait until one closes
+#[tokio::test]
+async fn test_connection_limits() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--max-connections-per-ip", "1"],
+    )
+    .await;
+    let idle_connection = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    assert!(balancebeam.wait_for_output("Connection received").await);
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    let _ = stream
+        .write_all(b"GET /refused HTTP/1.1\r\nHost: test\r\n\r\n")
+        .await;
+    let mut response = Vec::new();
+    let _ = stream.read_to_end(&mut response).await;
+    assert!(
+        response.is_empty(),
+        "Connection over the per-IP limit was served"
+    );
+    assert!(
+        balancebeam
+            .wait_for_output("Refusing connection from 127.0.0.1")
+            .await
+    );
+    drop(idle_connection);
+    delay_for(Duration::from_millis(200)).await;
+    balancebeam
+        .get("/allowed")
+        .await
+        .expect("Connection was refused after the client's other one closed");
+    drop(balancebeam);
+
+    let balancebeam = Arc::new(
+        BalanceBeam::new_with_args(
+            &[&upstream.address],
+            None,
+            None,
+            &["--max-connections", "1"],
+        )
+        .await,
+    );
+    let idle_connection = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    assert!(balancebeam.wait_for_output("Connection received").await);
+    let balancebeam_shared = Arc::clone(&balancebeam);
+    let waiting_request = tokio::spawn(async move { balancebeam_shared.get("/queued").await });
+    delay_for(Duration::from_millis(500)).await;
+    assert!(
+        !balancebeam.output_contains("GET /queued"),
+        "Connection over --max-connections was served while the limit was reached"
+    );
+    drop(idle_connection);
+    let response_text = tokio::time::timeout(Duration::from_secs(5), waiting_request)
+        .await
+        .expect("Waiting connection was not served after a slot opened up")
+        .unwrap()
+        .expect("Error sending request to balancebeam");
+    assert!(response_text.contains("GET /queued HTTP/1.1"));
+}
+
+/// Reads one response with a Content-Length body off `stream`, returning its headers and body
+async fn read_one_response<S: tokio::io::AsyncRead + Unpin>(stream: &mut S) -> String {
+    let mut response = Vec::new();
+    let mut buffer = [0_u8; 512];
+    loop {
+        if let Some(end) = response.windows(4).position(|window| window == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&response[..end]).to_lowercase();
+            let content_length: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("content-length: "))
+                .map_or(0, |value| value.trim().parse().unwrap());
+            if response.len() >= end + 4 + content_length {
+                return String::from_utf8_lossy(&response).to_string();
+            }
+        }
+        let bytes_read = stream.read(&mut buffer).await.unwrap();
+        assert!(bytes_read > 0, "balancebeam hung up before responding");
+        response.extend_from_slice(&buffer[..bytes_read]);
+    }
+}
+
+/// Clients should have their connections closed when they ask for it (or, on HTTP/1.0, don't ask
+/// for keep-alive), and the Connection header they send shouldn't reach the upstream
+#[tokio::test]
+async fn test_client_keep_alive() {
+    init_logging();
+    let (balancebeam, upstream) = setup().await;
+
+    for (request, keep_alive) in [
+        (&b"GET /a HTTP/1.1\r\nHost: test\r\n\r\n"[..], true),
+        (
+            b"GET /b HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+            false,
+        ),
+        (b"GET /c HTTP/1.0\r\n\r\n", false),
+        (b"GET /d HTTP/1.0\r\nConnection: keep-alive\r\n\r\n", true),
+    ] {
+        let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+            .await
+            .expect("Could not connect to balancebeam");
+        stream.write_all(request).await.unwrap();
+        let response = read_one_response(&mut stream).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        // (The echoed request is the body)
+        let (head, echoed) = response.split_once("\r\n\r\n").unwrap();
+        assert!(!echoed.contains("connection:"), "{}", response);
+
+        // balancebeam should hang up by itself, or wait for another request
+        let mut rest = Vec::new();
+        let read = tokio::time::timeout(Duration::from_millis(500), stream.read_to_end(&mut rest));
+        assert_eq!(read.await.is_ok(), !keep_alive, "{}", response);
+        let header = if !keep_alive {
+            "connection: close"
+        } else if request.starts_with(b"GET /d HTTP/1.0") {
+            "connection: keep-alive"
+        } else {
+            ""
+        };
+        assert!(head.to_lowercase().contains(header), "{}", response);
+    }
+    assert_eq!(Box::new(upstream).stop().await, 4);
+}
+
+/// HTTP/1.0 clients should have their requests sent upstream as HTTP/1.1 (with a Host, if they
+/// left it out), and get a streamed chunked response without its chunk framing, ending when
+/// balancebeam closes the connection
+#[tokio::test]
+async fn test_http_10_client() {
+    init_logging();
+    let (balancebeam, upstream) = setup().await;
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(b"GET /old HTTP/1.0\r\n\r\n")
+        .await
+        .unwrap();
+    let response = read_one_response(&mut stream).await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+    assert!(response.contains("GET /old HTTP/1.1\n"), "{}", response);
+    let host = format!("host: {}\n", upstream.address);
+    assert!(response.contains(&host), "{}", response);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+
+    let upstream = RawServer::new(
+        b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+          5\r\nhello\r\n7\r\n, world\r\n0\r\n\r\n",
+    )
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--body-high-water-mark", "8"],
+    )
+    .await;
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(b"GET /chunked HTTP/1.0\r\nConnection: keep-alive\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    let read = tokio::time::timeout(Duration::from_secs(2), stream.read_to_string(&mut response));
+    read.await
+        .expect("balancebeam didn't close the connection")
+        .unwrap();
+    let (head, body) = response.split_once("\r\n\r\n").unwrap();
+    let head = head.to_lowercase();
+    assert!(!head.contains("transfer-encoding"), "{}", response);
+    assert!(head.contains("connection: close"), "{}", response);
+    assert_eq!(body, "hello, world");
+}
+
+/// An upstream that closes its connection after each response shouldn't break the client's
+/// connection: the next request should go out on a new upstream connection
+#[tokio::test]
+async fn test_upstream_connection_close() {
+    init_logging();
+    let upstream =
+        RawServer::new(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok")
+            .await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    for _ in 0..3 {
+        stream
+            .write_all(b"GET / HTTP/1.1\r\nHost: test\r\n\r\n")
+            .await
+            .unwrap();
+        let response = read_one_response(&mut stream).await;
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        assert!(response.ends_with("\r\n\r\nok"), "{}", response);
+    }
+    assert_eq!(Box::new(upstream).stop().await, 3);
+}
+
+/// Responses that can't have a body (to HEAD requests, and 204s and 304s) should be passed on
+/// straight away, even if they come with a Content-Length, and interim 100 Continue responses
+/// should be skipped over in favor of the real response
+#[tokio::test]
+async fn test_responses_without_bodies() {
+    init_logging();
+    // The upstreams never send the bodies their Content-Lengths promise, or hang up
+    for (method, raw_response, status) in [
+        (
+            "HEAD",
+            &b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n"[..],
+            200,
+        ),
+        (
+            "GET",
+            b"HTTP/1.1 204 No Content\r\nContent-Length: 5\r\n\r\n",
+            204,
+        ),
+        (
+            "GET",
+            b"HTTP/1.1 304 Not Modified\r\nContent-Length: 5\r\n\r\n",
+            304,
+        ),
+    ] {
+        let upstream = RawServer::new_stalled(raw_response).await;
+        let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+        let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+            .await
+            .expect("Could not connect to balancebeam");
+        let request = format!(
+            "{} / HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+            method
+        );
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        tokio::time::timeout(Duration::from_secs(2), stream.read_to_string(&mut response))
+            .await
+            .expect("balancebeam waited for a body that was never coming")
+            .unwrap();
+        assert!(
+            response.starts_with(&format!("HTTP/1.1 {}", status)),
+            "{}",
+            response
+        );
+        assert!(response.ends_with("\r\n\r\n"), "{}", response);
+    }
+
+    let upstream = RawServer::new(
+        b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok",
+    )
+    .await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, None).await;
+    balancebeam
+        .request(reqwest::Method::POST, "/upload", "data")
+        .await
+        .expect("Error sending request to balancebeam")
+        .expect_status(200)
+        .expect_body("ok");
+}
+
+/// Upstreams and clients can both be on UNIX sockets, given as unix:PATH to --upstream and --bind
+#[tokio::test]
+async fn test_unix_sockets() {
+    init_logging();
+    let socket_path = |name: &str| {
+        std::env::temp_dir().join(format!("balancebeam-{}-{}.sock", name, std::process::id()))
+    };
+    let upstream_path = socket_path("upstream");
+    let bind_path = socket_path("bind");
+    let _ = std::fs::remove_file(&upstream_path);
+
+    // The upstream answers with the head of the request it got
+    let mut upstream_listener = tokio::net::UnixListener::bind(&upstream_path).unwrap();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = upstream_listener.accept().await {
+            let mut request = Vec::new();
+            let mut buffer = [0_u8; 512];
+            while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+                match stream.read(&mut buffer).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(bytes_read) => request.extend_from_slice(&buffer[..bytes_read]),
+                }
+            }
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                request.len()
+            );
+            let _ = stream.write_all(response.as_bytes()).await;
+            let _ = stream.write_all(&request).await;
+        }
+    });
+
+    let upstream = format!("unix:{}", upstream_path.display());
+    let bind = format!("unix:{}", bind_path.display());
+    let mut balancebeam =
+        BalanceBeam::new_with_args(&[&upstream], None, None, &["--bind", &bind]).await;
+    assert_eq!(balancebeam.address, bind);
+
+    let mut stream = tokio::net::UnixStream::connect(&bind_path).await.unwrap();
+    stream
+        .write_all(b"GET /over-unix HTTP/1.1\r\nHost: test\r\n\r\n")
+        .await
+        .unwrap();
+    let response = read_one_response(&mut stream).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+    assert!(response.contains("GET /over-unix HTTP/1.1"), "{}", response);
+    assert!(
+        response
+            .to_lowercase()
+            .contains("x-forwarded-for: 127.0.0.1"),
+        "{}",
+        response
+    );
+
+    // The socket file goes away with balancebeam
+    balancebeam.shutdown().await;
+    assert!(!bind_path.exists());
+    std::fs::remove_file(&upstream_path).unwrap();
+}
+
+/// --bind can be repeated to listen on several addresses at once, and requests to any of them
+/// should be proxied (with the port each came in on in X-Forwarded-Port)
+#[tokio::test]
+async fn test_multiple_binds() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let bind_path =
+        std::env::temp_dir().join(format!("balancebeam-binds-{}.sock", std::process::id()));
+    let unix_bind = format!("unix:{}", bind_path.display());
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &[
+            "--bind",
+            "127.0.0.1:0",
+            "--bind",
+            "127.0.0.1:0",
+            "--bind",
+            &unix_bind,
+        ],
+    )
+    .await;
+    let addresses = balancebeam.listen_addresses(3).await;
+    assert_eq!(
+        addresses.len(),
+        3,
+        "Not every address was bound: {:?}",
+        addresses
+    );
+    assert_ne!(addresses[0], addresses[1]);
+    assert_eq!(addresses[2], unix_bind);
+
+    for address in &addresses[..2] {
+        let response_text = reqwest::get(&format!("http://{}/binds", address))
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .unwrap();
+        let port = address.rsplit(':').next().unwrap();
+        assert!(
+            response_text.contains(&format!("x-forwarded-port: {}\n", port)),
+            "{}",
+            response_text
+        );
+    }
+    let mut stream = tokio::net::UnixStream::connect(&bind_path).await.unwrap();
+    stream
+        .write_all(b"GET /binds HTTP/1.1\r\nHost: test\r\n\r\n")
+        .await
+        .unwrap();
+    let response = read_one_response(&mut stream).await;
+    assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+}
+
+/// With --workers, connections are accepted by several workers, each with its own SO_REUSEPORT
+/// socket, and the metrics say how many each one accepted
+#[tokio::test]
+async fn test_workers() {
+    init_logging();
+    let upstream = MockServer::new(MockResponse::new(200).body("ok")).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--workers", "4", "--metrics-bind", "127.0.0.1:0"],
+    )
+    .await;
+    let n_requests = 40;
+    for _ in 0..n_requests {
+        balancebeam
+            .request(reqwest::Method::GET, "/", "")
+            .await
+            .expect("Error sending request to balancebeam")
+            .expect_status(200);
+    }
+
+    let metrics_address = balancebeam.metrics_address().await;
+    let metrics = reqwest::get(&format!("http://{}/metrics", metrics_address))
+        .await
+        .expect("Error fetching metrics")
+        .text()
+        .await
+        .unwrap();
+    let worker_counts: Vec<usize> = metrics
+        .lines()
+        .filter(|line| line.starts_with("balancebeam_worker_connections_total{"))
+        .map(|line| line.rsplit(' ').next().unwrap().parse().unwrap())
+        .collect();
+    assert_eq!(
+        worker_counts.iter().sum::<usize>(),
+        n_requests,
+        "{}",
+        metrics
+    );
+    // (The kernel picks a socket by hashing each connection's addresses, so 40 connections from
+    // different ports all landing on one of four sockets would be very unlikely)
+    assert!(worker_counts.len() > 1, "{}", metrics);
+    assert!(
+        worker_counts.len() <= 4,
+        "More workers than asked for: {}",
+        metrics
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, n_requests);
+}
+
+/// --allow and --deny decide which clients get their requests proxied. The rest are answered with
+/// a 403, or with --deny-silently, have their connections closed without a response
+#[tokio::test]
+async fn test_access_lists() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let expect_status = |args: &'static [&'static str], status: Option<u16>| {
+        let upstream_address = upstream.address.clone();
+        async move {
+            let balancebeam =
+                BalanceBeam::new_with_args(&[&upstream_address], None, None, args).await;
+            let response = balancebeam.request(reqwest::Method::GET, "/", "").await;
+            match status {
+                Some(status) => {
+                    response
+                        .expect("Error sending request to balancebeam")
+                        .expect_status(status);
+                }
+                None => assert!(response.is_err(), "Denied client got a response"),
+            }
+        }
+    };
+
+    expect_status(&["--allow", "127.0.0.0/8"], Some(200)).await;
+    expect_status(&["--allow", "10.0.0.0/8"], Some(403)).await;
+    // --deny wins over --allow
+    expect_status(
+        &["--allow", "127.0.0.0/8", "--deny", "127.0.0.1"],
+        Some(403),
+    )
+    .await;
+    expect_status(&["--deny", "127.0.0.1", "--deny-silently"], None).await;
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
+
+/// Each request gets an X-Request-Id, which the upstream and the client both see and balancebeam
+/// logs it under. One that a client sends is only kept if the client is a trusted proxy
+#[tokio::test]
+async fn test_request_id() {
+    init_logging();
+    let (balancebeam, upstream) = setup().await;
+    let send = |balancebeam: &BalanceBeam, request_id: &'static str| {
+        let url = format!("http://{}/with-id", balancebeam.address);
+        async move {
+            let response = reqwest::Client::new()
+                .get(&url)
+                .header("x-request-id", request_id)
+                .send()
+                .await
+                .expect("Error sending request to balancebeam");
+            let request_id = response
+                .headers()
+                .get("x-request-id")
+                .expect("Response has no X-Request-Id")
+                .to_str()
+                .unwrap()
+                .to_string();
+            (request_id, response.text().await.unwrap())
+        }
+    };
+
+    let (request_id, echoed) = send(&balancebeam, "from-the-client").await;
+    assert_ne!(request_id, "from-the-client");
+    assert_eq!(request_id.len(), 36, "Not a UUID: {}", request_id);
+    assert!(
+        echoed.contains(&format!("x-request-id: {}\n", request_id)),
+        "Upstream didn't get the request ID: {}",
+        echoed
+    );
+    assert!(
+        balancebeam
+            .wait_for_output(&format!("[{}] Forwarded response to client", request_id))
+            .await
+    );
+    drop(balancebeam);
+
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--trusted-proxy", "127.0.0.0/8"],
+    )
+    .await;
+    let (request_id, echoed) = send(&balancebeam, "from-the-proxy").await;
+    assert_eq!(request_id, "from-the-proxy");
+    assert!(
+        echoed.contains("x-request-id: from-the-proxy\n"),
+        "{}",
+        echoed
+    );
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// With --http2, a client that opens with the HTTP/2 preface should be able to send several
+/// requests at once over its connection (each going upstream as HTTP/1.1), including ones with
+/// bodies too big to buffer, while HTTP/1.1 clients are served as before
+#[tokio::test]
+async fn test_http2_prior_knowledge() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--http2", "--body-high-water-mark", "1024"],
+    )
+    .await;
+
+    let client = reqwest::Client::builder()
+        .http2_prior_knowledge()
+        .build()
+        .unwrap();
+    let big_body = "x".repeat(100 * 1024);
+    let mut responses = Vec::new();
+    for i in 0..5 {
+        let request = if i == 0 {
+            let url = format!("http://{}/big", balancebeam.address);
+            client.post(&url).body(big_body.clone())
+        } else {
+            client.get(&format!("http://{}/stream/{}", balancebeam.address, i))
+        };
+        responses.push(tokio::spawn(request.send()));
+    }
+    let host = format!("host: {}\n", balancebeam.address);
+    for (i, response) in responses.into_iter().enumerate() {
+        let response = response
+            .await
+            .unwrap()
+            .expect("Error sending HTTP/2 request to balancebeam");
+        assert_eq!(response.version(), reqwest::Version::HTTP_2);
+        assert_eq!(response.status().as_u16(), 200);
+        let text = response.text().await.unwrap();
+        if i == 0 {
+            assert!(text.starts_with("POST /big HTTP/1.1"), "{}", text);
+            assert!(text.ends_with(&big_body));
+        } else {
+            let request_line = format!("GET /stream/{} HTTP/1.1", i);
+            assert!(text.starts_with(&request_line), "{}", text);
+            assert!(text.contains(&host), "{}", text);
+        }
+    }
+
+    let response_text = balancebeam
+        .get("/http1")
+        .await
+        .expect("Error sending HTTP/1.1 request to balancebeam");
+    assert!(response_text.starts_with("GET /http1 HTTP/1.1"));
+    assert_eq!(Box::new(upstream).stop().await, 6);
+}
+
+/// With --max-queued-connections, connections past --max-connections should wait for a slot up to
+/// the queue's size, and any beyond that should be closed right away, with both showing up in the
+/// metrics
+#[tokio::test]
+async fn test_connection_queue() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &[
+            "--max-connections",
+            "1",
+            "--max-queued-connections",
+            "1",
+            "--metrics-bind",
+            "127.0.0.1:0",
+        ],
+    )
+    .await;
+    let connect = || async {
+        let stream = tokio::net::TcpStream::connect(&balancebeam.address)
+            .await
+            .expect("Could not connect to balancebeam");
+        delay_for(Duration::from_millis(200)).await;
+        stream
+    };
+    let served = connect().await;
+    let mut queued = connect().await;
+    let mut rejected = connect().await;
+    let mut buffer = [0_u8; 16];
+    let read = tokio::time::timeout(Duration::from_secs(2), rejected.read(&mut buffer));
+    let bytes_read = read.await.expect("Connection wasn't closed").unwrap_or(0);
+    assert_eq!(bytes_read, 0);
+
+    let metrics_address = balancebeam.metrics_address().await;
+    let metrics = reqwest::get(&format!("http://{}/metrics", metrics_address))
+        .await
+        .expect("Error fetching metrics")
+        .text()
+        .await
+        .unwrap();
+    for line in &[
+        "balancebeam_queued_connections 1",
+        "balancebeam_rejected_connections_total 1",
+    ] {
+        assert!(
+            metrics.lines().any(|metric| metric == *line),
+            "Metrics are missing {:?}:\n{}",
+            line,
+            metrics
+        );
+    }
+
+    // The queued connection gets its turn once the one being served closes
+    drop(served);
+    queued
+        .write_all(b"GET /queued HTTP/1.1\r\nHost: balancebeam\r\n\r\n")
+        .await
+        .unwrap();
+    let response = tokio::time::timeout(Duration::from_secs(2), read_one_response(&mut queued))
+        .await
+        .expect("Queued connection was never served");
+    assert!(response.contains("GET /queued HTTP/1.1"), "{}", response);
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
+
+/// With --error-pages-dir, errors are answered with the page for their status, with its
+/// placeholders filled in. Statuses without a page get the built-in one.
+#[tokio::test]
+async fn test_error_pages() {
+    init_logging();
+    let upstream = RawServer::new(b"HTTP/1.1 200 OK\r\nContent-Len").await;
+    let pages_dir = std::env::temp_dir().join(format!(
+        "balancebeam-error-pages-{}",
+        upstream.address.replace(':', "-")
+    ));
+    std::fs::create_dir_all(&pages_dir).unwrap();
+    std::fs::write(
+        pages_dir.join("502.html"),
+        "<p>{{status}} {{reason}}: request {{ request_id }} at {{timestamp}} {{unknown}}</p>",
+    )
+    .unwrap();
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--error-pages-dir", pages_dir.to_str().unwrap()],
+    )
+    .await;
+
+    let response = reqwest::Client::new()
+        .get(&format!("http://{}/broken", balancebeam.address))
+        .send()
+        .await
+        .expect("Error sending request to balancebeam");
+    assert_eq!(response.status().as_u16(), 502);
+    assert_eq!(
+        response.headers()["content-type"],
+        "text/html; charset=utf-8"
+    );
+    let request_id = response.headers()["x-request-id"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    let body = response.text().await.unwrap();
+    std::fs::remove_dir_all(&pages_dir).unwrap();
+    let prefix = format!("<p>502 Bad Gateway: request {} at ", request_id);
+    assert!(body.starts_with(&prefix), "{}", body);
+    assert!(body.ends_with("Z {{unknown}}</p>"), "{}", body);
+
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost\r\n\r\n")
+        .await
+        .unwrap();
+    let response = read_one_response(&mut stream).await;
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+    assert!(
+        response.contains("<h1>400 Bad Request</h1>") && response.contains("Request ID: -"),
+        "{}",
+        response
+    );
+}
+
+/// Requests whose headers take up more than --max-request-header-bytes, or number more than
+/// --max-request-headers, should get a 431 and have their connection closed
+#[tokio::test]
+async fn test_request_header_limits() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &[
+            "--max-request-header-bytes",
+            "4KiB",
+            "--max-request-headers",
+            "8",
+        ],
+    )
+    .await;
+    let send = |request: String| {
+        let address = balancebeam.address.clone();
+        async move {
+            let mut stream = tokio::net::TcpStream::connect(&address)
+                .await
+                .expect("Could not connect to balancebeam");
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let response = read_one_response(&mut stream).await;
+            let mut rest = Vec::new();
+            let read = tokio::time::timeout(Duration::from_secs(2), stream.read_to_end(&mut rest));
+            let closed = read.await.is_ok();
+            (response, closed)
+        }
+    };
+
+    let big_header = format!("X-Big: {}\r\n", "x".repeat(3000));
+    let (response, _) = send(format!(
+        "GET /fits HTTP/1.1\r\nHost: balancebeam\r\nConnection: close\r\n{}\r\n",
+        big_header
+    ))
+    .await;
+    assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+
+    let (response, closed) = send(format!(
+        "GET /too-big HTTP/1.1\r\nHost: balancebeam\r\n{}{}\r\n",
+        big_header, big_header
+    ))
+    .await;
+    assert!(response.starts_with("HTTP/1.1 431"), "{}", response);
+    assert!(closed, "balancebeam kept the connection open");
+
+    let many_headers: String = (0..8)
+        .map(|i| format!("X-Header-{}: {}\r\n", i, i))
+        .collect();
+    let (response, closed) = send(format!(
+        "GET /too-many HTTP/1.1\r\nHost: balancebeam\r\n{}\r\n",
+        many_headers
+    ))
+    .await;
+    assert!(response.starts_with("HTTP/1.1 431"), "{}", response);
+    assert!(closed, "balancebeam kept the connection open");
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
+
+/// With --normalize-paths, the upstream should get request paths with repeated slashes collapsed
+/// and dot segments resolved, and paths with encoded traversal sequences should get a 400. With
+/// --reject-suspicious-paths, paths that would need normalizing get a 400 too.
+#[tokio::test]
+async fn test_path_normalization() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam =
+        BalanceBeam::new_with_args(&[&upstream.address], None, None, &["--normalize-paths"]).await;
+    let request = |path: &str| {
+        format!(
+            "GET {} HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+            path
+        )
+    };
+
+    for (path, normalized) in &[
+        ("/a//b/./c/../d?x=1", "/a/b/d?x=1"),
+        ("/../../etc/", "/etc/"),
+        ("/a/b/..", "/a/"),
+    ] {
+        let response = send_raw_request(&balancebeam, request(path).as_bytes()).await;
+        let line = format!("GET {} HTTP/1.1", normalized);
+        assert!(
+            response.contains(&line),
+            "{} wasn't normalized: {}",
+            path,
+            response
+        );
+    }
+    for path in &["/%2e%2e/etc/passwd", "/a%2fb", "/%252e%252e/etc"] {
+        let response = send_raw_request(&balancebeam, request(path).as_bytes()).await;
+        assert!(
+            response.starts_with("HTTP/1.1 400"),
+            "{}: {}",
+            path,
+            response
+        );
+    }
+
+    let strict_balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--reject-suspicious-paths"],
+    )
+    .await;
+    let response = send_raw_request(&strict_balancebeam, request("/a/../b").as_bytes()).await;
+    assert!(response.starts_with("HTTP/1.1 400"), "{}", response);
+    let response = send_raw_request(&strict_balancebeam, request("/a/b/").as_bytes()).await;
+    assert!(response.contains("GET /a/b/ HTTP/1.1"), "{}", response);
+    assert_eq!(Box::new(upstream).stop().await, 4);
+}
@@ -1,6 +1,10 @@
 mod common;
 
-use common::{init_logging, BalanceBeam, EchoServer, ErrorServer, Server};
+use common::{
+    init_logging, BalanceBeam, EchoServer, ErrorServer, MockResponse, MockServer, RawServer, Server,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::process::Command;
 
 use std::time::Duration;
 use tokio::time::delay_for;
@@ -80,6 +84,121 @@ async fn test_load_distribution() {
     log::info!("All done :)");
 }
 
+/// With --strategy round-robin, requests should be spread exactly evenly across the upstreams
+#[tokio::test]
+async fn test_round_robin_distribution() {
+    init_logging();
+    let n_upstreams = 3;
+    let n_requests = 30;
+    let mut upstreams = Vec::new();
+    for _ in 0..n_upstreams {
+        upstreams.push(EchoServer::new().await);
+    }
+    let upstream_addresses: Vec<&str> = upstreams
+        .iter()
+        .map(|upstream| upstream.address.as_str())
+        .collect();
+    let balancebeam = BalanceBeam::new_with_args(
+        &upstream_addresses,
+        None,
+        None,
+        &["--strategy", "round-robin"],
+    )
+    .await;
+
+    for i in 0..n_requests {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+
+    for upstream in upstreams {
+        assert_eq!(Box::new(upstream).stop().await, n_requests / n_upstreams);
+    }
+}
+
+/// With --strategy least-connections, an upstream that is busy with a slow request shouldn't get any
+/// new connections while another upstream is idle
+#[tokio::test]
+async fn test_least_connections() {
+    init_logging();
+    let slow_upstream = MockServer::new(
+        MockResponse::new(200)
+            .body("slow")
+            .delay(Duration::from_millis(1500)),
+    )
+    .await;
+    let fast_upstream = MockServer::new(MockResponse::new(200).body("fast")).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&slow_upstream.address, &fast_upstream.address],
+        None,
+        None,
+        &["--strategy", "least-connections"],
+    )
+    .await;
+
+    // Both upstreams are idle, so the first connection goes to the first upstream (the slow one)
+    let (slow_response, fast_responses) = tokio::join!(balancebeam.get("/slow"), async {
+        delay_for(Duration::from_millis(200)).await;
+        let mut responses = Vec::new();
+        for i in 0..6 {
+            // Give balancebeam a moment to notice the previous client connection closing
+            delay_for(Duration::from_millis(50)).await;
+            responses.push(balancebeam.get(&format!("/request-{}", i)).await);
+        }
+        responses
+    });
+    assert_eq!(
+        slow_response.expect("Error sending request to balancebeam"),
+        "slow"
+    );
+    for response in fast_responses {
+        assert_eq!(
+            response.expect("Error sending request to balancebeam"),
+            "fast"
+        );
+    }
+    assert_eq!(slow_upstream.requests_received(), 1);
+    assert_eq!(fast_upstream.requests_received(), 6);
+}
+
+/// With --strategy ewma, once each upstream has answered a request, requests should go to the one
+/// that answers fastest
+#[tokio::test]
+async fn test_ewma() {
+    init_logging();
+    let slow_upstream = MockServer::new(
+        MockResponse::new(200)
+            .body("slow")
+            .delay(Duration::from_millis(300)),
+    )
+    .await;
+    let fast_upstream = MockServer::new(MockResponse::new(200).body("fast")).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&slow_upstream.address, &fast_upstream.address],
+        None,
+        None,
+        &["--strategy", "ewma"],
+    )
+    .await;
+
+    let mut responses = Vec::new();
+    for i in 0..10 {
+        let response = balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+        responses.push(response);
+    }
+    // Neither upstream has a latency yet for the first two requests, so each gets one
+    assert_eq!(responses.iter().filter(|body| *body == "slow").count(), 1);
+    assert_eq!(slow_upstream.requests_received(), 1);
+    assert_eq!(fast_upstream.requests_received(), 9);
+}
+
 async fn try_failover(balancebeam: &BalanceBeam, upstreams: &mut Vec<Box<dyn Server>>) {
     // Send some initial requests. Everything should work
     log::info!("Sending some initial requests. These should definitely work.");
@@ -131,6 +250,33 @@ async fn test_passive_health_checks() {
     log::info!("All done :)");
 }
 
+/// Once every upstream has failed, clients should get a 502 rather than having their connection
+/// dropped
+#[tokio::test]
+async fn test_all_upstreams_dead() {
+    let (balancebeam, mut upstreams) = setup(2).await;
+    while let Some(upstream) = upstreams.pop() {
+        upstream.stop().await;
+    }
+
+    for i in 0..3 {
+        let response = reqwest::get(&format!("http://{}/request-{}", balancebeam.address, i))
+            .await
+            .expect(
+                "Error sending request to balancebeam. It should accept the connection and send \
+                back an HTTP error even when there are no upstreams left.",
+            );
+        assert_eq!(response.status().as_u16(), 502);
+    }
+
+    // Give the log lines a moment to make their way through the output pipe
+    delay_for(Duration::from_millis(200)).await;
+    assert!(
+        balancebeam.output_contains("(0 upstreams left)"),
+        "balancebeam did not log that it ran out of upstreams"
+    );
+}
+
 /// Verify that the active health checks are monitoring HTTP status, rather than simply depending
 /// on whether connections can be established to determine whether an upstream is up:
 ///
@@ -294,3 +440,1587 @@ async fn test_rate_limiting() {
 
     log::info!("All done :)");
 }
+
+/// A 429 should tell the client when it can try again, and where it stands against the limit
+#[tokio::test]
+async fn test_rate_limit_headers() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(&[&upstream.address], None, Some(2)).await;
+    for _ in 0..2 {
+        balancebeam
+            .request(reqwest::Method::GET, "/", "")
+            .await
+            .expect("Error sending request to balancebeam")
+            .expect_status(200);
+    }
+    // Tokens come back at one every 30 seconds, and the bucket holds 2
+    let response = balancebeam
+        .request(reqwest::Method::GET, "/", "")
+        .await
+        .expect("Error sending rate limited request to balancebeam");
+    response
+        .expect_status(429)
+        .expect_header("retry-after", "30")
+        .expect_header("x-ratelimit-limit", "2")
+        .expect_header("x-ratelimit-remaining", "0")
+        .expect_header("x-ratelimit-reset", "60");
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// Stands in for a Redis server: takes one connection, answers each command on it with the next of
+/// `replies`, then hangs up. Returns its address and a receiver for the commands it got.
+async fn fake_redis(
+    replies: Vec<&'static [u8]>,
+) -> (String, tokio::sync::mpsc::UnboundedReceiver<Vec<String>>) {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap().to_string();
+    let (commands_sender, commands_receiver) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut stream = BufReader::new(stream);
+        for reply in replies {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            let count: usize = line.trim()[1..].parse().unwrap();
+            let mut command = Vec::new();
+            for _ in 0..count {
+                line.clear();
+                stream.read_line(&mut line).await.unwrap();
+                let len: usize = line.trim()[1..].parse().unwrap();
+                let mut argument = vec![0_u8; len + 2];
+                stream.read_exact(&mut argument).await.unwrap();
+                argument.truncate(len);
+                command.push(String::from_utf8(argument).unwrap());
+            }
+            commands_sender.send(command).unwrap();
+            stream.get_mut().write_all(reply).await.unwrap();
+        }
+    });
+    (address, commands_receiver)
+}
+
+/// With --rate-limit-redis, buckets are kept in Redis (so every replica shares them), and if Redis
+/// goes away requests are let through rather than refused
+#[tokio::test]
+async fn test_rate_limit_redis_store() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let (redis_address, mut commands) = fake_redis(vec![
+        b"*2\r\n:1\r\n$3\r\n1.5\r\n",
+        b"*2\r\n:0\r\n$3\r\n0.5\r\n",
+    ])
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        Some(2),
+        &["--rate-limit-redis", &redis_address],
+    )
+    .await;
+
+    balancebeam
+        .request(reqwest::Method::GET, "/", "")
+        .await
+        .expect("Error sending request to balancebeam")
+        .expect_status(200);
+    let command = commands.recv().await.unwrap();
+    assert_eq!(command[0], "EVAL");
+    assert_eq!(command[3], "balancebeam:ratelimit:default 127.0.0.1");
+    assert_eq!(command[4], "2");
+
+    balancebeam
+        .request(reqwest::Method::GET, "/", "")
+        .await
+        .expect("Error sending rate limited request to balancebeam")
+        .expect_status(429)
+        .expect_header("x-ratelimit-remaining", "0");
+
+    // The fake server has hung up now
+    balancebeam
+        .request(reqwest::Method::GET, "/", "")
+        .await
+        .expect("Error sending request to balancebeam")
+        .expect_status(200);
+    assert!(balancebeam.wait_for_output("letting the request through").await);
+
+    assert_eq!(Box::new(upstream).stop().await, 2);
+}
+
+/// A slow call to Redis shouldn't hold up the ones made while it's waiting (which would then time
+/// out and let their requests through)
+#[tokio::test]
+async fn test_rate_limit_redis_slow_call() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let mut listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let redis_address = listener.local_addr().unwrap().to_string();
+    // Every call is refused a token, but the first connection's reply comes too late to count
+    tokio::spawn(async move {
+        for delay in &[Duration::from_millis(1500), Duration::from_millis(0)] {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let delay = *delay;
+            tokio::spawn(async move {
+                let mut command = [0_u8; 4096];
+                let _ = stream.read(&mut command).await;
+                delay_for(delay).await;
+                let _ = stream.write_all(b"*2\r\n:0\r\n$3\r\n0.5\r\n").await;
+            });
+        }
+    });
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        Some(2),
+        &["--rate-limit-redis", &redis_address],
+    )
+    .await;
+
+    let url = format!("http://{}/", balancebeam.address);
+    let slow = {
+        let url = url.clone();
+        tokio::spawn(async move { reqwest::get(&url).await })
+    };
+    delay_for(Duration::from_millis(200)).await;
+    let response = reqwest::get(&url).await.unwrap();
+    assert_eq!(response.status().as_u16(), 429);
+    let response = slow.await.unwrap().unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    assert!(balancebeam.wait_for_output("timed out").await);
+
+    assert_eq!(Box::new(upstream).stop().await, 1);
+}
+
+/// Requests should be allowed again once the client's token bucket has had time to refill
+#[tokio::test]
+async fn test_rate_limiting_window_slides() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        Some(2),
+        &["--rate-limit-window-secs", "1"],
+    )
+    .await;
+
+    for i in 0..2 {
+        balancebeam
+            .request(reqwest::Method::GET, &format!("/request-{}", i), "")
+            .await
+            .expect("Error sending request to balancebeam")
+            .expect_status(200);
+    }
+    balancebeam
+        .request(reqwest::Method::GET, "/overboard", "")
+        .await
+        .expect("Error sending rate limited request to balancebeam")
+        .expect_status(429);
+
+    log::info!("Waiting for the earlier requests to leave the window");
+    delay_for(Duration::from_millis(1200)).await;
+    balancebeam
+        .request(reqwest::Method::GET, "/after-window", "")
+        .await
+        .expect("Error sending request to balancebeam")
+        .expect_status(200);
+
+    assert_eq!(Box::new(upstream).stop().await, 3);
+}
+
+/// Passing the same upstream twice should not give it a double share of the load: the duplicate is
+/// dropped with a warning, and the remaining upstreams still serve requests.
+#[tokio::test]
+async fn test_duplicate_upstreams_collapsed() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let other_upstream = EchoServer::new().await;
+    let balancebeam = BalanceBeam::new(
+        &[
+            &upstream.address,
+            &other_upstream.address,
+            &upstream.address,
+        ],
+        None,
+        None,
+    )
+    .await;
+    assert!(
+        balancebeam.output_contains(&format!("Ignoring duplicate upstream {}", upstream.address)),
+        "balancebeam did not warn about the duplicate upstream"
+    );
+
+    for i in 0..20 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    let first_count = Box::new(upstream).stop().await;
+    let second_count = Box::new(other_upstream).stop().await;
+    assert_eq!(first_count + second_count, 20);
+    assert!(first_count > 0 && second_count > 0);
+}
+
+/// Upstreams can be given by hostname, in which case every address the name resolves to joins the
+/// pool alongside the upstreams given by IP address
+#[tokio::test]
+async fn test_hostname_upstreams() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let other_upstream = EchoServer::new().await;
+    let hostname_upstream = upstream.address.replace("127.0.0.1", "localhost");
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&hostname_upstream, &other_upstream.address],
+        None,
+        None,
+        &["--strategy", "round-robin"],
+    )
+    .await;
+    assert!(
+        balancebeam
+            .wait_for_output(&format!(
+                "Upstream {} resolves to {}",
+                hostname_upstream, upstream.address
+            ))
+            .await,
+        "balancebeam did not log what {} resolves to",
+        hostname_upstream
+    );
+
+    for i in 0..10 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert_eq!(Box::new(upstream).stop().await, 5);
+    assert_eq!(Box::new(other_upstream).stop().await, 5);
+}
+
+/// balancebeam should refuse to start when given more distinct upstreams than --max-upstreams
+#[tokio::test]
+async fn test_max_upstreams_exceeded() {
+    init_logging();
+    let mut cmd = Command::new(BalanceBeam::target_bin_path());
+    cmd.args(["--bind", "127.0.0.1:0", "--max-upstreams", "2"]);
+    // Three distinct upstreams (plus a duplicate, which doesn't count towards the limit)
+    for upstream in [
+        "127.0.0.1:1001",
+        "127.0.0.1:1002",
+        "127.0.0.1:1003",
+        "127.0.0.1:1001",
+    ] {
+        cmd.arg("--upstream").arg(upstream);
+    }
+    let output = cmd.output().await.expect("Could not run balancebeam");
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("3 upstreams were specified, but --max-upstreams is 2"),
+        "Unexpected output from balancebeam: {}",
+        stderr
+    );
+}
+
+/// Writes `contents` to a config file that is unique to this test run, returning its path
+fn write_config_file(name: &str, contents: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!(
+        "balancebeam-test-{}-{}.toml",
+        std::process::id(),
+        name
+    ));
+    std::fs::write(&path, contents).expect("Could not write config file");
+    path
+}
+
+/// A client that has been quiet can make --rate-limit-burst requests at once, and paths with their
+/// own limit in the config file are limited separately (and instead of) the default limit
+#[tokio::test]
+async fn test_rate_limit_burst_and_routes() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config_path = write_config_file(
+        "rate-limit-routes",
+        r#"
+[rate_limit]
+max_requests_per_minute = 2
+burst = 4
+
+[[rate_limit.routes]]
+prefix = "/login/"
+max_requests_per_minute = 1
+"#,
+    );
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--config", config_path.to_str().unwrap()],
+    )
+    .await;
+
+    let expect_status = |path: &'static str, status: u16| {
+        let balancebeam = &balancebeam;
+        async move {
+            balancebeam
+                .request(reqwest::Method::GET, path, "")
+                .await
+                .expect("Error sending request to balancebeam")
+                .expect_status(status);
+        }
+    };
+    expect_status("/login", 200).await;
+    expect_status("/login/again", 429).await;
+    // (Not under /login, so it's limited by the default limit's burst of 4)
+    for _ in 0..4 {
+        expect_status("/loginpage", 200).await;
+    }
+    expect_status("/loginpage", 429).await;
+
+    assert_eq!(Box::new(upstream).stop().await, 5);
+    std::fs::remove_file(config_path).unwrap();
+}
+
+/// Upstreams (with weights), the strategy, and other settings can come from a config file, while
+/// options on the command line (here, --bind from the test harness) take precedence
+#[tokio::test]
+async fn test_config_file_weighted_upstreams() {
+    init_logging();
+    let light_upstream = EchoServer::new().await;
+    let heavy_upstream = EchoServer::new().await;
+    let config_path = write_config_file(
+        "weighted",
+        &format!(
+            r#"
+bind = "127.0.0.1:1"
+strategy = "round-robin"
+upstreams = ["{}", {{ address = "{}", weight = 3 }}]
+
+[health_check]
+interval = 0
+"#,
+            light_upstream.address, heavy_upstream.address
+        ),
+    );
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        None,
+        None,
+        &["--config", config_path.to_str().unwrap()],
+    )
+    .await;
+
+    for i in 0..40 {
+        let path = format!("/request-{}", i);
+        let response_text = balancebeam
+            .get(&path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert!(response_text.contains(&format!("GET {} HTTP/1.1", path)));
+    }
+    assert_eq!(Box::new(light_upstream).stop().await, 10);
+    assert_eq!(Box::new(heavy_upstream).stop().await, 30);
+    std::fs::remove_file(config_path).unwrap();
+}
+
+/// A canary in the config file should take its percentage of the default upstreams' requests,
+/// which can be changed through the admin API
+#[tokio::test]
+async fn test_canary() {
+    init_logging();
+    let stable_upstream = MockServer::new(MockResponse::new(200).body("stable")).await;
+    let canary_upstream = MockServer::new(MockResponse::new(200).body("canary")).await;
+    let config_path = write_config_file(
+        "canary",
+        &format!(
+            r#"
+upstreams = ["{}"]
+
+[canary]
+upstreams = ["{}"]
+percent = 0
+"#,
+            stable_upstream.address, canary_upstream.address
+        ),
+    );
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        None,
+        None,
+        &[
+            "--config",
+            config_path.to_str().unwrap(),
+            "--admin-bind",
+            "127.0.0.1:0",
+        ],
+    )
+    .await;
+    let admin_url = format!("http://{}", balancebeam.admin_address().await);
+    let client = reqwest::Client::new();
+    let admin = |method: reqwest::Method, path: &str| {
+        let request = client.request(method, &format!("{}{}", admin_url, path));
+        async move {
+            let response = request.send().await.expect("Error calling the admin API");
+            (response.status().as_u16(), response.text().await.unwrap())
+        }
+    };
+
+    for _ in 0..5 {
+        let response_text = balancebeam
+            .get("/")
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response_text, "stable");
+    }
+
+    let (status, canary) = admin(reqwest::Method::POST, "/canary?percent=100").await;
+    assert_eq!(status, 200);
+    assert_eq!(
+        canary,
+        format!("percent=100 upstreams={}\n", canary_upstream.address)
+    );
+    for _ in 0..5 {
+        let response_text = balancebeam
+            .get("/")
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response_text, "canary");
+    }
+
+    let (status, _) = admin(reqwest::Method::POST, "/canary?percent=101").await;
+    assert_eq!(status, 400);
+    let (status, canary) = admin(reqwest::Method::GET, "/canary").await;
+    assert_eq!(status, 200);
+    assert!(canary.starts_with("percent=100 "), "{:?}", canary);
+
+    assert_eq!(stable_upstream.requests_received(), 5);
+    assert_eq!(canary_upstream.requests_received(), 5);
+    std::fs::remove_file(config_path).unwrap();
+}
+
+/// balancebeam should refuse to start with a config file it doesn't understand, rather than
+/// silently ignoring the parts it doesn't recognize
+#[tokio::test]
+async fn test_config_file_invalid() {
+    init_logging();
+    let config_path = write_config_file(
+        "invalid",
+        r#"
+upstreams = ["127.0.0.1:1001"]
+
+[health_check]
+intervl = 5
+"#,
+    );
+    let output = Command::new(BalanceBeam::target_bin_path())
+        .arg("--config")
+        .arg(&config_path)
+        .output()
+        .await
+        .expect("Could not run balancebeam");
+    std::fs::remove_file(config_path).unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("Could not parse config file") && stderr.contains("intervl"),
+        "Unexpected output from balancebeam: {}",
+        stderr
+    );
+}
+
+/// On SIGHUP, balancebeam should re-read its config file and send new connections to the new set
+/// of upstreams. A config file that can't be loaded should leave the running configuration alone.
+#[tokio::test]
+async fn test_config_reload_on_sighup() {
+    init_logging();
+    let old_upstream = MockServer::new(MockResponse::new(200).body("old")).await;
+    let new_upstream = MockServer::new(MockResponse::new(200).body("new")).await;
+    let config_path = write_config_file(
+        "reload",
+        &format!("upstreams = [\"{}\"]\n", old_upstream.address),
+    );
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        None,
+        None,
+        &["--config", config_path.to_str().unwrap()],
+    )
+    .await;
+    assert_eq!(balancebeam.get("/").await.unwrap(), "old");
+
+    std::fs::write(
+        &config_path,
+        format!("upstreams = [\"{}\"]\n", new_upstream.address),
+    )
+    .unwrap();
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGHUP);
+    assert!(
+        balancebeam.wait_for_output("Reloaded configuration").await,
+        "balancebeam did not reload its configuration on SIGHUP"
+    );
+    for _ in 0..3 {
+        assert_eq!(balancebeam.get("/").await.unwrap(), "new");
+    }
+
+    std::fs::write(&config_path, "upstreams = [").unwrap();
+    balancebeam.send_signal(nix::sys::signal::Signal::SIGHUP);
+    assert!(
+        balancebeam
+            .wait_for_output("Not reloading configuration")
+            .await,
+        "balancebeam did not report the broken config file"
+    );
+    assert_eq!(balancebeam.get("/").await.unwrap(), "new");
+
+    assert_eq!(old_upstream.requests_received(), 1);
+    assert_eq!(new_upstream.requests_received(), 4);
+    std::fs::remove_file(config_path).unwrap();
+}
+
+/// With --strategy ip-hash, a client should keep getting the same upstream, and should move to a
+/// single other upstream (rather than being spread around) once that one dies
+#[tokio::test]
+async fn test_ip_hash() {
+    init_logging();
+    let mut upstreams = Vec::new();
+    for i in 0..3 {
+        upstreams.push(MockServer::new(MockResponse::new(200).body(&i.to_string())).await);
+    }
+    let upstream_addresses: Vec<&str> = upstreams
+        .iter()
+        .map(|upstream| upstream.address.as_str())
+        .collect();
+    let balancebeam =
+        BalanceBeam::new_with_args(&upstream_addresses, None, None, &["--strategy", "ip-hash"])
+            .await;
+
+    let mut served_by = Vec::new();
+    for _ in 0..10 {
+        served_by.push(
+            balancebeam
+                .get("/")
+                .await
+                .expect("Error sending request to balancebeam"),
+        );
+    }
+    served_by.dedup();
+    assert_eq!(
+        served_by.len(),
+        1,
+        "Requests were spread out: {:?}",
+        served_by
+    );
+    let first_choice: usize = served_by[0].parse().unwrap();
+
+    Box::new(upstreams.remove(first_choice)).stop().await;
+    let mut served_by = Vec::new();
+    for _ in 0..10 {
+        served_by.push(
+            balancebeam
+                .get("/")
+                .await
+                .expect("Error sending request to balancebeam"),
+        );
+    }
+    served_by.dedup();
+    assert_eq!(
+        served_by.len(),
+        1,
+        "Requests were spread out: {:?}",
+        served_by
+    );
+    assert_ne!(served_by[0], first_choice.to_string());
+}
+
+/// An upstream that keeps answering with errors should be skipped once its circuit opens, and get
+/// just one probe connection after the cooldown
+#[tokio::test]
+async fn test_circuit_breaker() {
+    init_logging();
+    let failing_upstream = MockServer::new(MockResponse::new(500).body("failing")).await;
+    let healthy_upstream = MockServer::new(MockResponse::new(200).body("healthy")).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&failing_upstream.address, &healthy_upstream.address],
+        None,
+        None,
+        &[
+            "--strategy",
+            "round-robin",
+            "--circuit-breaker-failures",
+            "2",
+            "--circuit-breaker-cooldown-secs",
+            "3",
+        ],
+    )
+    .await;
+
+    // Round robin alternates between the two until the failing upstream's circuit opens
+    for _ in 0..4 {
+        balancebeam
+            .get("/")
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    for _ in 0..3 {
+        let response_text = balancebeam
+            .get("/")
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response_text, "healthy");
+    }
+    assert!(balancebeam.output_contains("Opened the circuit"));
+
+    delay_for(Duration::from_secs(3)).await;
+    let mut failures = 0;
+    for _ in 0..5 {
+        let response_text = balancebeam
+            .get("/")
+            .await
+            .expect("Error sending request to balancebeam");
+        if response_text == "failing" {
+            failures += 1;
+        }
+    }
+    assert_eq!(
+        failures, 1,
+        "Expected exactly one probe of the failing upstream"
+    );
+    assert!(balancebeam.output_contains("Probe of upstream"));
+}
+
+/// An upstream that answers with errors much more often than the rest should be ejected, and
+/// readmitted once its ejection is up
+#[tokio::test]
+async fn test_outlier_ejection() {
+    init_logging();
+    let failing_upstream = MockServer::new(MockResponse::new(500).body("failing")).await;
+    let healthy_upstream = MockServer::new(MockResponse::new(200).body("healthy")).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&failing_upstream.address, &healthy_upstream.address],
+        None,
+        None,
+        &[
+            "--strategy",
+            "round-robin",
+            "--outlier-error-percent",
+            "50",
+            "--outlier-min-responses",
+            "3",
+            "--outlier-ejection-secs",
+            "2",
+        ],
+    )
+    .await;
+
+    // Round robin alternates between the two until the analysis catches up
+    for _ in 0..6 {
+        balancebeam
+            .get("/")
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+    assert!(balancebeam.wait_for_output("as an outlier").await);
+    for _ in 0..4 {
+        let response_text = balancebeam
+            .get("/")
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response_text, "healthy");
+    }
+
+    assert!(balancebeam.wait_for_output("Readmitting upstream").await);
+    let mut failures = 0;
+    for _ in 0..4 {
+        let response_text = balancebeam
+            .get("/")
+            .await
+            .expect("Error sending request to balancebeam");
+        if response_text == "failing" {
+            failures += 1;
+        }
+    }
+    assert_eq!(failures, 2, "The failing upstream wasn't readmitted");
+}
+
+/// With --shadow-upstream, every request should also be sent to the shadow, whose responses
+/// (however slow or broken) clients never see
+#[tokio::test]
+async fn test_shadow_upstream() {
+    init_logging();
+    let upstream = MockServer::new(MockResponse::new(200).body("upstream")).await;
+    let shadow = MockServer::new(
+        MockResponse::new(500)
+            .body("shadow")
+            .delay(Duration::from_millis(500)),
+    )
+    .await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--shadow-upstream", &shadow.address],
+    )
+    .await;
+
+    let start = std::time::Instant::now();
+    for i in 0..5 {
+        let response_text = balancebeam
+            .get(&format!("/request-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response_text, "upstream");
+    }
+    assert!(
+        start.elapsed() < Duration::from_millis(500),
+        "Clients waited for the shadow upstream"
+    );
+    delay_for(Duration::from_millis(800)).await;
+    assert_eq!(upstream.requests_received(), 5);
+    assert_eq!(shadow.requests_received(), 5);
+}
+
+/// If the upstream connection dies while a GET is being forwarded, the GET should be retried on
+/// another upstream. A POST shouldn't be (it may have already taken effect), and gets a 502.
+#[tokio::test]
+async fn test_retry_on_another_upstream() {
+    init_logging();
+    // Hangs up after reading each request, without responding
+    let dying_upstream = RawServer::new(b"").await;
+    let healthy_upstream = MockServer::new(MockResponse::new(200).body("healthy")).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&dying_upstream.address, &healthy_upstream.address],
+        None,
+        None,
+        &["--strategy", "round-robin", "--max-retries", "1"],
+    )
+    .await;
+
+    for _ in 0..4 {
+        let response = balancebeam
+            .request(reqwest::Method::GET, "/", "")
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, "healthy");
+    }
+    assert!(balancebeam.output_contains("retrying on"));
+
+    let mut bad_gateways = 0;
+    for _ in 0..2 {
+        let response = balancebeam
+            .request(reqwest::Method::POST, "/", "body")
+            .await
+            .expect("Error sending request to balancebeam");
+        if response.status == 502 {
+            bad_gateways += 1;
+        }
+    }
+    assert_eq!(bad_gateways, 1, "POSTs should not be retried");
+    assert!(Box::new(dying_upstream).stop().await >= 5);
+}
+
+/// An upstream that hangs up on some of its requests shouldn't cost clients anything: the GETs it
+/// drops are retried on another upstream, and the rest are answered as usual
+#[tokio::test]
+async fn test_flaky_upstream_failover() {
+    init_logging();
+    let flaky_upstream = MockServer::new(MockResponse::new(200).body("flaky").fail_every(2)).await;
+    let healthy_upstream = MockServer::new(MockResponse::new(200).body("healthy")).await;
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&flaky_upstream.address, &healthy_upstream.address],
+        None,
+        None,
+        &["--strategy", "round-robin", "--max-retries", "1"],
+    )
+    .await;
+
+    let mut flaky_responses = 0;
+    for _ in 0..10 {
+        let response = balancebeam
+            .request(reqwest::Method::GET, "/", "")
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(response.status, 200);
+        if response.body == "flaky" {
+            flaky_responses += 1;
+        }
+    }
+    assert!(
+        flaky_responses > 0,
+        "The flaky upstream never got to answer"
+    );
+    assert!(balancebeam.output_contains("retrying on"));
+    // (Every other request the flaky upstream got was dropped and sent on)
+    assert!(Box::new(flaky_upstream).stop().await > flaky_responses);
+}
+
+/// Requests should go to the upstreams of the route with the longest matching path prefix, and to
+/// the default upstreams if no route matches
+#[tokio::test]
+async fn test_path_routing() {
+    init_logging();
+    let default_upstream = MockServer::new(MockResponse::new(200).body("default")).await;
+    let api_upstream = MockServer::new(MockResponse::new(200).body("api")).await;
+    let admin_upstream = MockServer::new(MockResponse::new(200).body("admin")).await;
+    let api_route = format!("/api={}", api_upstream.address);
+    let admin_route = format!("/api/admin/={}", admin_upstream.address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&default_upstream.address],
+        None,
+        None,
+        &["--route", &api_route, "--route", &admin_route],
+    )
+    .await;
+
+    for (path, expected_upstream) in &[
+        ("/api", "api"),
+        ("/api/users?page=2", "api"),
+        ("/api/admin/users", "admin"),
+        ("/apis", "default"),
+        ("/", "default"),
+    ] {
+        let response_text = balancebeam
+            .get(path)
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(
+            &response_text, expected_upstream,
+            "{} went to the wrong upstream",
+            path
+        );
+    }
+}
+
+/// Virtual hosts from the config file should get requests by Host header (whatever the path), each
+/// health-checked at its own path
+#[tokio::test]
+async fn test_virtual_hosts() {
+    init_logging();
+    let default_upstream = MockServer::new(MockResponse::new(200).body("default")).await;
+    let api_upstream = MockServer::new(MockResponse::new(200).body("api")).await;
+    let broken_upstream = MockServer::new(MockResponse::new(500)).await;
+    let config_path = write_config_file(
+        "virtual-hosts",
+        &format!(
+            r#"
+upstreams = ["{}"]
+
+[health_check]
+interval = 1
+
+[[virtual_hosts]]
+hosts = ["api.example.com", "api.example.org"]
+upstreams = ["{}"]
+
+[[virtual_hosts]]
+hosts = ["broken.example.com"]
+upstreams = ["{}"]
+health_check_path = "/broken-health"
+"#,
+            default_upstream.address, api_upstream.address, broken_upstream.address
+        ),
+    );
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        None,
+        None,
+        &["--config", config_path.to_str().unwrap()],
+    )
+    .await;
+
+    let client = reqwest::Client::new();
+    for (host, expected_upstream) in &[
+        ("api.example.com", "api"),
+        ("API.example.org:1100", "api"),
+        ("www.example.com", "default"),
+    ] {
+        let response_text = client
+            .get(&format!("http://{}/some/path", balancebeam.address))
+            .header("host", *host)
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .expect("Error reading response from balancebeam");
+        assert_eq!(
+            &response_text, expected_upstream,
+            "Request for {} went to the wrong upstream",
+            host
+        );
+    }
+
+    assert!(
+        balancebeam.wait_for_output("GET /broken-health").await,
+        "balancebeam did not health-check the virtual host at its own path"
+    );
+    std::fs::remove_file(config_path).unwrap();
+}
+
+/// Health checks can expect other statuses than 200, look for something in the response body, and
+/// send their own Host header, and pools of upstreams in the config file can each have their own
+#[tokio::test]
+async fn test_health_check_expectations() {
+    init_logging();
+    let default_upstream = EchoServer::new().await;
+    let strict_upstream = MockServer::new(MockResponse::new(200).body("not ok")).await;
+    let teapot_upstream = MockServer::new(MockResponse::new(418).body("short and stout")).await;
+    let host_upstream = EchoServer::new().await;
+    let config_path = write_config_file(
+        "health-check-expectations",
+        &format!(
+            r#"
+upstreams = ["{}"]
+
+[health_check]
+interval = 1
+path = "/healthz"
+body_contains = "GET /healthz"
+
+[[routes]]
+prefix = "/strict"
+upstreams = ["{}"]
+health_check = {{ body_regex = "^ok$" }}
+
+[[routes]]
+prefix = "/teapot"
+upstreams = ["{}"]
+health_check = {{ expected_status = "200, 400-418", body_contains = "stout" }}
+
+[[virtual_hosts]]
+hosts = ["echo.example.com"]
+upstreams = ["{}"]
+health_check = {{ host = "health.internal", body_contains = "host: health.internal", timeout_ms = 500 }}
+"#,
+            default_upstream.address,
+            strict_upstream.address,
+            teapot_upstream.address,
+            host_upstream.address
+        ),
+    );
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        None,
+        None,
+        &["--config", config_path.to_str().unwrap()],
+    )
+    .await;
+
+    let dead_log = format!("Marked upstream {} as dead", strict_upstream.address);
+    assert!(
+        balancebeam.wait_for_output(&dead_log).await,
+        "An upstream whose body doesn't match body_regex wasn't marked dead"
+    );
+    // Let another round of checks go by, in case they were wrong about anything else
+    delay_for(Duration::from_millis(1500)).await;
+    for upstream in &[
+        &default_upstream.address,
+        &teapot_upstream.address,
+        &host_upstream.address,
+    ] {
+        assert!(
+            !balancebeam.output_contains(&format!("Marked upstream {} as dead", upstream)),
+            "Upstream {} should have passed its health check",
+            upstream
+        );
+    }
+
+    let client = reqwest::Client::new();
+    for (path, host, expected_status) in &[
+        ("/strict", "balancebeam", 502),
+        ("/teapot", "balancebeam", 418),
+        ("/", "echo.example.com", 200),
+        ("/", "balancebeam", 200),
+    ] {
+        let response = client
+            .get(&format!("http://{}{}", balancebeam.address, path))
+            .header("host", *host)
+            .send()
+            .await
+            .expect("Error sending request to balancebeam");
+        assert_eq!(
+            response.status().as_u16(),
+            *expected_status,
+            "Wrong status for {} on {}",
+            path,
+            host
+        );
+    }
+    std::fs::remove_file(config_path).unwrap();
+}
+
+/// When an upstream is marked dead (or alive again), balancebeam should log it, count it in the
+/// metrics, and POST an event about it to --health-webhook
+#[tokio::test]
+async fn test_health_webhook() {
+    init_logging();
+    let healthy_upstream = MockServer::new(MockResponse::new(200)).await;
+    let failing_upstream = MockServer::new(MockResponse::new(500)).await;
+    // Stands in for the webhook, passing on the requests it gets
+    let mut webhook = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let webhook_address = webhook.local_addr().unwrap();
+    let (events_sender, mut events) = tokio::sync::mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = webhook.accept().await {
+            let mut request = Vec::new();
+            let mut buffer = [0_u8; 1024];
+            while !request.ends_with(b"}") {
+                match stream.read(&mut buffer).await {
+                    Ok(0) | Err(_) => break,
+                    Ok(bytes_read) => request.extend_from_slice(&buffer[..bytes_read]),
+                }
+            }
+            let _ = stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n").await;
+            let _ = events_sender.send(String::from_utf8_lossy(&request).to_string());
+        }
+    });
+    let webhook_url = format!("http://{}/hooks?source=balancebeam", webhook_address);
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&healthy_upstream.address, &failing_upstream.address],
+        Some(1),
+        None,
+        &[
+            "--health-webhook",
+            &webhook_url,
+            "--metrics-bind",
+            "127.0.0.1:0",
+        ],
+    )
+    .await;
+
+    let event = tokio::time::timeout(Duration::from_secs(5), events.recv())
+        .await
+        .expect("The webhook wasn't told about the failing upstream")
+        .unwrap();
+    assert!(
+        event.starts_with("POST /hooks?source=balancebeam HTTP/1.1\r\n"),
+        "{}",
+        event
+    );
+    assert!(
+        event.contains("content-type: application/json"),
+        "{}",
+        event
+    );
+    let expected = format!(
+        "\"upstream\":\"{}\",\"state\":\"dead\"",
+        failing_upstream.address
+    );
+    assert!(event.contains(&expected), "{}", event);
+    assert!(balancebeam.output_contains(&format!(
+        "Marked upstream {} as dead",
+        failing_upstream.address
+    )));
+
+    let metrics_address = balancebeam.metrics_address().await;
+    let metrics = reqwest::get(&format!("http://{}/metrics", metrics_address))
+        .await
+        .expect("Error fetching metrics")
+        .text()
+        .await
+        .unwrap();
+    let transitions = format!(
+        "balancebeam_upstream_health_transitions_total{{upstream=\"{}\",to=\"dead\"}} 1",
+        failing_upstream.address
+    );
+    assert!(
+        metrics.lines().any(|line| line == transitions),
+        "Metrics are missing {:?}:\n{}",
+        transitions,
+        metrics
+    );
+    assert!(!metrics.contains(&healthy_upstream.address), "{}", metrics);
+}
+
+#[tokio::test]
+async fn test_header_rules() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config_path = write_config_file(
+        "header-rules",
+        &format!(
+            r#"
+upstreams = ["{}"]
+
+[headers]
+request = [
+    {{ action = "add", name = "Via", value = "1.1 balancebeam" }},
+    {{ action = "replace", name = "X-Forwarded-For", value = "hidden" }},
+    {{ action = "remove", name = "X-Sent-By" }},
+]
+response = [
+    {{ action = "remove", name = "Date" }},
+    {{ action = "add", name = "X-Served-By", value = "balancebeam" }},
+]
+"#,
+            upstream.address
+        ),
+    );
+    let balancebeam = BalanceBeam::new_with_args(
+        &[],
+        None,
+        None,
+        &["--config", config_path.to_str().unwrap()],
+    )
+    .await;
+
+    let response = balancebeam
+        .request(reqwest::Method::GET, "/headers", "")
+        .await
+        .expect("Error sending request to balancebeam");
+    response
+        .expect_status(200)
+        .expect_body_contains("via: 1.1 balancebeam\n")
+        .expect_body_contains("x-forwarded-for: hidden\n")
+        .expect_no_header("date")
+        .expect_header("x-served-by", "balancebeam");
+    assert!(
+        !response.body.contains("x-sent-by"),
+        "Removed header was forwarded to the upstream: {:?}",
+        response
+    );
+    assert!(
+        response.body.matches("x-forwarded-for").count() == 1,
+        "Replaced header kept its old value: {:?}",
+        response
+    );
+    std::fs::remove_file(config_path).unwrap();
+}
+
+/// The admin API should list upstreams, take a drained upstream out of rotation until it's
+/// re-enabled, and report rate limit usage and the running configuration
+#[tokio::test]
+async fn test_admin_api() {
+    init_logging();
+    let upstreams = vec![EchoServer::new().await, EchoServer::new().await];
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstreams[0].address, &upstreams[1].address],
+        None,
+        Some(100),
+        &["--strategy", "round-robin", "--admin-bind", "127.0.0.1:0"],
+    )
+    .await;
+    let admin_url = format!("http://{}", balancebeam.admin_address().await);
+    let client = reqwest::Client::new();
+    let admin = |method: reqwest::Method, path: &str| {
+        let request = client.request(method, &format!("{}{}", admin_url, path));
+        async move {
+            let response = request.send().await.expect("Error calling the admin API");
+            (response.status().as_u16(), response.text().await.unwrap())
+        }
+    };
+
+    let (status, listing) = admin(reqwest::Method::GET, "/upstreams").await;
+    assert_eq!(status, 200);
+    for upstream in &upstreams {
+        let line = format!(
+            "{} health=up drained=false active_connections=0",
+            upstream.address
+        );
+        assert!(
+            listing.lines().any(|listed| listed == line),
+            "Upstream listing is missing {:?}: {:?}",
+            line,
+            listing
+        );
+    }
+
+    let drain_path = format!("/upstreams/{}/drain", upstreams[0].address);
+    let (status, drained) = admin(reqwest::Method::POST, &drain_path).await;
+    assert_eq!(status, 200);
+    assert!(
+        drained.contains("drained=true"),
+        "Unexpected response: {:?}",
+        drained
+    );
+    for i in 0..4 {
+        balancebeam
+            .get(&format!("/drained-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let enable_path = format!("/upstreams/{}/enable", upstreams[0].address);
+    let (status, enabled) = admin(reqwest::Method::POST, &enable_path).await;
+    assert_eq!(status, 200);
+    assert!(
+        enabled.contains("drained=false"),
+        "Unexpected response: {:?}",
+        enabled
+    );
+    for i in 0..4 {
+        balancebeam
+            .get(&format!("/enabled-{}", i))
+            .await
+            .expect("Error sending request to balancebeam");
+    }
+
+    let (status, _) = admin(reqwest::Method::POST, "/upstreams/127.0.0.1:1/drain").await;
+    assert_eq!(status, 404);
+
+    let (status, rate_limits) = admin(reqwest::Method::GET, "/rate-limits").await;
+    assert_eq!(status, 200);
+    // 8 requests were made, but the client's tokens start coming back straight away
+    let used: usize = rate_limits
+        .strip_prefix("requests 127.0.0.1 ")
+        .and_then(|rest| rest.strip_suffix("/100\n"))
+        .and_then(|used| used.parse().ok())
+        .unwrap_or_else(|| panic!("Unexpected rate limit usage: {:?}", rate_limits));
+    assert!(
+        (6..=8).contains(&used),
+        "Unexpected rate limit usage: {:?}",
+        rate_limits
+    );
+
+    let (status, config) = admin(reqwest::Method::GET, "/config").await;
+    assert_eq!(status, 200);
+    assert!(
+        config.contains("max_requests_per_minute: 100"),
+        "Config dump is missing the rate limit: {:?}",
+        config
+    );
+
+    // While drained, the first upstream got nothing; afterwards, the two took turns again
+    let mut counts = Vec::new();
+    for upstream in upstreams {
+        counts.push(Box::new(upstream).stop().await);
+    }
+    assert_eq!(counts, vec![2, 6]);
+}
+
+/// A client whose connection is already kept open to an upstream should have its next request go
+/// elsewhere once that upstream is drained, and the metrics should say which upstreams are drained
+#[tokio::test]
+async fn test_drain_kept_alive_connection() {
+    init_logging();
+    let upstreams = [
+        MockServer::new(MockResponse::new(200).body("first")).await,
+        MockServer::new(MockResponse::new(200).body("second")).await,
+    ];
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstreams[0].address, &upstreams[1].address],
+        None,
+        None,
+        &[
+            "--strategy",
+            "round-robin",
+            "--admin-bind",
+            "127.0.0.1:0",
+            "--metrics-bind",
+            "127.0.0.1:0",
+        ],
+    )
+    .await;
+    let admin_url = format!("http://{}", balancebeam.admin_address().await);
+    let client = reqwest::Client::new();
+    let get = || async {
+        client
+            .get(&format!("http://{}/kept-alive", balancebeam.address))
+            .send()
+            .await
+            .expect("Error sending request to balancebeam")
+            .text()
+            .await
+            .unwrap()
+    };
+
+    let first = get().await;
+    assert_eq!(
+        get().await,
+        first,
+        "The kept-alive connection changed upstreams"
+    );
+    let (drained, other) = if first == "first" {
+        (&upstreams[0], "second")
+    } else {
+        (&upstreams[1], "first")
+    };
+    let response = client
+        .post(&format!(
+            "{}/upstreams/{}/drain",
+            admin_url, drained.address
+        ))
+        .send()
+        .await
+        .expect("Error calling the admin API");
+    assert_eq!(response.status().as_u16(), 200);
+    for _ in 0..3 {
+        assert_eq!(get().await, other);
+    }
+
+    let metrics_address = balancebeam.metrics_address().await;
+    let metrics = reqwest::get(&format!("http://{}/metrics", metrics_address))
+        .await
+        .expect("Error fetching metrics")
+        .text()
+        .await
+        .unwrap();
+    let line = format!(
+        "balancebeam_upstream_drained{{upstream=\"{}\"}} 1",
+        drained.address
+    );
+    assert!(
+        metrics.lines().any(|metric| metric == line),
+        "Metrics are missing {:?}:\n{}",
+        line,
+        metrics
+    );
+    assert_eq!(drained.requests_received(), 2);
+}
+
+/// Requests for paths under an `[[auth]]` prefix should get a 401 with WWW-Authenticate challenges
+/// unless they carry one of its credentials, which aren't passed on to the upstream
+#[tokio::test]
+async fn test_auth_gate() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config_path = write_config_file(
+        "auth",
+        "[[auth]]\n\
+         prefix = \"/private/\"\n\
+         realm = \"tests\"\n\
+         basic = [\"alice:open sesame\"]\n\
+         bearer_tokens = [\"let-me-in\"]\n",
+    );
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstream.address],
+        None,
+        None,
+        &["--config", config_path.to_str().unwrap()],
+    )
+    .await;
+    let client = reqwest::Client::new();
+    let url = |path: &str| format!("http://{}{}", balancebeam.address, path);
+
+    let response = client.get(&url("/public")).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+
+    let response = client.get(&url("/private/page")).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+    let challenges: Vec<&str> = response
+        .headers()
+        .get_all("www-authenticate")
+        .iter()
+        .map(|value| value.to_str().unwrap())
+        .collect();
+    assert_eq!(
+        challenges,
+        vec!["Basic realm=\"tests\"", "Bearer realm=\"tests\""]
+    );
+
+    let response = client
+        .get(&url("/private"))
+        .basic_auth("alice", Some("wrong"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 401);
+
+    for request in [
+        client
+            .get(&url("/private/page"))
+            .basic_auth("alice", Some("open sesame")),
+        client.get(&url("/private/page")).bearer_auth("let-me-in"),
+    ] {
+        let response = request.send().await.unwrap();
+        assert_eq!(response.status().as_u16(), 200);
+        let echoed = response.text().await.unwrap().to_lowercase();
+        assert!(
+            !echoed.contains("authorization"),
+            "Credentials were passed on to the upstream: {}",
+            echoed
+        );
+    }
+    std::fs::remove_file(config_path).unwrap();
+    assert_eq!(Box::new(upstream).stop().await, 3);
+}
+
+/// Sends a request as it is (reqwest would clean up the paths these tests need) and returns the
+/// status line of the response
+async fn send_raw_request(balancebeam: &BalanceBeam, raw_request: &[u8]) -> String {
+    let mut stream = tokio::net::TcpStream::connect(&balancebeam.address)
+        .await
+        .expect("Could not connect to balancebeam");
+    stream.write_all(raw_request).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response.lines().next().unwrap_or_default().to_string()
+}
+
+/// Paths that the upstream would take to be under an `[[auth]]` prefix should need its credentials
+/// too, whether or not --normalize-paths is on, and paths that can't be normalized should be
+/// refused
+#[tokio::test]
+async fn test_auth_gate_path_variants() {
+    init_logging();
+    let upstream = EchoServer::new().await;
+    let config_path = write_config_file(
+        "auth-paths",
+        "[[auth]]\n\
+         prefix = \"/private\"\n\
+         bearer_tokens = [\"let-me-in\"]\n",
+    );
+    for args in &[&[][..], &["--normalize-paths"][..]] {
+        let mut args = args.to_vec();
+        args.extend(&["--config", config_path.to_str().unwrap()]);
+        let balancebeam = BalanceBeam::new_with_args(&[&upstream.address], None, None, &args).await;
+        for (path, status) in &[
+            ("//private/page", "401"),
+            ("/public/../private/page", "401"),
+            ("/%70rivate/page", "401"),
+            ("/public/%2e%2e/private/page", "400"),
+            ("/public%2f..%2fprivate/page", "400"),
+        ] {
+            let request = format!(
+                "GET {} HTTP/1.1\r\nHost: test\r\nConnection: close\r\n\r\n",
+                path
+            );
+            let status_line = send_raw_request(&balancebeam, request.as_bytes()).await;
+            assert!(
+                status_line.starts_with(&format!("HTTP/1.1 {}", status)),
+                "{} with {:?} got {:?}",
+                path,
+                args,
+                status_line
+            );
+        }
+    }
+    std::fs::remove_file(config_path).unwrap();
+    assert_eq!(Box::new(upstream).stop().await, 0);
+}
+
+/// With --sticky-sessions, a client's requests should keep going to the upstream named in the
+/// cookie it was given, until that upstream goes away and it's given a new one
+#[tokio::test]
+async fn test_sticky_sessions() {
+    init_logging();
+    let mut upstreams = vec![
+        MockServer::new(MockResponse::new(200).body("first")).await,
+        MockServer::new(MockResponse::new(200).body("second")).await,
+    ];
+    let balancebeam = BalanceBeam::new_with_args(
+        &[&upstreams[0].address, &upstreams[1].address],
+        None,
+        None,
+        &["--strategy", "round-robin", "--sticky-sessions"],
+    )
+    .await;
+    // (A new client each time, so requests don't share a connection)
+    let get = |cookie: Option<String>| {
+        let url = format!("http://{}/sticky", balancebeam.address);
+        async move {
+            let mut request = reqwest::Client::new().get(&url);
+            if let Some(cookie) = cookie {
+                request = request.header("cookie", format!("theme=dark; {}", cookie));
+            }
+            let response = request.send().await.expect("Error sending request");
+            let cookie = response.headers().get("set-cookie").map(|value| {
+                let value = value.to_str().unwrap();
+                value.split(';').next().unwrap().to_string()
+            });
+            (response.text().await.unwrap(), cookie)
+        }
+    };
+
+    let (first_body, cookie) = get(None).await;
+    let cookie = cookie.expect("No sticky cookie was set");
+    assert!(cookie.starts_with("BALANCEBEAM_STICKY="), "{}", cookie);
+    for _ in 0..4 {
+        let (body, new_cookie) = get(Some(cookie.clone())).await;
+        assert_eq!(body, first_body, "Request didn't stick to its upstream");
+        assert_eq!(new_cookie, None, "Cookie was set again needlessly");
+    }
+
+    let sticky_index = if first_body == "first" { 0 } else { 1 };
+    let sticky_upstream = upstreams.remove(sticky_index);
+    assert_eq!(Box::new(sticky_upstream).stop().await, 5);
+    let (body, new_cookie) = get(Some(cookie.clone())).await;
+    assert_ne!(body, first_body);
+    let new_cookie = new_cookie.expect("The cookie wasn't updated");
+    assert_ne!(new_cookie, cookie);
+    let (body_again, _) = get(Some(new_cookie)).await;
+    assert_eq!(body_again, body);
+}
+
+/// An X-Balancebeam-Upstream header from a client in --upstream-override-from should send the
+/// request to the upstream it names, even a drained one; from anyone else, it should be ignored.
+/// Either way, it shouldn't reach the upstream
+#[tokio::test]
+async fn test_upstream_override() {
+    init_logging();
+    let upstreams = [EchoServer::new().await, EchoServer::new().await];
+    let addresses = [upstreams[0].address.as_str(), upstreams[1].address.as_str()];
+    let trusting = BalanceBeam::new_with_args(
+        &addresses,
+        None,
+        None,
+        &[
+            "--strategy",
+            "round-robin",
+            "--upstream-override-from",
+            "127.0.0.0/8",
+            "--admin-bind",
+            "127.0.0.1:0",
+        ],
+    )
+    .await;
+    let untrusting = BalanceBeam::new_with_args(
+        &addresses,
+        None,
+        None,
+        &[
+            "--strategy",
+            "round-robin",
+            "--upstream-override-from",
+            "10.0.0.0/8",
+        ],
+    )
+    .await;
+    // (A new client each time, so requests don't share a connection)
+    let get = |balancebeam: &BalanceBeam, upstream: &str| {
+        let request = reqwest::Client::new()
+            .get(&format!("http://{}/override", balancebeam.address))
+            .header("x-balancebeam-upstream", upstream);
+        async move {
+            let response = request.send().await.expect("Error sending request");
+            (response.status().as_u16(), response.text().await.unwrap())
+        }
+    };
+
+    let drain_url = format!(
+        "http://{}/upstreams/{}/drain",
+        trusting.admin_address().await,
+        upstreams[1].address
+    );
+    let response = reqwest::Client::new()
+        .post(&drain_url)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status().as_u16(), 200);
+    for balancebeam in &[&trusting, &untrusting] {
+        for _ in 0..4 {
+            let (status, body) = get(balancebeam, &upstreams[1].address).await;
+            assert_eq!(status, 200);
+            assert!(
+                !body.contains("x-balancebeam-upstream"),
+                "The override header was forwarded: {:?}",
+                body
+            );
+        }
+    }
+    let (status, _) = get(&trusting, "127.0.0.1:1").await;
+    assert_eq!(status, 400, "An unknown upstream should be refused");
+
+    // The trusting balancebeam sent all of its requests to the drained upstream; the other one
+    // took turns
+    let [first, second] = upstreams;
+    assert_eq!(Box::new(first).stop().await, 2);
+    assert_eq!(Box::new(second).stop().await, 6);
+}
@@ -1,5 +1,6 @@
-use rand::Rng;
-use std::time::Duration;
+use crate::common::test_response::TestResponse;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::process::{Child, Command};
 use tokio::time::delay_for;
@@ -8,10 +9,11 @@ pub struct BalanceBeam {
     #[allow(dead_code)]
     child: Child, // process is killed when dropped (Command::kill_on_drop)
     pub address: String,
+    output: Arc<Mutex<Vec<String>>>,
 }
 
 impl BalanceBeam {
-    fn target_bin_path() -> std::path::PathBuf {
+    pub fn target_bin_path() -> std::path::PathBuf {
         let mut path = std::env::current_exe().expect("Could not get current test executable path");
         path.pop();
         path.pop();
@@ -24,10 +26,28 @@ impl BalanceBeam {
         active_health_check_interval: Option<usize>,
         max_requests_per_minute: Option<usize>,
     ) -> BalanceBeam {
-        let mut rng = rand::thread_rng();
-        let address = format!("127.0.0.1:{}", rng.gen_range(1024, 65535));
+        BalanceBeam::new_with_args(
+            upstreams,
+            active_health_check_interval,
+            max_requests_per_minute,
+            &[],
+        )
+        .await
+    }
+
+    /// Like `new`, but passes `extra_args` through to the balancebeam command line as-is.
+    pub async fn new_with_args(
+        upstreams: &[&str],
+        active_health_check_interval: Option<usize>,
+        max_requests_per_minute: Option<usize>,
+        extra_args: &[&str],
+    ) -> BalanceBeam {
+        // Bind to an ephemeral port (unless the test picks its own address); the real address is
+        // read back from balancebeam's logs below
         let mut cmd = Command::new(BalanceBeam::target_bin_path());
-        cmd.arg("--bind").arg(&address);
+        if !extra_args.contains(&"--bind") {
+            cmd.arg("--bind").arg("127.0.0.1:0");
+        }
         for upstream in upstreams {
             cmd.arg("--upstream").arg(upstream);
         }
@@ -39,6 +59,7 @@ impl BalanceBeam {
             cmd.arg("--max-requests-per-minute")
                 .arg(max_requests_per_minute.to_string());
         }
+        cmd.args(extra_args);
         cmd.kill_on_drop(true);
         cmd.stdout(std::process::Stdio::piped());
         cmd.stderr(std::process::Stdio::piped());
@@ -49,7 +70,10 @@ impl BalanceBeam {
 
         // Print output from the child. We want to intercept and log this output (instead of letting
         // the child inherit stderr and print directly to the terminal) so that the output can be
-        // suppressed if the test passes and displayed if it fails.
+        // suppressed if the test passes and displayed if it fails. Each line is also saved so that
+        // tests can make assertions about what balancebeam logged.
+        let output = Arc::new(Mutex::new(Vec::new()));
+        let stdout_output = output.clone();
         let stdout = child
             .stdout
             .take()
@@ -62,8 +86,10 @@ impl BalanceBeam {
                 .expect("I/O error reading from child stdout")
             {
                 println!("Balancebeam output: {}", line);
+                stdout_output.lock().unwrap().push(line);
             }
         });
+        let stderr_output = output.clone();
         let stderr = child
             .stderr
             .take()
@@ -76,12 +102,126 @@ impl BalanceBeam {
                 .expect("I/O error reading from child stderr")
             {
                 println!("Balancebeam output: {}", line);
+                stderr_output.lock().unwrap().push(line);
             }
         });
 
-        // Hack: wait for executable to start running
-        delay_for(Duration::from_secs(1)).await;
-        BalanceBeam { child, address }
+        // Wait for balancebeam to report the port it bound to, which also tells us it's ready
+        let address =
+            BalanceBeam::wait_for_logged_address(&output, "Listening for requests on ").await;
+        BalanceBeam {
+            child,
+            address,
+            output,
+        }
+    }
+
+    /// Waits for balancebeam to log a line containing `prefix`, and returns whatever follows it
+    /// (e.g. the address it bound to).
+    async fn wait_for_logged_address(output: &Mutex<Vec<String>>, prefix: &str) -> String {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let address = output.lock().unwrap().iter().find_map(|line| {
+                line.find(prefix)
+                    .map(|start| line[start + prefix.len()..].trim().to_string())
+            });
+            if let Some(address) = address {
+                return address;
+            }
+            if Instant::now() > deadline {
+                panic!("balancebeam did not log {:?} within 5 seconds", prefix);
+            }
+            delay_for(Duration::from_millis(20)).await;
+        }
+    }
+
+    /// Returns the address of balancebeam's status endpoint (only available when it was started
+    /// with `--status-bind`).
+    #[allow(dead_code)]
+    pub async fn status_address(&self) -> String {
+        BalanceBeam::wait_for_logged_address(&self.output, "Serving status on ").await
+    }
+
+    /// Returns the address of balancebeam's metrics endpoint (only available when it was started
+    /// with `--metrics-bind`).
+    #[allow(dead_code)]
+    pub async fn metrics_address(&self) -> String {
+        BalanceBeam::wait_for_logged_address(&self.output, "Serving metrics on ").await
+    }
+
+    /// Waits for balancebeam to report binding `count` addresses (when it was given several with
+    /// `--bind`), and returns them in the order they were given.
+    #[allow(dead_code)]
+    pub async fn listen_addresses(&self, count: usize) -> Vec<String> {
+        let prefix = "Listening for requests on ";
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let addresses: Vec<String> = self
+                .output
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(|line| {
+                    line.find(prefix)
+                        .map(|start| line[start + prefix.len()..].trim().to_string())
+                })
+                .collect();
+            if addresses.len() >= count || Instant::now() > deadline {
+                return addresses;
+            }
+            delay_for(Duration::from_millis(20)).await;
+        }
+    }
+
+    /// Returns the address of balancebeam's admin API (only available when it was started with
+    /// `--admin-bind`).
+    #[allow(dead_code)]
+    pub async fn admin_address(&self) -> String {
+        BalanceBeam::wait_for_logged_address(&self.output, "Serving admin API on ").await
+    }
+
+    #[allow(dead_code)]
+    pub fn send_signal(&self, signal: nix::sys::signal::Signal) {
+        let pid = nix::unistd::Pid::from_raw(self.child.id() as i32);
+        nix::sys::signal::kill(pid, signal)
+            .unwrap_or_else(|err| panic!("Could not send {} to balancebeam: {}", signal, err));
+    }
+
+    /// Waits up to 5 seconds for balancebeam to print a line containing `needle`, returning
+    /// whether it did.
+    #[allow(dead_code)]
+    pub async fn wait_for_output(&self, needle: &str) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if self.output_contains(needle) {
+                return true;
+            }
+            delay_for(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    /// Sends balancebeam SIGTERM and waits for it to exit, giving its last log lines a moment to
+    /// make their way through the output pipes.
+    #[allow(dead_code)]
+    pub async fn shutdown(&mut self) -> std::process::ExitStatus {
+        self.send_signal(nix::sys::signal::Signal::SIGTERM);
+        let status = tokio::time::timeout(Duration::from_secs(5), &mut self.child)
+            .await
+            .expect("balancebeam did not exit within 5 seconds of SIGTERM")
+            .expect("Error waiting for balancebeam to exit");
+        delay_for(Duration::from_millis(100)).await;
+        status
+    }
+
+    /// Returns true if any line that balancebeam has printed so far contains `needle`.
+    #[allow(dead_code)]
+    pub fn output_contains(&self, needle: &str) -> bool {
+        self.output
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|line| line.contains(needle))
     }
 
     #[allow(dead_code)]
@@ -108,4 +248,23 @@ impl BalanceBeam {
             .text()
             .await
     }
+
+    /// Sends a request through balancebeam and returns the full response (status, headers, and
+    /// body) so that tests can make assertions about it.
+    #[allow(dead_code)]
+    pub async fn request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: &str,
+    ) -> Result<TestResponse, reqwest::Error> {
+        let client = reqwest::Client::new();
+        let response = client
+            .request(method, &format!("http://{}{}", self.address, path))
+            .header("x-sent-by", "balancebeam-tests")
+            .body(body.to_string())
+            .send()
+            .await?;
+        TestResponse::from_reqwest(response).await
+    }
 }
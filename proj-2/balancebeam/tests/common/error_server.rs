@@ -2,7 +2,6 @@ use crate::common::server::Server;
 use async_trait::async_trait;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Response};
-use rand::Rng;
 use std::sync::{atomic, Arc};
 use tokio::sync::oneshot;
 
@@ -29,8 +28,8 @@ pub struct ErrorServer {
 impl ErrorServer {
     #[allow(dead_code)]
     pub async fn new() -> ErrorServer {
-        let mut rng = rand::thread_rng();
-        ErrorServer::new_at_address(format!("127.0.0.1:{}", rng.gen_range(1024, 65535))).await
+        // Bind to an ephemeral port, so that concurrently running tests can't collide
+        ErrorServer::new_at_address("127.0.0.1:0".to_string()).await
     }
 
     #[allow(dead_code)]
@@ -44,23 +43,23 @@ impl ErrorServer {
             requests_received: atomic::AtomicUsize::new(0),
         });
         let server_task_state = server_state.clone();
+        let service = make_service_fn(move |_| {
+            let server_task_state = server_task_state.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |_req| {
+                    server_task_state
+                        .requests_received
+                        .fetch_add(1, atomic::Ordering::SeqCst);
+                    return_error()
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&bind_addr).serve(service);
+        let address = server.local_addr().to_string();
         let server_task = tokio::spawn(async move {
-            let service = make_service_fn(|_| {
-                let server_task_state = server_task_state.clone();
-                async move {
-                    Ok::<_, hyper::Error>(service_fn(move |_req| {
-                        server_task_state
-                            .requests_received
-                            .fetch_add(1, atomic::Ordering::SeqCst);
-                        return_error()
-                    }))
-                }
+            let server = server.with_graceful_shutdown(async {
+                shutdown_rx.await.ok();
             });
-            let server = hyper::Server::bind(&bind_addr)
-                .serve(service)
-                .with_graceful_shutdown(async {
-                    shutdown_rx.await.ok();
-                });
             // Start serving and wait for the server to exit
             if let Err(e) = server.await {
                 log::error!("Error in ErrorServer: {}", e);
@@ -71,7 +70,7 @@ impl ErrorServer {
             shutdown_signal_sender: shutdown_tx,
             server_task,
             state: server_state,
-            address: bind_addr_string,
+            address,
         }
     }
 }
@@ -0,0 +1,185 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Response};
+use std::net::SocketAddr;
+use std::sync::{atomic, Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::time::delay_for;
+
+/// The response a `MockServer` sends back for every request it receives.
+#[derive(Clone, Debug)]
+pub struct MockResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: String,
+    delay: Duration,
+    /// Hang up on every this many requests (0 = never)
+    fail_every: usize,
+}
+
+#[allow(dead_code)]
+impl MockResponse {
+    pub fn new(status: u16) -> MockResponse {
+        MockResponse {
+            status,
+            headers: Vec::new(),
+            body: String::new(),
+            delay: Duration::from_secs(0),
+            fail_every: 0,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> MockResponse {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: &str) -> MockResponse {
+        self.body = body.to_string();
+        self
+    }
+
+    /// Waits this long before responding, to simulate a slow upstream.
+    pub fn delay(mut self, delay: Duration) -> MockResponse {
+        self.delay = delay;
+        self
+    }
+
+    /// Hangs up without responding to every `n`th request, starting with the first, to simulate a
+    /// flaky upstream.
+    pub fn fail_every(mut self, n: usize) -> MockResponse {
+        self.fail_every = n;
+        self
+    }
+
+    fn to_hyper(&self) -> Response<Body> {
+        let mut builder = Response::builder().status(self.status);
+        for (name, value) in &self.headers {
+            builder = builder.header(name.as_str(), value.as_str());
+        }
+        builder.body(Body::from(self.body.clone())).unwrap()
+    }
+}
+
+#[derive(Debug)]
+struct ServerState {
+    pub requests_received: atomic::AtomicUsize,
+    /// Remote address of each connection accepted, in order
+    pub peer_addresses: Mutex<Vec<SocketAddr>>,
+}
+
+async fn respond(
+    server_state: Arc<ServerState>,
+    response: Arc<MockResponse>,
+) -> Result<Response<Body>, Box<dyn std::error::Error + Send + Sync>> {
+    let received = server_state
+        .requests_received
+        .fetch_add(1, atomic::Ordering::SeqCst);
+    // (hyper closes the connection without responding when the service fails)
+    if response.fail_every > 0 && received.is_multiple_of(response.fail_every) {
+        return Err("failing on purpose".into());
+    }
+    if response.delay > Duration::from_secs(0) {
+        delay_for(response.delay).await;
+    }
+    Ok(response.to_hyper())
+}
+
+/// An upstream server that answers every request with a fixed `MockResponse`. It listens on an
+/// ephemeral port, and shuts itself down when dropped if `stop` was never called.
+pub struct MockServer {
+    shutdown_signal_sender: Option<oneshot::Sender<()>>,
+    server_task: Option<tokio::task::JoinHandle<()>>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+#[allow(dead_code)]
+impl MockServer {
+    pub async fn new(response: MockResponse) -> MockServer {
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, shutdown_rx) = oneshot::channel::<()>();
+
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+            peer_addresses: Mutex::new(Vec::new()),
+        });
+        let server_task_state = server_state.clone();
+        let response = Arc::new(response);
+        let service = make_service_fn(move |conn: &AddrStream| {
+            let server_task_state = server_task_state.clone();
+            server_task_state
+                .peer_addresses
+                .lock()
+                .unwrap()
+                .push(conn.remote_addr());
+            let response = response.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |_req| {
+                    respond(server_task_state.clone(), response.clone())
+                }))
+            }
+        });
+        // Bind before spawning the server task so that the chosen port is known up front
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(service);
+        let address = server.local_addr().to_string();
+        let server = server.with_graceful_shutdown(async {
+            shutdown_rx.await.ok();
+        });
+        let server_task = tokio::spawn(async move {
+            // Start serving and wait for the server to exit
+            if let Err(e) = server.await {
+                log::error!("Error in MockServer: {}", e);
+            }
+        });
+
+        MockServer {
+            shutdown_signal_sender: Some(shutdown_tx),
+            server_task: Some(server_task),
+            state: server_state,
+            address,
+        }
+    }
+
+    pub fn requests_received(&self) -> usize {
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    /// Where each connection to this server came from
+    pub fn peer_addresses(&self) -> Vec<SocketAddr> {
+        self.state.peer_addresses.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Server for MockServer {
+    async fn stop(mut self: Box<Self>) -> usize {
+        // Tell the hyper server to stop
+        if let Some(sender) = self.shutdown_signal_sender.take() {
+            let _ = sender.send(());
+        }
+        // Wait for it to stop
+        if let Some(server_task) = self.server_task.take() {
+            server_task.await.expect("MockServer server task panicked");
+        }
+
+        self.requests_received()
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        // We can't wait for the task from here, but signalling it is enough for hyper to close the
+        // listener and wind down its connections
+        if let Some(sender) = self.shutdown_signal_sender.take() {
+            let _ = sender.send(());
+        }
+    }
+}
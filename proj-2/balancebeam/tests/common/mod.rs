@@ -1,14 +1,23 @@
 mod balancebeam;
 mod echo_server;
 mod error_server;
+mod mock_server;
+mod raw_server;
 mod server;
+mod test_response;
 
 use std::sync;
 
 pub use balancebeam::BalanceBeam;
 pub use echo_server::EchoServer;
 pub use error_server::ErrorServer;
+#[allow(unused_imports)]
+pub use mock_server::{MockResponse, MockServer};
+#[allow(unused_imports)]
+pub use raw_server::RawServer;
 pub use server::Server;
+#[allow(unused_imports)]
+pub use test_response::TestResponse;
 
 static INIT_TESTS: sync::Once = sync::Once::new();
 
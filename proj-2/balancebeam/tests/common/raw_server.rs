@@ -0,0 +1,147 @@
+use crate::common::server::Server;
+use async_trait::async_trait;
+use std::sync::{atomic, Arc};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+
+#[derive(Debug)]
+struct ServerState {
+    pub requests_received: atomic::AtomicUsize,
+    pub response: Vec<u8>,
+    pub delay: Duration,
+    /// Keep the connection open (without sending anything more) after writing the response, until
+    /// the client hangs up
+    pub stall: bool,
+}
+
+/// Reads a request off the connection, waits for the configured delay, writes back the configured
+/// bytes verbatim, and then hangs up (or, if configured to stall, waits for the client to hang up
+/// first). Unlike the hyper-based servers, this makes no attempt to send
+/// a well-formed response.
+async fn reply_and_close(server_state: Arc<ServerState>, mut stream: TcpStream) {
+    let mut request_buffer = Vec::new();
+    let mut buffer = [0_u8; 512];
+    while !request_buffer
+        .windows(4)
+        .any(|window| window == b"\r\n\r\n")
+    {
+        match stream.read(&mut buffer).await {
+            Ok(0) | Err(_) => return,
+            Ok(bytes_read) => request_buffer.extend_from_slice(&buffer[..bytes_read]),
+        }
+    }
+    server_state
+        .requests_received
+        .fetch_add(1, atomic::Ordering::SeqCst);
+    tokio::time::delay_for(server_state.delay).await;
+    let _ = stream.write_all(&server_state.response).await;
+    if server_state.stall {
+        while let Ok(bytes_read) = stream.read(&mut buffer).await {
+            if bytes_read == 0 {
+                break;
+            }
+        }
+    }
+}
+
+pub struct RawServer {
+    shutdown_signal_sender: oneshot::Sender<()>,
+    server_task: tokio::task::JoinHandle<()>,
+    pub address: String,
+    state: Arc<ServerState>,
+}
+
+impl RawServer {
+    #[allow(dead_code)]
+    pub async fn new(response: &[u8]) -> RawServer {
+        RawServer::new_delayed(response, Duration::from_secs(0)).await
+    }
+
+    /// Creates a server that waits `delay` after reading each request before responding
+    #[allow(dead_code)]
+    pub async fn new_delayed(response: &[u8], delay: Duration) -> RawServer {
+        RawServer::new_at_address("127.0.0.1:0".to_string(), response, delay).await
+    }
+
+    /// Creates a server that sends `response` and then goes quiet without closing the connection,
+    /// like an upstream that has stalled partway through a response
+    #[allow(dead_code)]
+    pub async fn new_stalled(response: &[u8]) -> RawServer {
+        RawServer::start(
+            "127.0.0.1:0".to_string(),
+            response,
+            Duration::from_secs(0),
+            true,
+        )
+        .await
+    }
+
+    #[allow(dead_code)]
+    pub async fn new_at_address(
+        bind_addr_string: String,
+        response: &[u8],
+        delay: Duration,
+    ) -> RawServer {
+        RawServer::start(bind_addr_string, response, delay, false).await
+    }
+
+    async fn start(
+        bind_addr_string: String,
+        response: &[u8],
+        delay: Duration,
+        stall: bool,
+    ) -> RawServer {
+        let mut listener = TcpListener::bind(&bind_addr_string).await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        // Create a one-shot channel that can be used to tell the server to shut down
+        let (shutdown_tx, mut shutdown_rx) = oneshot::channel::<()>();
+
+        // Start a separate server task
+        let server_state = Arc::new(ServerState {
+            requests_received: atomic::AtomicUsize::new(0),
+            response: response.to_vec(),
+            delay,
+            stall,
+        });
+        let server_task_state = server_state.clone();
+        let server_task = tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = &mut shutdown_rx => break,
+                    accepted = listener.accept() => {
+                        if let Ok((stream, _)) = accepted {
+                            tokio::spawn(reply_and_close(server_task_state.clone(), stream));
+                        }
+                    }
+                }
+            }
+        });
+
+        RawServer {
+            shutdown_signal_sender: shutdown_tx,
+            server_task,
+            state: server_state,
+            address,
+        }
+    }
+}
+
+#[async_trait]
+impl Server for RawServer {
+    async fn stop(self: Box<Self>) -> usize {
+        // Tell the accept loop to stop
+        let _ = self.shutdown_signal_sender.send(());
+        // Wait for it to stop
+        self.server_task
+            .await
+            .expect("RawServer server task panicked");
+
+        self.state.requests_received.load(atomic::Ordering::SeqCst)
+    }
+
+    fn address(&self) -> String {
+        self.address.clone()
+    }
+}
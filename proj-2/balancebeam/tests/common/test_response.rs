@@ -0,0 +1,75 @@
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+
+/// A fully-read response from balancebeam. The `expect_*` methods panic with a descriptive message
+/// if the response doesn't match, and return `&Self` so that they can be chained.
+#[derive(Debug)]
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: String,
+}
+
+#[allow(dead_code)]
+impl TestResponse {
+    pub async fn from_reqwest(response: reqwest::Response) -> Result<TestResponse, reqwest::Error> {
+        let status = response.status();
+        let headers = response.headers().clone();
+        let body = response.text().await?;
+        Ok(TestResponse {
+            status,
+            headers,
+            body,
+        })
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    pub fn expect_status(&self, status: u16) -> &Self {
+        assert_eq!(
+            self.status.as_u16(),
+            status,
+            "Unexpected status code in response: {:?}",
+            self
+        );
+        self
+    }
+
+    pub fn expect_header(&self, name: &str, value: &str) -> &Self {
+        assert_eq!(
+            self.header(name),
+            Some(value),
+            "Unexpected value for header {} in response: {:?}",
+            name,
+            self
+        );
+        self
+    }
+
+    pub fn expect_no_header(&self, name: &str) -> &Self {
+        assert!(
+            self.headers.get(name).is_none(),
+            "Expected no {} header in response: {:?}",
+            name,
+            self
+        );
+        self
+    }
+
+    pub fn expect_body(&self, body: &str) -> &Self {
+        assert_eq!(self.body, body, "Unexpected body in response: {:?}", self);
+        self
+    }
+
+    pub fn expect_body_contains(&self, needle: &str) -> &Self {
+        assert!(
+            self.body.contains(needle),
+            "Expected response body to contain {:?}: {:?}",
+            needle,
+            self
+        );
+        self
+    }
+}
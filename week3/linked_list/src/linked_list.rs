@@ -86,34 +86,327 @@ impl<T> LinkedList<T> {
         self.size -= 1;
         Some(node.value)
     }
+
+    /// Moves the first `n` elements (modulo the size) to the back of the list, keeping their order.
+    /// Nodes are re-linked rather than cloned, so this is a single O(n) walk.
+    pub fn rotate_left(&mut self, n: usize) {
+        if self.size == 0 {
+            return;
+        }
+        let n = n % self.size;
+        if n == 0 {
+            return;
+        }
+        // Cut the list after the n-th node...
+        let mut front = self.head.take();
+        let mut cursor = front.as_mut();
+        for _ in 1..n {
+            cursor = cursor.and_then(|node| node.next.as_mut());
+        }
+        self.head = cursor.expect("n is less than size").next.take();
+        // ...and hang the front segment off the end of what's left
+        *self.last_link() = front;
+    }
+
+    /// Consumes the list, keeping only the first `n` elements. If the list has `n` elements or
+    /// fewer, it is returned unchanged.
+    pub fn take(mut self, n: usize) -> LinkedList<T> {
+        if n >= self.size {
+            return self;
+        }
+        if n == 0 {
+            return LinkedList::new();
+        }
+        let mut cursor = self.head.as_mut();
+        for _ in 1..n {
+            cursor = cursor.and_then(|node| node.next.as_mut());
+        }
+        // Wrap the cut-off nodes in a list of their own so they are freed by its (iterative) Drop
+        let remainder = LinkedList {
+            head: cursor.expect("n is less than size").next.take(),
+            size: self.size - n,
+        };
+        drop(remainder);
+        self.size = n;
+        self
+    }
+
+    /// Consumes the list, dropping the first `n` elements. If the list has `n` elements or fewer,
+    /// the result is empty.
+    pub fn skip(mut self, n: usize) -> LinkedList<T> {
+        for _ in 0..n {
+            if self.pop_front().is_none() {
+                break;
+            }
+        }
+        self
+    }
+
+    /// Consumes the list, splitting it into the elements that satisfy `pred` and those that don't.
+    /// Both lists keep the original relative order, and nodes are moved rather than cloned.
+    pub fn partition<F: FnMut(&T) -> bool>(mut self, mut pred: F) -> (LinkedList<T>, LinkedList<T>) {
+        let mut matching = LinkedList::new();
+        let mut rest = LinkedList::new();
+        let mut matching_tail = &mut matching.head;
+        let mut rest_tail = &mut rest.head;
+        let mut matching_size = 0;
+        let mut current = self.head.take();
+        while let Some(mut node) = current {
+            current = node.next.take();
+            if pred(&node.value) {
+                matching_tail = &mut matching_tail.insert(node).next;
+                matching_size += 1;
+            } else {
+                rest_tail = &mut rest_tail.insert(node).next;
+            }
+        }
+        matching.size = matching_size;
+        rest.size = self.size - matching_size;
+        (matching, rest)
+    }
+
+    /// Swaps the values at indices `i` and `j`. The nodes themselves stay where they are; only the
+    /// values move. This is O(max(i, j)) since both nodes have to be walked to.
+    ///
+    /// Panics if either index is out of bounds.
+    pub fn swap(&mut self, i: usize, j: usize) {
+        let size = self.size;
+        assert!(i < size && j < size, "swap indices ({}, {}) out of bounds for size {}", i, j, size);
+        if i == j {
+            return;
+        }
+        let (lo, hi) = if i < j { (i, j) } else { (j, i) };
+        let Node { value: lo_value, next } = &mut **self.node_at_mut(lo).unwrap();
+        let mut hi_node = next.as_mut();
+        for _ in lo + 1..hi {
+            hi_node = hi_node.and_then(|node| node.next.as_mut());
+        }
+        std::mem::swap(lo_value, &mut hi_node.unwrap().value);
+    }
+
+    /// Removes and returns the element at `index`, moving the last element into its place (so the
+    /// order of the remaining elements is not preserved). Returns None if `index` is out of bounds.
+    ///
+    /// This avoids re-linking anything in the middle of the list, but it is still O(n): a singly
+    /// linked list has to be walked to find its tail.
+    pub fn swap_remove(&mut self, index: usize) -> Option<T> {
+        if index >= self.size {
+            return None;
+        }
+        let last = self.pop_back()?;
+        if index == self.size {
+            // We were asked to remove the last element, which pop_back already did
+            return Some(last);
+        }
+        let node = self.node_at_mut(index)?;
+        Some(std::mem::replace(&mut node.value, last))
+    }
+
+    fn pop_back(&mut self) -> Option<T> {
+        let mut link = &mut self.head;
+        while link.as_ref()?.next.is_some() {
+            link = &mut link.as_mut().unwrap().next;
+        }
+        let node = link.take()?;
+        self.size -= 1;
+        Some(node.value)
+    }
+
+    fn node_at_mut(&mut self, index: usize) -> Option<&mut Box<Node<T>>> {
+        let mut cursor = self.head.as_mut();
+        for _ in 0..index {
+            cursor = cursor?.next.as_mut();
+        }
+        cursor
+    }
+
+    /// Returns the empty `next` slot at the end of the list (or the head slot if the list is empty)
+    fn last_link(&mut self) -> &mut Option<Box<Node<T>>> {
+        let mut link = &mut self.head;
+        while link.is_some() {
+            link = &mut link.as_mut().unwrap().next;
+        }
+        link
+    }
+
+    /// Returns true if every element is less than or equal to the one after it.
+    pub fn is_sorted(&self) -> bool
+    where
+        T: PartialOrd,
+    {
+        let mut current = &self.head;
+        while let Some(node) = current {
+            if let Some(next) = &node.next {
+                if node.value > next.value {
+                    return false;
+                }
+            }
+            current = &node.next;
+        }
+        true
+    }
+
+    /// Returns a reference to the first element that satisfies `pred`, walking from the front.
+    ///
+    /// There is deliberately no binary search: reaching the middle of a singly-linked list already
+    /// costs O(n) pointer hops, so bisecting a sorted list is never cheaper than this linear scan.
+    pub fn find<F: FnMut(&T) -> bool>(&self, mut pred: F) -> Option<&T> {
+        let mut current = &self.head;
+        while let Some(node) = current {
+            if pred(&node.value) {
+                return Some(&node.value);
+            }
+            current = &node.next;
+        }
+        None
+    }
+
+    /// Returns how many elements are equal to `value`.
+    pub fn count(&self, value: &T) -> usize
+    where
+        T: PartialEq,
+    {
+        let mut count = 0;
+        let mut current = &self.head;
+        while let Some(node) = current {
+            if node.value == *value {
+                count += 1;
+            }
+            current = &node.next;
+        }
+        count
+    }
+
+    /// Returns a reference to the smallest element, or None if the list is empty. If several
+    /// elements are equally small, the first of them is returned.
+    pub fn min(&self) -> Option<&T>
+    where
+        T: Ord,
+    {
+        self.extreme(|candidate, best| candidate < best)
+    }
+
+    /// Returns a reference to the largest element, or None if the list is empty. If several
+    /// elements are equally large, the first of them is returned.
+    pub fn max(&self) -> Option<&T>
+    where
+        T: Ord,
+    {
+        self.extreme(|candidate, best| candidate > best)
+    }
+
+    /// Builds a new list by applying `f` to each element from front to back. The original list is
+    /// left untouched.
+    pub fn map<U, F: FnMut(&T) -> U>(&self, mut f: F) -> LinkedList<U> {
+        let mut mapped = LinkedList::new();
+        let mut tail = &mut mapped.head;
+        let mut current = &self.head;
+        while let Some(node) = current {
+            tail = &mut tail.insert(Box::new(Node::new(f(&node.value), None))).next;
+            current = &node.next;
+        }
+        mapped.size = self.size;
+        mapped
+    }
+
+    /// Consumes the list, applying `f` to each element and collecting the `Some` results, in order,
+    /// into a new list.
+    pub fn filter_map<U, F: FnMut(T) -> Option<U>>(mut self, mut f: F) -> LinkedList<U> {
+        let mut kept = LinkedList::new();
+        let mut tail = &mut kept.head;
+        let mut current = self.head.take();
+        while let Some(mut node) = current {
+            current = node.next.take();
+            if let Some(value) = f(node.value) {
+                tail = &mut tail.insert(Box::new(Node::new(value, None))).next;
+                kept.size += 1;
+            }
+        }
+        kept
+    }
+
+    /// Consumes both lists, producing one that alternates between their elements (starting with
+    /// this list's first element). Once the shorter list runs out, the rest of the longer one is
+    /// appended as-is. Nodes are re-linked rather than cloned.
+    pub fn interleave(mut self, mut other: LinkedList<T>) -> LinkedList<T> {
+        let mut merged = LinkedList::new();
+        merged.size = self.size + other.size;
+        let mut tail = &mut merged.head;
+        let mut from_self = self.head.take();
+        let mut from_other = other.head.take();
+        while let Some(mut node) = from_self {
+            from_self = node.next.take();
+            tail = &mut tail.insert(node).next;
+            // Swap sources so the next node comes from the other list
+            std::mem::swap(&mut from_self, &mut from_other);
+        }
+        // Whichever list still has nodes left is already linked together, so it can be attached
+        // in one go
+        *tail = from_other;
+        merged
+    }
+
+    /// Walks the list once, replacing the best element so far only when `better` says the current
+    /// one beats it strictly, so ties keep the earliest element.
+    fn extreme<F: Fn(&T, &T) -> bool>(&self, better: F) -> Option<&T> {
+        let mut best = &self.head.as_ref()?.value;
+        let mut current = &self.head;
+        while let Some(node) = current {
+            if better(&node.value, best) {
+                best = &node.value;
+            }
+            current = &node.next;
+        }
+        Some(best)
+    }
 }
 
-pub struct LinkedListIter<'a, T: Copy> {
+/// Borrowing iterator returned by `(&list).into_iter()`. Yields a reference to each element, so
+/// it works for any T, not just Copy types.
+pub struct LinkedListIter<'a, T> {
     current: &'a Option<Box<Node<T>>>,
 }
 
-impl<T: Copy> Iterator for LinkedListIter<'_, T> {
-    type Item = T;
-    fn next(&mut self) -> Option<T> {
+impl<'a, T> Iterator for LinkedListIter<'a, T> {
+    type Item = &'a T;
+    fn next(&mut self) -> Option<&'a T> {
         match self.current {
             Some(node) => {
                 self.current = &node.next;
-                Some(node.value)
+                Some(&node.value)
             }
-            None => None, // YOU FILL THIS IN!
+            None => None,
         }
     }
 }
 
-impl<T> Iterator for LinkedList<T> {
+/// Owning iterator returned by `LinkedList::into_iter`. Pops elements off the front of the list
+/// it owns, so they are yielded by value, in order.
+///
+/// LinkedList doesn't implement Iterator itself: method lookup would then pick Iterator's
+/// consuming `count`, `min`, `max`, `map` and `is_sorted` over the list's own by-reference ones.
+/// Use `list.into_iter().next()` (or `pop_front`) where `list.next()` used to work.
+pub struct LinkedListIntoIter<T> {
+    list: LinkedList<T>,
+}
+
+impl<T> Iterator for LinkedListIntoIter<T> {
     type Item = T;
     fn next(&mut self) -> Option<T> {
-        self.pop_front()
+        self.list.pop_front()
     }
 }
 
-impl<'a, T: Copy> IntoIterator for &'a LinkedList<T> {
+impl<T> IntoIterator for LinkedList<T> {
     type Item = T;
+    type IntoIter = LinkedListIntoIter<T>;
+    fn into_iter(self) -> LinkedListIntoIter<T> {
+        LinkedListIntoIter { list: self }
+    }
+}
+
+impl<'a, T> IntoIterator for &'a LinkedList<T> {
+    type Item = &'a T;
     type IntoIter = LinkedListIter<'a, T>;
     fn into_iter(self) -> LinkedListIter<'a, T> {
         LinkedListIter {
@@ -147,3 +440,350 @@ impl<T> Drop for LinkedList<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn from_slice(values: &[u32]) -> LinkedList<u32> {
+        let mut list = LinkedList::new();
+        for val in values.iter().rev() {
+            list.push_front(*val);
+        }
+        list
+    }
+
+    #[test]
+    fn test_rotate_left() {
+        let mut list = from_slice(&[1, 2, 3, 4, 5]);
+        list.rotate_left(2);
+        assert!(list == from_slice(&[3, 4, 5, 1, 2]));
+        assert_eq!(list.get_size(), 5);
+        // n wraps around the size
+        list.rotate_left(7);
+        assert!(list == from_slice(&[5, 1, 2, 3, 4]));
+        assert_eq!(list.get_size(), 5);
+    }
+
+    #[test]
+    fn test_rotate_left_noops() {
+        let mut list = from_slice(&[1, 2, 3, 4, 5]);
+        list.rotate_left(0);
+        assert!(list == from_slice(&[1, 2, 3, 4, 5]));
+        list.rotate_left(5);
+        assert!(list == from_slice(&[1, 2, 3, 4, 5]));
+        assert_eq!(list.get_size(), 5);
+        // The list is still intact afterwards
+        assert_eq!(list.pop_front(), Some(1));
+        list.push_front(0);
+        assert!(list == from_slice(&[0, 2, 3, 4, 5]));
+    }
+
+    #[test]
+    fn test_rotate_left_empty() {
+        let mut list: LinkedList<u32> = LinkedList::new();
+        list.rotate_left(0);
+        list.rotate_left(3);
+        assert!(list.is_empty());
+        assert_eq!(list.get_size(), 0);
+    }
+
+    #[test]
+    fn test_is_sorted() {
+        assert!(from_slice(&[1, 2, 3, 4, 5]).is_sorted());
+        // Equal neighbours are still in order
+        assert!(from_slice(&[1, 2, 2, 3]).is_sorted());
+        assert!(from_slice(&[7]).is_sorted());
+    }
+
+    #[test]
+    fn test_is_sorted_unsorted() {
+        assert!(!from_slice(&[2, 1]).is_sorted());
+        assert!(!from_slice(&[1, 2, 3, 5, 4]).is_sorted());
+        assert!(!from_slice(&[5, 4, 3, 2, 1]).is_sorted());
+    }
+
+    #[test]
+    fn test_is_sorted_empty() {
+        assert!(LinkedList::<u32>::new().is_sorted());
+    }
+
+    #[test]
+    fn test_find_hit() {
+        let list = from_slice(&[1, 3, 4, 6, 8]);
+        // The first match wins
+        assert_eq!(list.find(|val| val % 2 == 0), Some(&4));
+        assert_eq!(list.find(|val| *val == 1), Some(&1));
+        assert_eq!(list.find(|val| *val > 7), Some(&8));
+    }
+
+    #[test]
+    fn test_find_miss() {
+        let list = from_slice(&[1, 3, 5]);
+        assert_eq!(list.find(|val| val % 2 == 0), None);
+        assert_eq!(LinkedList::<u32>::new().find(|_| true), None);
+    }
+
+    #[test]
+    fn test_partition() {
+        let list = from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        let (evens, odds) = list.partition(|val| val % 2 == 0);
+        // Both halves keep their original relative order
+        assert!(evens == from_slice(&[2, 4, 6, 8, 10]));
+        assert!(odds == from_slice(&[1, 3, 5, 7, 9]));
+        assert_eq!(evens.get_size(), 5);
+        assert_eq!(odds.get_size(), 5);
+    }
+
+    #[test]
+    fn test_partition_all_or_none_match() {
+        let (matching, rest) = from_slice(&[1, 2, 3]).partition(|_| true);
+        assert!(matching == from_slice(&[1, 2, 3]));
+        assert_eq!(matching.get_size(), 3);
+        assert!(rest.is_empty());
+        assert_eq!(rest.get_size(), 0);
+
+        let (matching, rest) = from_slice(&[1, 2, 3]).partition(|_| false);
+        assert!(matching.is_empty());
+        assert_eq!(matching.get_size(), 0);
+        assert!(rest == from_slice(&[1, 2, 3]));
+        assert_eq!(rest.get_size(), 3);
+    }
+
+    #[test]
+    fn test_partition_empty() {
+        let (matching, rest) = LinkedList::<u32>::new().partition(|_| true);
+        assert!(matching.is_empty());
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn test_take() {
+        let taken = from_slice(&[1, 2, 3, 4, 5]).take(3);
+        assert!(taken == from_slice(&[1, 2, 3]));
+        assert_eq!(taken.get_size(), 3);
+
+        let taken = from_slice(&[1, 2, 3, 4, 5]).take(0);
+        assert!(taken.is_empty());
+        assert_eq!(taken.get_size(), 0);
+    }
+
+    #[test]
+    fn test_take_whole_list() {
+        let taken = from_slice(&[1, 2, 3]).take(3);
+        assert!(taken == from_slice(&[1, 2, 3]));
+        assert_eq!(taken.get_size(), 3);
+
+        let taken = from_slice(&[1, 2, 3]).take(100);
+        assert!(taken == from_slice(&[1, 2, 3]));
+        assert_eq!(taken.get_size(), 3);
+    }
+
+    #[test]
+    fn test_skip() {
+        let skipped = from_slice(&[1, 2, 3, 4, 5]).skip(3);
+        assert!(skipped == from_slice(&[4, 5]));
+        assert_eq!(skipped.get_size(), 2);
+
+        let skipped = from_slice(&[1, 2, 3, 4, 5]).skip(0);
+        assert!(skipped == from_slice(&[1, 2, 3, 4, 5]));
+        assert_eq!(skipped.get_size(), 5);
+    }
+
+    #[test]
+    fn test_skip_whole_list() {
+        let skipped = from_slice(&[1, 2, 3]).skip(3);
+        assert!(skipped.is_empty());
+        assert_eq!(skipped.get_size(), 0);
+
+        let skipped = from_slice(&[1, 2, 3]).skip(100);
+        assert!(skipped.is_empty());
+        assert_eq!(skipped.get_size(), 0);
+    }
+
+    #[test]
+    fn test_swap() {
+        let mut list = from_slice(&[1, 2, 3, 4, 5]);
+        list.swap(0, 3);
+        assert!(list == from_slice(&[4, 2, 3, 1, 5]));
+        // The order of the indices doesn't matter
+        list.swap(3, 0);
+        assert!(list == from_slice(&[1, 2, 3, 4, 5]));
+        list.swap(0, 4);
+        assert!(list == from_slice(&[5, 2, 3, 4, 1]));
+        list.swap(2, 2);
+        assert!(list == from_slice(&[5, 2, 3, 4, 1]));
+        assert_eq!(list.get_size(), 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "out of bounds")]
+    fn test_swap_out_of_bounds() {
+        let mut list = from_slice(&[1, 2, 3]);
+        list.swap(0, 3);
+    }
+
+    #[test]
+    fn test_swap_remove() {
+        let mut list = from_slice(&[1, 2, 3, 4, 5]);
+        // The last element moves into the hole
+        assert_eq!(list.swap_remove(1), Some(2));
+        assert!(list == from_slice(&[1, 5, 3, 4]));
+        assert_eq!(list.get_size(), 4);
+        assert_eq!(list.swap_remove(0), Some(1));
+        assert!(list == from_slice(&[4, 5, 3]));
+        assert_eq!(list.get_size(), 3);
+    }
+
+    #[test]
+    fn test_swap_remove_last() {
+        let mut list = from_slice(&[1, 2, 3]);
+        assert_eq!(list.swap_remove(2), Some(3));
+        assert!(list == from_slice(&[1, 2]));
+        assert_eq!(list.get_size(), 2);
+        let mut single = from_slice(&[7]);
+        assert_eq!(single.swap_remove(0), Some(7));
+        assert!(single.is_empty());
+    }
+
+    #[test]
+    fn test_swap_remove_out_of_bounds() {
+        let mut list = from_slice(&[1, 2, 3]);
+        assert_eq!(list.swap_remove(3), None);
+        assert!(list == from_slice(&[1, 2, 3]));
+        assert_eq!(list.get_size(), 3);
+        assert_eq!(LinkedList::<u32>::new().swap_remove(0), None);
+    }
+
+    #[test]
+    fn test_count() {
+        let list = from_slice(&[2, 3, 2, 2, 1]);
+        assert_eq!(list.count(&2), 3);
+        assert_eq!(list.count(&1), 1);
+        assert_eq!(list.count(&7), 0);
+        assert_eq!(LinkedList::new().count(&7), 0);
+    }
+
+    #[test]
+    fn test_min_max() {
+        let list = from_slice(&[2, 3, 2, 2, 1]);
+        assert_eq!(list.min(), Some(&1));
+        assert_eq!(list.max(), Some(&3));
+        let single = from_slice(&[42]);
+        assert_eq!(single.min(), Some(&42));
+        assert_eq!(single.max(), Some(&42));
+    }
+
+    #[test]
+    fn test_min_max_ties_pick_first() {
+        let list = from_slice(&[1, 5, 1, 5]);
+        let first_one = list.find(|val| *val == 1).unwrap();
+        let first_five = list.find(|val| *val == 5).unwrap();
+        assert!(std::ptr::eq(list.min().unwrap(), first_one));
+        assert!(std::ptr::eq(list.max().unwrap(), first_five));
+    }
+
+    #[test]
+    fn test_min_max_empty() {
+        assert_eq!(LinkedList::<u32>::new().min(), None);
+        assert_eq!(LinkedList::<u32>::new().max(), None);
+    }
+
+    #[test]
+    fn test_map() {
+        let list = from_slice(&[10, 2, 7]);
+        let strings: LinkedList<String> = list.map(|val| val.to_string());
+        assert_eq!(strings.get_size(), 3);
+        assert_eq!(strings.to_string(), " 10 2 7");
+        assert_eq!(strings.find(|_| true), Some(&"10".to_string()));
+        // The original list is only borrowed
+        assert!(list == from_slice(&[10, 2, 7]));
+    }
+
+    #[test]
+    fn test_map_empty() {
+        let doubled = LinkedList::<u32>::new().map(|val| val * 2);
+        assert!(doubled.is_empty());
+        assert_eq!(doubled.get_size(), 0);
+    }
+
+    #[test]
+    fn test_filter_map() {
+        let list = from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]);
+        let tens = list.filter_map(|val| if val % 2 == 0 { Some(val * 10) } else { None });
+        assert!(tens == from_slice(&[20, 40, 60, 80, 100]));
+        assert_eq!(tens.get_size(), 5);
+        let strings = from_slice(&[1, 2]).filter_map(|val| Some(val.to_string()));
+        assert_eq!(strings.to_string(), " 1 2");
+        assert_eq!(strings.get_size(), 2);
+    }
+
+    #[test]
+    fn test_filter_map_filters_everything() {
+        let none = from_slice(&[1, 2, 3]).filter_map(|_| None::<u32>);
+        assert!(none.is_empty());
+        assert_eq!(none.get_size(), 0);
+        assert!(LinkedList::<u32>::new().filter_map(Some).is_empty());
+    }
+
+    #[test]
+    fn test_interleave() {
+        let woven = from_slice(&[1, 3]).interleave(from_slice(&[2, 4]));
+        assert!(woven == from_slice(&[1, 2, 3, 4]));
+        assert_eq!(woven.get_size(), 4);
+    }
+
+    #[test]
+    fn test_interleave_uneven() {
+        // Whatever is left of the longer list goes on the end
+        let woven = from_slice(&[1, 3, 5]).interleave(from_slice(&[2, 4]));
+        assert!(woven == from_slice(&[1, 2, 3, 4, 5]));
+        assert_eq!(woven.get_size(), 5);
+        let woven = from_slice(&[1]).interleave(from_slice(&[2, 4, 6]));
+        assert!(woven == from_slice(&[1, 2, 4, 6]));
+        assert_eq!(woven.get_size(), 4);
+    }
+
+    #[test]
+    fn test_interleave_empty() {
+        let woven = LinkedList::new().interleave(from_slice(&[2, 4]));
+        assert!(woven == from_slice(&[2, 4]));
+        assert_eq!(woven.get_size(), 2);
+        let woven = from_slice(&[1, 3]).interleave(LinkedList::new());
+        assert!(woven == from_slice(&[1, 3]));
+        assert_eq!(woven.get_size(), 2);
+        let woven = LinkedList::<u32>::new().interleave(LinkedList::new());
+        assert!(woven.is_empty());
+    }
+
+    #[test]
+    fn test_into_iter_by_value() {
+        let words = ["list", "linked", "a"].map(String::from);
+        let mut list = LinkedList::new();
+        for word in words.iter().rev() {
+            list.push_front(word.clone());
+        }
+        // Elements come out owned, front to back
+        let collected: Vec<String> = list.into_iter().collect();
+        assert_eq!(collected, words);
+    }
+
+    #[test]
+    fn test_into_iter_next() {
+        let mut iter = from_slice(&[1, 2, 3]).into_iter();
+        assert_eq!(iter.next(), Some(1));
+        assert_eq!(iter.next(), Some(2));
+        assert_eq!(iter.next(), Some(3));
+        assert_eq!(iter.next(), None);
+        assert_eq!(LinkedList::<u32>::new().into_iter().next(), None);
+    }
+
+    #[test]
+    fn test_for_loop_by_value() {
+        let mut sum = 0;
+        for val in from_slice(&[1, 2, 3, 4]) {
+            sum += val;
+        }
+        assert_eq!(sum, 10);
+    }
+}
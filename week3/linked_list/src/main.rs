@@ -15,10 +15,19 @@ fn main() {
     println!("size: {}", list.get_size());
     println!("{}", list.to_string()); // ToString impl for anything impl Display
 
-    // If you implement iterator trait:
-    //for val in &list {
-    //    println!("{}", val);
-    //}
+    // Iterating by reference doesn't consume the list
+    for val in &list {
+        println!("{}", val);
+    }
+    let mut words: LinkedList<String> = LinkedList::new();
+    for word in ["list", "linked", "a"] {
+        words.push_front(word.to_string());
+    }
+    let mut sentence = String::new();
+    for word in &words {
+        sentence += word;
+    }
+    assert_eq!(sentence, "alinkedlist");
     // Clone traits
     println!("list now is {}" , list);
     println!("After Clone======================"); 
@@ -28,6 +37,73 @@ fn main() {
     // PartialEq traits
     println!("list = list_clone ? {} " , list == list_clone);
 
+    // Searching
+    println!("list is sorted ? {}", list.is_sorted());
+    println!("first even element: {:?}", list.find(|val| val % 2 == 0));
+
+    // Rotation
+    let mut rotated: LinkedList<u32> = LinkedList::new();
+    for i in (1..=5).rev() {
+        rotated.push_front(i);
+    }
+    rotated.rotate_left(2);
+    println!("[1,2,3,4,5] rotated left by 2 is {}", rotated);
+    assert_eq!(rotated.to_string(), " 3 4 5 1 2");
+    rotated.rotate_left(5);
+    assert_eq!(rotated.to_string(), " 3 4 5 1 2");
+    assert_eq!(rotated.get_size(), 5);
+
+    // Partitioning
+    let mut numbers: LinkedList<u32> = LinkedList::new();
+    for i in (1..=10).rev() {
+        numbers.push_front(i);
+    }
+    let (evens, odds) = numbers.partition(|val| val % 2 == 0);
+    println!("evens: {}, odds: {}", evens, odds);
+
+    // Taking and skipping
+    println!("first 3: {}, after 3: {}", list.clone().take(3), list.clone().skip(3));
+
+    // Swapping
+    let mut letters: LinkedList<char> = LinkedList::new();
+    for letter in ['e', 'd', 'c', 'b', 'a'] {
+        letters.push_front(letter);
+    }
+    letters.swap(0, 3);
+    letters.swap_remove(1);
+    println!("after swaps: {}", letters);
+
+    // Counting
+    let mut repeated: LinkedList<u32> = LinkedList::new();
+    for i in [1, 2, 2, 3, 2] {
+        repeated.push_front(i);
+    }
+    println!("2 appears {} times in{}", repeated.count(&2), repeated);
+
+    // Min and max
+    println!("min and max of{} are {:?} and {:?}", list, list.min(), list.max());
+
+    // Mapping
+    println!("doubled: {}", list.map(|val| val * 2));
+
+    // Filter-mapping
+    let mut one_to_ten: LinkedList<u32> = LinkedList::new();
+    for i in (1..=10).rev() {
+        one_to_ten.push_front(i);
+    }
+    let tens = one_to_ten.filter_map(|val| if val % 2 == 0 { Some(val * 10) } else { None });
+    println!("evens times ten: {}", tens);
+
+    // Interleaving
+    let mut odds: LinkedList<u32> = LinkedList::new();
+    let mut evens: LinkedList<u32> = LinkedList::new();
+    for i in (1..=5).rev() {
+        odds.push_front(2 * i - 1);
+        evens.push_front(2 * i);
+    }
+    let woven = odds.interleave(evens);
+    println!("interleaved: {}", woven);
+
     for i in list{
         println!("{}" , i);
     }
@@ -1,44 +1,165 @@
+mod thread_pool;
+
 use crossbeam_channel;
+use std::collections::HashMap;
 use std::{thread, time};
+use thread_pool::ThreadPool;
 
-fn parallel_map<T, U, F>(mut input_vec: Vec<T>, num_threads: usize, f: F) -> Vec<U>
+/// Applies `f` to every element of `input_vec` using `num_threads` worker threads, returning the
+/// results in the same order as the input.
+///
+/// If `f` panics on any element, the panic is re-raised on the calling thread with its original
+/// payload once all the workers have been joined, rather than handing back a partial result.
+/// Panics if `num_threads` is 0.
+fn parallel_map<T, U, F>(input_vec: Vec<T>, num_threads: usize, f: F) -> Vec<U>
 where
     F: FnOnce(T) -> U + Send + Copy + 'static,
     T: Send + 'static,
-    U: Send + 'static + Default,
+    U: Send + 'static,
+{
+    parallel_map_pinned(input_vec, num_threads, &[], f)
+}
+
+/// Same as `parallel_map`, but worker `i` is pinned to the CPU core `core_ids[i]` before it starts
+/// processing, which can help cache locality for CPU-bound `f`. Workers beyond the end of
+/// `core_ids` (or whose core can't be pinned to) run unpinned.
+fn parallel_map_pinned<T, U, F>(
+    input_vec: Vec<T>,
+    num_threads: usize,
+    core_ids: &[usize],
+    f: F,
+) -> Vec<U>
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
 {
     let mut output_vec: Vec<U> = Vec::with_capacity(input_vec.len());
-    // TODO: implement parallel map!
+    let (receiver_output, threads) = spawn_workers(input_vec, num_threads, core_ids, f);
+    join_workers(threads);
+    // Results arrive in whatever order the workers finished them; put them back in input order
+    let mut indexed_output: Vec<(usize, U)> = receiver_output.iter().collect();
+    indexed_output.sort_by_key(|(index, _)| *index);
+    output_vec.extend(indexed_output.into_iter().map(|(_, value)| value));
+    output_vec
+}
+
+/// Like `parallel_map`, but returns an iterator that yields each result (in input order) as soon
+/// as it and everything before it have been computed, instead of waiting for the whole Vec.
+/// Results that finish early are buffered until it's their turn.
+fn parallel_map_ordered_stream<T, U, F>(
+    input_vec: Vec<T>,
+    num_threads: usize,
+    f: F,
+) -> impl Iterator<Item = U>
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    let (receiver, workers) = spawn_workers(input_vec, num_threads, &[], f);
+    OrderedStream {
+        receiver,
+        pending: HashMap::new(),
+        next_index: 0,
+        workers,
+    }
+}
+
+struct OrderedStream<U> {
+    receiver: crossbeam_channel::Receiver<(usize, U)>,
+    /// Results that arrived before the one at next_index, keyed by input index
+    pending: HashMap<usize, U>,
+    next_index: usize,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl<U> Iterator for OrderedStream<U> {
+    type Item = U;
+    fn next(&mut self) -> Option<U> {
+        loop {
+            if let Some(value) = self.pending.remove(&self.next_index) {
+                self.next_index += 1;
+                return Some(value);
+            }
+            match self.receiver.recv() {
+                Ok((index, value)) => {
+                    self.pending.insert(index, value);
+                }
+                // Every worker is gone. If that was because one panicked, re-raise it here.
+                Err(_) => {
+                    join_workers(self.workers.drain(..).collect());
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// Spawns `num_threads` workers that apply `f` to `input_vec`, pinning worker `i` to
+/// `core_ids[i]` if given. Returns the channel the workers send `(index, f(value))` pairs on (in
+/// completion order) and their join handles.
+///
+/// Panics if `num_threads` is 0, since nothing would ever process the input.
+fn spawn_workers<T, U, F>(
+    input_vec: Vec<T>,
+    num_threads: usize,
+    core_ids: &[usize],
+    f: F,
+) -> (crossbeam_channel::Receiver<(usize, U)>, Vec<thread::JoinHandle<()>>)
+where
+    F: FnOnce(T) -> U + Send + Copy + 'static,
+    T: Send + 'static,
+    U: Send + 'static,
+{
+    assert!(num_threads > 0, "num_threads must be at least 1");
     let (sender_input , receiver_input) = crossbeam_channel::unbounded();
     let (sender_output , receiver_output) = crossbeam_channel::unbounded();
     let mut threads = Vec::new();
     // spawn threads , get input from receiver_input , send output to sender_output
-    for _ in 0..num_threads {
+    for worker in 0..num_threads {
         let receiver_input = receiver_input.clone();
         let sender_output = sender_output.clone();
+        let core_id = core_ids.get(worker).map(|&id| core_affinity::CoreId { id });
         threads.push(thread::spawn(move || {
+            if let Some(core_id) = core_id {
+                core_affinity::set_for_current(core_id);
+            }
             while let Ok((index , value)) = receiver_input.recv(){
-                sender_output.send((index , f(value))).expect("Trying to send back f(value) , but there is no receivers");
+                // The receiver only goes away if the caller stopped listening (e.g. dropped the
+                // stream early), in which case the result isn't wanted anyway
+                if sender_output.send((index , f(value))).is_err() {
+                    break;
+                }
             }
             drop(sender_output);
         }));
     }
-    let mut count = 0;
-    for value in input_vec {
-        sender_input.send((count,value)).expect("Trying to send input , but there is no receivers");
-        count += 1;
+    drop(receiver_input);
+    for (index, value) in input_vec.into_iter().enumerate() {
+        // If every worker has already died (i.e. they all panicked), there is nobody left to
+        // send to. Stop feeding input; the panic is reported when the workers are joined.
+        if sender_input.send((index, value)).is_err() {
+            break;
+        }
     }
     drop(sender_input);
     drop(sender_output);
+    (receiver_output, threads)
+}
+
+/// Joins every worker, then re-raises the first worker panic (if any) on the calling thread with
+/// its original payload.
+fn join_workers(threads: Vec<thread::JoinHandle<()>>) {
+    let mut panic_payload = None;
     for thread in threads {
-        thread.join().expect("Panic occurred in thread");
+        if let Err(payload) = thread.join() {
+            panic_payload.get_or_insert(payload);
+        }
     }
-    output_vec.resize_with(output_vec.capacity(), Default::default);
-    // println!("output_vec with len {}" , output_vec.len());
-    while let Ok((index , value)) = receiver_output.recv(){
-        output_vec[index] = value;
+    if let Some(payload) = panic_payload {
+        std::panic::resume_unwind(payload);
     }
-    output_vec
 }
 
 fn main() {
@@ -49,4 +170,147 @@ fn main() {
         num * num
     });
     println!("squares: {:?}", squares);
+
+    // Pin the workers to the first couple of cores
+    let v: Vec<u64> = (1..=20).collect();
+    let pinned_squares = parallel_map_pinned(v, 4, &[0, 1], |num| num * num);
+    println!("pinned squares: {:?}", pinned_squares);
+
+    // Stream results in order as they become ready
+    let v: Vec<u64> = (1..=20).collect();
+    let streamed: Vec<u64> = parallel_map_ordered_stream(v, 4, |num| num * 3).collect();
+    println!("streamed: {:?}", streamed);
+
+    // A persistent pool reuses the same workers across several maps
+    let pool = ThreadPool::new(4);
+    let doubled = pool.map((1..=10).collect(), |num: u32| num * 2);
+    let lengths = pool.map(vec!["a", "bb", "ccc"], |s: &str| s.len());
+    println!("doubled: {:?}, lengths: {:?}", doubled, lengths);
+    let worker_panics = pool.shutdown();
+    assert!(worker_panics.is_empty());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_parallel_map_keeps_input_order() {
+        let v: Vec<u64> = (1..=50).collect();
+        let expected: Vec<u64> = v.iter().map(|num| num * num).collect();
+        assert_eq!(parallel_map(v, 7, |num| num * num), expected);
+    }
+
+    #[test]
+    fn test_parallel_map_empty_input() {
+        let squares = parallel_map(Vec::<u64>::new(), 4, |num| num * num);
+        assert!(squares.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "bad input 13")]
+    fn test_parallel_map_reraises_closure_panic() {
+        let v: Vec<u64> = (1..=20).collect();
+        parallel_map(v, 4, |num| {
+            if num == 13 {
+                panic!("bad input {}", num);
+            }
+            num * num
+        });
+    }
+
+    #[test]
+    fn test_parallel_map_panic_is_not_a_result() {
+        // Every worker panics, so none of them is left to take the rest of the input
+        let result = std::panic::catch_unwind(|| {
+            parallel_map((0..100).collect::<Vec<u32>>(), 3, |num: u32| -> u32 {
+                panic!("bad input {}", num)
+            })
+        });
+        let payload = result.expect_err("parallel_map should not return a result");
+        let message = payload.downcast_ref::<String>().unwrap();
+        assert!(message.starts_with("bad input "));
+    }
+
+    #[test]
+    fn test_parallel_map_pinned_keeps_input_order() {
+        // Pinning shouldn't change the answer
+        let v: Vec<u64> = (1..=50).collect();
+        let expected: Vec<u64> = v.iter().map(|num| num * num).collect();
+        assert_eq!(parallel_map_pinned(v, 4, &[0, 1], |num| num * num), expected);
+    }
+
+    #[test]
+    fn test_parallel_map_pinned_no_cores() {
+        // Every worker runs unpinned
+        let v: Vec<u64> = (1..=20).collect();
+        let expected: Vec<u64> = v.iter().map(|num| num + 1).collect();
+        assert_eq!(parallel_map_pinned(v, 3, &[], |num| num + 1), expected);
+    }
+
+    #[test]
+    fn test_parallel_map_pinned_more_cores_than_threads() {
+        // The cores past the last worker are simply unused
+        let v: Vec<u64> = (1..=20).collect();
+        let expected: Vec<u64> = v.iter().map(|num| num * 2).collect();
+        let core_ids: Vec<usize> = (0..16).collect();
+        assert_eq!(parallel_map_pinned(v, 2, &core_ids, |num| num * 2), expected);
+    }
+
+    #[test]
+    fn test_ordered_stream_reorders_results() {
+        // Later elements sleep less, so they finish before the earlier ones and the stream has to
+        // hold them back until it's their turn
+        let finished = Arc::new(Mutex::new(Vec::new()));
+        let v: Vec<(u64, Arc<Mutex<Vec<u64>>>)> =
+            (0..8).map(|num| (num, finished.clone())).collect();
+        let streamed: Vec<u64> = parallel_map_ordered_stream(v, 8, |(num, finished)| {
+            thread::sleep(time::Duration::from_millis(20 * (8 - num)));
+            finished.lock().unwrap().push(num);
+            num * 3
+        })
+        .collect();
+        assert_eq!(streamed, (0..8).map(|num| num * 3).collect::<Vec<u64>>());
+        // (The results really did come in out of order)
+        assert_ne!(*finished.lock().unwrap(), (0..8).collect::<Vec<u64>>());
+    }
+
+    #[test]
+    fn test_ordered_stream_empty_input() {
+        let mut stream = parallel_map_ordered_stream(Vec::<u64>::new(), 4, |num| num * 3);
+        assert_eq!(stream.next(), None);
+    }
+
+    #[test]
+    fn test_ordered_stream_single_thread() {
+        let v: Vec<u64> = (1..=20).collect();
+        let expected: Vec<u64> = v.iter().map(|num| num * 3).collect();
+        let streamed: Vec<u64> = parallel_map_ordered_stream(v, 1, |num| num * 3).collect();
+        assert_eq!(streamed, expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "bad input 5")]
+    fn test_ordered_stream_reraises_closure_panic() {
+        let stream = parallel_map_ordered_stream((1..=10).collect(), 2, |num: u32| {
+            if num == 5 {
+                panic!("bad input {}", num);
+            }
+            num
+        });
+        for _ in stream {}
+    }
+
+    #[test]
+    #[should_panic(expected = "num_threads must be at least 1")]
+    fn test_parallel_map_rejects_zero_threads() {
+        parallel_map(vec![1, 2, 3], 0, |num: u32| num * 2);
+    }
+
+    #[test]
+    #[should_panic(expected = "num_threads must be at least 1")]
+    fn test_ordered_stream_rejects_zero_threads() {
+        let _ = parallel_map_ordered_stream(vec![1, 2, 3], 0, |num: u32| num * 2);
+    }
 }
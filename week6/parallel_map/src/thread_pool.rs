@@ -0,0 +1,185 @@
+use std::any::Any;
+use std::thread;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// A fixed set of worker threads that can run many `map` calls without re-spawning threads each
+/// time. Call `shutdown` when finished with the pool to join the workers.
+pub struct ThreadPool {
+    sender: Option<crossbeam_channel::Sender<Job>>,
+    workers: Vec<thread::JoinHandle<()>>,
+}
+
+impl ThreadPool {
+    /// Spawns a pool of `num_threads` workers. Panics if `num_threads` is 0, since nothing would
+    /// ever run the pool's jobs.
+    pub fn new(num_threads: usize) -> ThreadPool {
+        assert!(num_threads > 0, "num_threads must be at least 1");
+        let (sender, receiver) = crossbeam_channel::unbounded::<Job>();
+        let mut workers = Vec::new();
+        for _ in 0..num_threads {
+            let receiver = receiver.clone();
+            workers.push(thread::spawn(move || {
+                // Runs until the pool drops its sender and the queue is drained
+                while let Ok(job) = receiver.recv() {
+                    job();
+                }
+            }));
+        }
+        ThreadPool {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    /// Applies `f` to every element of `input_vec` on the pool's workers, returning the results in
+    /// input order.
+    ///
+    /// A panic in `f` takes down the worker that ran it (its payload is returned by `shutdown`),
+    /// and this call panics rather than returning an incomplete result.
+    pub fn map<T, U, F>(&self, input_vec: Vec<T>, f: F) -> Vec<U>
+    where
+        F: FnOnce(T) -> U + Send + Copy + 'static,
+        T: Send + 'static,
+        U: Send + 'static,
+    {
+        let sender = self
+            .sender
+            .as_ref()
+            .expect("ThreadPool used after shutdown");
+        let num_inputs = input_vec.len();
+        let (sender_output, receiver_output) = crossbeam_channel::unbounded();
+        for (index, value) in input_vec.into_iter().enumerate() {
+            let sender_output = sender_output.clone();
+            let job: Job = Box::new(move || {
+                // The receiver only goes away if map itself panicked, so there's nobody to tell
+                let _ = sender_output.send((index, f(value)));
+            });
+            sender
+                .send(job)
+                .expect("Trying to send a job, but every worker has died");
+        }
+        // Each job owns a clone of sender_output, so the iterator below ends once every job has
+        // either sent its result or been dropped by a panicking worker
+        drop(sender_output);
+        let mut indexed_output: Vec<(usize, U)> = receiver_output.iter().collect();
+        assert_eq!(
+            indexed_output.len(),
+            num_inputs,
+            "A worker panicked while running ThreadPool::map"
+        );
+        indexed_output.sort_by_key(|(index, _)| *index);
+        indexed_output.into_iter().map(|(_, value)| value).collect()
+    }
+
+    /// Stops accepting work, waits for the workers to finish whatever is queued, and joins them.
+    /// Returns the panic payloads of any workers that died along the way.
+    pub fn shutdown(mut self) -> Vec<Box<dyn Any + Send + 'static>> {
+        self.join_workers()
+    }
+
+    fn join_workers(&mut self) -> Vec<Box<dyn Any + Send + 'static>> {
+        // Dropping the only sender makes each worker's recv() fail once the queue is empty
+        drop(self.sender.take());
+        self.workers
+            .drain(..)
+            .filter_map(|worker| worker.join().err())
+            .collect()
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        if self.sender.is_some() {
+            eprintln!("Warning: ThreadPool dropped without calling shutdown(); joining workers");
+            let panics = self.join_workers();
+            if !panics.is_empty() {
+                eprintln!(
+                    "Warning: {} ThreadPool worker(s) had panicked",
+                    panics.len()
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::sync::{Arc, Barrier};
+
+    thread_local! {
+        /// Held by a worker thread until it exits
+        static WORKER_TOKEN: RefCell<Option<Arc<()>>> = const { RefCell::new(None) };
+    }
+
+    /// Hands each of the pool's `num_threads` workers a clone of `token`, which it holds until
+    /// its thread exits. The barrier keeps any worker from taking two of the jobs.
+    fn hand_out_tokens(pool: &ThreadPool, num_threads: usize, token: &Arc<()>) {
+        let barrier = Arc::new(Barrier::new(num_threads + 1));
+        for _ in 0..num_threads {
+            let token = token.clone();
+            let barrier = barrier.clone();
+            let job: Job = Box::new(move || {
+                WORKER_TOKEN.with(|held| *held.borrow_mut() = Some(token));
+                barrier.wait();
+            });
+            pool.sender.as_ref().unwrap().send(job).unwrap();
+        }
+        barrier.wait();
+    }
+
+    #[test]
+    fn test_shutdown_joins_every_worker() {
+        let pool = ThreadPool::new(4);
+        let token = Arc::new(());
+        hand_out_tokens(&pool, 4, &token);
+        assert_eq!(Arc::strong_count(&token), 5);
+
+        assert_eq!(
+            pool.map((1..=10).collect(), |num: u32| num * 2),
+            (1..=10).map(|num| num * 2).collect::<Vec<u32>>()
+        );
+        assert_eq!(
+            pool.map(vec!["a", "bb", "ccc"], |s: &str| s.len()),
+            vec![1, 2, 3]
+        );
+
+        assert!(pool.shutdown().is_empty());
+        // Every worker thread has exited (dropping its token) by the time shutdown returns
+        assert_eq!(Arc::strong_count(&token), 1);
+    }
+
+    #[test]
+    fn test_drop_joins_every_worker() {
+        let pool = ThreadPool::new(3);
+        let token = Arc::new(());
+        hand_out_tokens(&pool, 3, &token);
+        drop(pool);
+        assert_eq!(Arc::strong_count(&token), 1);
+    }
+
+    #[test]
+    fn test_shutdown_returns_worker_panics() {
+        let pool = ThreadPool::new(2);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            pool.map(vec![1, 2, 3], |num: u32| {
+                if num == 2 {
+                    panic!("bad input {}", num);
+                }
+                num
+            })
+        }));
+        assert!(result.is_err());
+        let panics = pool.shutdown();
+        assert_eq!(panics.len(), 1);
+        assert_eq!(panics[0].downcast_ref::<String>().unwrap(), "bad input 2");
+    }
+
+    #[test]
+    #[should_panic(expected = "num_threads must be at least 1")]
+    fn test_new_rejects_zero_threads() {
+        ThreadPool::new(0);
+    }
+}